@@ -1,4 +1,25 @@
+use crate::diagnostics::{
+    DiagnosticsDisplay, SharedDiagnostics, WorkspaceDocumentDiagnosticReport,
+};
+use crate::file_watcher::{FileSystemWatcher, FileWatcher};
+use crate::general::{SharedCapabilities, ShutdownState};
+use crate::language_features::code_lens::BufferCodeLenses;
+use crate::language_features::codeaction::SharedCodeActions;
+use crate::language_features::completion::{IncompleteCompletion, SharedCompletions};
+use crate::language_features::goto::PartialReferences;
+use crate::language_features::highlights::BufferHighlights;
+use crate::language_features::hover::SharedHovers;
+use crate::language_features::inlay_hints::BufferInlayHints;
+use crate::language_features::on_type_formatting::TypedText;
+use crate::language_features::selection_range::SelectionRangeState;
+use crate::language_features::semantic_tokens::SemanticTokensState;
+use crate::language_features::signature_help::SignatureHelpState;
+use crate::language_features::type_hierarchy::TypeHierarchyItem;
+use crate::progress::WorkDoneProgressState;
+use crate::text_sync::PendingDidChange;
 use crate::types::*;
+use crate::util::editor_quote;
+use crate::workspace::{ChunkedEdit, PendingEdit, WorkspaceSymbolItem};
 use crossbeam_channel::Sender;
 use jsonrpc_core::{self, Call, Error, Failure, Id, Output, Success, Value, Version};
 use lsp_types::notification::Notification;
@@ -6,8 +27,10 @@ use lsp_types::request::*;
 use lsp_types::*;
 use ropey;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::time::{Duration, Instant};
+use url::Url;
 
 // Copy of Kakoune's timestamped buffer content.
 pub struct Document {
@@ -21,28 +44,112 @@ pub struct Document {
 }
 
 pub type ResponsesCallback = Box<dyn FnOnce(&mut Context, EditorMeta, Vec<Value>) -> ()>;
+pub type DebouncedCallback = Box<dyn FnOnce(&mut Context) -> ()>;
 type BatchNumber = usize;
 type BatchCount = BatchNumber;
 
+/// Requests whose results are only of interest until the same client asks again, e.g. hover
+/// after the cursor has moved on.
+const SUPERSEDABLE_METHODS: &[&str] = &[
+    request::Completion::METHOD,
+    request::HoverRequest::METHOD,
+    request::SignatureHelpRequest::METHOD,
+];
+
 pub struct Context {
     batch_counter: BatchNumber,
     pub batches:
         HashMap<BatchNumber, (BatchCount, Vec<serde_json::value::Value>, ResponsesCallback)>,
+    pub call_hierarchy_item: Option<PreparedHierarchyItem<CallHierarchyItem>>,
+    // Edit being applied chunk by chunk, see edit_chunk_size, and how many have been so far.
+    pub chunked_edit: Option<ChunkedEdit>,
+    pub chunked_edits: u64,
+    // Requests cancelled as superseded, their late responses are dropped.
+    pub cancelled_requests: HashSet<Id>,
     pub capabilities: Option<ServerCapabilities>,
+    pub code_actions: Vec<CodeAction>,
+    pub code_lenses: HashMap<String, BufferCodeLenses>,
+    pub completion_items: Vec<CompletionItem>,
     pub config: Config,
+    // Callbacks waiting for their deadline, keyed by what they update, e.g. a buffer's inlay hints.
+    pub debounced: HashMap<String, (Instant, DebouncedCallback)>,
+    // Less severe diagnostics are not shown, from Kakoune's lsp_diagnostic_min_severity option.
+    pub diagnostic_min_severity: DiagnosticSeverity,
     pub diagnostics: HashMap<String, Vec<Diagnostic>>,
+    // How diagnostics are presented, switched with lsp-diagnostics-display.
+    pub diagnostics_display: DiagnosticsDisplay,
+    // Width of the window diagnostics shown inline should fit into, if known.
+    pub diagnostics_window_width: Option<usize>,
+    // Delay before showing published diagnostics, from Kakoune's lsp_debounce_diagnostics option.
+    pub diagnostics_debounce: Duration,
+    pub document_colors: HashMap<String, Vec<ColorInformation>>,
+    pub document_highlights: HashMap<String, BufferHighlights>,
+    pub document_links: HashMap<String, Vec<DocumentLink>>,
     pub editor_tx: Sender<EditorResponse>,
+    pub file_watcher: Option<FileWatcher>,
+    // Watchers registered by the server, by registration id.
+    pub file_watchers: HashMap<String, Vec<FileSystemWatcher>>,
+    pub incomplete_completion: Option<IncompleteCompletion>,
+    pub inlay_hints: HashMap<String, BufferInlayHints>,
     pub lang_srv_tx: Sender<ServerMessage>,
     pub language_id: String,
+    // Buffer changes held back for a moment to coalesce rapid edits, see text_sync.
+    pub pending_did_change: HashMap<String, PendingDidChange>,
+    // Edit of a rename or code action previewed to the user, until they apply or cancel it.
+    pub pending_edit: Option<PendingEdit>,
     pub pending_requests: Vec<EditorRequest>,
     pub request_counter: u64,
     pub response_waitlist: HashMap<Id, (EditorMeta, &'static str, BatchNumber)>,
     pub root_path: String,
     pub session: SessionId,
+    // Settings from Kakoune's lsp_server_configuration option.
+    pub settings: serde_json::Map<String, Value>,
+    // Capabilities of all language servers of the editor session.
+    pub shared_capabilities: SharedCapabilities,
+    // Diagnostics of all language servers of the editor session, to show them along with ours.
+    pub shared_diagnostics: SharedDiagnostics,
+    // Answers of all language servers of the editor session to requests sent to several of them.
+    pub shared_completions: SharedCompletions,
+    pub shared_hovers: SharedHovers,
+    pub shared_code_actions: SharedCodeActions,
     pub documents: HashMap<String, Document>,
     pub offset_encoding: OffsetEncoding,
+    // References received so far via $/progress for in-flight textDocument/references requests,
+    // by their partial result token.
+    pub partial_references: HashMap<String, PartialReferences>,
+    // In-flight requests never superseded by newer ones, e.g. hover info the user asked for.
+    pub pinned_requests: HashSet<Id>,
+    // Edits of renames and code actions are previewed first, see lsp-preview-edits-enable.
+    pub preview_edits: bool,
+    pub selection_ranges: HashMap<String, SelectionRangeState>,
     pub semantic_highlighting_faces: Vec<String>,
     pub semantic_highlighting_lines: HashMap<String, Vec<SemanticHighlightingInformation>>,
+    pub semantic_tokens: HashMap<String, SemanticTokensState>,
+    // Name and version the server identified itself with, if any.
+    pub server_info: Option<ServerInfo>,
+    // Progress of shutting the language server down.
+    pub shutdown: ShutdownState,
+    pub signature_help: Option<SignatureHelpState>,
+    // Files whose diagnostics are left from a language server which crashed, until the new one
+    // publishes its own.
+    pub stale_diagnostics: HashSet<String>,
+    pub type_hierarchy_item: Option<PreparedHierarchyItem<TypeHierarchyItem>>,
+    // Whether the server supports type hierarchy, not covered by lsp-types yet.
+    pub type_hierarchy_provider: bool,
+    pub typed_text: HashMap<String, TypedText>,
+    // Operations in progress the server created work done tokens for.
+    pub work_done_progress: HashMap<NumberOrString, WorkDoneProgressState>,
+    // Partial results of in-flight workspace/diagnostic requests by their partial result token,
+    // streamed via $/progress.
+    pub workspace_diagnostic_chunks: HashMap<String, Vec<WorkspaceDocumentDiagnosticReport>>,
+    // Diagnostics of files not open in the editor as last reported by workspace/diagnostic.
+    pub workspace_diagnostics: HashMap<String, Vec<Diagnostic>>,
+    // Whether the server supports workspace/diagnostic, not covered by lsp-types yet.
+    pub workspace_diagnostics_provider: bool,
+    // Starts with the project root, more folders can be added by the user.
+    pub workspace_folders: Vec<WorkspaceFolder>,
+    // Last listed workspace symbols along with their lines in the symbols buffer.
+    pub workspace_symbols: Vec<(String, WorkspaceSymbolItem)>,
 }
 
 impl Context {
@@ -56,24 +163,73 @@ impl Context {
         offset_encoding: OffsetEncoding,
     ) -> Self {
         let session = initial_request.meta.session.clone();
+        let workspace_folders = vec![WorkspaceFolder {
+            uri: Url::from_file_path(&root_path).unwrap(),
+            name: root_path.clone(),
+        }];
         Context {
             batch_counter: 0,
             batches: HashMap::default(),
+            call_hierarchy_item: None,
+            chunked_edit: None,
+            chunked_edits: 0,
+            cancelled_requests: HashSet::default(),
             capabilities: None,
+            code_actions: Vec::new(),
+            code_lenses: HashMap::default(),
+            completion_items: Vec::new(),
             config,
+            debounced: HashMap::default(),
+            diagnostic_min_severity: DiagnosticSeverity::Hint,
             diagnostics: HashMap::default(),
+            diagnostics_debounce: Duration::from_millis(0),
+            diagnostics_display: DiagnosticsDisplay::Both,
+            diagnostics_window_width: None,
+            document_colors: HashMap::default(),
+            document_highlights: HashMap::default(),
+            document_links: HashMap::default(),
             editor_tx,
+            file_watcher: None,
+            file_watchers: HashMap::default(),
+            incomplete_completion: None,
+            inlay_hints: HashMap::default(),
             lang_srv_tx,
             language_id: language_id.to_string(),
+            pending_did_change: HashMap::default(),
+            pending_edit: None,
             pending_requests: vec![initial_request],
             request_counter: 0,
             response_waitlist: HashMap::default(),
             root_path,
             session,
+            settings: serde_json::Map::new(),
+            shared_capabilities: SharedCapabilities::default(),
+            shared_diagnostics: SharedDiagnostics::default(),
+            shared_completions: SharedCompletions::default(),
+            shared_hovers: SharedHovers::default(),
+            shared_code_actions: SharedCodeActions::default(),
             documents: HashMap::default(),
             offset_encoding,
+            partial_references: HashMap::new(),
+            pinned_requests: HashSet::default(),
+            preview_edits: false,
+            selection_ranges: HashMap::default(),
             semantic_highlighting_faces: Vec::new(),
             semantic_highlighting_lines: HashMap::default(),
+            semantic_tokens: HashMap::default(),
+            server_info: None,
+            shutdown: ShutdownState::Running,
+            signature_help: None,
+            stale_diagnostics: HashSet::default(),
+            type_hierarchy_item: None,
+            type_hierarchy_provider: false,
+            typed_text: HashMap::default(),
+            work_done_progress: HashMap::default(),
+            workspace_diagnostic_chunks: HashMap::new(),
+            workspace_diagnostics: HashMap::default(),
+            workspace_diagnostics_provider: false,
+            workspace_folders,
+            workspace_symbols: Vec::new(),
         }
     }
 
@@ -88,11 +244,45 @@ impl Context {
     ) where
         R::Params: ToParams,
         R::Result: for<'a> Deserialize<'a>,
+    {
+        self.single_call::<R, _>(meta, params, false, callback);
+    }
+
+    /// Like `call`, but the request is not superseded by newer requests of the same method, e.g.
+    /// because the user explicitly asked for its result.
+    pub fn call_pinned<
+        R: Request,
+        F: for<'a> FnOnce(&'a mut Context, EditorMeta, R::Result) -> () + 'static,
+    >(
+        &mut self,
+        meta: EditorMeta,
+        params: R::Params,
+        callback: F,
+    ) where
+        R::Params: ToParams,
+        R::Result: for<'a> Deserialize<'a>,
+    {
+        self.single_call::<R, _>(meta, params, true, callback);
+    }
+
+    fn single_call<
+        R: Request,
+        F: for<'a> FnOnce(&'a mut Context, EditorMeta, R::Result) -> () + 'static,
+    >(
+        &mut self,
+        meta: EditorMeta,
+        params: R::Params,
+        pinned: bool,
+        callback: F,
+    ) where
+        R::Params: ToParams,
+        R::Result: for<'a> Deserialize<'a>,
     {
         let ops: Vec<R::Params> = vec![params];
-        self.batch_call::<R, _>(
+        self.send_batch::<R, _>(
             meta,
             ops,
+            pinned,
             Box::new(
                 move |ctx: &mut Context, meta: EditorMeta, mut results: Vec<R::Result>| {
                     if let Some(result) = results.pop() {
@@ -115,6 +305,24 @@ impl Context {
         R::Params: ToParams,
         R::Result: for<'a> Deserialize<'a>,
     {
+        self.send_batch::<R, _>(meta, ops, false, callback);
+    }
+
+    fn send_batch<
+        R: Request,
+        F: for<'a> FnOnce(&'a mut Context, EditorMeta, Vec<R::Result>) -> () + 'static,
+    >(
+        &mut self,
+        meta: EditorMeta,
+        ops: Vec<R::Params>,
+        pinned: bool,
+        callback: F,
+    ) where
+        R::Params: ToParams,
+        R::Result: for<'a> Deserialize<'a>,
+    {
+        self.flush_did_change();
+        self.cancel_superseded_requests(R::METHOD, &meta);
         let batch_id = self.next_batch_id();
         self.batches.insert(
             batch_id,
@@ -139,6 +347,9 @@ impl Context {
             let id = self.next_request_id();
             self.response_waitlist
                 .insert(id.clone(), (meta.clone(), R::METHOD, batch_id));
+            if pinned {
+                self.pinned_requests.insert(id.clone());
+            }
 
             let call = jsonrpc_core::MethodCall {
                 jsonrpc: Some(Version::V2),
@@ -156,6 +367,24 @@ impl Context {
         }
     }
 
+    /// Cancel in-flight requests superseded by a new request of the same method from the same
+    /// client, dropping their responses so that stale results don't overwrite newer ones.
+    fn cancel_superseded_requests(&mut self, method: &str, meta: &EditorMeta) {
+        let ids = superseded_requests(&self.response_waitlist, &self.pinned_requests, method, meta);
+        for id in ids {
+            if let Some((_, _, batch_id)) = self.response_waitlist.remove(&id) {
+                self.batches.remove(&batch_id);
+            }
+            self.cancelled_requests.insert(id.clone());
+            let id = match id {
+                Id::Num(id) => NumberOrString::Number(id as _),
+                Id::Str(id) => NumberOrString::String(id),
+                Id::Null => continue,
+            };
+            self.notify::<notification::Cancel>(CancelParams { id });
+        }
+    }
+
     pub fn reply(&mut self, id: Id, result: Result<Value, Error>) {
         let output = match result {
             Ok(result) => Output::Success(Success {
@@ -179,6 +408,22 @@ impl Context {
     }
 
     pub fn notify<N: Notification>(&mut self, params: N::Params)
+    where
+        N::Params: ToParams,
+    {
+        self.flush_did_change();
+        self.send_notification::<N>(params);
+    }
+
+    /// Send buffer changes held back, the server must know about them before anything else.
+    pub fn flush_did_change(&mut self) {
+        let pending = std::mem::take(&mut self.pending_did_change);
+        for (_, pending) in pending {
+            self.send_notification::<notification::DidChangeTextDocument>(pending.params);
+        }
+    }
+
+    fn send_notification<N: Notification>(&mut self, params: N::Params)
     where
         N::Params: ToParams,
     {
@@ -231,6 +476,51 @@ impl Context {
         id
     }
 
+    /// Run the callback after the delay unless another one is scheduled with the same key in the
+    /// meantime, which replaces it.
+    pub fn debounce<F>(&mut self, key: String, delay: Duration, callback: F)
+    where
+        F: for<'a> FnOnce(&'a mut Context) + 'static,
+    {
+        if delay == Duration::from_millis(0) {
+            self.debounced.remove(&key);
+            callback(self);
+            return;
+        }
+        self.debounced
+            .insert(key, (Instant::now() + delay, Box::new(callback)));
+    }
+
+    /// Run debounced callbacks whose deadline has passed.
+    pub fn run_debounced(&mut self) {
+        let now = Instant::now();
+        let due = self
+            .debounced
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in due {
+            if let Some((_, callback)) = self.debounced.remove(&key) {
+                callback(self);
+            }
+        }
+    }
+
+    /// Turn a debounce delay in milliseconds set in Kakoune into a duration, complaining about
+    /// negative ones.
+    pub fn debounce_delay(&self, meta: &EditorMeta, millis: i64) -> Duration {
+        if millis < 0 {
+            let msg = format!("Debounce delay must not be negative, got {}ms", millis);
+            self.exec(
+                meta.clone(),
+                format!("lsp-show-error {}", editor_quote(&msg)),
+            );
+            return Duration::from_millis(0);
+        }
+        Duration::from_millis(millis as u64)
+    }
+
     pub fn meta_for_session(&self) -> EditorMeta {
         EditorMeta {
             session: self.session.clone(),
@@ -239,6 +529,7 @@ impl Context {
             filetype: "".to_string(), // filetype is not used by ctx.exec, but it's definitely a code smell
             version: 0,
             fifo: None,
+            fan_out: None,
         }
     }
 
@@ -251,6 +542,90 @@ impl Context {
             filetype: "".to_string(), // filetype is not used by ctx.exec, but it's definitely a code smell
             version: document.version,
             fifo: None,
+            fan_out: None,
         })
     }
 }
+
+/// Ids of in-flight requests superseded by a new request of the method from the client of meta.
+/// Pinned requests and the ones the editor is blocked waiting for are never superseded.
+fn superseded_requests(
+    response_waitlist: &HashMap<Id, (EditorMeta, &'static str, BatchNumber)>,
+    pinned_requests: &HashSet<Id>,
+    method: &str,
+    meta: &EditorMeta,
+) -> Vec<Id> {
+    if !SUPERSEDABLE_METHODS.contains(&method) || meta.client.is_none() {
+        return vec![];
+    }
+    response_waitlist
+        .iter()
+        .filter(|(id, (request_meta, request_method, _))| {
+            *request_method == method
+                && request_meta.client == meta.client
+                && request_meta.fifo.is_none()
+                && !pinned_requests.contains(id)
+        })
+        .map(|(id, _)| id.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(client: &str, fifo: Option<&str>) -> EditorMeta {
+        EditorMeta {
+            session: "session".to_string(),
+            client: Some(client.to_string()),
+            buffile: "/src/main.rs".to_string(),
+            filetype: "rust".to_string(),
+            version: 1,
+            fifo: fifo.map(str::to_string),
+            fan_out: None,
+        }
+    }
+
+    #[test]
+    fn superseded_completion_is_cancelled() {
+        let completion = request::Completion::METHOD;
+        let mut waitlist = HashMap::new();
+        waitlist.insert(Id::Num(1), (meta("client0", None), completion, 0));
+        waitlist.insert(Id::Num(2), (meta("client1", None), completion, 1));
+        waitlist.insert(
+            Id::Num(3),
+            (meta("client0", None), request::HoverRequest::METHOD, 2),
+        );
+        waitlist.insert(
+            Id::Num(4),
+            (meta("client0", Some("/tmp/fifo")), completion, 3),
+        );
+        let pinned = HashSet::new();
+        assert_eq!(
+            superseded_requests(&waitlist, &pinned, completion, &meta("client0", None)),
+            vec![Id::Num(1)]
+        );
+        // Requests of other methods are left alone.
+        assert!(superseded_requests(
+            &waitlist,
+            &pinned,
+            request::Formatting::METHOD,
+            &meta("client0", None)
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn pinned_hover_is_not_cancelled() {
+        let hover = request::HoverRequest::METHOD;
+        let mut waitlist = HashMap::new();
+        waitlist.insert(Id::Num(1), (meta("client0", None), hover, 0));
+        waitlist.insert(Id::Num(2), (meta("client0", None), hover, 1));
+        let mut pinned = HashSet::new();
+        pinned.insert(Id::Num(1));
+        assert_eq!(
+            superseded_requests(&waitlist, &pinned, hover, &meta("client0", None)),
+            vec![Id::Num(2)]
+        );
+    }
+}
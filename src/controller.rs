@@ -1,17 +1,24 @@
 use crate::context::*;
 use crate::diagnostics;
-use crate::general;
+use crate::general::{self, ShutdownState};
+use crate::language_features;
+use crate::language_features::call_hierarchy::CallDirection;
+use crate::language_features::type_hierarchy::TypeDirection;
 use crate::language_features::*;
 use crate::language_server_transport;
+use crate::progress;
+use crate::settings;
 use crate::text_sync::*;
 use crate::types::*;
 use crate::util::*;
 use crate::workspace;
-use crossbeam_channel::{select, Receiver, Sender};
+use crossbeam_channel::{after, never, select, Receiver, Sender};
 use jsonrpc_core::{Call, ErrorCode, MethodCall, Output, Params};
 use lsp_types::notification::Notification;
 use lsp_types::request::Request;
 use lsp_types::*;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 // This is an error code defined by the language server protocol, signifying that a request was
 // cancelled because the content changed before it could be fulfilled. In this case, the user
@@ -23,12 +30,24 @@ const CONTENT_MODIFIED: i64 = -32801;
 /// Controller spawns language server for the given language and project root (passed as `route`).
 /// Then it takes care of dispatching editor requests to this language server and dispatching
 /// responses back to editor.
+///
+/// If the language server exits without being asked to, the session is told so via `to_session`,
+/// to restart it and keep showing the diagnostics published so far until the new server publishes
+/// its own.
+///
+/// Diagnostics are also put into `shared`, so that other language servers attached to the same
+/// buffers show them along with their own, and so are the capabilities of the server, for the
+/// session to tell which server to route requests to. Answers to requests the session sent to
+/// several servers are merged there too.
 pub fn start(
     to_editor: Sender<EditorResponse>,
     from_editor: Receiver<EditorRequest>,
+    to_session: Sender<ServerCrash>,
     route: &Route,
     initial_request: EditorRequest,
     config: Config,
+    stale_diagnostics: HashMap<String, Vec<Diagnostic>>,
+    shared: Shared,
 ) {
     let lang_srv: language_server_transport::LanguageServerTransport;
     let options;
@@ -37,11 +56,13 @@ pub fn start(
         // should be fine to unwrap because request was already routed which means language is configured
         let lang = &config.language[&route.language];
         options = lang.initialization_options.clone();
-        offset_encoding = lang.offset_encoding.clone();
-        lang_srv = match language_server_transport::start(&lang.command, &lang.args) {
+        // Until negotiated with the server.
+        offset_encoding = lang.offset_encoding.unwrap_or(OffsetEncoding::Utf16);
+        let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout);
+        lang_srv = match language_server_transport::start(lang, &route.root, shutdown_timeout) {
             Ok(ls) => ls,
             Err(err) => {
-                if !lang.command.contains('/') {
+                if lang.transport == Transport::Stdio && !lang.command.contains('/') {
                     panic!("{}", err);
                 }
                 let command = format!(
@@ -73,23 +94,67 @@ pub fn start(
         route.root.clone(),
         offset_encoding,
     );
+    ctx.stale_diagnostics = stale_diagnostics.keys().cloned().collect();
+    ctx.diagnostics = stale_diagnostics;
+    ctx.shared_capabilities = shared.capabilities;
+    ctx.shared_diagnostics = shared.diagnostics;
+    ctx.shared_completions = shared.completions;
+    ctx.shared_hovers = shared.hovers;
+    ctx.shared_code_actions = shared.code_actions;
 
     general::initialize(&route.root, options, initial_request_meta, &mut ctx);
 
+    let mut crashed = false;
+    // Requests are not read anymore once the editor is gone, waiting for the server to shut down.
+    let mut editor_gone = false;
     'event_loop: loop {
+        if ctx.shutdown == ShutdownState::Exited {
+            break 'event_loop;
+        }
+        let from_editor = if editor_gone {
+            never()
+        } else {
+            from_editor.clone()
+        };
+        let shutdown_timer = ctx.shutdown.deadline().map_or_else(never, |deadline| {
+            after(deadline.saturating_duration_since(Instant::now()))
+        });
+        let file_events = ctx
+            .file_watcher
+            .as_ref()
+            .map_or_else(never, |watcher| watcher.receiver().clone());
+        let debounce_timer = ctx
+            .debounced
+            .values()
+            .map(|(deadline, _)| *deadline)
+            .min()
+            .map_or_else(never, |deadline| {
+                after(deadline.saturating_duration_since(Instant::now()))
+            });
         select! {
             recv(from_editor) -> msg => {
                 if msg.is_err() {
-                    break 'event_loop;
+                    editor_gone = true;
+                    general::shutdown(&mut ctx);
+                    continue 'event_loop;
                 }
                 let msg = msg.unwrap();
+                if msg.method == notification::Exit::METHOD {
+                    general::shutdown(&mut ctx);
+                    continue 'event_loop;
+                }
                 // initialize request must be first request from client to language server
                 // initialized response contains capabilities which we save for future use
                 // capabilities also serve as a marker of completing initialization
                 // we park all requests from editor before initialization is complete
                 // and then dispatch them
                 if ctx.capabilities.is_some() {
-                    dispatch_editor_request(msg, &mut ctx);
+                    debounce_editor_request(msg, &mut ctx);
+                } else if msg.method == notification::WillSaveTextDocument::METHOD {
+                    // Don't hold up saving, the server will read the saved file once it's ready.
+                    if msg.meta.fifo.is_some() {
+                        ctx.exec(msg.meta, "nop".to_string());
+                    }
                 } else {
                     debug!("Language server is not initialized, parking request");
                     {
@@ -99,6 +164,7 @@ pub fn start(
                             notification::DidChangeTextDocument::METHOD => (),
                             notification::DidCloseTextDocument::METHOD => (),
                             notification::DidSaveTextDocument::METHOD => (),
+                            notification::DidChangeWorkspaceFolders::METHOD => (),
                             // TODO if auto-hover or auto-hl-references is not enabled we might want warning about parking as well
                             request::HoverRequest::METHOD => (),
                             request::DocumentHighlightRequest::METHOD => (),
@@ -112,8 +178,20 @@ pub fn start(
                     ctx.pending_requests.push(msg);
                 }
             }
+            recv(shutdown_timer) -> _ => {
+                general::shutdown_timeout(&mut ctx);
+            }
+            recv(debounce_timer) -> _ => {
+                ctx.run_debounced();
+            }
+            recv(file_events) -> msg => {
+                if let Ok(changes) = msg {
+                    general::did_change_watched_files(changes, &mut ctx);
+                }
+            }
             recv(lang_srv.from_lang_server.receiver()) -> msg => {
                 if msg.is_err() {
+                    crashed = ctx.shutdown == ShutdownState::Running;
                     break 'event_loop;
                 }
                 let msg = msg.unwrap();
@@ -138,7 +216,10 @@ pub fn start(
                     ServerMessage::Response(output) => {
                         match output {
                             Output::Success(success) => {
-                                if let Some((meta, _, batch_id)) = ctx.response_waitlist.remove(&success.id) {
+                                ctx.pinned_requests.remove(&success.id);
+                                if ctx.cancelled_requests.remove(&success.id) {
+                                    debug!("Dropping response to cancelled request {:?}", success.id);
+                                } else if let Some((meta, _, batch_id)) = ctx.response_waitlist.remove(&success.id) {
                                     if let Some((batch_amt, mut vals, callback)) = ctx.batches.remove(&batch_id) {
                                         vals.push(success.result);
                                         if batch_amt == 1 {
@@ -152,15 +233,24 @@ pub fn start(
                                 }
                             }
                             Output::Failure(failure) => {
-                                error!("Error response from server: {:?}", failure);
-                                if let Some(request) = ctx.response_waitlist.remove(&failure.id) {
+                                ctx.pinned_requests.remove(&failure.id);
+                                if ctx.cancelled_requests.remove(&failure.id) {
+                                    debug!("Dropping error response to cancelled request {:?}", failure.id);
+                                } else if let Some(request) = ctx.response_waitlist.remove(&failure.id) {
+                                    error!("Error response from server: {:?}", failure);
                                     let (meta, method, _) = request;
+                                    if meta.fan_out.is_some() {
+                                        show_answers_without(meta.clone(), method, &ctx);
+                                    }
                                     match failure.error.code {
                                         ErrorCode::ServerError(CONTENT_MODIFIED) => {
                                             // Nothing to do, but sending command back to the editor is required to handle case when
                                             // editor is blocked waiting for response via fifo.
                                             ctx.exec(meta, "nop".to_string());
                                         },
+                                        _ if method == request::SemanticTokensFullDeltaRequest::METHOD => {
+                                            semantic_tokens::tokens_delta_failed(meta, &mut ctx);
+                                        }
                                         code => {
                                             let msg = match code {
                                                 ErrorCode::MethodNotFound => format!(
@@ -176,6 +266,7 @@ pub fn start(
                                         }
                                     }
                                 } else {
+                                    error!("Error response from server: {:?}", failure);
                                     error!("Id {:?} is not in waitlist!", failure.id);
                                 }
                             }
@@ -185,6 +276,52 @@ pub fn start(
             }
         }
     }
+    if crashed {
+        error!("{} language server exited unexpectedly", route.language);
+        let crash = ServerCrash {
+            route: route.clone(),
+            crashed_at: Instant::now(),
+            diagnostics: std::mem::take(&mut ctx.diagnostics),
+            documents: std::mem::take(&mut ctx.documents),
+        };
+        if to_session.send(crash).is_err() {
+            error!("Failed to report language server crash to the session");
+        }
+    } else {
+        // Diagnostics of a crashed server are still shown by other ones until it is restarted.
+        diagnostics::clear_shared_diagnostics(&ctx);
+    }
+    ctx.shared_capabilities.lock().unwrap().remove(route);
+}
+
+/// State shared by the controllers of an editor session, with each other and with the session.
+#[derive(Clone, Default)]
+pub struct Shared {
+    pub capabilities: general::SharedCapabilities,
+    pub diagnostics: diagnostics::SharedDiagnostics,
+    pub completions: completion::SharedCompletions,
+    pub hovers: hover::SharedHovers,
+    pub code_actions: codeaction::SharedCodeActions,
+}
+
+/// What is left of a controller whose language server crashed, to restart it.
+pub struct ServerCrash {
+    pub route: Route,
+    pub crashed_at: Instant,
+    pub diagnostics: HashMap<String, Vec<Diagnostic>>,
+    // Buffers which were open, to open them again with the new server.
+    pub documents: HashMap<String, Document>,
+}
+
+/// Show the answers of the other language servers a request was sent to, as the server failed to
+/// answer it.
+fn show_answers_without(meta: EditorMeta, method: &str, ctx: &Context) {
+    match method {
+        completion::CompletionRequest::METHOD => completion::show_completions(meta, None, ctx),
+        request::HoverRequest::METHOD => hover::show_hover(meta, None, ctx),
+        request::CodeActionRequest::METHOD => codeaction::show_code_actions(meta, None, ctx),
+        _ => (),
+    }
 }
 
 pub fn dispatch_pending_editor_requests(mut ctx: &mut Context) {
@@ -195,12 +332,19 @@ pub fn dispatch_pending_editor_requests(mut ctx: &mut Context) {
     }
 }
 
+/// Dispatch the request once its debounce delay has passed, a newer request of the same method for
+/// the same buffer replacing it meanwhile.
+pub fn debounce_editor_request(request: EditorRequest, ctx: &mut Context) {
+    let delay = ctx.debounce_delay(&request.meta, request.debounce);
+    let key = format!("{} {}", request.method, request.meta.buffile);
+    ctx.debounce(key, delay, move |ctx| dispatch_editor_request(request, ctx));
+}
+
 fn dispatch_editor_request(request: EditorRequest, mut ctx: &mut Context) {
     ensure_did_open(&request, ctx);
     let meta = request.meta;
     let params = request.params;
     let method: &str = &request.method;
-    let ranges: Option<Vec<Range>> = request.ranges;
     match method {
         notification::DidOpenTextDocument::METHOD => {
             text_document_did_open(meta, params, &mut ctx);
@@ -214,21 +358,72 @@ fn dispatch_editor_request(request: EditorRequest, mut ctx: &mut Context) {
         notification::DidSaveTextDocument::METHOD => {
             text_document_did_save(meta, &mut ctx);
         }
+        notification::WillSaveTextDocument::METHOD => {
+            text_document_will_save(meta, params, &mut ctx);
+        }
         notification::DidChangeConfiguration::METHOD => {
             workspace::did_change_configuration(params, &mut ctx);
         }
+        "settings/set" => {
+            settings::set_config(meta, params, &mut ctx);
+        }
         request::Completion::METHOD => {
             completion::text_document_completion(meta, params, &mut ctx);
         }
+        request::ResolveCompletionItem::METHOD => {
+            completion::completion_item_resolve(meta, params, &mut ctx);
+        }
+        "completionItem/accept" => {
+            completion::completion_item_accept(meta, params, &mut ctx);
+        }
+        request::CodeLensRequest::METHOD => {
+            code_lens::text_document_code_lens(meta, &mut ctx);
+        }
+        "textDocument/codeLens/menu" => {
+            code_lens::code_lens_menu(meta, params, &mut ctx);
+        }
         request::CodeActionRequest::METHOD => {
             codeaction::text_document_codeaction(meta, params, &mut ctx);
         }
+        "textDocument/diagnostics/quickfix" => {
+            codeaction::diagnostic_quickfix(meta, params, &mut ctx);
+        }
+        "codeAction/perform" => {
+            codeaction::code_action_perform(meta, params, &mut ctx);
+        }
+        "window/workDoneProgress/cancel/menu" => {
+            progress::cancel_progress_menu(meta, params, &mut ctx);
+        }
+        notification::WorkDoneProgressCancel::METHOD => {
+            progress::cancel_progress(meta, params, &mut ctx);
+        }
+        request::MonikerRequest::METHOD => {
+            moniker::text_document_moniker(meta, params, &mut ctx);
+        }
         request::ExecuteCommand::METHOD => {
             workspace::execute_command(meta, params, &mut ctx);
         }
+        notification::DidChangeWorkspaceFolders::METHOD => {
+            workspace::did_change_workspace_folders(meta, params, &mut ctx);
+        }
+        notification::DidRenameFiles::METHOD => {
+            workspace::did_rename_file(meta, params, &mut ctx);
+        }
+        notification::DidCreateFiles::METHOD => {
+            workspace::did_create_files(params, &mut ctx);
+        }
+        notification::DidDeleteFiles::METHOD => {
+            workspace::did_delete_files(params, &mut ctx);
+        }
         request::HoverRequest::METHOD => {
             hover::text_document_hover(meta, params, &mut ctx);
         }
+        "textDocument/hover/buffer" => {
+            hover::text_document_hover_buffer(meta, params, &mut ctx);
+        }
+        request::GotoDeclaration::METHOD => {
+            goto::text_document_declaration(meta, params, &mut ctx);
+        }
         request::GotoDefinition::METHOD => {
             goto::text_document_definition(meta, params, &mut ctx);
         }
@@ -241,42 +436,137 @@ fn dispatch_editor_request(request: EditorRequest, mut ctx: &mut Context) {
         request::References::METHOD => {
             goto::text_document_references(meta, params, &mut ctx);
         }
-        notification::Exit::METHOD => {
-            general::exit(&mut ctx);
-        }
         request::SignatureHelpRequest::METHOD => {
             signature_help::text_document_signature_help(meta, params, &mut ctx);
         }
+        "textDocument/signatureHelp/cycle" => {
+            signature_help::signature_help_cycle(meta, params, &mut ctx);
+        }
+        "textDocument/signatureHelp/retrigger" => {
+            signature_help::signature_help_retrigger(meta, params, &mut ctx);
+        }
         request::DocumentHighlightRequest::METHOD => {
             highlights::text_document_highlights(meta, params, &mut ctx);
         }
+        "textDocument/documentHighlight/find" => {
+            highlights::find_reference(meta, params, &mut ctx);
+        }
+        request::LinkedEditingRange::METHOD => {
+            linked_editing::text_document_linked_editing_range(meta, params, &mut ctx);
+        }
+        request::CallHierarchyIncomingCalls::METHOD => {
+            call_hierarchy::call_hierarchy(meta, params, CallDirection::Incoming, &mut ctx);
+        }
+        request::CallHierarchyOutgoingCalls::METHOD => {
+            call_hierarchy::call_hierarchy(meta, params, CallDirection::Outgoing, &mut ctx);
+        }
+        type_hierarchy::TypeHierarchySupertypes::METHOD => {
+            type_hierarchy::type_hierarchy(meta, params, TypeDirection::Supertypes, &mut ctx);
+        }
+        type_hierarchy::TypeHierarchySubtypes::METHOD => {
+            type_hierarchy::type_hierarchy(meta, params, TypeDirection::Subtypes, &mut ctx);
+        }
+        request::ColorPresentationRequest::METHOD => {
+            document_color::color_presentation(meta, params, &mut ctx);
+        }
+        "textDocument/documentLink/open" => {
+            document_link::document_link_open(meta, params, &mut ctx);
+        }
+        inlay_hints::InlayHintRequest::METHOD => {
+            inlay_hints::inlay_hints(meta, params, &mut ctx);
+        }
+        "textDocument/inlayHint/hover" => {
+            inlay_hints::inlay_hints_hover(meta, params, &mut ctx);
+        }
+        "textDocument/inlayHint/apply" => {
+            inlay_hints::inlay_hints_apply(meta, params, &mut ctx);
+        }
+        "textDocument/inlayHint/goto" => {
+            inlay_hints::inlay_hints_goto(meta, params, &mut ctx);
+        }
+        request::SelectionRangeRequest::METHOD => {
+            language_features::selection_range::text_document_selection_range(
+                meta, params, &mut ctx,
+            );
+        }
+        request::FoldingRangeRequest::METHOD => {
+            folding::text_document_folding_range(meta, params, &mut ctx);
+        }
         request::DocumentSymbolRequest::METHOD => {
             document_symbol::text_document_document_symbol(meta, &mut ctx);
         }
+        "textDocument/documentSymbol/breadcrumb" => {
+            document_symbol::document_symbol_breadcrumb(meta, params, &mut ctx);
+        }
+        "textDocument/documentSymbol/goto" => {
+            document_symbol::goto_symbol(meta, params, &mut ctx);
+        }
         request::Formatting::METHOD => {
             formatting::text_document_formatting(meta, params, &mut ctx);
         }
-        request::RangeFormatting::METHOD => match ranges {
-            Some(range) => {
-                range_formatting::text_document_range_formatting(meta, params, range, &mut ctx)
-            }
-            None => warn!("No range provided to {}", method),
-        },
+        request::OnTypeFormatting::METHOD => {
+            on_type_formatting::text_document_on_type_formatting(meta, params, &mut ctx);
+        }
+        request::RangeFormatting::METHOD => {
+            range_formatting::text_document_range_formatting(meta, params, &mut ctx);
+        }
         request::WorkspaceSymbol::METHOD => {
             workspace::workspace_symbol(meta, params, &mut ctx);
         }
+        "workspaceSymbol/jump" => {
+            workspace::workspace_symbol_jump(meta, params, &mut ctx);
+        }
         request::Rename::METHOD => {
             rename::text_document_rename(meta, params, &mut ctx);
         }
+        request::PrepareRenameRequest::METHOD => {
+            rename::text_document_prepare_rename(meta, params, &mut ctx);
+        }
         "textDocument/diagnostics" => {
             diagnostics::editor_diagnostics(meta, &mut ctx);
         }
+        "textDocument/diagnostics/goto" => {
+            diagnostics::diagnostic_goto(meta, params, &mut ctx);
+        }
+        "textDocument/diagnostics/list" => {
+            diagnostics::diagnostics_list(meta, &ctx);
+        }
+        "textDocument/diagnostics/minSeverity" => {
+            diagnostics::diagnostic_min_severity(meta, params, &mut ctx);
+        }
+        "textDocument/diagnostics/details" => {
+            diagnostics::diagnostic_details(meta, params, &mut ctx);
+        }
+        "textDocument/diagnostics/openDoc" => {
+            diagnostics::diagnostic_open_doc(meta, params, &mut ctx);
+        }
+        "textDocument/diagnostics/display" => {
+            diagnostics::diagnostics_display(meta, params, &mut ctx);
+        }
+        diagnostics::WorkspaceDiagnosticRequest::METHOD => {
+            diagnostics::workspace_diagnostics(meta, &mut ctx);
+        }
         "capabilities" => {
             general::capabilities(meta, &mut ctx);
         }
         "apply-workspace-edit" => {
             workspace::apply_edit_from_editor(meta, params, ctx);
         }
+        "workspace/applyEdit/chunk" => {
+            workspace::apply_next_edit_chunk(meta, params, &mut ctx);
+        }
+        "workspace/previewEdits" => {
+            workspace::preview_pending_edit(meta, params, &mut ctx);
+        }
+        "workspace/previewEdits/mode" => {
+            workspace::preview_edits_mode(meta, params, &mut ctx);
+        }
+        "workspace/previewEdits/apply" => {
+            workspace::confirm_pending_edit(meta, params, &mut ctx);
+        }
+        "workspace/previewEdits/cancel" => {
+            workspace::cancel_pending_edit(meta, params, &mut ctx);
+        }
         "semantic-scopes" => {
             semantic_highlighting::debug_scopes(meta, &mut ctx);
         }
@@ -308,6 +598,9 @@ fn dispatch_editor_request(request: EditorRequest, mut ctx: &mut Context) {
         clangd::SwitchSourceHeaderRequest::METHOD => {
             clangd::switch_source_header(meta, ctx);
         }
+        clangd::AstRequest::METHOD => {
+            clangd::ast(meta, params, ctx);
+        }
 
         // eclipse.jdt.ls
         "eclipse.jdt.ls/organizeImports" => {
@@ -318,6 +611,9 @@ fn dispatch_editor_request(request: EditorRequest, mut ctx: &mut Context) {
         rust_analyzer::InlayHints::METHOD => {
             rust_analyzer::inlay_hints(meta, params, ctx);
         }
+        rust_analyzer::ExpandMacroRequest::METHOD => {
+            rust_analyzer::expand_macro(meta, params, ctx);
+        }
 
         _ => {
             warn!("Unsupported method: {}", method);
@@ -331,6 +627,34 @@ fn dispatch_server_request(request: MethodCall, ctx: &mut Context) {
         request::ApplyWorkspaceEdit::METHOD => {
             workspace::apply_edit_from_server(request.id, request.params, ctx);
         }
+        request::SemanticTokensRefesh::METHOD => {
+            semantic_tokens::refresh_all_tokens(ctx);
+            ctx.reply(request.id, Ok(serde_json::Value::Null));
+        }
+        request::CodeLensRefresh::METHOD => {
+            code_lens::refresh_all_code_lenses(ctx);
+            ctx.reply(request.id, Ok(serde_json::Value::Null));
+        }
+        inlay_hints::InlayHintRefreshRequest::METHOD => {
+            inlay_hints::refresh_all_inlay_hints(ctx);
+            ctx.reply(request.id, Ok(serde_json::Value::Null));
+        }
+        request::RegisterCapability::METHOD => {
+            general::register_capability(request.id, request.params, ctx);
+        }
+        request::UnregisterCapability::METHOD => {
+            general::unregister_capability(request.id, request.params, ctx);
+        }
+        request::WorkspaceConfiguration::METHOD => {
+            settings::workspace_configuration(request.id, request.params, ctx);
+        }
+        request::WorkDoneProgressCreate::METHOD => {
+            progress::work_done_progress_create(request.id, request.params, ctx);
+        }
+        request::WorkspaceFoldersRequest::METHOD => {
+            let folders = serde_json::to_value(&ctx.workspace_folders).unwrap();
+            ctx.reply(request.id, Ok(folders));
+        }
         _ => {
             warn!("Unsupported method: {}", method);
         }
@@ -340,7 +664,9 @@ fn dispatch_server_request(request: MethodCall, ctx: &mut Context) {
 fn dispatch_server_notification(method: &str, params: Params, mut ctx: &mut Context) {
     match method {
         notification::PublishDiagnostics::METHOD => {
-            diagnostics::publish_diagnostics(params, &mut ctx);
+            if ctx.config.language[&ctx.language_id].provides("diagnostics") {
+                diagnostics::publish_diagnostics(params, &mut ctx);
+            }
         }
         "$cquery/publishSemanticHighlighting" => {
             cquery::publish_semantic_highlighting(params, &mut ctx);
@@ -388,6 +714,14 @@ fn dispatch_server_notification(method: &str, params: Params, mut ctx: &mut Cont
                 ),
             );
         }
+        "$/progress" => {
+            if !diagnostics::workspace_diagnostics_progress(params.clone(), &mut ctx)
+                && !goto::references_progress(params.clone(), &mut ctx)
+                && !progress::work_done_progress(params, &mut ctx)
+            {
+                debug!("Unhandled progress notification");
+            }
+        }
         notification::SemanticHighlighting::METHOD => {
             semantic_highlighting::semantic_highlighting_notification(params, &mut ctx);
         }
@@ -414,10 +748,15 @@ fn dispatch_server_notification(method: &str, params: Params, mut ctx: &mut Cont
 /// most of the time in `if buffile.is_empty() || ctx.documents.contains_key(buffile)` condition.
 fn ensure_did_open(request: &EditorRequest, mut ctx: &mut Context) {
     let buffile = &request.meta.buffile;
-    if buffile.is_empty() || ctx.documents.contains_key(buffile) {
+    if buffile.is_empty()
+        || ctx.documents.contains_key(buffile)
+        || request.method == notification::DidOpenTextDocument::METHOD
+    {
         return;
     };
-    if request.method == notification::DidChangeTextDocument::METHOD {
+    if request.method == notification::DidChangeTextDocument::METHOD
+        || request.method == notification::WillSaveTextDocument::METHOD
+    {
         return text_document_did_open(request.meta.clone(), request.params.clone(), &mut ctx);
     }
     match std::fs::read_to_string(buffile) {
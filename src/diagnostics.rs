@@ -4,89 +4,213 @@ use crate::types::*;
 use crate::util::*;
 use itertools::Itertools;
 use jsonrpc_core::Params;
+use lsp_types::request::Request;
 use lsp_types::*;
-use std::collections::HashSet;
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+use url::Url;
+
+/// Which decorations present diagnostics besides underlining their ranges, which is only left out
+/// when they are off.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticsDisplay {
+    Gutter,
+    Inline,
+    Both,
+    Off,
+}
+
+impl DiagnosticsDisplay {
+    fn gutter(self) -> bool {
+        self == DiagnosticsDisplay::Gutter || self == DiagnosticsDisplay::Both
+    }
+
+    fn inline(self) -> bool {
+        self == DiagnosticsDisplay::Inline || self == DiagnosticsDisplay::Both
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DiagnosticsDisplayParams {
+    pub mode: DiagnosticsDisplay,
+    // Width of the window the command was run in, messages shown inline are cut to fit it.
+    pub window_width: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DiagnosticMinSeverityParams {
+    pub severity: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DiagnosticGotoParams {
+    pub position: KakounePosition,
+    #[serde(default)]
+    pub previous: bool,
+    // Least severe diagnostics to go to, any if empty.
+    #[serde(default)]
+    pub severity: String,
+}
+
+/// Diagnostics published by the language servers of the editor session by buffer and then by
+/// language, so that a buffer served by several servers shows the diagnostics of all of them.
+pub type SharedDiagnostics =
+    Arc<Mutex<HashMap<String, BTreeMap<String, (OffsetEncoding, Vec<Diagnostic>)>>>>;
+
+/// Prefix of tokens used to tag partial results of workspace/diagnostic streamed via $/progress.
+const WORKSPACE_DIAGNOSTIC_TOKEN_PREFIX: &str = "kak-lsp-workspace-diagnostic-";
+
+pub enum WorkspaceDiagnosticRequest {}
+
+impl Request for WorkspaceDiagnosticRequest {
+    type Params = WorkspaceDiagnosticParams;
+    type Result = WorkspaceDiagnosticReport;
+    const METHOD: &'static str = "workspace/diagnostic";
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceDiagnosticParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<String>,
+    pub previous_result_ids: Vec<PreviousResultId>,
+    #[serde(flatten)]
+    pub partial_result_params: PartialResultParams,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviousResultId {
+    pub uri: Url,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct WorkspaceDiagnosticReport {
+    pub items: Vec<WorkspaceDocumentDiagnosticReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum WorkspaceDocumentDiagnosticReport {
+    Full {
+        uri: Url,
+        #[serde(default)]
+        items: Vec<Diagnostic>,
+    },
+    Unchanged {
+        uri: Url,
+    },
+}
+
+impl WorkspaceDocumentDiagnosticReport {
+    pub fn uri(&self) -> &Url {
+        match self {
+            WorkspaceDocumentDiagnosticReport::Full { uri, .. } => uri,
+            WorkspaceDocumentDiagnosticReport::Unchanged { uri } => uri,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct WorkspaceDiagnosticProgress {
+    token: NumberOrString,
+    value: WorkspaceDiagnosticReport,
+}
 
 pub fn publish_diagnostics(params: Params, ctx: &mut Context) {
     let params: PublishDiagnosticsParams = params.parse().expect("Failed to parse params");
+    let path = params.uri.to_file_path().unwrap();
+    let buffile = path.to_str().unwrap().to_string();
+    ctx.shared_diagnostics
+        .lock()
+        .unwrap()
+        .entry(buffile.clone())
+        .or_default()
+        .insert(
+            ctx.language_id.clone(),
+            (ctx.offset_encoding, params.diagnostics.clone()),
+        );
+    ctx.diagnostics.insert(buffile.clone(), params.diagnostics);
+    ctx.stale_diagnostics.remove(&buffile);
+    // Servers might publish diagnostics on every change, only show the last ones after a while.
+    ctx.debounce(
+        format!("diagnostics {}", buffile),
+        ctx.diagnostics_debounce,
+        move |ctx| show_diagnostics(&buffile, ctx),
+    );
+}
+
+/// Show diagnostics left from a crashed language server once the buffer is open again, marked
+/// as stale.
+pub fn show_stale_diagnostics(buffile: &str, ctx: &mut Context) {
+    if ctx.stale_diagnostics.contains(buffile) {
+        show_diagnostics(buffile, ctx);
+    }
+}
+
+fn show_diagnostics(buffile: &str, ctx: &mut Context) {
     let session = ctx.session.clone();
     let client = None;
-    let path = params.uri.to_file_path().unwrap();
-    let buffile = path.to_str().unwrap();
-    ctx.diagnostics
-        .insert(buffile.to_string(), params.diagnostics);
     let document = ctx.documents.get(buffile);
     if document.is_none() {
         return;
     }
     let document = document.unwrap();
     let version = document.version;
-    let diagnostics = &ctx.diagnostics[buffile];
+    // Less severe diagnostics are kept for hover but not shown otherwise.
+    let diagnostics = ctx.diagnostics[buffile]
+        .iter()
+        .cloned()
+        .chain(other_servers_diagnostics(buffile, &document.text, ctx))
+        .filter(|x| severity(x) <= ctx.diagnostic_min_severity)
+        .collect::<Vec<_>>();
+    let display = ctx.diagnostics_display;
     let ranges = diagnostics
         .iter()
+        .filter(|_| display != DiagnosticsDisplay::Off)
         .map(|x| {
             format!(
                 "{}|{}",
                 lsp_range_to_kakoune(&x.range, &document.text, ctx.offset_encoding),
-                match x.severity {
-                    Some(DiagnosticSeverity::Error) => "DiagnosticError",
+                match severity(x) {
+                    DiagnosticSeverity::Error => "DiagnosticError",
                     _ => "DiagnosticWarning",
                 }
             )
         })
         .join(" ");
 
-    let mut error_count = 0;
-    let mut warning_count = 0;
+    let error_count = diagnostics
+        .iter()
+        .filter(|x| severity(x) == DiagnosticSeverity::Error)
+        .count();
+    let warning_count = diagnostics.len() - error_count;
     let line_flags = diagnostics
         .iter()
+        .filter(|_| display.gutter())
         .map(|x| {
             format!(
                 "{}|{}",
                 x.range.start.line + 1,
-                match x.severity {
-                    Some(DiagnosticSeverity::Error) => {
-                        error_count += 1;
-                        "%opt[lsp_diagnostic_line_error_sign]"
-                    }
-                    _ => {
-                        warning_count += 1;
-                        "%opt[lsp_diagnostic_line_warning_sign]"
-                    }
+                match severity(x) {
+                    DiagnosticSeverity::Error => "%opt[lsp_diagnostic_line_error_sign]",
+                    _ => "%opt[lsp_diagnostic_line_warning_sign]",
                 }
             )
         })
         .join(" ");
-    let mut lines_with_errors = HashSet::new();
-    let diagnostic_ranges = diagnostics
-        .iter()
-        .map(|x| {
-            let face = match x.severity {
-                Some(DiagnosticSeverity::Error) => "InlayDiagnosticError",
-                _ => "InlayDiagnosticWarning",
-            };
-            // Pretend the language server sent us the diagnostic past the end of line
-            let line = x.range.end.line;
-            let line_text = get_line(line as usize, &document.text);
-            let mut pos =
-                lsp_position_to_kakoune(&x.range.end, &document.text, ctx.offset_encoding);
-            pos.column = line_text.len_bytes() as u32;
-            // separate all but the first diagnostic on the same line
-            let sep = if lines_with_errors.insert(line) {
-                ""
-            } else {
-                ", "
-            };
-            editor_quote(&format!(
-                "{}+0|{{{}}}{{\\}}{} {}",
-                pos,
-                face,
-                sep,
-                x.message.replace("|", "\\|")
-            ))
-        })
-        .join(" ");
+    let diagnostic_ranges = if display.inline() {
+        let stale = ctx.stale_diagnostics.contains(buffile);
+        inline_diagnostics(&diagnostics, &document.text, stale, ctx)
+    } else {
+        String::new()
+    };
     // Always show a space on line one if no other highlighter is there,
     // to make sure the column always has the right width
     // Also wrap line_flags in another eval and quotes, to make sure the %opt[] tags are expanded
@@ -117,39 +241,478 @@ pub fn publish_diagnostics(params: Params, ctx: &mut Context) {
         filetype: "".to_string(), // filetype is not used by ctx.exec, but it's definitely a code smell
         version,
         fifo: None,
+        fan_out: None,
     };
     ctx.exec(meta, command);
 }
 
-pub fn editor_diagnostics(meta: EditorMeta, ctx: &mut Context) {
-    let content = ctx
+/// Diagnostics other language servers published for the buffer, with ranges converted to the
+/// offset encoding of this one.
+fn other_servers_diagnostics(buffile: &str, text: &Rope, ctx: &Context) -> Vec<Diagnostic> {
+    let shared_diagnostics = ctx.shared_diagnostics.lock().unwrap();
+    let servers = match shared_diagnostics.get(buffile) {
+        Some(servers) => servers,
+        None => return vec![],
+    };
+    servers
+        .iter()
+        .filter(|(language_id, _)| **language_id != ctx.language_id)
+        .flat_map(|(_, (offset_encoding, diagnostics))| {
+            let convert = move |position: &Position| {
+                let position = lsp_position_to_kakoune(position, text, *offset_encoding);
+                kakoune_position_to_lsp(&position, text, ctx.offset_encoding)
+            };
+            diagnostics.iter().map(move |x| Diagnostic {
+                range: Range {
+                    start: convert(&x.range.start),
+                    end: convert(&x.range.end),
+                },
+                ..x.clone()
+            })
+        })
+        .collect()
+}
+
+/// Forget diagnostics the language server published, e.g. when it exits.
+pub fn clear_shared_diagnostics(ctx: &Context) {
+    let mut shared_diagnostics = ctx.shared_diagnostics.lock().unwrap();
+    for servers in shared_diagnostics.values_mut() {
+        servers.remove(&ctx.language_id);
+    }
+    shared_diagnostics.retain(|_, servers| !servers.is_empty());
+}
+
+/// Virtual text shown past the end of lines with diagnostics: the most severe diagnostic of each
+/// line, cut to fit into the window if its width is known.
+fn inline_diagnostics(
+    diagnostics: &[Diagnostic],
+    text: &Rope,
+    stale: bool,
+    ctx: &Context,
+) -> String {
+    let mut lines: BTreeMap<u32, &Diagnostic> = BTreeMap::new();
+    for x in diagnostics {
+        // Pretend the language server sent us the diagnostic past the end of line
+        let line = x.range.end.line;
+        match lines.get(&line) {
+            Some(most_severe) if severity(most_severe) <= severity(x) => (),
+            _ => {
+                lines.insert(line, x);
+            }
+        }
+    }
+    lines
+        .into_iter()
+        .map(|(line, x)| {
+            let face = match severity(x) {
+                DiagnosticSeverity::Error => "InlayDiagnosticError",
+                _ => "InlayDiagnosticWarning",
+            };
+            let line_text = get_line(line as usize, text).to_string();
+            let line_text = line_text.trim_end_matches(|c| c == '\n' || c == '\r');
+            let mut pos = lsp_position_to_kakoune(&x.range.end, text, ctx.offset_encoding);
+            pos.column = get_line(line as usize, text).len_bytes() as u32;
+            let message = x.message.lines().next().unwrap_or_default();
+            let message = if stale {
+                format!("{} (stale)", message)
+            } else {
+                message.to_string()
+            };
+            let message = match ctx.diagnostics_window_width {
+                // Leave room for the line itself and the space separating the message.
+                Some(width) => {
+                    truncate_to_width(&message, width.saturating_sub(line_text.width() + 1))
+                }
+                None => message,
+            };
+            editor_quote(&format!(
+                "{}+0|{{{}}}{{\\}} {}",
+                pos,
+                face,
+                message.replace("|", "\\|")
+            ))
+        })
+        .join(" ")
+}
+
+/// Cut text to the display width, marking the cut with an ellipsis.
+fn truncate_to_width(text: &str, width: usize) -> String {
+    if text.width() <= width {
+        return text.to_string();
+    }
+    let mut truncated = String::new();
+    let mut truncated_width = 0;
+    for c in text.chars() {
+        let c_width = c.width().unwrap_or(0);
+        // One column is left for the ellipsis.
+        if truncated_width + c_width + 1 > width {
+            break;
+        }
+        truncated.push(c);
+        truncated_width += c_width;
+    }
+    if width > 0 {
+        truncated.push('…');
+    }
+    truncated
+}
+
+/// Severity of the diagnostic, those without one are errors as per spec.
+fn severity(diagnostic: &Diagnostic) -> DiagnosticSeverity {
+    diagnostic.severity.unwrap_or(DiagnosticSeverity::Error)
+}
+
+fn parse_severity(name: &str) -> Option<DiagnosticSeverity> {
+    match name {
+        "error" => Some(DiagnosticSeverity::Error),
+        "warning" => Some(DiagnosticSeverity::Warning),
+        "info" => Some(DiagnosticSeverity::Information),
+        "hint" => Some(DiagnosticSeverity::Hint),
+        _ => None,
+    }
+}
+
+/// Switch how diagnostics are presented and show the ones published so far accordingly.
+pub fn diagnostics_display(_meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = DiagnosticsDisplayParams::deserialize(params)
+        .expect("Params should follow DiagnosticsDisplayParams structure");
+    ctx.diagnostics_display = params.mode;
+    ctx.diagnostics_window_width = Some(params.window_width);
+    show_all_diagnostics(ctx);
+}
+
+/// Show only diagnostics at least as severe as the one set in `lsp_diagnostic_min_severity`.
+pub fn diagnostic_min_severity(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = DiagnosticMinSeverityParams::deserialize(params)
+        .expect("Params should follow DiagnosticMinSeverityParams structure");
+    let severity = match parse_severity(&params.severity) {
+        Some(severity) => severity,
+        None => {
+            let msg = format!(
+                "Invalid lsp_diagnostic_min_severity {}, expected error, warning, info or hint",
+                params.severity
+            );
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+    };
+    if severity != ctx.diagnostic_min_severity {
+        ctx.diagnostic_min_severity = severity;
+        show_all_diagnostics(ctx);
+    }
+}
+
+/// Show diagnostics published so far again, e.g. after changing how they are presented.
+fn show_all_diagnostics(ctx: &mut Context) {
+    let buffiles = ctx
+        .documents
+        .keys()
+        .filter(|buffile| ctx.diagnostics.contains_key(*buffile))
+        .cloned()
+        .collect::<Vec<_>>();
+    for buffile in buffiles {
+        show_diagnostics(&buffile, ctx);
+    }
+}
+
+/// Move the cursor to the next or previous diagnostic of the buffer, wrapping around.
+pub fn diagnostic_goto(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = DiagnosticGotoParams::deserialize(params)
+        .expect("Params should follow DiagnosticGotoParams structure");
+    let min_severity = if params.severity.is_empty() {
+        DiagnosticSeverity::Hint
+    } else {
+        match parse_severity(&params.severity) {
+            Some(severity) => severity,
+            None => {
+                let msg = format!(
+                    "Invalid severity {}, expected error, warning, info or hint",
+                    params.severity
+                );
+                ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+                return;
+            }
+        }
+    };
+    let positions = ctx
         .diagnostics
+        .get(&meta.buffile)
+        .into_iter()
+        .flatten()
+        .filter(|x| severity(x) <= min_severity)
+        .filter_map(|x| get_kakoune_position(&meta.buffile, &x.range.start, ctx))
+        .sorted_by_key(|p| (p.line, p.column))
+        .dedup()
+        .collect::<Vec<_>>();
+    match adjacent_position(&positions, &params.position, params.previous) {
+        Some(p) => {
+            let command = format!("select {}.{},{}.{}", p.line, p.column, p.line, p.column);
+            ctx.exec(meta, command);
+        }
+        None => {
+            let msg = "No diagnostics found";
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+        }
+    }
+}
+
+/// First position after the cursor, or last one before it, among sorted positions, wrapping
+/// around the ends.
+fn adjacent_position<'a>(
+    positions: &'a [KakounePosition],
+    cursor: &KakounePosition,
+    previous: bool,
+) -> Option<&'a KakounePosition> {
+    let cursor = (cursor.line, cursor.column);
+    if previous {
+        positions
+            .iter()
+            .rev()
+            .find(|p| (p.line, p.column) < cursor)
+            .or_else(|| positions.last())
+    } else {
+        positions
+            .iter()
+            .find(|p| (p.line, p.column) > cursor)
+            .or_else(|| positions.first())
+    }
+}
+
+/// Diagnostics whose range contains the position.
+pub fn diagnostics_at(
+    diagnostics: &[Diagnostic],
+    pos: Position,
+) -> impl Iterator<Item = &Diagnostic> {
+    diagnostics.iter().filter(move |x| {
+        let start = x.range.start;
+        let end = x.range.end;
+        (start.line < pos.line && pos.line < end.line)
+            || (start.line == pos.line
+                && pos.line == end.line
+                && start.character <= pos.character
+                && pos.character <= end.character)
+            || (start.line == pos.line && pos.line <= end.line && start.character <= pos.character)
+            || (start.line <= pos.line && end.line == pos.line && pos.character <= end.character)
+    })
+}
+
+/// Diagnostic code, e.g. the name of a lint, which might be a number or a string.
+pub fn diagnostic_code(diagnostic: &Diagnostic) -> Option<String> {
+    match diagnostic.code.as_ref()? {
+        NumberOrString::Number(code) => Some(code.to_string()),
+        NumberOrString::String(code) => Some(code.clone()),
+    }
+}
+
+/// Message of the diagnostic followed by its code if any.
+pub fn diagnostic_message(diagnostic: &Diagnostic) -> String {
+    let message = diagnostic.message.trim();
+    match diagnostic_code(diagnostic) {
+        Some(code) => format!("{} [{}]", message, code),
+        None => message.to_string(),
+    }
+}
+
+/// Related location as `path:line:column: message`, the path relative to the project root.
+pub fn related_information_label(related: &DiagnosticRelatedInformation, ctx: &Context) -> String {
+    let Location { uri, range } = &related.location;
+    let fallback = KakounePosition {
+        line: range.start.line + 1,
+        column: range.start.character + 1,
+    };
+    // Locations outside of the file system, e.g. in a library archive, are shown by their URI.
+    let (file, pos) = match uri.to_file_path() {
+        Ok(path) => {
+            let pos = path
+                .to_str()
+                .and_then(|path| get_file_contents(path, ctx))
+                .map_or(fallback, |contents| {
+                    lsp_range_to_kakoune(range, &contents, ctx.offset_encoding).start
+                });
+            let stripped = path.strip_prefix(&ctx.root_path).unwrap_or(&path);
+            (stripped.display().to_string(), pos)
+        }
+        Err(_) => (uri.to_string(), fallback),
+    };
+    format!("{}:{}:{}: {}", file, pos.line, pos.column, related.message)
+}
+
+/// Show the diagnostics at the cursor with a menu to jump to the locations related to them, e.g.
+/// where a moved value was first used.
+pub fn diagnostic_details(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let pos = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
+    let diagnostics = ctx
+        .diagnostics
+        .get(&meta.buffile)
+        .map(|diagnostics| {
+            diagnostics_at(diagnostics, pos)
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if diagnostics.is_empty() {
+        let msg = "No diagnostics at the cursor";
+        ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+        return;
+    }
+    let related = diagnostics
         .iter()
-        .flat_map(|(filename, diagnostics)| {
-            diagnostics
-                .iter()
-                .map(|x| {
-                    let p = get_kakoune_position(filename, &x.range.start, ctx).unwrap();
-                    format!(
-                        "{}:{}:{}: {}:{}",
-                        Path::new(filename)
-                            .strip_prefix(&ctx.root_path)
-                            .ok()
-                            .and_then(|p| Some(p.to_str().unwrap()))
-                            .or_else(|| Some(filename))
-                            .unwrap(),
-                        p.line,
-                        p.column,
-                        match x.severity {
-                            Some(DiagnosticSeverity::Error) => "error",
-                            _ => "warning",
-                        },
-                        x.message
-                    )
+        .flat_map(|x| x.related_information.iter().flatten())
+        .collect::<Vec<_>>();
+    if related.is_empty() {
+        let messages = diagnostics.iter().map(diagnostic_message).join("\n");
+        ctx.exec(meta, format!("info {}", editor_quote(&messages)));
+        return;
+    }
+    let menu_args = related
+        .into_iter()
+        .filter_map(|related| {
+            let Location { uri, range } = &related.location;
+            let path = uri.to_file_path().ok()?;
+            let path_str = path.to_str()?;
+            let pos = match get_file_contents(path_str, ctx) {
+                Some(contents) => lsp_range_to_kakoune(range, &contents, ctx.offset_encoding).start,
+                None => return None,
+            };
+            let command = format!(
+                "eval -try-client %opt{{jumpclient}} -verbatim -- edit -existing {} {} {}",
+                editor_quote(path_str),
+                pos.line,
+                pos.column,
+            );
+            Some(format!(
+                "{} {}",
+                editor_quote(&related_information_label(related, ctx)),
+                editor_quote(&command)
+            ))
+        })
+        .join(" ");
+    ctx.exec(meta, format!("menu {}", menu_args));
+}
+
+/// Open the documentation of the code of the diagnostic at the cursor, offering a menu if there
+/// are several.
+pub fn diagnostic_open_doc(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let pos = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
+    let docs = ctx
+        .diagnostics
+        .get(&meta.buffile)
+        .map(|diagnostics| {
+            diagnostics_at(diagnostics, pos)
+                .filter_map(|x| {
+                    let href = &x.code_description.as_ref()?.href;
+                    let label = diagnostic_code(x).unwrap_or_else(|| href.to_string());
+                    Some((
+                        label,
+                        format!("lsp-open-url {}", editor_quote(href.as_str())),
+                    ))
                 })
+                .unique()
                 .collect::<Vec<_>>()
         })
-        .join("\n");
+        .unwrap_or_default();
+    match docs.len() {
+        0 => {
+            let msg = "No documentation linked to diagnostics at the cursor";
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+        }
+        1 => {
+            let (_, command) = docs.into_iter().next().unwrap();
+            ctx.exec(meta, command);
+        }
+        _ => {
+            let menu_args = docs
+                .into_iter()
+                .map(|(label, command)| {
+                    format!("{} {}", editor_quote(&label), editor_quote(&command))
+                })
+                .join(" ");
+            ctx.exec(meta, format!("menu {}", menu_args));
+        }
+    }
+}
+
+pub fn editor_diagnostics(meta: EditorMeta, ctx: &mut Context) {
+    let content = diagnostic_lines(ctx.diagnostics.iter(), ctx, format_diagnostics_line);
+    let command = format!(
+        "lsp-show-diagnostics {} {}",
+        editor_quote(&ctx.root_path),
+        editor_quote(&content),
+    );
+    ctx.exec(meta, command);
+}
+
+pub fn workspace_diagnostics(meta: EditorMeta, ctx: &mut Context) {
+    if !ctx.workspace_diagnostics_provider {
+        let msg = "Language server does not support workspace diagnostics";
+        ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+        return;
+    }
+    // A token unique to the request keeps chunks of overlapping requests apart.
+    let token = format!(
+        "{}{}",
+        WORKSPACE_DIAGNOSTIC_TOKEN_PREFIX, ctx.request_counter
+    );
+    let req_params = WorkspaceDiagnosticParams {
+        identifier: None,
+        previous_result_ids: vec![],
+        partial_result_params: PartialResultParams {
+            partial_result_token: Some(NumberOrString::String(token.clone())),
+        },
+    };
+    ctx.workspace_diagnostic_chunks
+        .insert(token.clone(), vec![]);
+    ctx.call::<WorkspaceDiagnosticRequest, _>(meta, req_params, move |ctx, meta, result| {
+        let chunks = ctx
+            .workspace_diagnostic_chunks
+            .remove(&token)
+            .unwrap_or_default();
+        workspace_diagnostics_response(meta, chunks, result, ctx)
+    });
+}
+
+/// Collect a chunk of workspace/diagnostic partial results sent via $/progress.
+/// Returns false if the progress notification is not related to workspace diagnostics.
+pub fn workspace_diagnostics_progress(params: Params, ctx: &mut Context) -> bool {
+    let params: WorkspaceDiagnosticProgress = match params.parse() {
+        Ok(params) => params,
+        Err(_) => return false,
+    };
+    let token = match &params.token {
+        NumberOrString::String(token) if token.starts_with(WORKSPACE_DIAGNOSTIC_TOKEN_PREFIX) => {
+            token
+        }
+        _ => return false,
+    };
+    // Chunks arriving after the response of their request are dropped.
+    if let Some(chunks) = ctx.workspace_diagnostic_chunks.get_mut(token) {
+        chunks.extend(params.value.items);
+    }
+    true
+}
+
+fn workspace_diagnostics_response(
+    meta: EditorMeta,
+    mut chunks: Vec<WorkspaceDocumentDiagnosticReport>,
+    result: WorkspaceDiagnosticReport,
+    ctx: &mut Context,
+) {
+    chunks.extend(result.items);
+    let documents = &ctx.documents;
+    ctx.workspace_diagnostics =
+        merge_workspace_reports(&ctx.workspace_diagnostics, chunks, |path| {
+            documents.contains_key(path)
+        });
+    // Diagnostics for open buffers are kept up to date by textDocument/publishDiagnostics.
+    let mut diagnostics = ctx.diagnostics.clone();
+    for (path, items) in &ctx.workspace_diagnostics {
+        if !ctx.documents.contains_key(path) {
+            diagnostics.insert(path.clone(), items.clone());
+        }
+    }
+    let content = diagnostic_lines(diagnostics.iter(), ctx, format_workspace_diagnostics_line);
     let command = format!(
         "lsp-show-diagnostics {} {}",
         editor_quote(&ctx.root_path),
@@ -157,3 +720,270 @@ pub fn editor_diagnostics(meta: EditorMeta, ctx: &mut Context) {
     );
     ctx.exec(meta, command);
 }
+
+/// Diagnostics of files not open in the editor according to the workspace reports, later reports
+/// superseding earlier ones for the same file. Files reported unchanged keep their previous ones.
+fn merge_workspace_reports<F>(
+    previous: &HashMap<String, Vec<Diagnostic>>,
+    reports: Vec<WorkspaceDocumentDiagnosticReport>,
+    is_open: F,
+) -> HashMap<String, Vec<Diagnostic>>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut diagnostics: HashMap<String, Vec<Diagnostic>> = HashMap::default();
+    for report in reports {
+        let path = match report.uri().to_file_path() {
+            Ok(path) => path.to_str().unwrap().to_string(),
+            Err(_) => continue,
+        };
+        if is_open(&path) {
+            continue;
+        }
+        match report {
+            WorkspaceDocumentDiagnosticReport::Full { items, .. } => {
+                diagnostics.insert(path, items);
+            }
+            WorkspaceDocumentDiagnosticReport::Unchanged { .. } => {
+                if !diagnostics.contains_key(&path) {
+                    let items = previous.get(&path).cloned().unwrap_or_default();
+                    diagnostics.insert(path, items);
+                }
+            }
+        }
+    }
+    diagnostics
+}
+
+/// Show diagnostics of open buffers formatted like grep output, to walk through them with
+/// `grep-next-match`.
+pub fn diagnostics_list(meta: EditorMeta, ctx: &Context) {
+    let diagnostics = ctx
+        .diagnostics
+        .iter()
+        .filter(|(buffile, _)| ctx.documents.contains_key(*buffile))
+        .flat_map(|(buffile, diagnostics)| {
+            diagnostics.iter().map(move |x| {
+                let p =
+                    get_kakoune_position(buffile, &x.range.start, ctx).unwrap_or(KakounePosition {
+                        line: x.range.start.line + 1,
+                        column: x.range.start.character + 1,
+                    });
+                (relative_path(buffile, ctx), p, x)
+            })
+        })
+        .sorted_by_key(|(path, p, _)| (path.clone(), p.line, p.column))
+        .collect::<Vec<_>>();
+    let content = diagnostics
+        .iter()
+        .map(|(path, p, x)| format_diagnostics_list_line(path, p, x))
+        .join("\n");
+    let command = format!(
+        "lsp-show-diagnostics-list {} {}",
+        editor_quote(&ctx.root_path),
+        editor_quote(&content),
+    );
+    ctx.exec(meta, command);
+}
+
+fn relative_path(filename: &str, ctx: &Context) -> String {
+    Path::new(filename)
+        .strip_prefix(&ctx.root_path)
+        .ok()
+        .and_then(|p| p.to_str())
+        .unwrap_or(filename)
+        .to_string()
+}
+
+fn severity_label(x: &Diagnostic) -> &'static str {
+    match severity(x) {
+        DiagnosticSeverity::Error => "error",
+        DiagnosticSeverity::Warning => "warning",
+        DiagnosticSeverity::Information => "info",
+        _ => "hint",
+    }
+}
+
+fn format_diagnostics_list_line(path: &str, p: &KakounePosition, x: &Diagnostic) -> String {
+    // Multiline messages would break grep-style navigation.
+    let message = diagnostic_message(x).lines().join(" ");
+    format!(
+        "{}:{}:{}: [{}] {}",
+        path,
+        p.line,
+        p.column,
+        severity_label(x),
+        message
+    )
+}
+
+/// Format a workspace diagnostic as a `path:line:col: severity: message` line.
+fn format_workspace_diagnostics_line(path: &str, p: &KakounePosition, x: &Diagnostic) -> String {
+    let message = diagnostic_message(x).lines().join(" ");
+    format!(
+        "{}:{}:{}: {}: {}",
+        path,
+        p.line,
+        p.column,
+        severity_label(x),
+        message
+    )
+}
+
+/// Format a diagnostic the way `lsp-diagnostics` always has, telling errors from the rest only.
+fn format_diagnostics_line(path: &str, p: &KakounePosition, x: &Diagnostic) -> String {
+    format!(
+        "{}:{}:{}: {}:{}",
+        path,
+        p.line,
+        p.column,
+        match x.severity {
+            Some(DiagnosticSeverity::Error) => "error",
+            _ => "warning",
+        },
+        x.message
+    )
+}
+
+/// Format diagnostics as a grep-style list sorted by file, one line per diagnostic.
+fn diagnostic_lines<'a, I, F>(diagnostics: I, ctx: &Context, format_line: F) -> String
+where
+    I: Iterator<Item = (&'a String, &'a Vec<Diagnostic>)>,
+    F: Fn(&str, &KakounePosition, &Diagnostic) -> String,
+{
+    diagnostics
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .flat_map(|(filename, diagnostics)| {
+            diagnostics
+                .iter()
+                .map(|x| {
+                    let p = get_kakoune_position(filename, &x.range.start, ctx).unwrap_or(
+                        KakounePosition {
+                            line: x.range.start.line + 1,
+                            column: x.range.start.character + 1,
+                        },
+                    );
+                    format_line(&relative_path(filename, ctx), &p, x)
+                })
+                .collect::<Vec<_>>()
+        })
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_wide_characters_to_width() {
+        assert_eq!(truncate_to_width("unused variable", 20), "unused variable");
+        assert_eq!(truncate_to_width("unused variable", 7), "unused…");
+        // Wide characters take two columns each.
+        assert_eq!(truncate_to_width("未使用の変数", 6), "未使…");
+        assert_eq!(truncate_to_width("unused", 0), "");
+    }
+
+    #[test]
+    fn adjacent_position_wraps_around() {
+        let positions = vec![
+            KakounePosition { line: 2, column: 5 },
+            KakounePosition { line: 7, column: 1 },
+        ];
+        let cursor = KakounePosition { line: 2, column: 5 };
+        assert_eq!(
+            adjacent_position(&positions, &cursor, false),
+            Some(&positions[1])
+        );
+        assert_eq!(
+            adjacent_position(&positions, &cursor, true),
+            Some(&positions[1])
+        );
+        let cursor = KakounePosition { line: 9, column: 1 };
+        assert_eq!(
+            adjacent_position(&positions, &cursor, false),
+            Some(&positions[0])
+        );
+        assert_eq!(adjacent_position(&[], &cursor, false), None);
+    }
+
+    #[test]
+    fn workspace_reports_skip_open_files_and_keep_unchanged() {
+        let diagnostic = |message: &str| {
+            Diagnostic::new_simple(
+                Range::new(Position::new(0, 0), Position::new(0, 1)),
+                message.to_string(),
+            )
+        };
+        let full = |path: &str, message: &str| WorkspaceDocumentDiagnosticReport::Full {
+            uri: Url::from_file_path(path).unwrap(),
+            items: vec![diagnostic(message)],
+        };
+        let unchanged = |path: &str| WorkspaceDocumentDiagnosticReport::Unchanged {
+            uri: Url::from_file_path(path).unwrap(),
+        };
+        let mut previous = HashMap::new();
+        previous.insert("/p/b.rs".to_string(), vec![diagnostic("b")]);
+        let reports = vec![
+            full("/p/a.rs", "a"),
+            unchanged("/p/b.rs"),
+            full("/p/open.rs", "open"),
+            full("/p/a.rs", "a2"),
+        ];
+        let merged = merge_workspace_reports(&previous, reports, |path| path == "/p/open.rs");
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged["/p/a.rs"], vec![diagnostic("a2")]);
+        assert_eq!(merged["/p/b.rs"], vec![diagnostic("b")]);
+    }
+
+    #[test]
+    fn diagnostics_list_line_is_grep_style() {
+        let mut diagnostic = Diagnostic::new_simple(
+            Range::new(Position::new(4, 8), Position::new(4, 9)),
+            "mismatched types\nexpected `u32`".to_string(),
+        );
+        diagnostic.severity = Some(DiagnosticSeverity::Warning);
+        diagnostic.code = Some(NumberOrString::String("E0308".to_string()));
+        let p = KakounePosition { line: 5, column: 9 };
+        assert_eq!(
+            format_diagnostics_list_line("src/main.rs", &p, &diagnostic),
+            "src/main.rs:5:9: [warning] mismatched types expected `u32` [E0308]"
+        );
+    }
+
+    #[test]
+    fn workspace_diagnostics_line_has_severity_and_message() {
+        let mut diagnostic = Diagnostic::new_simple(
+            Range::new(Position::new(0, 0), Position::new(0, 1)),
+            "consider using `let`".to_string(),
+        );
+        diagnostic.severity = Some(DiagnosticSeverity::Hint);
+        let p = KakounePosition { line: 1, column: 1 };
+        assert_eq!(
+            format_workspace_diagnostics_line("src/lib.rs", &p, &diagnostic),
+            "src/lib.rs:1:1: hint: consider using `let`"
+        );
+    }
+
+    #[test]
+    fn message_with_number_or_string_code() {
+        let diagnostic = |code| Diagnostic {
+            message: "unused variable: `x`\n".to_string(),
+            code,
+            ..Diagnostic::default()
+        };
+        assert_eq!(
+            diagnostic_message(&diagnostic(Some(NumberOrString::String(
+                "unused_variables".to_string()
+            )))),
+            "unused variable: `x` [unused_variables]"
+        );
+        assert_eq!(
+            diagnostic_message(&diagnostic(Some(NumberOrString::Number(6133)))),
+            "unused variable: `x` [6133]"
+        );
+        assert_eq!(
+            diagnostic_message(&diagnostic(None)),
+            "unused variable: `x`"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! Merging of the answers of several language servers to an editor request. Requests for
+//! completions, hover info and code actions go to all servers of the buffer which provide the
+//! feature, and the controller of the last server to answer shows all answers at once.
+
+use crate::types::EditorMeta;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Answers to the latest request of a kind by buffer, until all servers have answered it.
+pub type SharedAnswers<T> = Arc<Mutex<HashMap<String, Answers<T>>>>;
+
+pub struct Answers<T> {
+    id: u64,
+    pending: usize,
+    // Answers by position of the server, servers which failed to answer are left out.
+    answers: Vec<(usize, T)>,
+}
+
+/// Add the answer of the server to the ones of the other servers the request was sent to, None if
+/// the server failed to answer. All answers are returned once every server has answered, in the
+/// order of servers, and None until then. Requests sent to a single server are answered right away.
+pub fn collect<T>(
+    shared: &SharedAnswers<T>,
+    meta: &EditorMeta,
+    answer: Option<T>,
+) -> Option<Vec<T>> {
+    let fan_out = match meta.fan_out {
+        Some(fan_out) => fan_out,
+        None => return Some(answer.into_iter().collect()),
+    };
+    let mut shared = shared.lock().unwrap();
+    let answers = shared
+        .entry(meta.buffile.clone())
+        .or_insert_with(|| Answers {
+            id: fan_out.id,
+            pending: fan_out.servers,
+            answers: vec![],
+        });
+    if answers.id != fan_out.id {
+        // The answer to a request replaced by a newer one is dropped, and so are the answers
+        // collected for an older request, as it may never be answered by all servers.
+        if answers.id > fan_out.id {
+            return None;
+        }
+        *answers = Answers {
+            id: fan_out.id,
+            pending: fan_out.servers,
+            answers: vec![],
+        };
+    }
+    answers.pending -= 1;
+    answers
+        .answers
+        .extend(answer.map(|answer| (fan_out.index, answer)));
+    if answers.pending > 0 {
+        return None;
+    }
+    let mut answers = shared.remove(&meta.buffile).unwrap().answers;
+    answers.sort_by_key(|(index, _)| *index);
+    Some(answers.into_iter().map(|(_, answer)| answer).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::FanOut;
+
+    fn meta(id: u64, index: usize, servers: usize) -> EditorMeta {
+        EditorMeta {
+            session: "session".to_string(),
+            client: Some("client".to_string()),
+            buffile: "/src/main.ts".to_string(),
+            filetype: "typescript".to_string(),
+            version: 1,
+            fifo: None,
+            fan_out: Some(FanOut { id, index, servers }),
+        }
+    }
+
+    #[test]
+    fn answers_are_merged_in_order_of_servers() {
+        let shared = SharedAnswers::default();
+        assert_eq!(collect(&shared, &meta(1, 2, 3), Some("eslint")), None);
+        assert_eq!(collect(&shared, &meta(1, 1, 3), None), None);
+        assert_eq!(
+            collect(&shared, &meta(1, 0, 3), Some("typescript")),
+            Some(vec!["typescript", "eslint"])
+        );
+        assert!(shared.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn answers_to_replaced_requests_are_dropped() {
+        let shared = SharedAnswers::default();
+        assert_eq!(collect(&shared, &meta(1, 0, 2), Some("old")), None);
+        assert_eq!(collect(&shared, &meta(2, 0, 2), Some("new")), None);
+        assert_eq!(collect(&shared, &meta(1, 1, 2), Some("old")), None);
+        assert_eq!(
+            collect(&shared, &meta(2, 1, 2), Some("newer")),
+            Some(vec!["new", "newer"])
+        );
+    }
+}
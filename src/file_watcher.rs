@@ -0,0 +1,442 @@
+//! Watch files the language server registered interest in via `workspace/didChangeWatchedFiles`.
+//!
+//! Directories which can contain matches are watched through the native notification API of the
+//! platform, by way of the notify crate. Its events are debounced: a file changed several times in
+//! a row yields a single event, and events arriving together are sent to the server at once.
+//! Events from version control metadata and build output are dropped as they are never of interest.
+
+use crate::workspace::expand_glob_braces;
+use crossbeam_channel::{unbounded, Receiver};
+use itertools::Itertools;
+use lsp_types::*;
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(200);
+
+/// Directories whose contents are ignored unless a pattern names them literally.
+const IGNORED_DIRS: &[&str] = &[".git", ".hg", ".svn", "node_modules", "target"];
+
+/// LSP glob semantics: `*` and `?` never match a path separator, only `**` spans directories.
+const MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+// Watcher registration as of LSP 3.17 which added relative patterns, not covered by lsp-types yet.
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DidChangeWatchedFilesRegistrationOptions {
+    pub watchers: Vec<FileSystemWatcher>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FileSystemWatcher {
+    pub glob_pattern: GlobPattern,
+    pub kind: Option<WatchKind>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum GlobPattern {
+    String(String),
+    Relative(RelativePattern),
+}
+
+/// Pattern matched relative to a base directory.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RelativePattern {
+    pub base_uri: OneOf<WorkspaceFolder, Url>,
+    pub pattern: String,
+}
+
+pub struct FileWatcher {
+    // Watching stops once it's dropped, which also ends the thread forwarding its events.
+    _watcher: Option<RecommendedWatcher>,
+    receiver: Receiver<Vec<FileEvent>>,
+}
+
+impl FileWatcher {
+    /// Start watching files matching the watchers, relative globs being resolved against the root.
+    pub fn start(root_path: &str, watchers: Vec<FileSystemWatcher>) -> Self {
+        let patterns = watcher_patterns(root_path, &watchers);
+        let (notify_sender, notify_receiver) = mpsc::channel();
+        let watcher = match notify::watcher(notify_sender, DEBOUNCE_DELAY) {
+            Ok(mut watcher) => {
+                for (path, mode) in watch_roots(&patterns) {
+                    if let Err(e) = watcher.watch(&path, mode) {
+                        warn!("Failed to watch {}: {}", path.display(), e);
+                    }
+                }
+                Some(watcher)
+            }
+            Err(e) => {
+                error!("Failed to start file watcher: {}", e);
+                None
+            }
+        };
+        let (sender, receiver) = unbounded();
+        thread::Builder::new()
+            .name("File watcher".to_string())
+            .spawn(move || {
+                while let Ok(event) = notify_receiver.recv() {
+                    let changes = iter::once(event)
+                        .chain(notify_receiver.try_iter())
+                        .flat_map(changed_paths);
+                    let events = file_events(changes, &patterns);
+                    if !events.is_empty() && sender.send(events).is_err() {
+                        break;
+                    }
+                }
+            })
+            .unwrap();
+        FileWatcher {
+            _watcher: watcher,
+            receiver,
+        }
+    }
+
+    pub fn receiver(&self) -> &Receiver<Vec<FileEvent>> {
+        &self.receiver
+    }
+}
+
+/// Absolute glob pattern of a watcher.
+struct WatchPattern {
+    pattern: glob::Pattern,
+    // Path named by the literal prefix of the pattern, and how deep below it matches can be.
+    root: PathBuf,
+    depth: Option<usize>,
+    // Kinds of events the watcher is interested in.
+    kind: WatchKind,
+}
+
+fn watcher_patterns(root_path: &str, watchers: &[FileSystemWatcher]) -> Vec<WatchPattern> {
+    watchers
+        .iter()
+        .flat_map(|watcher| {
+            // Plain globs are relative to the project root unless absolute.
+            let (base, glob) = match &watcher.glob_pattern {
+                GlobPattern::String(glob) => (PathBuf::from(root_path), glob),
+                GlobPattern::Relative(relative) => {
+                    let base_uri = match &relative.base_uri {
+                        OneOf::Left(folder) => &folder.uri,
+                        OneOf::Right(uri) => uri,
+                    };
+                    match base_uri.to_file_path() {
+                        Ok(base) => (base, &relative.pattern),
+                        Err(_) => {
+                            warn!("Unsupported file watcher base: {}", base_uri);
+                            return vec![];
+                        }
+                    }
+                }
+            };
+            let kind = watcher.kind.unwrap_or(WatchKind::all());
+            expand_glob_braces(glob)
+                .into_iter()
+                .filter_map(|glob| {
+                    let (base, glob) = if Path::new(&glob).is_absolute() {
+                        (PathBuf::from("/"), glob.trim_start_matches('/').to_string())
+                    } else {
+                        (base.clone(), glob)
+                    };
+                    // The base is a path rather than a glob, its brackets and such match literally.
+                    let escaped_base = glob::Pattern::escape(&base.to_string_lossy());
+                    let absolute_glob = format!("{}/{}", escaped_base.trim_end_matches('/'), glob);
+                    let pattern = match glob::Pattern::new(&absolute_glob) {
+                        Ok(pattern) => pattern,
+                        Err(e) => {
+                            warn!("Invalid file watcher glob {}: {}", absolute_glob, e);
+                            return None;
+                        }
+                    };
+                    let (literal, depth) = match_root(&glob);
+                    Some(WatchPattern {
+                        pattern,
+                        root: base.join(literal),
+                        depth,
+                        kind,
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Longest prefix of the relative glob free of wildcards, and how deep below it matches can be,
+/// unlimited if the glob contains `**`.
+fn match_root(glob: &str) -> (PathBuf, Option<usize>) {
+    let is_literal = |component: &str| !component.contains(|c| "*?[".contains(c));
+    let components = glob.split('/').collect::<Vec<_>>();
+    let literal = components
+        .iter()
+        .take_while(|component| is_literal(component))
+        .count();
+    let base = PathBuf::from(components[..literal].join("/"));
+    let rest = &components[literal..];
+    let depth = if rest.contains(&"**") {
+        None
+    } else {
+        Some(rest.len())
+    };
+    (base, depth)
+}
+
+/// Directories to watch so that all matches of the patterns are seen. Directories within a
+/// recursively watched one are left out, they are covered already.
+fn watch_roots(patterns: &[WatchPattern]) -> Vec<(PathBuf, RecursiveMode)> {
+    let mut roots = BTreeMap::new();
+    for pattern in patterns {
+        let (dir, mode) = match pattern.depth {
+            // The glob names a single file, its directory tells about it being created.
+            Some(0) => match pattern.root.parent() {
+                Some(parent) => (parent.to_path_buf(), RecursiveMode::NonRecursive),
+                None => continue,
+            },
+            Some(1) => (pattern.root.clone(), RecursiveMode::NonRecursive),
+            _ => (pattern.root.clone(), RecursiveMode::Recursive),
+        };
+        if !dir.is_dir() {
+            debug!("Not watching missing directory {}", dir.display());
+            continue;
+        }
+        let entry = roots.entry(dir).or_insert(mode);
+        if mode == RecursiveMode::Recursive {
+            *entry = mode;
+        }
+    }
+    merge_watch_roots(roots)
+}
+
+/// Drop directories within recursively watched ones. Ancestors come first in the sorted map.
+fn merge_watch_roots(roots: BTreeMap<PathBuf, RecursiveMode>) -> Vec<(PathBuf, RecursiveMode)> {
+    let mut merged: Vec<(PathBuf, RecursiveMode)> = vec![];
+    for (dir, mode) in roots {
+        let covered = merged.iter().any(|(ancestor, ancestor_mode)| {
+            *ancestor_mode == RecursiveMode::Recursive && dir.starts_with(ancestor)
+        });
+        if !covered {
+            merged.push((dir, mode));
+        }
+    }
+    merged
+}
+
+/// Paths affected by an event along with the kind of change.
+fn changed_paths(event: DebouncedEvent) -> Vec<(PathBuf, WatchKind)> {
+    match event {
+        DebouncedEvent::Create(path) => vec![(path, WatchKind::Create)],
+        DebouncedEvent::Write(path) => vec![(path, WatchKind::Change)],
+        DebouncedEvent::Remove(path) => vec![(path, WatchKind::Delete)],
+        DebouncedEvent::Rename(from, to) => {
+            vec![(from, WatchKind::Delete), (to, WatchKind::Create)]
+        }
+        DebouncedEvent::Error(e, path) => {
+            warn!("File watcher error for {:?}: {}", path, e);
+            vec![]
+        }
+        // Notices are followed by the debounced events, the rest is of no interest.
+        _ => vec![],
+    }
+}
+
+/// Whether the path is within a directory ignored below the literal prefix of the pattern.
+fn is_ignored(pattern: &WatchPattern, path: &Path) -> bool {
+    let relative = match path.strip_prefix(&pattern.root) {
+        Ok(relative) => relative,
+        Err(_) => return false,
+    };
+    relative.parent().map_or(false, |dirs| {
+        dirs.iter()
+            .any(|dir| IGNORED_DIRS.iter().any(|ignored| dir == *ignored))
+    })
+}
+
+/// Events to report to the server, duplicates being coalesced.
+fn file_events(
+    changes: impl Iterator<Item = (PathBuf, WatchKind)>,
+    patterns: &[WatchPattern],
+) -> Vec<FileEvent> {
+    changes
+        .unique()
+        // Only report kinds of events some watcher of the file is interested in.
+        .filter(|(path, kind)| {
+            patterns.iter().any(|pattern| {
+                pattern.kind.contains(*kind)
+                    && pattern.pattern.matches_path_with(path, MATCH_OPTIONS)
+                    && !is_ignored(pattern, path)
+            })
+        })
+        .filter_map(|(path, kind)| {
+            let typ = if kind == WatchKind::Create {
+                FileChangeType::Created
+            } else if kind == WatchKind::Change {
+                FileChangeType::Changed
+            } else {
+                FileChangeType::Deleted
+            };
+            Some(FileEvent {
+                uri: Url::from_file_path(path).ok()?,
+                typ,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watcher(glob_pattern: GlobPattern, kind: Option<WatchKind>) -> FileSystemWatcher {
+        FileSystemWatcher { glob_pattern, kind }
+    }
+
+    fn changed(paths: &[&str], kind: WatchKind) -> Vec<(PathBuf, WatchKind)> {
+        paths
+            .iter()
+            .map(|path| (PathBuf::from(path), kind))
+            .collect()
+    }
+
+    #[test]
+    fn string_glob_is_relative_to_root() {
+        let watchers = [watcher(GlobPattern::String("**/*.rs".to_string()), None)];
+        let patterns = watcher_patterns("/project", &watchers);
+        let changes = changed(
+            &[
+                "/project/src/main.rs",
+                "/project/Cargo.toml",
+                "/other/lib.rs",
+                "/project/target/debug/build/out.rs",
+                "/project/src/main.rs",
+            ],
+            WatchKind::Create,
+        );
+        // Duplicates are coalesced and build output is ignored.
+        assert_eq!(
+            file_events(changes.into_iter(), &patterns),
+            vec![FileEvent {
+                uri: Url::parse("file:///project/src/main.rs").unwrap(),
+                typ: FileChangeType::Created,
+            }]
+        );
+    }
+
+    #[test]
+    fn star_does_not_match_across_directories() {
+        let watchers = [watcher(GlobPattern::String("*.rs".to_string()), None)];
+        let patterns = watcher_patterns("/project", &watchers);
+        let changes = changed(
+            &["/project/build.rs", "/project/src/a/b.rs"],
+            WatchKind::Create,
+        );
+        assert_eq!(
+            file_events(changes.into_iter(), &patterns),
+            vec![FileEvent {
+                uri: Url::parse("file:///project/build.rs").unwrap(),
+                typ: FileChangeType::Created,
+            }]
+        );
+    }
+
+    #[test]
+    fn matching_starts_at_literal_prefix() {
+        let watchers = [
+            watcher(GlobPattern::String("**/*.rs".to_string()), None),
+            watcher(GlobPattern::String("crates/*/Cargo.toml".to_string()), None),
+            watcher(GlobPattern::String("/etc/Cargo.toml".to_string()), None),
+        ];
+        let roots = watcher_patterns("/project", &watchers)
+            .into_iter()
+            .map(|pattern| (pattern.root, pattern.depth))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            roots,
+            vec![
+                (PathBuf::from("/project"), None),
+                (PathBuf::from("/project/crates"), Some(2)),
+                (PathBuf::from("/etc/Cargo.toml"), Some(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn root_with_glob_metacharacters_matches_literally() {
+        let watchers = [watcher(GlobPattern::String("src/*.rs".to_string()), None)];
+        let patterns = watcher_patterns("/home/u/proj[1]", &watchers);
+        assert_eq!(patterns[0].root, PathBuf::from("/home/u/proj[1]/src"));
+        let changes = changed(
+            &["/home/u/proj[1]/src/main.rs", "/home/u/proj1/src/main.rs"],
+            WatchKind::Create,
+        );
+        assert_eq!(
+            file_events(changes.into_iter(), &patterns),
+            vec![FileEvent {
+                uri: Url::from_file_path("/home/u/proj[1]/src/main.rs").unwrap(),
+                typ: FileChangeType::Created,
+            }]
+        );
+    }
+
+    #[test]
+    fn recursively_watched_directories_cover_their_contents() {
+        let roots = vec![
+            (PathBuf::from("/project"), RecursiveMode::NonRecursive),
+            (PathBuf::from("/project/crates"), RecursiveMode::Recursive),
+            (
+                PathBuf::from("/project/crates/foo"),
+                RecursiveMode::NonRecursive,
+            ),
+            (PathBuf::from("/project/src"), RecursiveMode::NonRecursive),
+        ];
+        assert_eq!(
+            merge_watch_roots(roots.into_iter().collect()),
+            vec![
+                (PathBuf::from("/project"), RecursiveMode::NonRecursive),
+                (PathBuf::from("/project/crates"), RecursiveMode::Recursive),
+                (PathBuf::from("/project/src"), RecursiveMode::NonRecursive),
+            ]
+        );
+    }
+
+    #[test]
+    fn relative_pattern_under_workspace_folder() {
+        let watchers = [watcher(
+            GlobPattern::Relative(RelativePattern {
+                base_uri: OneOf::Left(WorkspaceFolder {
+                    uri: Url::parse("file:///project/crates/foo").unwrap(),
+                    name: "foo".to_string(),
+                }),
+                pattern: "*.{toml,lock}".to_string(),
+            }),
+            Some(WatchKind::Delete),
+        )];
+        let patterns = watcher_patterns("/project", &watchers);
+        // Renaming deletes the old path and creates the new one, only deleting is of interest.
+        let changes = changed_paths(DebouncedEvent::Rename(
+            PathBuf::from("/project/crates/foo/Cargo.toml"),
+            PathBuf::from("/project/crates/foo/build.toml"),
+        ))
+        .into_iter()
+        .chain(changed(&["/project/Cargo.toml"], WatchKind::Delete));
+        assert_eq!(
+            file_events(changes, &patterns),
+            vec![FileEvent {
+                uri: Url::parse("file:///project/crates/foo/Cargo.toml").unwrap(),
+                typ: FileChangeType::Deleted,
+            }]
+        );
+    }
+}
@@ -1,17 +1,34 @@
 use crate::context::*;
 use crate::controller;
-use crate::language_features::semantic_highlighting;
+use crate::file_watcher::{DidChangeWatchedFilesRegistrationOptions, FileWatcher};
+use crate::language_features::{completion, inlay_hints, semantic_highlighting, type_hierarchy};
+use crate::settings;
 use crate::types::*;
 use crate::util::*;
+use crate::workspace;
 use itertools::Itertools;
+use jsonrpc_core::{Id, Params};
 use lsp_types::notification::*;
 use lsp_types::request::*;
 use lsp_types::*;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use toml;
 use url::Url;
 
+/// Initialize request taking raw params and returning raw result, to advertise and read
+/// capabilities not covered by lsp-types yet.
+enum InitializeWithExtensions {}
+
+impl Request for InitializeWithExtensions {
+    type Params = Value;
+    type Result = Value;
+    const METHOD: &'static str = Initialize::METHOD;
+}
+
 pub fn initialize(
     root_path: &str,
     initialization_options: Option<Value>,
@@ -24,7 +41,7 @@ pub fn initialize(
     let params = InitializeParams {
         capabilities: ClientCapabilities {
             workspace: Some(WorkspaceClientCapabilities {
-                apply_edit: Some(false),
+                apply_edit: Some(true),
                 workspace_edit: Some(WorkspaceEditClientCapabilities {
                     document_changes: Some(true),
                     resource_operations: Some(vec![
@@ -43,7 +60,9 @@ pub fn initialize(
                 did_change_configuration: Some(DynamicRegistrationClientCapabilities {
                     dynamic_registration: Some(false),
                 }),
-                did_change_watched_files: None,
+                did_change_watched_files: Some(DynamicRegistrationClientCapabilities {
+                    dynamic_registration: Some(true),
+                }),
                 symbol: Some(WorkspaceSymbolClientCapabilities {
                     dynamic_registration: Some(false),
                     symbol_kind: Some(SymbolKindCapability {
@@ -76,36 +95,62 @@ pub fn initialize(
                             SymbolKind::TypeParameter,
                         ]),
                     }),
-                    tag_support: None,
+                    tag_support: Some(TagSupport {
+                        value_set: vec![SymbolTag::Deprecated],
+                    }),
                 }),
                 execute_command: Some(DynamicRegistrationClientCapabilities {
                     dynamic_registration: Some(false),
                 }),
-                workspace_folders: Some(false),
-                configuration: Some(false),
-                semantic_tokens: None,
-                code_lens: None,
-                file_operations: None,
+                workspace_folders: Some(true),
+                configuration: Some(true),
+                semantic_tokens: Some(SemanticTokensWorkspaceClientCapabilities {
+                    refresh_support: Some(true),
+                }),
+                code_lens: Some(CodeLensWorkspaceClientCapabilities {
+                    refresh_support: Some(true),
+                }),
+                file_operations: Some(WorkspaceFileOperationsClientCapabilities {
+                    dynamic_registration: Some(false),
+                    did_create: Some(true),
+                    did_rename: Some(true),
+                    did_delete: Some(true),
+                    will_rename: Some(true),
+                    ..WorkspaceFileOperationsClientCapabilities::default()
+                }),
             }),
             text_document: Some(TextDocumentClientCapabilities {
                 synchronization: Some(TextDocumentSyncClientCapabilities {
                     dynamic_registration: Some(false),
-                    will_save: Some(false),
-                    will_save_wait_until: Some(false),
+                    will_save: Some(true),
+                    will_save_wait_until: Some(true),
                     did_save: Some(true),
                 }),
                 completion: Some(CompletionClientCapabilities {
                     dynamic_registration: Some(false),
                     completion_item: Some(CompletionItemCapability {
                         snippet_support: Some(ctx.config.snippet_support),
-                        commit_characters_support: Some(false),
+                        commit_characters_support: Some(true),
                         documentation_format: Some(vec![MarkupKind::PlainText]),
-                        deprecated_support: Some(false),
+                        deprecated_support: Some(true),
                         preselect_support: Some(false),
-                        tag_support: None,
-                        insert_replace_support: None,
-                        resolve_support: None,
-                        insert_text_mode_support: None,
+                        tag_support: Some(TagSupport {
+                            value_set: vec![CompletionItemTag::Deprecated],
+                        }),
+                        insert_replace_support: Some(true),
+                        resolve_support: Some(CompletionItemCapabilityResolveSupport {
+                            properties: vec![
+                                "documentation".to_string(),
+                                "detail".to_string(),
+                                "additionalTextEdits".to_string(),
+                            ],
+                        }),
+                        insert_text_mode_support: Some(InsertTextModeSupport {
+                            value_set: vec![
+                                InsertTextMode::AsIs,
+                                InsertTextMode::AdjustIndentation,
+                            ],
+                        }),
                     }),
                     completion_item_kind: Some(CompletionItemKindCapability {
                         value_set: Some(vec![
@@ -147,11 +192,11 @@ pub fn initialize(
                     signature_information: Some(SignatureInformationSettings {
                         documentation_format: Some(vec![MarkupKind::PlainText]),
                         parameter_information: Some(ParameterInformationSettings {
-                            label_offset_support: Some(false),
+                            label_offset_support: Some(true),
                         }),
-                        active_parameter_support: None,
+                        active_parameter_support: Some(true),
                     }),
-                    context_support: Some(false),
+                    context_support: Some(true),
                 }),
                 references: Some(DynamicRegistrationClientCapabilities {
                     dynamic_registration: Some(false),
@@ -162,8 +207,10 @@ pub fn initialize(
                 document_symbol: Some(DocumentSymbolClientCapabilities {
                     dynamic_registration: Some(false),
                     symbol_kind: None,
-                    hierarchical_document_symbol_support: None,
-                    tag_support: None,
+                    hierarchical_document_symbol_support: Some(true),
+                    tag_support: Some(TagSupport {
+                        value_set: vec![SymbolTag::Deprecated],
+                    }),
                 }),
                 formatting: Some(DynamicRegistrationClientCapabilities {
                     dynamic_registration: Some(false),
@@ -176,19 +223,19 @@ pub fn initialize(
                 }),
                 declaration: Some(GotoCapability {
                     dynamic_registration: Some(false),
-                    link_support: Some(false),
+                    link_support: Some(true),
                 }),
                 definition: Some(GotoCapability {
                     dynamic_registration: Some(false),
-                    link_support: Some(false),
+                    link_support: Some(true),
                 }),
                 type_definition: Some(GotoCapability {
                     dynamic_registration: Some(false),
-                    link_support: Some(false),
+                    link_support: Some(true),
                 }),
                 implementation: Some(GotoCapability {
                     dynamic_registration: Some(false),
-                    link_support: Some(false),
+                    link_support: Some(true),
                 }),
                 code_action: Some(CodeActionClientCapabilities {
                     dynamic_registration: Some(false),
@@ -210,8 +257,10 @@ pub fn initialize(
                     }),
                     is_preferred_support: Some(false),
                     disabled_support: None,
-                    data_support: None,
-                    resolve_support: None,
+                    data_support: Some(true),
+                    resolve_support: Some(CodeActionCapabilityResolveSupport {
+                        properties: vec!["edit".to_string()],
+                    }),
                     honors_change_annotations: None,
                 }),
                 code_lens: Some(DynamicRegistrationClientCapabilities {
@@ -226,27 +275,36 @@ pub fn initialize(
                 }),
                 rename: Some(RenameClientCapabilities {
                     dynamic_registration: Some(false),
-                    prepare_support: Some(false),
-                    prepare_support_default_behavior: None,
+                    prepare_support: Some(true),
+                    prepare_support_default_behavior: Some(
+                        PrepareSupportDefaultBehavior::Identifier,
+                    ),
                     honors_change_annotations: None,
                 }),
                 publish_diagnostics: Some(PublishDiagnosticsClientCapabilities {
-                    related_information: Some(false),
+                    related_information: Some(true),
                     tag_support: None,
                     version_support: None,
-                    code_description_support: None,
+                    code_description_support: Some(true),
                     data_support: None,
                 }),
-                folding_range: None,
-                selection_range: None,
+                folding_range: Some(FoldingRangeClientCapabilities {
+                    dynamic_registration: Some(false),
+                    range_limit: None,
+                    // Kakoune folds whole lines.
+                    line_folding_only: Some(true),
+                }),
+                selection_range: Some(SelectionRangeClientCapabilities {
+                    dynamic_registration: Some(false),
+                }),
                 semantic_highlighting_capabilities: Some(SemanticHighlightingClientCapability {
                     semantic_highlighting: true,
                 }),
                 semantic_tokens: Some(SemanticTokensClientCapabilities {
                     dynamic_registration: Some(false),
                     requests: SemanticTokensClientCapabilitiesRequests {
-                        range: Some(false),
-                        full: Some(SemanticTokensFullOptions::Bool(true)),
+                        range: Some(true),
+                        full: Some(SemanticTokensFullOptions::Delta { delta: Some(true) }),
                     },
                     token_types: ctx
                         .config
@@ -266,12 +324,18 @@ pub fn initialize(
                     overlapping_token_support: None,
                     multiline_token_support: None,
                 }),
-                linked_editing_range: None,
-                call_hierarchy: None,
-                moniker: None,
+                linked_editing_range: Some(LinkedEditingRangeClientCapabilities {
+                    dynamic_registration: Some(false),
+                }),
+                call_hierarchy: Some(CallHierarchyClientCapabilities {
+                    dynamic_registration: Some(false),
+                }),
+                moniker: Some(DynamicRegistrationClientCapabilities {
+                    dynamic_registration: Some(false),
+                }),
             }),
             window: Some(WindowClientCapabilities {
-                work_done_progress: Some(false),
+                work_done_progress: Some(true),
                 show_message: None,
                 show_document: None,
             }),
@@ -283,7 +347,7 @@ pub fn initialize(
         root_uri: Some(Url::from_file_path(root_path).unwrap()),
         root_path: None,
         trace: Some(TraceOption::Off),
-        workspace_folders: None,
+        workspace_folders: Some(ctx.workspace_folders.clone()),
         client_info: Some(ClientInfo {
             name: env!("CARGO_PKG_NAME").to_owned(),
             version: Some(env!("CARGO_PKG_VERSION").to_owned()),
@@ -291,16 +355,229 @@ pub fn initialize(
         locale: None,
     };
 
-    ctx.call::<Initialize, _>(meta, params, move |ctx: &mut Context, _meta, result| {
-        ctx.capabilities = Some(result.capabilities);
-        ctx.semantic_highlighting_faces = semantic_highlighting::make_scope_map(ctx);
-        ctx.notify::<Initialized>(InitializedParams {});
-        controller::dispatch_pending_editor_requests(ctx)
-    });
+    let mut params = serde_json::to_value(params).unwrap();
+    for capabilities in vec![
+        completion::client_capabilities(),
+        inlay_hints::client_capabilities(),
+        type_hierarchy::client_capabilities(),
+        workspace::client_capabilities(),
+        position_encodings_capabilities(ctx.config.language[&ctx.language_id].offset_encoding),
+    ] {
+        settings::merge(&mut params["capabilities"], capabilities);
+    }
+
+    ctx.call::<InitializeWithExtensions, _>(
+        meta,
+        params,
+        move |ctx: &mut Context, _meta, result| {
+            // Configured encoding is the only one advertised, but servers might pick another one
+            // anyway, so it is not negotiated.
+            if ctx.config.language[&ctx.language_id]
+                .offset_encoding
+                .is_none()
+            {
+                ctx.offset_encoding = negotiated_offset_encoding(&result);
+            }
+            ctx.workspace_diagnostics_provider =
+                result["capabilities"]["diagnosticProvider"]["workspaceDiagnostics"] == true;
+            let type_hierarchy_provider = &result["capabilities"]["typeHierarchyProvider"];
+            ctx.type_hierarchy_provider =
+                !type_hierarchy_provider.is_null() && *type_hierarchy_provider != false;
+            let result: InitializeResult =
+                serde_json::from_value(result).expect("Failed to parse initialize result");
+            let route = Route {
+                session: ctx.session.clone(),
+                language: ctx.language_id.clone(),
+                root: ctx.root_path.clone(),
+            };
+            ctx.shared_capabilities
+                .lock()
+                .unwrap()
+                .insert(route, result.capabilities.clone());
+            ctx.capabilities = Some(result.capabilities);
+            ctx.server_info = result.server_info;
+            let commands = ctx
+                .capabilities
+                .as_ref()
+                .and_then(|caps| caps.execute_command_provider.as_ref())
+                .map_or(String::new(), |provider| {
+                    provider.commands.iter().map(|c| editor_quote(c)).join(" ")
+                });
+            ctx.exec(
+                ctx.meta_for_session(),
+                format!("set-option global lsp_server_commands {}", commands),
+            );
+            ctx.semantic_highlighting_faces = semantic_highlighting::make_scope_map(ctx);
+            ctx.notify::<Initialized>(InitializedParams {});
+            controller::dispatch_pending_editor_requests(ctx)
+        },
+    );
+}
+
+/// Position encodings of LSP 3.17 the client supports, the configured one or else any of those
+/// kak-lsp can handle, the preferred one first.
+fn position_encodings_capabilities(configured: Option<OffsetEncoding>) -> Value {
+    let encodings = match configured {
+        Some(encoding) => vec![encoding],
+        None => vec![
+            OffsetEncoding::Utf8,
+            OffsetEncoding::Utf32,
+            OffsetEncoding::Utf16,
+        ],
+    };
+    serde_json::json!({
+        "general": {
+            "positionEncodings": encodings,
+        },
+    })
+}
+
+/// Offset encoding the server picked out of the advertised ones, UTF-16 if it didn't pick any.
+fn negotiated_offset_encoding(initialize_result: &Value) -> OffsetEncoding {
+    let encoding = &initialize_result["capabilities"]["positionEncoding"];
+    if encoding.is_null() {
+        return OffsetEncoding::Utf16;
+    }
+    match serde_json::from_value(encoding.clone()) {
+        Ok(encoding) => encoding,
+        Err(_) => {
+            warn!("Unsupported position encoding {}, using utf-16", encoding);
+            OffsetEncoding::Utf16
+        }
+    }
+}
+
+/// Handle dynamic registration of capabilities. Only file watchers are supported, other
+/// registrations are acknowledged but ignored.
+pub fn register_capability(id: Id, params: Params, ctx: &mut Context) {
+    let params: RegistrationParams = params.parse().expect("Failed to parse params");
+    for registration in params.registrations {
+        match registration.method.as_str() {
+            DidChangeWatchedFiles::METHOD => {
+                let options = registration.register_options.clone().unwrap_or(Value::Null);
+                match serde_json::from_value::<DidChangeWatchedFilesRegistrationOptions>(options) {
+                    Ok(options) => {
+                        ctx.file_watchers.insert(registration.id, options.watchers);
+                    }
+                    Err(e) => warn!(
+                        "Invalid file watcher registration {}: {}",
+                        registration.id, e
+                    ),
+                }
+            }
+            method => warn!("Unsupported capability registration: {}", method),
+        }
+    }
+    restart_file_watcher(ctx);
+    ctx.reply(id, Ok(Value::Null));
+}
+
+pub fn unregister_capability(id: Id, params: Params, ctx: &mut Context) {
+    let params: UnregistrationParams = params.parse().expect("Failed to parse params");
+    for unregistration in params.unregisterations {
+        ctx.file_watchers.remove(&unregistration.id);
+    }
+    restart_file_watcher(ctx);
+    ctx.reply(id, Ok(Value::Null));
+}
+
+fn restart_file_watcher(ctx: &mut Context) {
+    // Dropping the old watcher stops it.
+    ctx.file_watcher = None;
+    let watchers = ctx
+        .file_watchers
+        .values()
+        .flatten()
+        .cloned()
+        .collect::<Vec<_>>();
+    if !watchers.is_empty() {
+        ctx.file_watcher = Some(FileWatcher::start(&ctx.root_path, watchers));
+    }
+}
+
+/// Forward changes of watched files to the server.
+pub fn did_change_watched_files(changes: Vec<FileEvent>, ctx: &mut Context) {
+    ctx.notify::<DidChangeWatchedFiles>(DidChangeWatchedFilesParams { changes });
+}
+
+/// Capabilities of the running language servers of an editor session by route, known once they
+/// are initialized.
+pub type SharedCapabilities = Arc<Mutex<HashMap<Route, ServerCapabilities>>>;
+
+/// Progress of shutting the language server down: `shutdown` request first, then `exit`
+/// notification once the server answered it or took too long to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutdownState {
+    Running,
+    Requested { deadline: Instant },
+    Exited,
+}
+
+impl ShutdownState {
+    /// Start waiting for the answer to the shutdown request, false if already shutting down.
+    pub fn request(&mut self, deadline: Instant) -> bool {
+        match self {
+            ShutdownState::Running => {
+                *self = ShutdownState::Requested { deadline };
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Move on to exit, false if already there.
+    pub fn exit(&mut self) -> bool {
+        match self {
+            ShutdownState::Exited => false,
+            _ => {
+                *self = ShutdownState::Exited;
+                true
+            }
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        match self {
+            ShutdownState::Requested { deadline } => Some(*deadline),
+            _ => None,
+        }
+    }
+
+    /// Whether the server has not answered the shutdown request in time.
+    pub fn is_overdue(&self, now: Instant) -> bool {
+        self.deadline().map_or(false, |deadline| now >= deadline)
+    }
+}
+
+/// Ask the language server to shut down, it's told to exit once it answers.
+pub fn shutdown(ctx: &mut Context) {
+    // A server which is not initialized yet can't be asked to shut down.
+    if ctx.capabilities.is_none() {
+        exit(ctx);
+        return;
+    }
+    let timeout = Duration::from_secs(ctx.config.server.shutdown_timeout);
+    if !ctx.shutdown.request(Instant::now() + timeout) {
+        return;
+    }
+    ctx.call::<Shutdown, _>(ctx.meta_for_session(), (), |ctx, _, _| exit(ctx));
+}
+
+/// Give up waiting for the answer to the shutdown request.
+pub fn shutdown_timeout(ctx: &mut Context) {
+    if ctx.shutdown.is_overdue(Instant::now()) {
+        warn!(
+            "{} language server ignored shutdown request, exiting anyway",
+            ctx.language_id
+        );
+        exit(ctx);
+    }
 }
 
 pub fn exit(ctx: &mut Context) {
-    ctx.notify::<Exit>(());
+    if ctx.shutdown.exit() {
+        ctx.notify::<Exit>(());
+    }
 }
 
 pub fn capabilities(meta: EditorMeta, ctx: &mut Context) {
@@ -324,6 +601,15 @@ pub fn capabilities(meta: EditorMeta, ctx: &mut Context) {
         features.push("lsp-completion (hooked on InsertIdle)".to_string());
     }
 
+    match server_capabilities.declaration_provider {
+        Some(DeclarationCapability::Simple(true))
+        | Some(DeclarationCapability::RegistrationOptions(_))
+        | Some(DeclarationCapability::Options(_)) => {
+            features.push("lsp-declaration".to_string());
+        }
+        _ => (),
+    };
+
     match server_capabilities.definition_provider {
         Some(OneOf::Left(true)) | Some(OneOf::Right(_)) => {
             features.push("lsp-definition (mapped to `gd` by default)".to_string());
@@ -331,6 +617,10 @@ pub fn capabilities(meta: EditorMeta, ctx: &mut Context) {
         _ => (),
     };
 
+    if server_capabilities.type_definition_provider.is_some() {
+        features.push("lsp-type-definition".to_string());
+    }
+
     if server_capabilities.implementation_provider.is_some() {
         features.push("lsp-implementation".to_string());
     }
@@ -383,6 +673,64 @@ pub fn capabilities(meta: EditorMeta, ctx: &mut Context) {
         }
     }
 
+    if let Some(ref provider) = server_capabilities.execute_command_provider {
+        features.push(format!(
+            "lsp-execute-command: [{}]",
+            provider.commands.join(", ")
+        ));
+    }
+
+    if server_capabilities.code_lens_provider.is_some() {
+        features.push("lsp-code-lenses".to_string());
+    }
+
+    if server_capabilities.linked_editing_range_provider.is_some() {
+        features.push("lsp-linked-edit".to_string());
+    }
+
+    match server_capabilities.call_hierarchy_provider {
+        Some(CallHierarchyServerCapability::Simple(false)) | None => (),
+        _ => {
+            features.push("lsp-incoming-calls".to_string());
+            features.push("lsp-outgoing-calls".to_string());
+        }
+    }
+
+    if ctx.type_hierarchy_provider {
+        features.push("lsp-supertypes".to_string());
+        features.push("lsp-subtypes".to_string());
+    }
+
+    match server_capabilities.color_provider {
+        Some(ColorProviderCapability::Simple(false)) | None => (),
+        _ => features.push("lsp-color-presentation".to_string()),
+    }
+
+    match server_capabilities.selection_range_provider {
+        Some(SelectionRangeProviderCapability::Simple(false)) | None => (),
+        _ => {
+            features.push("lsp-selection-range-expand".to_string());
+            features.push("lsp-selection-range-shrink".to_string());
+        }
+    }
+
+    match server_capabilities.folding_range_provider {
+        Some(FoldingRangeProviderCapability::Simple(false)) | None => (),
+        _ => {
+            features.push("lsp-folding-fold-all".to_string());
+            features.push("lsp-fold-around-cursor".to_string());
+        }
+    }
+
+    match server_capabilities.moniker_provider {
+        Some(OneOf::Left(false)) | None => (),
+        _ => features.push("lsp-moniker".to_string()),
+    }
+
+    if server_capabilities.document_link_provider.is_some() {
+        features.push("lsp-document-link-open".to_string());
+    }
+
     features.push("lsp-diagnostics".to_string());
 
     if let Some(ref provider) = server_capabilities.semantic_tokens_provider {
@@ -469,3 +817,39 @@ fn request_initialization_options_from_kakoune(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shutdown_then_exit_once() {
+        let now = Instant::now();
+        let deadline = now + Duration::from_secs(2);
+        let mut state = ShutdownState::Running;
+        assert!(!state.is_overdue(now));
+        assert!(state.request(deadline));
+        // Shutdown is only requested once.
+        assert!(!state.request(deadline + Duration::from_secs(1)));
+        assert_eq!(state.deadline(), Some(deadline));
+        assert!(!state.is_overdue(now));
+        assert!(state.is_overdue(deadline));
+        assert!(state.exit());
+        // A late answer to the shutdown request doesn't send exit again.
+        assert!(!state.exit());
+        assert!(!state.is_overdue(deadline));
+        assert_eq!(state, ShutdownState::Exited);
+    }
+
+    #[test]
+    fn offset_encoding_defaults_to_utf_16() {
+        let result = serde_json::json!({"capabilities": {"positionEncoding": "utf-8"}});
+        assert_eq!(negotiated_offset_encoding(&result), OffsetEncoding::Utf8);
+        let result = serde_json::json!({"capabilities": {"positionEncoding": "utf-32"}});
+        assert_eq!(negotiated_offset_encoding(&result), OffsetEncoding::Utf32);
+        let result = serde_json::json!({"capabilities": {"positionEncoding": "utf-7"}});
+        assert_eq!(negotiated_offset_encoding(&result), OffsetEncoding::Utf16);
+        let result = serde_json::json!({"capabilities": {}});
+        assert_eq!(negotiated_offset_encoding(&result), OffsetEncoding::Utf16);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::context::Context;
+use crate::language_features::hierarchy;
+use crate::types::{EditorMeta, EditorParams, KakounePosition, PreparedHierarchyItem};
+use crate::util::get_lsp_position;
+use lsp_types::request::{
+    CallHierarchyIncomingCalls, CallHierarchyOutgoingCalls, CallHierarchyPrepare,
+};
+use lsp_types::*;
+use serde::Deserialize;
+use url::Url;
+
+#[derive(Deserialize, Debug)]
+pub struct CallHierarchyParams {
+    pub position: KakounePosition,
+    // JSON encoded CallHierarchyItem picked by the user from a menu, empty otherwise.
+    pub item: String,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum CallDirection {
+    Incoming,
+    Outgoing,
+}
+
+impl CallDirection {
+    fn method(self) -> &'static str {
+        match self {
+            CallDirection::Incoming => "callHierarchy/incomingCalls",
+            CallDirection::Outgoing => "callHierarchy/outgoingCalls",
+        }
+    }
+}
+
+pub fn call_hierarchy(
+    meta: EditorMeta,
+    params: EditorParams,
+    direction: CallDirection,
+    ctx: &mut Context,
+) {
+    let params = CallHierarchyParams::deserialize(params).unwrap();
+    if !params.item.is_empty() {
+        let item: CallHierarchyItem = serde_json::from_str(&params.item).unwrap();
+        ctx.call_hierarchy_item = Some(PreparedHierarchyItem::new(
+            &meta,
+            params.position,
+            item.clone(),
+        ));
+        calls(meta, item, direction, ctx);
+        return;
+    }
+    if let Some(item) = hierarchy::prepared_item(&ctx.call_hierarchy_item, &meta, &params.position)
+    {
+        calls(meta, item, direction, ctx);
+        return;
+    }
+    let position = params.position;
+    let req_params = CallHierarchyPrepareParams {
+        text_document_position_params: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(&meta.buffile).unwrap(),
+            },
+            position: get_lsp_position(&meta.buffile, &position, ctx).unwrap(),
+        },
+        work_done_progress_params: Default::default(),
+    };
+    ctx.call::<CallHierarchyPrepare, _>(
+        meta,
+        req_params,
+        move |ctx: &mut Context, meta, result| {
+            let item = hierarchy::single_item(
+                &meta,
+                result.unwrap_or_default(),
+                "No call hierarchy item at the cursor position",
+                "lsp-call-hierarchy-request",
+                direction.method(),
+                ctx,
+            );
+            if let Some(item) = item {
+                ctx.call_hierarchy_item =
+                    Some(PreparedHierarchyItem::new(&meta, position, item.clone()));
+                calls(meta, item, direction, ctx);
+            }
+        },
+    );
+}
+
+fn calls(meta: EditorMeta, item: CallHierarchyItem, direction: CallDirection, ctx: &mut Context) {
+    match direction {
+        CallDirection::Incoming => {
+            let req_params = CallHierarchyIncomingCallsParams {
+                item,
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            };
+            ctx.call::<CallHierarchyIncomingCalls, _>(
+                meta,
+                req_params,
+                move |ctx: &mut Context, meta, result| {
+                    // Show places where the item is called from.
+                    let locations = result
+                        .unwrap_or_default()
+                        .into_iter()
+                        .flat_map(|call| {
+                            let uri = call.from.uri;
+                            call.from_ranges.into_iter().map(move |range| Location {
+                                uri: uri.clone(),
+                                range,
+                            })
+                        })
+                        .collect();
+                    hierarchy::show_locations(meta, locations, "No incoming calls found", ctx);
+                },
+            );
+        }
+        CallDirection::Outgoing => {
+            let uri = item.uri.clone();
+            let req_params = CallHierarchyOutgoingCallsParams {
+                item,
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            };
+            ctx.call::<CallHierarchyOutgoingCalls, _>(
+                meta,
+                req_params,
+                move |ctx: &mut Context, meta, result| {
+                    // Call ranges of outgoing calls are relative to the caller, i.e. the item.
+                    let locations = result
+                        .unwrap_or_default()
+                        .into_iter()
+                        .flat_map(|call| call.from_ranges)
+                        .map(|range| Location {
+                            uri: uri.clone(),
+                            range,
+                        })
+                        .collect();
+                    hierarchy::show_locations(meta, locations, "No outgoing calls found", ctx);
+                },
+            );
+        }
+    }
+}
@@ -1,33 +1,179 @@
 use crate::context::*;
+use crate::position::{
+    kakoune_range_to_lsp, lsp_position_to_kakoune, ordered_range, parse_selection_desc,
+};
 use crate::types::*;
 use crate::util::*;
 use lsp_types::request::Request;
 use lsp_types::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
 
 pub struct SwitchSourceHeaderRequest {}
 
 impl Request for SwitchSourceHeaderRequest {
     type Params = TextDocumentIdentifier;
-    type Result = Option<Url>;
+    // Servers reply with an empty URI rather than null if there is no counterpart.
+    type Result = Option<String>;
     const METHOD: &'static str = "textDocument/switchSourceHeader";
 }
 
+/// Check if the server is clangd, telling the user otherwise. Servers which don't tell their name
+/// are given a chance.
+fn is_clangd(meta: &EditorMeta, feature: &str, ctx: &Context) -> bool {
+    match &ctx.server_info {
+        Some(server_info) if server_info.name != "clangd" => {
+            let msg = format!("{} requires clangd, not {}", feature, server_info.name);
+            ctx.exec(
+                meta.clone(),
+                format!("lsp-show-error {}", editor_quote(&msg)),
+            );
+            false
+        }
+        _ => true,
+    }
+}
+
 pub fn switch_source_header(meta: EditorMeta, ctx: &mut Context) {
+    if !is_clangd(&meta, "Switching between source and header", ctx) {
+        return;
+    }
     let req_params = TextDocumentIdentifier {
         uri: Url::from_file_path(&meta.buffile).unwrap(),
     };
     ctx.call::<SwitchSourceHeaderRequest, _>(
         meta,
         req_params,
-        move |ctx: &mut Context, meta, response| match response {
-            Some(response) => {
-                let command = format!(
+        move |ctx: &mut Context, meta, response| {
+            let path = response
+                .and_then(|uri| Url::parse(&uri).ok())
+                .and_then(|uri| uri.to_file_path().ok());
+            let command = match path {
+                Some(path) => format!(
                     "eval -try-client %opt{{jumpclient}} -verbatim -- edit -existing {}",
-                    editor_quote(response.to_file_path().unwrap().to_str().unwrap()),
+                    editor_quote(path.to_str().unwrap()),
+                ),
+                None => format!(
+                    "lsp-show-error {}",
+                    editor_quote("No corresponding source or header file found")
+                ),
+            };
+            ctx.exec(meta, command);
+        },
+    );
+}
+
+pub enum AstRequest {}
+
+impl Request for AstRequest {
+    type Params = AstParams;
+    type Result = Option<AstNode>;
+    const METHOD: &'static str = "textDocument/ast";
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AstParams {
+    pub text_document: TextDocumentIdentifier,
+    pub range: Range,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AstNode {
+    pub role: String,
+    pub kind: String,
+    pub detail: Option<String>,
+    pub range: Option<Range>,
+    #[serde(default)]
+    pub children: Vec<AstNode>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AstEditorParams {
+    // Main selection as reported by Kakoune's %val{selection_desc}.
+    pub selection_desc: String,
+}
+
+/// Show the syntax tree of the innermost node enclosing the main selection.
+pub fn ast(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    if !is_clangd(&meta, "Showing AST", ctx) {
+        return;
+    }
+    let params = AstEditorParams::deserialize(params).unwrap();
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    let (anchor, cursor) = match parse_selection_desc(&params.selection_desc) {
+        Some(selection) => selection,
+        None => return,
+    };
+    let range = kakoune_range_to_lsp(
+        &ordered_range(anchor, cursor),
+        &document.text,
+        ctx.offset_encoding,
+    );
+    let req_params = AstParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        range,
+    };
+    ctx.call::<AstRequest, _>(
+        meta,
+        req_params,
+        move |ctx: &mut Context, meta, response| {
+            let node = match response {
+                Some(node) => node,
+                None => {
+                    let msg = "No AST node in the selection";
+                    ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+                    return;
+                }
+            };
+            let document = match ctx.documents.get(&meta.buffile) {
+                Some(document) => document,
+                None => return,
+            };
+            let path = Path::new(&meta.buffile);
+            let path = path.strip_prefix(&ctx.root_path).unwrap_or(path);
+            let mut lines = vec![];
+            visit_ast_node(&node, 0, &mut |node, depth| {
+                let position = node.range.map_or_else(
+                    || KakounePosition { line: 1, column: 1 },
+                    |range| {
+                        lsp_position_to_kakoune(&range.start, &document.text, ctx.offset_encoding)
+                    },
                 );
-                ctx.exec(meta, command);
-            }
-            None => return,
+                lines.push(format!(
+                    "{}:{}:{}: {}{} {}{}",
+                    path.display(),
+                    position.line,
+                    position.column,
+                    "  ".repeat(depth),
+                    node.role,
+                    node.kind,
+                    node.detail
+                        .as_ref()
+                        .map_or_else(String::new, |detail| format!(" {}", detail)),
+                ));
+            });
+            let command = format!(
+                "clangd-show-ast {} {}",
+                editor_quote(&ctx.root_path),
+                editor_quote(&lines.join("\n")),
+            );
+            ctx.exec(meta, command);
         },
     );
 }
+
+/// Visit the node and its descendants depth first.
+fn visit_ast_node<F: FnMut(&AstNode, usize)>(node: &AstNode, depth: usize, f: &mut F) {
+    f(node, depth);
+    for child in &node.children {
+        visit_ast_node(child, depth + 1, f);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::context::Context;
+use crate::language_features::codeaction::execute_command_editor_command;
+use crate::markup;
+use crate::position::{get_line, lsp_position_to_kakoune};
+use crate::types::{EditorMeta, EditorParams, PositionParams};
+use crate::util::{editor_quote, get_lsp_position};
+use itertools::Itertools;
+use lsp_types::request::{CodeLensRequest, CodeLensResolve};
+use lsp_types::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use url::Url;
+
+/// Code lenses of a buffer, as received for its `version`.
+pub struct BufferCodeLenses {
+    version: i32,
+    lenses: Vec<CodeLens>,
+}
+
+pub fn text_document_code_lens(meta: EditorMeta, ctx: &mut Context) {
+    let req_params = CodeLensParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    ctx.call::<CodeLensRequest, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        resolve_code_lenses(meta, result.unwrap_or_default(), ctx)
+    });
+}
+
+/// Refresh lenses of the buffer if they are displayed, e.g. after it's saved.
+pub fn refresh_code_lenses(meta: EditorMeta, ctx: &mut Context) {
+    if ctx.code_lenses.contains_key(&meta.buffile) {
+        // Buffer sync might be piggybacking on a blocking request, don't steal its fifo.
+        let meta = EditorMeta { fifo: None, ..meta };
+        text_document_code_lens(meta, ctx);
+    }
+}
+
+/// Handle `workspace/codeLens/refresh` by requesting lenses again for all buffers showing them.
+pub fn refresh_all_code_lenses(ctx: &mut Context) {
+    let buffiles = ctx.code_lenses.keys().cloned().collect::<Vec<_>>();
+    for buffile in buffiles {
+        if let Some(meta) = ctx.meta_for_buffer(buffile) {
+            text_document_code_lens(meta, ctx);
+        }
+    }
+}
+
+/// Servers may send lenses without commands to compute them lazily, resolve them all before
+/// displaying as titles come from commands.
+fn resolve_code_lenses(meta: EditorMeta, lenses: Vec<CodeLens>, ctx: &mut Context) {
+    let resolve_provider = ctx
+        .capabilities
+        .as_ref()
+        .and_then(|caps| caps.code_lens_provider.as_ref())
+        .and_then(|options| options.resolve_provider)
+        .unwrap_or(false);
+    let (resolved, unresolved): (Vec<_>, Vec<_>) =
+        lenses.into_iter().partition(|lens| lens.command.is_some());
+    if !resolve_provider || unresolved.is_empty() {
+        editor_code_lenses(meta, resolved, ctx);
+        return;
+    }
+    ctx.batch_call::<CodeLensResolve, _>(
+        meta,
+        unresolved,
+        move |ctx: &mut Context, meta, results| {
+            let lenses = resolved.into_iter().chain(results).collect();
+            editor_code_lenses(meta, lenses, ctx)
+        },
+    );
+}
+
+fn editor_code_lenses(meta: EditorMeta, mut lenses: Vec<CodeLens>, ctx: &mut Context) {
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    lenses.retain(|lens| lens.command.is_some());
+    lenses.sort_by_key(|lens| (lens.range.start.line, lens.range.start.character));
+    let mut lines: BTreeMap<u64, Vec<&str>> = BTreeMap::new();
+    for lens in &lenses {
+        lines
+            .entry(lens.range.start.line.into())
+            .or_default()
+            .push(&lens.command.as_ref().unwrap().title);
+    }
+    // Kakoune can't display virtual lines, show titles past the end of the line instead.
+    let ranges = lines
+        .iter()
+        .map(|(line, titles)| {
+            let line_text = get_line(*line as usize, &document.text);
+            let position = Position::new(*line as _, 0);
+            let mut position =
+                lsp_position_to_kakoune(&position, &document.text, ctx.offset_encoding);
+            position.column = line_text.len_bytes() as u32;
+            let label = format!(" {}", titles.iter().join(" | "));
+            editor_quote(&format!(
+                "{}+0|{}",
+                position,
+                markup::literal("CodeLens", &label.replace("|", "\\|"))
+            ))
+        })
+        .join(" ");
+    ctx.code_lenses.insert(
+        meta.buffile.clone(),
+        BufferCodeLenses {
+            version: meta.version,
+            lenses,
+        },
+    );
+    let command = format!("set buffer lsp_code_lenses {} {}", meta.version, ranges);
+    let command = format!(
+        "eval -buffer {} -verbatim -- {}",
+        editor_quote(&meta.buffile),
+        command
+    );
+    ctx.exec(meta, command)
+}
+
+/// Offer to run one of the lenses on the cursor line in a menu.
+pub fn code_lens_menu(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let position = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
+    let lenses = match ctx.code_lenses.get(&meta.buffile) {
+        Some(lenses) if lenses.version == meta.version => &lenses.lenses,
+        _ => {
+            let msg = "Code lenses are not up to date, request them with lsp-code-lenses";
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+            return;
+        }
+    };
+    let entries = lenses
+        .iter()
+        .filter(|lens| lens.range.start.line == position.line)
+        .filter_map(|lens| lens.command.as_ref())
+        .map(|command| {
+            format!(
+                "{} {}",
+                editor_quote(&command.title),
+                editor_quote(&execute_command_editor_command(command))
+            )
+        })
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        let msg = "No code lenses on the cursor line";
+        ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+        return;
+    }
+    ctx.exec(meta, format!("menu {}", entries.join(" ")));
+}
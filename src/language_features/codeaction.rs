@@ -1,15 +1,34 @@
 use crate::context::*;
+use crate::diagnostics::diagnostics_at;
+use crate::fan_out::{self, SharedAnswers};
 use crate::types::*;
 use crate::util::*;
+use crate::workspace;
 use itertools::Itertools;
 use lsp_types::request::*;
 use lsp_types::*;
+use regex::Regex;
 use serde::Deserialize;
 use url::Url;
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CodeActionsParams {
+    pub position: KakounePosition,
+    // Kinds of actions to request, e.g. "quickfix" or "source.organizeImports", any if empty.
+    #[serde(default)]
+    pub only: Vec<String>,
+    // Perform the action right away instead of showing a menu when there is only one.
+    #[serde(default)]
+    pub auto_apply: bool,
+    // Regex to filter actions by title, any action if empty.
+    #[serde(default)]
+    pub title_pattern: String,
+}
+
 pub fn text_document_codeaction(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
-    let params =
-        PositionParams::deserialize(params).expect("Params should follow PositionParams structure");
+    let params = CodeActionsParams::deserialize(params)
+        .expect("Params should follow CodeActionsParams structure");
     let position = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
 
     let buff_diags = ctx.diagnostics.get(&meta.buffile);
@@ -25,39 +44,150 @@ pub fn text_document_codeaction(meta: EditorMeta, params: EditorParams, ctx: &mu
         Vec::new()
     };
 
-    let req_params = CodeActionParams {
+    let req_params = code_action_params(&meta.buffile, position, diagnostics, &params.only);
+    ctx.call::<CodeActionRequest, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        editor_code_actions(meta, params, result, ctx)
+    });
+}
+
+fn code_action_params(
+    buffile: &str,
+    position: Position,
+    diagnostics: Vec<Diagnostic>,
+    only: &[String],
+) -> CodeActionParams {
+    CodeActionParams {
         text_document: TextDocumentIdentifier {
-            uri: Url::from_file_path(&meta.buffile).unwrap(),
+            uri: Url::from_file_path(buffile).unwrap(),
         },
         range: Range {
             start: position,
             end: position,
         },
         context: CodeActionContext {
-            diagnostics: diagnostics,
-            only: None,
+            diagnostics,
+            only: if only.is_empty() {
+                None
+            } else {
+                Some(only.iter().cloned().map(CodeActionKind::from).collect())
+            },
         },
         work_done_progress_params: Default::default(),
         partial_result_params: Default::default(),
+    }
+}
+
+/// Request quick fixes for the diagnostics at the cursor only, performing the fix right away if
+/// there is a single one.
+pub fn diagnostic_quickfix(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let position = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
+    let diagnostics = ctx
+        .diagnostics
+        .get(&meta.buffile)
+        .map(|diagnostics| {
+            diagnostics_at(diagnostics, position)
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if diagnostics.is_empty() {
+        let answer = CodeActionsAnswer {
+            actions: vec![],
+            auto_apply: true,
+            none: NO_DIAGNOSTICS.to_string(),
+        };
+        show_code_actions(meta, Some(answer), ctx);
+        return;
+    }
+    let params = CodeActionsParams {
+        position: params.position,
+        only: vec![CodeActionKind::QUICKFIX.as_str().to_string()],
+        auto_apply: true,
+        title_pattern: String::new(),
     };
+    let req_params = code_action_params(&meta.buffile, position, diagnostics, &params.only);
     ctx.call::<CodeActionRequest, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
-        editor_code_actions(meta, result, ctx)
+        let has_quickfix = result.iter().flatten().any(|c| match c {
+            CodeActionOrCommand::Command(_) => false,
+            CodeActionOrCommand::CodeAction(action) => action
+                .kind
+                .as_ref()
+                .map_or(false, |kind| is_kind_of(kind.as_str(), &params.only)),
+        });
+        if !has_quickfix {
+            let answer = CodeActionsAnswer {
+                actions: vec![],
+                auto_apply: true,
+                none: "No quickfix for the diagnostic at the cursor".to_string(),
+            };
+            show_code_actions(meta, Some(answer), ctx);
+            return;
+        }
+        editor_code_actions(meta, params, result, ctx)
     });
 }
 
+/// Check if action kind is one of the requested kinds or their sub-kinds,
+/// e.g. "refactor.extract.function" is a "refactor.extract" and a "refactor".
+fn is_kind_of(kind: &str, only: &[String]) -> bool {
+    only.iter()
+        .any(|only| kind == only || kind.starts_with(&format!("{}.", only)))
+}
+
+const NO_DIAGNOSTICS: &str = "No diagnostics at the cursor";
+
+/// Code actions of a language server, offered along with the ones of other servers of the buffer.
+pub struct CodeActionsAnswer {
+    // Titles of the actions and the commands performing them.
+    actions: Vec<(String, String)>,
+    auto_apply: bool,
+    // Why there are no actions, shown if no server has any.
+    none: String,
+}
+
+/// Code actions of the language servers a request was sent to, until all of them have answered.
+pub type SharedCodeActions = SharedAnswers<CodeActionsAnswer>;
+
 pub fn editor_code_actions(
     meta: EditorMeta,
+    params: CodeActionsParams,
     result: Option<CodeActionResponse>,
     ctx: &mut Context,
 ) {
-    let result = match result {
+    let mut result = match result {
         Some(result) => result,
-        None => return,
+        None => return show_code_actions(meta, None, ctx),
     };
 
-    if result.is_empty() {
-        ctx.exec(meta, format!("lsp-show-error 'No actions available'"));
-        return;
+    // Servers may ignore requested kinds, so filter them again.
+    if !params.only.is_empty() {
+        result.retain(|c| match c {
+            CodeActionOrCommand::Command(_) => false,
+            CodeActionOrCommand::CodeAction(action) => action
+                .kind
+                .as_ref()
+                .map_or(false, |kind| is_kind_of(kind.as_str(), &params.only)),
+        });
+    }
+
+    if !params.title_pattern.is_empty() {
+        let title_re = match Regex::new(&params.title_pattern) {
+            Ok(re) => re,
+            Err(e) => {
+                let answer = CodeActionsAnswer {
+                    actions: vec![],
+                    auto_apply: params.auto_apply,
+                    none: format!("Invalid code action title pattern: {}", e),
+                };
+                show_code_actions(meta, Some(answer), ctx);
+                return;
+            }
+        };
+        result.retain(|c| match c {
+            CodeActionOrCommand::Command(command) => title_re.is_match(&command.title),
+            CodeActionOrCommand::CodeAction(action) => title_re.is_match(&action.title),
+        });
     }
 
     for cmd in &result {
@@ -67,36 +197,126 @@ pub fn editor_code_actions(
         }
     }
 
-    let menu_args = result
-        .iter()
-        .map(|c| match c {
-            CodeActionOrCommand::Command(_) => c.clone(),
-            CodeActionOrCommand::CodeAction(action) => match &action.command {
-                Some(cmd) => CodeActionOrCommand::Command(cmd.clone()),
-                None => c.clone(),
-            },
-        })
+    // Code actions are kept in context and performed by index, as they might need resolving
+    // before being applied.
+    ctx.code_actions.clear();
+    let server = editor_quote(&ctx.language_id);
+    let actions = result
+        .into_iter()
         .map(|c| match c {
-            CodeActionOrCommand::Command(command) => {
-                let title = editor_quote(&command.title);
-                let cmd = editor_quote(&command.command);
-                // Double JSON serialization is performed to prevent parsing args as a TOML
-                // structure when they are passed back via lsp-execute-command.
-                let args = &serde_json::to_string(&command.arguments).unwrap();
-                let args = editor_quote(&serde_json::to_string(&args).unwrap());
-                let select_cmd = editor_quote(&format!("lsp-execute-command {} {}", cmd, args));
-                format!("{} {}", title, select_cmd)
-            }
+            CodeActionOrCommand::Command(command) => (
+                command.title.clone(),
+                execute_command_editor_command(&command),
+            ),
             CodeActionOrCommand::CodeAction(action) => {
-                let title = editor_quote(&action.title);
-                // Double JSON serialization is performed to prevent parsing args as a TOML
-                // structure when they are passed back via lsp-apply-workspace-edit.
-                let edit = &serde_json::to_string(&action.edit.unwrap()).unwrap();
-                let edit = editor_quote(&serde_json::to_string(&edit).unwrap());
-                let select_cmd = editor_quote(&format!("lsp-apply-workspace-edit {}", edit));
-                format!("{} {}", title, select_cmd)
+                let title = action.title.clone();
+                ctx.code_actions.push(action);
+                (
+                    title,
+                    format!(
+                        "lsp-code-action-perform {} {}",
+                        server,
+                        ctx.code_actions.len() - 1
+                    ),
+                )
             }
         })
+        .collect::<Vec<_>>();
+    let answer = CodeActionsAnswer {
+        actions,
+        auto_apply: params.auto_apply,
+        none: "No actions available".to_string(),
+    };
+    show_code_actions(meta, Some(answer), ctx);
+}
+
+/// Offer the code actions of all language servers the request was sent to once all of them have
+/// answered, None if this one failed to.
+pub fn show_code_actions(meta: EditorMeta, answer: Option<CodeActionsAnswer>, ctx: &Context) {
+    let answers = match fan_out::collect(&ctx.shared_code_actions, &meta, answer) {
+        Some(answers) => answers,
+        None => return,
+    };
+    let auto_apply = match answers.first() {
+        Some(answer) => answer.auto_apply,
+        None => return,
+    };
+    // Servers without diagnostics at the cursor only tell so if none of the others has any.
+    let none = answers
+        .iter()
+        .map(|answer| answer.none.clone())
+        .find(|none| none != NO_DIAGNOSTICS)
+        .unwrap_or_else(|| NO_DIAGNOSTICS.to_string());
+    let actions = answers
+        .into_iter()
+        .flat_map(|answer| answer.actions)
+        .collect::<Vec<_>>();
+
+    if actions.is_empty() {
+        ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&none)));
+        return;
+    }
+
+    if auto_apply && actions.len() == 1 {
+        let (_, command) = actions.into_iter().next().unwrap();
+        ctx.exec(meta, command);
+        return;
+    }
+
+    let menu_args = actions
+        .into_iter()
+        .map(|(title, command)| format!("{} {}", editor_quote(&title), editor_quote(&command)))
         .join(" ");
     ctx.exec(meta, format!("menu {}", menu_args));
 }
+
+/// Kakoune command to execute the LSP command.
+pub fn execute_command_editor_command(command: &Command) -> String {
+    let cmd = editor_quote(&command.command);
+    let args = serde_json::to_string(command.arguments.as_deref().unwrap_or_default()).unwrap();
+    format!("lsp-execute-command {} {}", cmd, editor_quote(&args))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CodeActionPerformParams {
+    // Index of the action in the last code actions menu.
+    pub index: usize,
+}
+
+/// Perform the code action picked from the menu, resolving its edit first if the server
+/// deferred computing it.
+pub fn code_action_perform(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = CodeActionPerformParams::deserialize(params)
+        .expect("Params should follow CodeActionPerformParams structure");
+    let action = match ctx.code_actions.get(params.index) {
+        Some(action) => action.clone(),
+        None => return,
+    };
+    let resolve_provider = match &ctx.capabilities.as_ref().unwrap().code_action_provider {
+        Some(CodeActionProviderCapability::Options(options)) => {
+            options.resolve_provider.unwrap_or(false)
+        }
+        _ => false,
+    };
+    if action.edit.is_some() || !resolve_provider {
+        perform_code_action(meta, action, ctx);
+        return;
+    }
+    ctx.call::<CodeActionResolveRequest, _>(
+        meta,
+        action,
+        move |ctx: &mut Context, meta, action| perform_code_action(meta, action, ctx),
+    );
+}
+
+/// Apply action's edit, then execute its command, as prescribed by the spec.
+fn perform_code_action(meta: EditorMeta, action: CodeAction, ctx: &mut Context) {
+    if let Some(edit) = action.edit {
+        workspace::apply_or_preview_edit(meta, edit, action.command, ctx);
+        return;
+    }
+    if let Some(command) = action.command {
+        // Goes through the editor to let it send the edited buffers to the server first.
+        ctx.exec(meta, execute_command_editor_command(&command));
+    }
+}
@@ -1,16 +1,46 @@
 use crate::context::*;
+use crate::fan_out::{self, SharedAnswers};
+use crate::markup;
+use crate::position::{get_line, lsp_position_to_kakoune};
+use crate::snippet;
+use crate::text_edit::apply_text_edits_to_buffer;
 use crate::types::*;
 use crate::util::*;
 use itertools::Itertools;
 use lsp_types::request::*;
 use lsp_types::*;
 use regex::Regex;
-use serde::Deserialize;
-use std;
+use serde::{Deserialize, Serialize};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 use url::Url;
 
+/// Start of a completion whose list the server flagged as incomplete, which has to be requested
+/// again as the user keeps typing rather than filtered.
+#[derive(PartialEq, Debug)]
+pub struct IncompleteCompletion {
+    buffile: String,
+    line: u32,
+    offset: u32,
+}
+
 pub fn text_document_completion(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = TextDocumentCompletionParams::deserialize(params).unwrap();
+    let start = IncompleteCompletion {
+        buffile: meta.buffile.clone(),
+        line: params.position.line,
+        offset: params.completion.offset,
+    };
+    let trigger_character = typed_trigger_character(&meta, ctx);
+    if params.completion.trigger_character_only && trigger_character.is_none() {
+        return;
+    }
+    let trigger_kind = if ctx.incomplete_completion.take() == Some(start) {
+        CompletionTriggerKind::TriggerForIncompleteCompletions
+    } else if trigger_character.is_some() {
+        CompletionTriggerKind::TriggerCharacter
+    } else {
+        CompletionTriggerKind::Invoked
+    };
     let req_params = CompletionParams {
         text_document_position: TextDocumentPositionParams {
             text_document: TextDocumentIdentifier {
@@ -18,122 +48,866 @@ pub fn text_document_completion(meta: EditorMeta, params: EditorParams, ctx: &mu
             },
             position: get_lsp_position(&meta.buffile, &params.position, ctx).unwrap(),
         },
-        context: None,
+        context: Some(CompletionContext {
+            trigger_kind,
+            trigger_character,
+        }),
         work_done_progress_params: Default::default(),
         partial_result_params: Default::default(),
     };
-    ctx.call::<Completion, _>(meta, req_params, |ctx: &mut Context, meta, result| {
+    ctx.call::<CompletionRequest, _>(meta, req_params, |ctx: &mut Context, meta, result| {
         editor_completion(meta, params, result, ctx)
     });
 }
 
+/// Trigger character of the server the text just typed ends with, e.g. `.` or `::`.
+/// Servers are expected to ignore trigger characters typed in comments or strings.
+fn typed_trigger_character(meta: &EditorMeta, ctx: &Context) -> Option<String> {
+    let options = ctx.capabilities.as_ref()?.completion_provider.as_ref()?;
+    let typed = match ctx.typed_text.get(&meta.buffile) {
+        Some(typed) if typed.version == meta.version => typed,
+        _ => return None,
+    };
+    options
+        .trigger_characters
+        .as_ref()?
+        .iter()
+        .filter(|ch| !ch.is_empty() && typed.text.ends_with(ch.as_str()))
+        .max_by_key(|ch| ch.len())
+        .cloned()
+}
+
+// Label details are introduced in LSP 3.17 which is not covered by lsp-types yet.
+
+pub enum CompletionRequest {}
+
+impl Request for CompletionRequest {
+    type Params = CompletionParams;
+    type Result = Option<CompletionResponseWithDetails>;
+    const METHOD: &'static str = "textDocument/completion";
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum CompletionResponseWithDetails {
+    Array(Vec<CompletionItemWithDetails>),
+    List(CompletionListWithDetails),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CompletionListWithDetails {
+    pub is_incomplete: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub item_defaults: Option<CompletionItemDefaults>,
+    pub items: Vec<CompletionItemWithDetails>,
+}
+
+/// Properties of items which don't specify them.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CompletionItemDefaults {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_characters: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edit_range: Option<CompletionEditRange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub insert_text_format: Option<InsertTextFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub insert_text_mode: Option<InsertTextMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum CompletionEditRange {
+    Range(Range),
+    InsertReplace { insert: Range, replace: Range },
+}
+
+/// Fill properties items leave out with the defaults of the list.
+fn apply_item_defaults(items: &mut [CompletionItemWithDetails], defaults: CompletionItemDefaults) {
+    for x in items {
+        if x.commit_characters.is_none() {
+            x.commit_characters = defaults.commit_characters.clone();
+        }
+        let text_edit_text = x.text_edit_text.take();
+        let x = &mut x.item;
+        if x.text_edit.is_none() {
+            // Items share the range, each one inserting its own text.
+            let new_text = text_edit_text
+                .or_else(|| x.insert_text.clone())
+                .unwrap_or_else(|| x.label.clone());
+            x.text_edit = defaults.edit_range.clone().map(|range| match range {
+                CompletionEditRange::Range(range) => {
+                    CompletionTextEdit::Edit(TextEdit { range, new_text })
+                }
+                CompletionEditRange::InsertReplace { insert, replace } => {
+                    CompletionTextEdit::InsertAndReplace(InsertReplaceEdit {
+                        new_text,
+                        insert,
+                        replace,
+                    })
+                }
+            });
+        }
+        if x.insert_text_format.is_none() {
+            x.insert_text_format = defaults.insert_text_format;
+        }
+        if x.insert_text_mode.is_none() {
+            x.insert_text_mode = defaults.insert_text_mode;
+        }
+        if x.data.is_none() {
+            x.data = defaults.data.clone();
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CompletionItemWithDetails {
+    #[serde(flatten)]
+    pub item: CompletionItem,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label_details: Option<CompletionItemLabelDetails>,
+    // Text to insert with the default edit range of the list.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text_edit_text: Option<String>,
+    // Characters accepting the item when typed while it's selected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_characters: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CompletionItemLabelDetails {
+    // Shown right after the label, e.g. function parameters.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    // Shown apart from the label, e.g. the module the item comes from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// Client capabilities for label details and item defaults, to be merged into the ones built with lsp-types.
+pub fn client_capabilities() -> serde_json::Value {
+    serde_json::json!({
+        "textDocument": {
+            "completion": {
+                "completionItem": {"labelDetailsSupport": true},
+                "completionList": {
+                    "itemDefaults": [
+                        "commitCharacters",
+                        "editRange",
+                        "insertTextFormat",
+                        "insertTextMode",
+                        "data",
+                    ],
+                },
+            },
+        },
+    })
+}
+
+/// Descriptions are truncated to this many columns so that the menu doesn't grow too wide.
+const MAX_DESCRIPTION_WIDTH: usize = 30;
+
+/// Completions of a language server for the position of the request.
+pub struct CompletionAnswer {
+    // Position as set in lsp_completions: <line>.<column>@<timestamp>.
+    position: String,
+    items: Vec<MenuItem>,
+}
+
+/// Item of the completion menu, laid out once the items of all servers are known so that their
+/// columns line up.
+struct MenuItem {
+    insert_text: String,
+    command: String,
+    // Label as shown, e.g. struck through if the item is deprecated.
+    label: String,
+    item: CompletionItem,
+    label_details: CompletionItemLabelDetails,
+}
+
+/// Completions of the language servers a request was sent to, until all of them have answered.
+pub type SharedCompletions = SharedAnswers<CompletionAnswer>;
+
 pub fn editor_completion(
     meta: EditorMeta,
     params: TextDocumentCompletionParams,
-    result: Option<CompletionResponse>,
+    result: Option<CompletionResponseWithDetails>,
     ctx: &mut Context,
 ) {
-    if result.is_none() {
-        return;
-    }
-    let items = match result.unwrap() {
-        CompletionResponse::Array(items) => items,
-        CompletionResponse::List(list) => list.items,
+    let result = match result {
+        Some(result) => result,
+        None => return show_completions(meta, None, ctx),
+    };
+    let items = match result {
+        CompletionResponseWithDetails::Array(items) => items,
+        CompletionResponseWithDetails::List(list) => {
+            if list.is_incomplete {
+                ctx.incomplete_completion = Some(IncompleteCompletion {
+                    buffile: meta.buffile.clone(),
+                    line: params.position.line,
+                    offset: params.completion.offset,
+                });
+            }
+            let mut items = list.items;
+            if let Some(defaults) = list.item_defaults {
+                apply_item_defaults(&mut items, defaults);
+            }
+            items
+        }
     };
+    let prefix = typed_prefix(&meta, &params, ctx).unwrap_or_default();
+    let indent = cursor_line(&meta, &params, ctx)
+        .map(|line| {
+            line.chars()
+                .take_while(|c| *c == ' ' || *c == '\t')
+                .collect::<String>()
+        })
+        .unwrap_or_default();
+    // Items are stored in menu order as the menu refers to them by index.
+    let (items, details): (Vec<_>, Vec<_>) = sort_and_filter(items, &prefix)
+        .into_iter()
+        .map(|x| {
+            let details = (
+                x.label_details.unwrap_or_default(),
+                x.commit_characters.unwrap_or_default(),
+            );
+            (x.item, details)
+        })
+        .unzip();
+    let (label_details, commit_characters): (Vec<_>, Vec<_>) = details.into_iter().unzip();
+    ctx.completion_items = items.clone();
     let unescape_markdown_re = Regex::new(r"\\(?P<c>.)").unwrap();
-    let maxlen = items.iter().map(|x| x.label.len()).max().unwrap_or(0);
-    let escape_bar = |s: &str| s.replace("|", r"\|");
+    let server = editor_quote(&ctx.language_id);
     let snippet_prefix_re = Regex::new(r"^[^\[\(<\n\$]+").unwrap();
 
     let items = items
         .into_iter()
-        .map(|x| {
-            let mut doc: String = match &x.documentation {
-                None => "".to_string(),
-                Some(doc) => match doc {
-                    Documentation::String(st) => st.clone(),
-                    Documentation::MarkupContent(mup) => match mup.kind {
-                        MarkupKind::PlainText => mup.value.clone(),
-                        // NOTE just in case server ignored our documentationFormat capability
-                        // we want to unescape markdown to make text a bit more readable
-                        MarkupKind::Markdown => unescape_markdown_re
-                            .replace_all(&mup.value, r"$c")
-                            .to_string(),
-                    },
-                },
-            };
-            if let Some(d) = x.detail {
-                doc = format!("{}\n\n{}", d, doc);
-            }
-            let doc = format!("info -style menu {}", editor_quote(&doc));
-            let mut entry = x.label.clone();
-            if let Some(k) = x.kind {
-                entry += &std::iter::repeat(" ")
-                    .take(maxlen - x.label.len())
-                    .collect::<String>();
-                entry += &format!(" {{MenuInfo}}{:?}", k);
-            }
-            // The generic textEdit property is not supported yet (#40).
-            // However, we can support simple text edits that only replace the token left of the
-            // cursor. Kakoune will do this very edit if we simply pass it the replacement string
-            // as completion.
-            let is_simple_text_edit = x.text_edit.as_ref().map_or(false, |cte| {
-                if let CompletionTextEdit::Edit(text_edit) = cte {
-                    text_edit.range.start.line + 1 == params.position.line
-                        && text_edit.range.start.character + 1 == params.completion.offset
-                        && text_edit.range.end.line + 1 == params.position.line
-                        && text_edit.range.end.character + 1 == params.position.column
-                } else {
-                    false
-                }
-            });
-            let insert_text = &if is_simple_text_edit {
-                if let CompletionTextEdit::Edit(te) = x.text_edit.unwrap() {
-                    te.new_text
-                } else {
-                    x.insert_text.unwrap_or(x.label)
-                }
+        .zip(label_details.into_iter().zip(commit_characters))
+        .enumerate()
+        .map(|(index, (x, (label_details, commit_characters)))| {
+            let doc = if needs_resolve(&x, |x| x.documentation.is_none(), ctx) {
+                format!("lsp-completion-item-resolve-request {} {}", server, index)
             } else {
-                x.insert_text.unwrap_or(x.label)
+                format!(
+                    "info -style menu {}",
+                    editor_quote(&completion_item_doc(&x, &unescape_markdown_re))
+                )
             };
             let do_snippet = ctx.config.snippet_support;
             let do_snippet = do_snippet
                 && x.insert_text_format
                     .map(|f| f == InsertTextFormat::Snippet)
                     .unwrap_or(false);
-            if do_snippet {
-                let snippet = insert_text;
+            let edit = simple_text_edit(&meta, &params, &x, ctx);
+            // Text right of the cursor is deleted once the item is accepted, except for snippets
+            // which move the cursor to their placeholders.
+            let replace_command = match &edit {
+                Some((_, suffix)) if !suffix.is_empty() && !do_snippet => {
+                    delete_suffix_command(suffix)
+                }
+                _ => String::new(),
+            };
+            let commit_characters = commit_characters
+                .iter()
+                .map(|c| editor_quote(c))
+                .join(" ");
+            let doc = format!(
+                "{}\nlsp-completion-item-selected {} {} {}\nset-option window lsp_completion_item_commit_characters {}",
+                doc,
+                server,
+                index,
+                editor_quote(&replace_command),
+                commit_characters
+            );
+            let is_deprecated = x.deprecated == Some(true)
+                || x.tags
+                    .as_ref()
+                    .map_or(false, |tags| tags.contains(&CompletionItemTag::Deprecated));
+            let label = if is_deprecated {
+                format!("{{Deprecated}}{}{{MenuInfo}}", markup::escape(&x.label))
+            } else {
+                x.label.clone()
+            };
+            let insert_text = match edit {
+                Some((new_text, _)) => new_text,
+                None => x.insert_text.clone().unwrap_or_else(|| x.label.clone()),
+            };
+            let insert_text = apply_insert_text_mode(x.insert_text_mode, insert_text, &indent);
+            let (insert_text, command) = if do_snippet {
+                let snippet = &insert_text;
                 let insert_text = snippet_prefix_re
                     .find(snippet)
                     .map(|x| x.as_str())
-                    .unwrap_or(&snippet);
+                    .unwrap_or(snippet);
                 let command = format!(
                     "{}\nlsp-snippets-insert-completion {} {}",
                     doc,
                     editor_quote(&regex::escape(insert_text)),
-                    editor_quote(snippet)
+                    editor_quote(&snippet::render(&snippet::parse(snippet)))
                 );
-                let command = format!("eval {}", editor_quote(&command));
-                editor_quote(&format!(
-                    "{}|{}|{}",
-                    escape_bar(insert_text),
-                    escape_bar(&command),
-                    escape_bar(&entry),
-                ))
+                (insert_text.to_string(), command)
             } else {
-                editor_quote(&format!(
-                    "{}|{}|{}",
-                    escape_bar(insert_text),
-                    escape_bar(&doc),
-                    escape_bar(&entry),
-                ))
+                (insert_text, doc)
+            };
+            MenuItem {
+                insert_text,
+                command: format!("eval {}", editor_quote(&command)),
+                label,
+                item: x,
+                label_details,
             }
         })
-        .join(" ");
+        .collect();
     let p = params.position;
-    let command = format!(
-        "set window lsp_completions {}.{}@{} {}\n",
-        p.line, params.completion.offset, meta.version, items
-    );
+    let answer = CompletionAnswer {
+        position: format!("{}.{}@{}", p.line, params.completion.offset, meta.version),
+        items,
+    };
+    show_completions(meta, Some(answer), ctx);
+}
+
+/// Show the completions of all language servers the request was sent to once all of them have
+/// answered, None if this one failed to.
+pub fn show_completions(meta: EditorMeta, answer: Option<CompletionAnswer>, ctx: &Context) {
+    let answers = match fan_out::collect(&ctx.shared_completions, &meta, answer) {
+        Some(answers) => answers,
+        None => return,
+    };
+    let position = match answers.first() {
+        Some(answer) => answer.position.clone(),
+        None => return,
+    };
+    let items = answers
+        .into_iter()
+        .flat_map(|answer| answer.items)
+        .collect::<Vec<_>>();
+    let columns = MenuColumns::new(items.iter().map(|x| (&x.item, &x.label_details)));
+    let escape_bar = |s: &str| s.replace("|", r"\|");
+    let items = items
+        .iter()
+        .map(|x| {
+            let entry = columns.entry(x.label.clone(), &x.item, &x.label_details);
+            editor_quote(&format!(
+                "{}|{}|{}",
+                escape_bar(&x.insert_text),
+                escape_bar(&x.command),
+                escape_bar(&entry),
+            ))
+        })
+        .join(" ");
+    let command = format!("set window lsp_completions {} {}\n", position, items);
     ctx.exec(meta, command);
 }
+
+/// Text of the item's edit and the text right of the cursor the edit replaces, if the edit can be
+/// done by Kakoune. The generic textEdit property is not supported yet (#40).
+/// However, we can support simple text edits that only replace the token left of the cursor.
+/// Kakoune will do this very edit if we simply pass it the replacement string as completion.
+/// Edits which also replace the rest of the word right of the cursor are done in two steps, the
+/// text right of the cursor being deleted once the item is accepted.
+fn simple_text_edit(
+    meta: &EditorMeta,
+    params: &TextDocumentCompletionParams,
+    item: &CompletionItem,
+    ctx: &Context,
+) -> Option<(String, String)> {
+    let (start, end, replace_end, new_text) = match item.text_edit.as_ref()? {
+        CompletionTextEdit::Edit(edit) => (edit.range.start, edit.range.end, None, &edit.new_text),
+        CompletionTextEdit::InsertAndReplace(edit) => {
+            if params.completion.insert_mode == "replace" {
+                let replace = edit.replace;
+                (
+                    replace.start,
+                    edit.insert.end,
+                    Some(replace.end),
+                    &edit.new_text,
+                )
+            } else {
+                (edit.insert.start, edit.insert.end, None, &edit.new_text)
+            }
+        }
+    };
+    if start.line + 1 != params.position.line
+        || start.character + 1 != params.completion.offset
+        || end.line + 1 != params.position.line
+        || end.character + 1 != params.position.column
+    {
+        return None;
+    }
+    let suffix = match replace_end {
+        Some(replace_end) if replace_end.line == end.line => {
+            let document = ctx.documents.get(&meta.buffile)?;
+            let replace_end =
+                lsp_position_to_kakoune(&replace_end, &document.text, ctx.offset_encoding);
+            let line = get_line(end.line as usize, &document.text).to_string();
+            let cursor = params.position.column as usize - 1;
+            let replace_end = replace_end.column as usize - 1;
+            line.get(cursor..replace_end)
+                .unwrap_or_default()
+                .to_string()
+        }
+        _ => String::new(),
+    };
+    Some((new_text.clone(), suffix))
+}
+
+/// Command deleting the text right of the cursor, as long as it's still there.
+fn delete_suffix_command(suffix: &str) -> String {
+    let extend = match suffix.chars().count() {
+        1 => String::new(),
+        n => format!("{}L", n - 1),
+    };
+    let regex = regex::escape(suffix).replace("<", "<lt>");
+    let keys = format!(";{}<a-k>\\A{}\\z<ret>d", extend, regex);
+    let command = format!("execute-keys -draft {}", editor_quote(&keys));
+    format!("try {}", editor_quote(&command))
+}
+
+/// Text of the line completion was requested on.
+fn cursor_line(
+    meta: &EditorMeta,
+    params: &TextDocumentCompletionParams,
+    ctx: &Context,
+) -> Option<String> {
+    let document = ctx.documents.get(&meta.buffile)?;
+    let line_number = (params.position.line as usize).checked_sub(1)?;
+    if line_number >= document.text.len_lines() {
+        return None;
+    }
+    Some(document.text.line(line_number).to_string())
+}
+
+/// Text typed between the start of the completion and the cursor.
+fn typed_prefix(
+    meta: &EditorMeta,
+    params: &TextDocumentCompletionParams,
+    ctx: &Context,
+) -> Option<String> {
+    let line = cursor_line(meta, params, ctx)?;
+    let start = (params.completion.offset as usize).checked_sub(1)?;
+    let end = (params.position.column as usize).checked_sub(1)?;
+    line.get(start..end).map(str::to_string)
+}
+
+/// Indent lines following the first one like the line the item is inserted into, as requested by
+/// items with `adjustIndentation` insert text mode. Text of other items is inserted as is.
+fn apply_insert_text_mode(mode: Option<InsertTextMode>, text: String, indent: &str) -> String {
+    if mode != Some(InsertTextMode::AdjustIndentation) {
+        return text;
+    }
+    text.split('\n')
+        .enumerate()
+        .map(|(i, line)| {
+            if i == 0 || line.is_empty() {
+                line.to_string()
+            } else {
+                format!("{}{}", indent, line)
+            }
+        })
+        .join("\n")
+}
+
+/// Keep items whose `filterText`, or label, fuzzily matches the prefix and order them by
+/// `sortText`, or label. Sorting is stable so items the server ranks the same keep its order.
+fn sort_and_filter(
+    items: Vec<CompletionItemWithDetails>,
+    prefix: &str,
+) -> Vec<CompletionItemWithDetails> {
+    let prefix = prefix.to_lowercase();
+    let mut items = items
+        .into_iter()
+        .filter(|x| {
+            let filter_text = x.item.filter_text.as_ref().unwrap_or(&x.item.label);
+            is_subsequence(&prefix, &filter_text.to_lowercase())
+        })
+        .collect::<Vec<_>>();
+    items.sort_by(|a, b| {
+        let key = |x: &CompletionItemWithDetails| -> String {
+            x.item
+                .sort_text
+                .clone()
+                .unwrap_or_else(|| x.item.label.clone())
+        };
+        key(a).cmp(&key(b))
+    });
+    items
+}
+
+fn is_subsequence(needle: &str, haystack: &str) -> bool {
+    let mut haystack = haystack.chars();
+    needle.chars().all(|c| haystack.any(|h| h == c))
+}
+
+/// Widths of the completion menu columns: label followed by its detail, description aligned to
+/// the right and kind.
+struct MenuColumns {
+    label: usize,
+    description: usize,
+}
+
+impl MenuColumns {
+    fn new<'a, I>(items: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a CompletionItem, &'a CompletionItemLabelDetails)>,
+    {
+        let mut columns = MenuColumns {
+            label: 0,
+            description: 0,
+        };
+        for (item, details) in items {
+            let label = item.label.width() + details.detail.as_deref().map_or(0, str::width);
+            columns.label = columns.label.max(label);
+            if let Some(description) = details.description.as_deref() {
+                let description = description.width().min(MAX_DESCRIPTION_WIDTH);
+                columns.description = columns.description.max(description);
+            }
+        }
+        columns
+    }
+
+    /// Menu entry markup for the item, given its already formatted label.
+    fn entry(
+        &self,
+        label: String,
+        item: &CompletionItem,
+        details: &CompletionItemLabelDetails,
+    ) -> String {
+        let detail = details.detail.as_deref().unwrap_or("");
+        let description = truncate(
+            details.description.as_deref().unwrap_or(""),
+            MAX_DESCRIPTION_WIDTH,
+        );
+        let mut rest = vec![];
+        if self.description > 0 {
+            rest.push(format!(
+                "{}{}",
+                " ".repeat(self.description - description.width()),
+                markup::escape(&description)
+            ));
+        }
+        if let Some(kind) = item.kind {
+            rest.push(format!("{:?}", kind));
+        }
+        let mut entry = label;
+        if !detail.is_empty() || !rest.is_empty() {
+            entry += "{MenuInfo}";
+            entry += &markup::escape(detail);
+        }
+        if !rest.is_empty() {
+            let width = item.label.width() + detail.width();
+            entry += &" ".repeat(self.label - width);
+            entry += &format!(" {}", rest.join(" "));
+        }
+        entry
+    }
+}
+
+/// Cut text to fit into the given number of columns, marking it with an ellipsis if it doesn't.
+fn truncate(text: &str, columns: usize) -> String {
+    if text.width() <= columns {
+        return text.to_string();
+    }
+    let mut result = String::new();
+    let mut width = 0;
+    for c in text.chars() {
+        let char_width = c.width().unwrap_or(0);
+        if width + char_width + 1 > columns {
+            break;
+        }
+        result.push(c);
+        width += char_width;
+    }
+    result.push('…');
+    result
+}
+
+fn completion_item_doc(item: &CompletionItem, unescape_markdown_re: &Regex) -> String {
+    let mut doc: String = match &item.documentation {
+        None => "".to_string(),
+        Some(doc) => match doc {
+            Documentation::String(st) => st.clone(),
+            Documentation::MarkupContent(mup) => match mup.kind {
+                MarkupKind::PlainText => mup.value.clone(),
+                // NOTE just in case server ignored our documentationFormat capability
+                // we want to unescape markdown to make text a bit more readable
+                MarkupKind::Markdown => unescape_markdown_re
+                    .replace_all(&mup.value, r"$c")
+                    .to_string(),
+            },
+        },
+    };
+    if let Some(d) = &item.detail {
+        doc = format!("{}\n\n{}", d, doc);
+    }
+    doc
+}
+
+/// Servers may leave out some properties of completion items until they are resolved.
+fn needs_resolve<F>(item: &CompletionItem, missing: F, ctx: &Context) -> bool
+where
+    F: Fn(&CompletionItem) -> bool,
+{
+    let resolve_provider = ctx
+        .capabilities
+        .as_ref()
+        .and_then(|caps| caps.completion_provider.as_ref())
+        .and_then(|provider| provider.resolve_provider)
+        .unwrap_or(false);
+    resolve_provider && missing(item)
+}
+
+fn resolve_completion_item<F, G>(
+    meta: EditorMeta,
+    index: usize,
+    missing: F,
+    ctx: &mut Context,
+    callback: G,
+) where
+    F: Fn(&CompletionItem) -> bool,
+    G: for<'a> FnOnce(&'a mut Context, EditorMeta, CompletionItem) + 'static,
+{
+    let item = match ctx.completion_items.get(index) {
+        Some(item) => item.clone(),
+        None => return,
+    };
+    if !needs_resolve(&item, missing, ctx) {
+        callback(ctx, meta, item);
+        return;
+    }
+    ctx.call::<ResolveCompletionItem, _>(meta, item, move |ctx, meta, item| {
+        if let Some(stored) = ctx.completion_items.get_mut(index) {
+            *stored = item.clone();
+        }
+        callback(ctx, meta, item)
+    });
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CompletionItemParams {
+    // Index of the item in the completion list last sent to the editor.
+    pub index: usize,
+}
+
+/// Show documentation of a completion item selected in the menu once the server has provided it.
+pub fn completion_item_resolve(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = CompletionItemParams::deserialize(params).unwrap();
+    resolve_completion_item(
+        meta,
+        params.index,
+        |item| item.documentation.is_none(),
+        ctx,
+        |ctx, meta, item| {
+            let unescape_markdown_re = Regex::new(r"\\(?P<c>.)").unwrap();
+            let doc = completion_item_doc(&item, &unescape_markdown_re);
+            ctx.exec(meta, format!("info -style menu {}", editor_quote(&doc)));
+        },
+    );
+}
+
+/// Apply additional edits of an accepted completion item, like adding a missing import.
+///
+/// This runs once Kakoune has inserted the item, so edits are applied bottom to top in a single
+/// draft of the client's context. That way none of them shifts the position of another or of the
+/// just inserted text, and undo reverts them together.
+pub fn completion_item_accept(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = CompletionItemParams::deserialize(params).unwrap();
+    resolve_completion_item(
+        meta,
+        params.index,
+        |item| item.additional_text_edits.is_none(),
+        ctx,
+        |ctx, meta, item| {
+            let mut edits = match item.additional_text_edits {
+                Some(edits) if !edits.is_empty() => edits,
+                _ => return,
+            };
+            let document = match ctx.documents.get(&meta.buffile) {
+                Some(document) => document,
+                None => return,
+            };
+            edits.sort_by(|a, b| b.range.start.cmp(&a.range.start));
+            let command = edits
+                .into_iter()
+                .map(|edit| {
+                    apply_text_edits_to_buffer(
+                        None,
+                        &[OneOf::Left(edit)],
+                        &document.text,
+                        ctx.offset_encoding,
+                    )
+                })
+                .join("\n");
+            ctx.exec(meta, format!("eval -draft {}", editor_quote(&command)));
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(label: &str, kind: Option<CompletionItemKind>) -> CompletionItem {
+        CompletionItem {
+            label: label.to_string(),
+            kind,
+            ..CompletionItem::default()
+        }
+    }
+
+    fn details(detail: Option<&str>, description: Option<&str>) -> CompletionItemLabelDetails {
+        CompletionItemLabelDetails {
+            detail: detail.map(str::to_string),
+            description: description.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn menu_entries_align_descriptions() {
+        let items = [
+            item("push", Some(CompletionItemKind::Method)),
+            item("HashMap", Some(CompletionItemKind::Struct)),
+        ];
+        let label_details = [
+            details(Some("(value)"), Some("Vec")),
+            details(None, Some("std::collections")),
+        ];
+        let columns = MenuColumns::new(items.iter().zip(&label_details));
+        let entries = items
+            .iter()
+            .zip(&label_details)
+            .map(|(item, details)| columns.entry(item.label.clone(), item, details))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![
+                "push{MenuInfo}(value)              Vec Method",
+                "HashMap{MenuInfo}     std::collections Struct",
+            ]
+        );
+    }
+
+    #[test]
+    fn sort_by_sort_text_and_filter_by_filter_text() {
+        let item = |label: &str, sort_text: Option<&str>, filter_text: Option<&str>| {
+            CompletionItemWithDetails {
+                item: CompletionItem {
+                    label: label.to_string(),
+                    sort_text: sort_text.map(str::to_string),
+                    filter_text: filter_text.map(str::to_string),
+                    ..CompletionItem::default()
+                },
+                label_details: None,
+                text_edit_text: None,
+                commit_characters: None,
+            }
+        };
+        let items = vec![
+            item("alpha", Some("2"), None),
+            item("beta", Some("1"), None),
+            item("gamma", Some("2"), None),
+            item("delta", Some("0"), Some("xyz")),
+            item("self.alpha", None, Some("alpha")),
+        ];
+        let labels = |items: Vec<CompletionItemWithDetails>| {
+            items.into_iter().map(|x| x.item.label).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            labels(sort_and_filter(items, "")),
+            vec!["delta", "beta", "alpha", "gamma", "self.alpha"]
+        );
+        let items = vec![
+            item("alpha", Some("2"), None),
+            item("beta", Some("1"), None),
+            item("gamma", Some("2"), None),
+            item("delta", Some("0"), Some("xyz")),
+            item("self.alpha", None, Some("alpha")),
+        ];
+        assert_eq!(
+            labels(sort_and_filter(items, "Al")),
+            vec!["alpha", "self.alpha"]
+        );
+    }
+
+    #[test]
+    fn truncate_wide_chars() {
+        assert_eq!(truncate("short", 5), "short");
+        assert_eq!(truncate("漢字漢字", 6), "漢字…");
+    }
+
+    #[test]
+    fn delete_suffix_checks_text_right_of_cursor() {
+        assert_eq!(
+            delete_suffix_command("x"),
+            r"try 'execute-keys -draft '';<a-k>\Ax\z<ret>d'''"
+        );
+        assert_eq!(
+            delete_suffix_command("a<b"),
+            r"try 'execute-keys -draft '';2L<a-k>\Aa<lt>b\z<ret>d'''"
+        );
+    }
+
+    #[test]
+    fn items_take_properties_from_list_defaults() {
+        let list: CompletionListWithDetails = serde_json::from_value(serde_json::json!({
+            "isIncomplete": false,
+            "itemDefaults": {
+                "commitCharacters": ["("],
+                "editRange": {
+                    "insert": {"start": {"line": 1, "character": 4}, "end": {"line": 1, "character": 6}},
+                    "replace": {"start": {"line": 1, "character": 4}, "end": {"line": 1, "character": 9}},
+                },
+                "insertTextFormat": 2,
+                "data": {"id": 1},
+            },
+            "items": [
+                {"label": "push"},
+                {"label": "pop", "textEditText": "pop()", "data": {"id": 2}},
+            ],
+        }))
+        .unwrap();
+        let mut items = list.items;
+        apply_item_defaults(&mut items, list.item_defaults.unwrap());
+        let new_texts = items
+            .iter()
+            .map(|x| match x.item.text_edit.as_ref().unwrap() {
+                CompletionTextEdit::InsertAndReplace(edit) => {
+                    assert_eq!(edit.insert.end, Position::new(1, 6));
+                    assert_eq!(edit.replace.end, Position::new(1, 9));
+                    edit.new_text.as_str()
+                }
+                CompletionTextEdit::Edit(_) => panic!("Expected insert/replace edit"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(new_texts, vec!["push", "pop()"]);
+        for x in &items {
+            assert_eq!(x.commit_characters, Some(vec!["(".to_string()]));
+            assert_eq!(x.item.insert_text_format, Some(InsertTextFormat::Snippet));
+        }
+        assert_eq!(items[0].item.data, Some(serde_json::json!({"id": 1})));
+        assert_eq!(items[1].item.data, Some(serde_json::json!({"id": 2})));
+    }
+
+    #[test]
+    fn adjust_indentation_of_following_lines() {
+        let snippet = "fn ${1:name}() {\n    $0\n}";
+        let item = |insert_text_mode| CompletionItem {
+            label: "fn".to_string(),
+            insert_text: Some(snippet.to_string()),
+            insert_text_mode: Some(insert_text_mode),
+            ..CompletionItem::default()
+        };
+        let insert = |item: CompletionItem| {
+            apply_insert_text_mode(item.insert_text_mode, item.insert_text.unwrap(), "\t")
+        };
+        assert_eq!(insert(item(InsertTextMode::AsIs)), snippet);
+        assert_eq!(
+            insert(item(InsertTextMode::AdjustIndentation)),
+            "fn ${1:name}() {\n\t    $0\n\t}"
+        );
+    }
+}
@@ -202,6 +202,7 @@ pub fn publish_semantic_highlighting(params: Params, ctx: &mut Context) {
         filetype: "".to_string(), // filetype is not used by ctx.exec, but it's definitely a code smell
         version,
         fifo: None,
+        fan_out: None,
     };
     ctx.exec(meta, command);
 }
@@ -0,0 +1,137 @@
+use crate::context::Context;
+use crate::markup;
+use crate::position::lsp_range_to_kakoune;
+use crate::types::{EditorMeta, EditorParams, PositionParams};
+use crate::util::{editor_quote, get_lsp_position};
+use itertools::Itertools;
+use lsp_types::request::{ColorPresentationRequest, DocumentColor};
+use lsp_types::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use url::Url;
+
+/// Request document colors for the buffer if the language server provides them.
+/// Called whenever buffer content changes to keep color swatches up to date.
+pub fn refresh_document_colors(meta: EditorMeta, ctx: &mut Context) {
+    match ctx.capabilities.as_ref().unwrap().color_provider {
+        Some(ColorProviderCapability::Simple(false)) | None => return,
+        _ => (),
+    }
+    // Buffer sync might be piggybacking on a blocking request, don't steal its fifo.
+    let meta = EditorMeta { fifo: None, ..meta };
+    let req_params = DocumentColorParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    ctx.call::<DocumentColor, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        editor_document_colors(meta, result, ctx)
+    });
+}
+
+fn editor_document_colors(meta: EditorMeta, result: Vec<ColorInformation>, ctx: &mut Context) {
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    let ranges = result
+        .iter()
+        .map(|ColorInformation { range, color }| {
+            let pos = lsp_range_to_kakoune(range, &document.text, ctx.offset_encoding).start;
+            let face = markup::rgb_face(color.red, color.green, color.blue);
+            editor_quote(&format!("{}+0|{}", pos, markup::literal(&face, "█")))
+        })
+        .join(" ");
+    ctx.document_colors.insert(meta.buffile.clone(), result);
+    let command = format!("set buffer lsp_document_colors {} {}", meta.version, ranges);
+    let command = format!(
+        "eval -buffer {} -verbatim -- {}",
+        editor_quote(&meta.buffile),
+        command
+    );
+    ctx.exec(meta, command)
+}
+
+/// Offer alternative presentations of the color under the cursor in a menu.
+pub fn color_presentation(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let position = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
+    let position = (position.line, position.character);
+    let color = ctx.document_colors.get(&meta.buffile).and_then(|colors| {
+        colors.iter().find(|ColorInformation { range, .. }| {
+            (range.start.line, range.start.character) <= position
+                && position <= (range.end.line, range.end.character)
+        })
+    });
+    let ColorInformation { range, color } = match color {
+        Some(color) => color.clone(),
+        None => {
+            let msg = "No color at the cursor position";
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+            return;
+        }
+    };
+    let uri = Url::from_file_path(&meta.buffile).unwrap();
+    let req_params = ColorPresentationParams {
+        text_document: TextDocumentIdentifier { uri: uri.clone() },
+        color,
+        range,
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    ctx.call::<ColorPresentationRequest, _>(
+        meta,
+        req_params,
+        move |ctx: &mut Context, meta, result| {
+            editor_color_presentation(meta, uri, range, result, ctx)
+        },
+    );
+}
+
+fn editor_color_presentation(
+    meta: EditorMeta,
+    uri: Url,
+    range: Range,
+    result: Vec<ColorPresentation>,
+    ctx: &mut Context,
+) {
+    if result.is_empty() {
+        let msg = "No color presentations available";
+        ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+        return;
+    }
+    let menu_args = result
+        .into_iter()
+        .map(
+            |ColorPresentation {
+                 label,
+                 text_edit,
+                 additional_text_edits,
+             }| {
+                let title = editor_quote(&label);
+                let edit = text_edit.unwrap_or(TextEdit {
+                    range,
+                    new_text: label,
+                });
+                let edits = std::iter::once(edit)
+                    .chain(additional_text_edits.unwrap_or_default())
+                    .collect();
+                let mut changes = HashMap::new();
+                changes.insert(uri.clone(), edits);
+                let edit = WorkspaceEdit {
+                    changes: Some(changes),
+                    ..WorkspaceEdit::default()
+                };
+                // Double JSON serialization is performed to prevent parsing args as a TOML
+                // structure when they are passed back via lsp-apply-workspace-edit.
+                let edit = &serde_json::to_string(&edit).unwrap();
+                let edit = editor_quote(&serde_json::to_string(&edit).unwrap());
+                let select_cmd = editor_quote(&format!("lsp-apply-workspace-edit {}", edit));
+                format!("{} {}", title, select_cmd)
+            },
+        )
+        .join(" ");
+    ctx.exec(meta, format!("menu {}", menu_args));
+}
@@ -0,0 +1,135 @@
+use crate::context::Context;
+use crate::position::lsp_range_to_kakoune;
+use crate::types::{EditorMeta, EditorParams, PositionParams};
+use crate::util::{editor_quote, get_lsp_position};
+use itertools::Itertools;
+use lsp_types::request::{DocumentLinkRequest, DocumentLinkResolve};
+use lsp_types::*;
+use serde::Deserialize;
+use std::time::Duration;
+use url::Url;
+
+/// Links rarely change while typing, so they are only requested again once the buffer has been
+/// left alone for a while.
+const REFRESH_DELAY: Duration = Duration::from_millis(500);
+
+/// Request document links for the buffer if the language server provides them.
+/// Called whenever buffer content changes to keep links highlighting up to date.
+pub fn refresh_document_links(meta: EditorMeta, ctx: &mut Context) {
+    if ctx
+        .capabilities
+        .as_ref()
+        .unwrap()
+        .document_link_provider
+        .is_none()
+    {
+        return;
+    }
+    // Buffer sync might be piggybacking on a blocking request, don't steal its fifo.
+    let meta = EditorMeta { fifo: None, ..meta };
+    let req_params = DocumentLinkParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    ctx.debounce(
+        format!("documentLink {}", meta.buffile),
+        REFRESH_DELAY,
+        move |ctx| {
+            // The buffer might have been closed meanwhile.
+            if !ctx.documents.contains_key(&meta.buffile) {
+                return;
+            }
+            ctx.call::<DocumentLinkRequest, _>(
+                meta,
+                req_params,
+                move |ctx: &mut Context, meta, result| editor_document_links(meta, result, ctx),
+            );
+        },
+    );
+}
+
+fn editor_document_links(meta: EditorMeta, result: Option<Vec<DocumentLink>>, ctx: &mut Context) {
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    let links = result.unwrap_or_default();
+    let ranges = links
+        .iter()
+        .map(|link| {
+            format!(
+                "{}|DocumentLink",
+                lsp_range_to_kakoune(&link.range, &document.text, ctx.offset_encoding)
+            )
+        })
+        .join(" ");
+    ctx.document_links.insert(meta.buffile.clone(), links);
+    let command = format!("set buffer lsp_document_links {} {}", meta.version, ranges);
+    let command = format!(
+        "eval -buffer {} -verbatim -- {}",
+        editor_quote(&meta.buffile),
+        command
+    );
+    ctx.exec(meta, command)
+}
+
+/// Open target of the link under the cursor, resolving it first if needed.
+pub fn document_link_open(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let position = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
+    let position = (position.line, position.character);
+    let link = ctx.document_links.get(&meta.buffile).and_then(|links| {
+        links.iter().find(|link| {
+            (link.range.start.line, link.range.start.character) <= position
+                && position < (link.range.end.line, link.range.end.character)
+        })
+    });
+    let link = match link {
+        Some(link) => link.clone(),
+        None => {
+            let msg = "No document link at the cursor position";
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+            return;
+        }
+    };
+    if let Some(target) = &link.target {
+        open_target(meta, target, ctx);
+        return;
+    }
+    let resolve_provider = ctx
+        .capabilities
+        .as_ref()
+        .unwrap()
+        .document_link_provider
+        .as_ref()
+        .and_then(|provider| provider.resolve_provider)
+        .unwrap_or(false);
+    if !resolve_provider {
+        let msg = "Document link has no target";
+        ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+        return;
+    }
+    ctx.call::<DocumentLinkResolve, _>(meta, link, move |ctx: &mut Context, meta, result| {
+        match result.target {
+            Some(target) => open_target(meta, &target, ctx),
+            None => {
+                let msg = "Document link has no target";
+                ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+            }
+        }
+    });
+}
+
+fn open_target(meta: EditorMeta, target: &Url, ctx: &mut Context) {
+    let command = match target.to_file_path() {
+        Ok(path) => format!(
+            "eval -try-client %opt{{jumpclient}} -verbatim -- edit -existing {}",
+            editor_quote(path.to_str().unwrap())
+        ),
+        Err(_) => format!("lsp-open-url {}", editor_quote(target.as_str())),
+    };
+    ctx.exec(meta, command);
+}
@@ -1,11 +1,24 @@
 use crate::context::*;
+use crate::position::lsp_range_to_kakoune;
 use crate::types::*;
 use crate::util::*;
+use itertools::Itertools;
 use lsp_types::request::*;
 use lsp_types::*;
+use serde::Deserialize;
 use url::Url;
 
 pub fn text_document_document_symbol(meta: EditorMeta, ctx: &mut Context) {
+    request_document_symbols(meta, ctx, |ctx, meta, symbols| {
+        editor_document_symbol(meta, symbols, ctx)
+    });
+}
+
+/// Request symbols of the buffer as a tree, whichever form the server sends them in.
+fn request_document_symbols<F>(meta: EditorMeta, ctx: &mut Context, callback: F)
+where
+    F: for<'a> FnOnce(&'a mut Context, EditorMeta, Vec<DocumentSymbol>) + 'static,
+{
     let req_params = DocumentSymbolParams {
         text_document: TextDocumentIdentifier {
             uri: Url::from_file_path(&meta.buffile).unwrap(),
@@ -16,32 +29,22 @@ pub fn text_document_document_symbol(meta: EditorMeta, ctx: &mut Context) {
     ctx.call::<DocumentSymbolRequest, _>(
         meta,
         req_params,
-        move |ctx: &mut Context, meta, result| editor_document_symbol(meta, result, ctx),
+        move |ctx: &mut Context, meta, result| {
+            let symbols = match result {
+                Some(DocumentSymbolResponse::Flat(symbols)) => symbol_information_tree(symbols),
+                Some(DocumentSymbolResponse::Nested(symbols)) => symbols,
+                None => vec![],
+            };
+            callback(ctx, meta, symbols)
+        },
     );
 }
 
-pub fn editor_document_symbol(
-    meta: EditorMeta,
-    result: Option<DocumentSymbolResponse>,
-    ctx: &mut Context,
-) {
-    let content = match result {
-        Some(DocumentSymbolResponse::Flat(result)) => {
-            if result.is_empty() {
-                return;
-            }
-            format_symbol_information(result, ctx)
-        }
-        Some(DocumentSymbolResponse::Nested(result)) => {
-            if result.is_empty() {
-                return;
-            }
-            format_document_symbol(result, &meta, ctx)
-        }
-        None => {
-            return;
-        }
-    };
+pub fn editor_document_symbol(meta: EditorMeta, symbols: Vec<DocumentSymbol>, ctx: &mut Context) {
+    if symbols.is_empty() {
+        return;
+    }
+    let content = format_document_symbol(symbols, &meta, ctx);
     let command = format!(
         "lsp-show-document-symbol {} {}",
         editor_quote(&ctx.root_path),
@@ -49,3 +52,256 @@ pub fn editor_document_symbol(
     );
     ctx.exec(meta, command);
 }
+
+/// Show the chain of symbols enclosing the cursor, e.g. `Module foo > Struct Bar > Method baz`.
+pub fn document_symbol_breadcrumb(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let position = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
+    request_document_symbols(meta, ctx, move |ctx, meta, symbols| {
+        let breadcrumb = enclosing_symbols(&symbols, position)
+            .iter()
+            .map(|symbol| format!("{:?} {}", symbol.kind, symbol.name))
+            .join(" > ");
+        let command = if breadcrumb.is_empty() {
+            format!(
+                "lsp-show-error {}",
+                editor_quote("No symbol encloses the cursor")
+            )
+        } else {
+            format!("info {}", editor_quote(&breadcrumb))
+        };
+        ctx.exec(meta, command);
+    });
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GotoSymbolParams {
+    pub position: KakounePosition,
+    // Symbol kind like `Function` or `Class`, any kind if empty.
+    pub kind: String,
+    // Positive to go to the next symbol, negative to go to the previous one.
+    pub offset: i64,
+}
+
+/// Jump to the next or previous symbol of the kind, relative to the cursor.
+pub fn goto_symbol(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = GotoSymbolParams::deserialize(params).unwrap();
+    let position = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
+    request_document_symbols(meta, ctx, move |ctx, meta, symbols| {
+        let symbols = flatten_symbols(&symbols)
+            .into_iter()
+            .filter(|symbol| {
+                params.kind.is_empty()
+                    || format!("{:?}", symbol.kind).eq_ignore_ascii_case(&params.kind)
+            })
+            .collect::<Vec<_>>();
+        let start = |symbol: &DocumentSymbol| {
+            let start = symbol.selection_range.start;
+            (start.line, start.character)
+        };
+        let cursor = (position.line, position.character);
+        let target = if params.offset > 0 {
+            symbols.iter().find(|symbol| start(symbol) > cursor)
+        } else {
+            symbols.iter().rev().find(|symbol| start(symbol) < cursor)
+        };
+        let document = match ctx.documents.get(&meta.buffile) {
+            Some(document) => document,
+            None => return,
+        };
+        let command = match target {
+            Some(symbol) => format!(
+                "select {}",
+                lsp_range_to_kakoune(&symbol.selection_range, &document.text, ctx.offset_encoding)
+            ),
+            None => {
+                let kind = if params.kind.is_empty() {
+                    "".to_string()
+                } else {
+                    format!("{} ", params.kind)
+                };
+                let msg = match (symbols.is_empty(), params.offset > 0) {
+                    (true, _) => format!("No {}symbols in the buffer", kind),
+                    (false, true) => format!("No {}symbols after the cursor", kind),
+                    (false, false) => format!("No {}symbols before the cursor", kind),
+                };
+                format!("lsp-show-error {}", editor_quote(&msg))
+            }
+        };
+        ctx.exec(meta, command);
+    });
+}
+
+/// All symbols of the tree sorted by the position of their name.
+fn flatten_symbols(symbols: &[DocumentSymbol]) -> Vec<&DocumentSymbol> {
+    let mut flat = vec![];
+    let mut stack = symbols.iter().collect::<Vec<_>>();
+    while let Some(symbol) = stack.pop() {
+        flat.push(symbol);
+        stack.extend(symbol.children.iter().flatten());
+    }
+    flat.sort_by_key(|symbol| {
+        let start = symbol.selection_range.start;
+        (start.line, start.character)
+    });
+    flat
+}
+
+fn contains(range: &Range, position: Position) -> bool {
+    let position = (position.line, position.character);
+    (range.start.line, range.start.character) <= position
+        && position <= (range.end.line, range.end.character)
+}
+
+/// Symbols enclosing the position, outermost first.
+fn enclosing_symbols(symbols: &[DocumentSymbol], position: Position) -> Vec<&DocumentSymbol> {
+    let mut path = vec![];
+    let mut level = symbols;
+    while let Some(symbol) = level
+        .iter()
+        .find(|symbol| contains(&symbol.range, position))
+    {
+        path.push(symbol);
+        level = symbol.children.as_deref().unwrap_or(&[]);
+    }
+    path
+}
+
+/// Turn flat symbols into a tree. A symbol becomes a child of the innermost symbol named after its
+/// `containerName` which encloses it, or of the last such symbol if none does.
+fn symbol_information_tree(symbols: Vec<SymbolInformation>) -> Vec<DocumentSymbol> {
+    let symbols = symbols
+        .into_iter()
+        .sorted_by_key(|symbol| {
+            let start = symbol.location.range.start;
+            (start.line, start.character)
+        })
+        .collect::<Vec<_>>();
+    let parents = symbols
+        .iter()
+        .enumerate()
+        .map(|(index, symbol)| {
+            let container = symbol.container_name.as_ref()?;
+            let candidates = symbols
+                .iter()
+                .enumerate()
+                .filter(|(other, candidate)| *other != index && &candidate.name == container)
+                .collect::<Vec<_>>();
+            candidates
+                .iter()
+                .filter(|(_, candidate)| {
+                    let range = &candidate.location.range;
+                    contains(range, symbol.location.range.start)
+                        && contains(range, symbol.location.range.end)
+                })
+                .last()
+                .or_else(|| candidates.last())
+                .map(|(parent, _)| *parent)
+        })
+        .collect::<Vec<_>>();
+    let mut children = vec![vec![]; symbols.len()];
+    let mut roots = vec![];
+    for (index, parent) in parents.iter().enumerate() {
+        match parent {
+            // Guard against cycles of symbols containing each other.
+            Some(parent) if !is_ancestor(index, *parent, &parents) => children[*parent].push(index),
+            _ => roots.push(index),
+        }
+    }
+    let mut symbols = symbols.into_iter().map(Some).collect::<Vec<_>>();
+    roots
+        .into_iter()
+        .map(|index| build_symbol(index, &mut symbols, &children))
+        .collect()
+}
+
+fn is_ancestor(index: usize, of: usize, parents: &[Option<usize>]) -> bool {
+    let mut current = Some(of);
+    let mut steps = 0;
+    while let Some(node) = current {
+        if node == index || steps > parents.len() {
+            return true;
+        }
+        current = parents[node];
+        steps += 1;
+    }
+    false
+}
+
+fn build_symbol(
+    index: usize,
+    symbols: &mut Vec<Option<SymbolInformation>>,
+    children: &[Vec<usize>],
+) -> DocumentSymbol {
+    let symbol = symbols[index].take().unwrap();
+    let symbol_children = children[index]
+        .iter()
+        .map(|child| build_symbol(*child, symbols, children))
+        .collect::<Vec<_>>();
+    #[allow(deprecated)]
+    DocumentSymbol {
+        name: symbol.name,
+        detail: None,
+        kind: symbol.kind,
+        tags: symbol.tags,
+        deprecated: symbol.deprecated,
+        range: symbol.location.range,
+        selection_range: symbol.location.range,
+        children: if symbol_children.is_empty() {
+            None
+        } else {
+            Some(symbol_children)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(deprecated)]
+    fn symbol(
+        name: &str,
+        kind: SymbolKind,
+        lines: (u32, u32),
+        container: Option<&str>,
+    ) -> SymbolInformation {
+        SymbolInformation {
+            name: name.to_string(),
+            kind,
+            tags: None,
+            deprecated: None,
+            location: Location {
+                uri: Url::parse("file:///src/lib.rs").unwrap(),
+                range: Range::new(Position::new(lines.0, 0), Position::new(lines.1, 1)),
+            },
+            container_name: container.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn breadcrumb_of_flat_symbols() {
+        let symbols = symbol_information_tree(vec![
+            symbol("baz", SymbolKind::Method, (4, 6), Some("Bar")),
+            symbol("foo", SymbolKind::Module, (0, 20), None),
+            symbol("Bar", SymbolKind::Struct, (2, 8), Some("foo")),
+            symbol("Bar", SymbolKind::Struct, (10, 18), Some("foo")),
+            symbol("qux", SymbolKind::Method, (12, 14), Some("Bar")),
+        ]);
+        let names = |position| {
+            enclosing_symbols(&symbols, position)
+                .iter()
+                .map(|symbol| symbol.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(Position::new(5, 0)), vec!["foo", "Bar", "baz"]);
+        assert_eq!(names(Position::new(13, 0)), vec!["foo", "Bar", "qux"]);
+        assert_eq!(names(Position::new(9, 0)), vec!["foo"]);
+        assert!(names(Position::new(30, 0)).is_empty());
+        let flat = flatten_symbols(&symbols)
+            .iter()
+            .map(|symbol| symbol.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(flat, vec!["foo", "Bar", "baz", "Bar", "qux"]);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::context::Context;
+use crate::position::{get_line, lsp_range_to_kakoune};
+use crate::types::{EditorMeta, EditorParams, KakounePosition, OffsetEncoding};
+use crate::util::{editor_quote, get_lsp_position};
+use itertools::Itertools;
+use lsp_types::request::FoldingRangeRequest;
+use lsp_types::*;
+use ropey::Rope;
+use serde::Deserialize;
+use url::Url;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FoldingRangeEditorParams {
+    pub position: KakounePosition,
+    // Fold only the innermost region around the position instead of all top-level regions.
+    pub around_cursor: bool,
+}
+
+pub fn text_document_folding_range(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = FoldingRangeEditorParams::deserialize(params).unwrap();
+    let line = get_lsp_position(&meta.buffile, &params.position, ctx)
+        .unwrap()
+        .line;
+    let around_cursor = params.around_cursor;
+    let req_params = FoldingRangeParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    ctx.call::<FoldingRangeRequest, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        let ranges = result.unwrap_or_default();
+        let ranges = if around_cursor {
+            innermost_range(ranges, line).into_iter().collect()
+        } else {
+            top_level_ranges(ranges)
+        };
+        editor_folding_range(meta, ranges, around_cursor, ctx)
+    });
+}
+
+/// The smallest region which contains the given line.
+fn innermost_range(ranges: Vec<FoldingRange>, line: u32) -> Option<FoldingRange> {
+    ranges
+        .into_iter()
+        .filter(|range| range.start_line <= line && line <= range.end_line)
+        .min_by_key(|range| range.end_line - range.start_line)
+}
+
+/// Regions which are not nested into other ones. Folding them hides nested regions anyway
+/// and Kakoune can't replace overlapping ranges.
+fn top_level_ranges(mut ranges: Vec<FoldingRange>) -> Vec<FoldingRange> {
+    ranges.sort_by_key(|range| (range.start_line, std::cmp::Reverse(range.end_line)));
+    let mut top_level: Vec<FoldingRange> = vec![];
+    for range in ranges {
+        match top_level.last() {
+            Some(last) if range.start_line <= last.end_line => (),
+            _ => top_level.push(range),
+        }
+    }
+    top_level
+}
+
+fn editor_folding_range(
+    meta: EditorMeta,
+    ranges: Vec<FoldingRange>,
+    around_cursor: bool,
+    ctx: &mut Context,
+) {
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    let specs = ranges
+        .iter()
+        .filter_map(|range| {
+            // Kakoune folds whole lines, so unless the language server insists on characters we
+            // keep the first line visible and hide the rest up to the end of the last line.
+            let start_character = range.start_character.unwrap_or_else(|| {
+                line_end_character(range.start_line, &document.text, ctx.offset_encoding)
+            });
+            let end_character = range.end_character.unwrap_or_else(|| {
+                line_end_character(range.end_line, &document.text, ctx.offset_encoding)
+            });
+            let lsp_range = Range {
+                start: Position::new(range.start_line, start_character),
+                end: Position::new(range.end_line, end_character),
+            };
+            if lsp_range.start >= lsp_range.end {
+                return None;
+            }
+            let kakoune_range =
+                lsp_range_to_kakoune(&lsp_range, &document.text, ctx.offset_encoding);
+            let lines = range.end_line - range.start_line;
+            let label = match range.kind {
+                Some(FoldingRangeKind::Comment) => format!(" … {} lines of comments", lines),
+                Some(FoldingRangeKind::Imports) => format!(" … {} lines of imports", lines),
+                Some(FoldingRangeKind::Region) | None => format!(" … {} lines", lines),
+            };
+            Some(editor_quote(&format!(
+                "{}|{{FoldPlaceholder}}{{\\}}{}",
+                kakoune_range, label
+            )))
+        })
+        .join(" ");
+    if specs.is_empty() {
+        return;
+    }
+    // Folding around cursor keeps the rest of folds intact.
+    let set_option = if around_cursor {
+        "set-option -add"
+    } else {
+        "set-option"
+    };
+    let command = format!("{} buffer lsp_folds {} {}", set_option, meta.version, specs);
+    ctx.exec(meta, command);
+}
+
+/// Position of the end of line, before the line break.
+fn line_end_character(line: u32, text: &Rope, offset_encoding: OffsetEncoding) -> u32 {
+    let line = get_line(line as usize, text).to_string();
+    let line = line.trim_end_matches(|c| c == '\n' || c == '\r');
+    match offset_encoding {
+        OffsetEncoding::Utf8 => line.len() as u32,
+        OffsetEncoding::Utf16 => line.encode_utf16().count() as u32,
+        OffsetEncoding::Utf32 => line.chars().count() as u32,
+    }
+}
@@ -1,10 +1,14 @@
 use crate::context::Context;
-use crate::position::lsp_range_to_kakoune;
-use crate::types::{EditorMeta, EditorParams, PositionParams};
+use crate::position::{get_line, lsp_position_to_kakoune, lsp_range_to_kakoune};
+use crate::types::{EditorMeta, EditorParams, KakouneRange, OffsetEncoding, PositionParams};
 use crate::util::{editor_quote, get_file_contents, get_lsp_position};
 use itertools::Itertools;
-use lsp_types::request::{GotoDefinition, GotoImplementation, GotoTypeDefinition, References};
+use jsonrpc_core::Params;
+use lsp_types::request::{
+    GotoDeclaration, GotoDefinition, GotoImplementation, GotoTypeDefinition, References,
+};
 use lsp_types::*;
+use ropey::Rope;
 use serde::Deserialize;
 use url::Url;
 
@@ -12,12 +16,17 @@ pub fn goto(meta: EditorMeta, result: Option<GotoDefinitionResponse>, ctx: &mut
     let locations = match result {
         Some(GotoDefinitionResponse::Scalar(location)) => vec![location],
         Some(GotoDefinitionResponse::Array(locations)) => locations,
-        Some(GotoDefinitionResponse::Link(locations)) => locations
+        Some(GotoDefinitionResponse::Link(mut links)) if links.len() == 1 => {
+            goto_link(meta, links.pop().unwrap(), ctx);
+            return;
+        }
+        Some(GotoDefinitionResponse::Link(links)) => links
             .into_iter()
             .map(
+                // Selection range is the name of the symbol rather than its whole definition.
                 |LocationLink {
                      target_uri: uri,
-                     target_range: range,
+                     target_selection_range: range,
                      ..
                  }| Location { uri, range },
             )
@@ -35,21 +44,88 @@ pub fn goto(meta: EditorMeta, result: Option<GotoDefinitionResponse>, ctx: &mut
     }
 }
 
-pub fn goto_location(meta: EditorMeta, Location { uri, range }: &Location, ctx: &mut Context) {
-    let path = uri.to_file_path().unwrap();
+/// Jump to the link target selecting the name of the symbol, and tell which text under the cursor
+/// the server matched if it says so.
+fn goto_link(meta: EditorMeta, link: LocationLink, ctx: &mut Context) {
+    let path = link.target_uri.to_file_path().unwrap();
     let path_str = path.to_str().unwrap();
-    if let Some(contents) = get_file_contents(path_str, ctx) {
-        let pos = lsp_range_to_kakoune(&range, &contents, ctx.offset_encoding).start;
-        let command = format!(
-            "eval -try-client %opt{{jumpclient}} -verbatim -- edit -existing {} {} {}",
-            editor_quote(path_str),
-            pos.line,
-            pos.column,
+    let contents = match get_file_contents(path_str, ctx) {
+        Some(contents) => contents,
+        None => return,
+    };
+    let selection = link_target_selection(&link, &contents, ctx.offset_encoding);
+    let jump = format!(
+        "edit -existing {} {} {}; select {}",
+        editor_quote(path_str),
+        selection.start.line,
+        selection.start.column,
+        selection
+    );
+    let mut command = format!(
+        "eval -try-client %opt{{jumpclient}} -- {}",
+        editor_quote(&jump)
+    );
+    let origin = link.origin_selection_range.and_then(|range| {
+        let document = ctx.documents.get(&meta.buffile)?;
+        range_text(&range, &document.text, ctx.offset_encoding)
+    });
+    if let Some(origin) = origin {
+        let stripped = path.strip_prefix(&ctx.root_path).unwrap_or(&path);
+        let msg = format!(
+            "{} -> {}:{}",
+            origin,
+            stripped.display(),
+            selection.start.line
         );
+        command = format!("{}; echo -- {}", command, editor_quote(&msg));
+    }
+    ctx.exec(meta, command);
+}
+
+/// Kakoune selection of the symbol the link points to.
+fn link_target_selection(
+    link: &LocationLink,
+    contents: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> KakouneRange {
+    lsp_range_to_kakoune(&link.target_selection_range, contents, offset_encoding)
+}
+
+/// Text of the range, only its first line if it spans several.
+fn range_text(range: &Range, text: &Rope, offset_encoding: OffsetEncoding) -> Option<String> {
+    let start = lsp_position_to_kakoune(&range.start, text, offset_encoding);
+    let end = lsp_position_to_kakoune(&range.end, text, offset_encoding);
+    let line = get_line(start.line as usize - 1, text).to_string();
+    let end = if end.line == start.line {
+        end.column as usize - 1
+    } else {
+        line.trim_end().len()
+    };
+    line.get(start.column as usize - 1..end)
+        .filter(|text| !text.is_empty())
+        .map(str::to_string)
+}
+
+pub fn goto_location(meta: EditorMeta, location: &Location, ctx: &mut Context) {
+    if let Some(command) = goto_location_command(location, ctx) {
         ctx.exec(meta, command);
     }
 }
 
+/// Editor command jumping to the start of the location, None if its file can't be read.
+pub fn goto_location_command(Location { uri, range }: &Location, ctx: &Context) -> Option<String> {
+    let path = uri.to_file_path().unwrap();
+    let path_str = path.to_str().unwrap();
+    let contents = get_file_contents(path_str, ctx)?;
+    let pos = lsp_range_to_kakoune(&range, &contents, ctx.offset_encoding).start;
+    Some(format!(
+        "eval -try-client %opt{{jumpclient}} -verbatim -- edit -existing {} {} {}",
+        editor_quote(path_str),
+        pos.line,
+        pos.column,
+    ))
+}
+
 pub fn goto_locations(meta: EditorMeta, locations: &[Location], ctx: &mut Context) {
     let select_location = locations
         .iter()
@@ -87,6 +163,33 @@ pub fn goto_locations(meta: EditorMeta, locations: &[Location], ctx: &mut Contex
     ctx.exec(meta, command);
 }
 
+pub fn text_document_declaration(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let supported = match ctx.capabilities.as_ref().unwrap().declaration_provider {
+        Some(DeclarationCapability::Simple(supported)) => supported,
+        Some(_) => true,
+        None => false,
+    };
+    if !supported {
+        let msg = "Language server does not support goto declaration";
+        ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+        return;
+    }
+    let params = PositionParams::deserialize(params).unwrap();
+    let req_params = GotoDefinitionParams {
+        text_document_position_params: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(&meta.buffile).unwrap(),
+            },
+            position: get_lsp_position(&meta.buffile, &params.position, ctx).unwrap(),
+        },
+        partial_result_params: Default::default(),
+        work_done_progress_params: Default::default(),
+    };
+    ctx.call::<GotoDeclaration, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        goto(meta, result, ctx);
+    });
+}
+
 pub fn text_document_definition(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = PositionParams::deserialize(params).unwrap();
     let req_params = GotoDefinitionParams {
@@ -138,8 +241,26 @@ pub fn text_document_type_definition(meta: EditorMeta, params: EditorParams, ctx
     });
 }
 
+/// Prefix of tokens used to tag partial results of textDocument/references streamed via $/progress.
+const REFERENCES_TOKEN_PREFIX: &str = "kak-lsp-references-";
+
+/// References received so far for an in-flight textDocument/references request.
+pub struct PartialReferences {
+    meta: EditorMeta,
+    locations: Vec<Location>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ReferencesProgress {
+    token: NumberOrString,
+    value: Vec<Location>,
+}
+
 pub fn text_document_references(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = PositionParams::deserialize(params).unwrap();
+    // Partial results streamed via $/progress are tagged with a token unique to the request, so
+    // that those of requests overlapping each other are not mixed up.
+    let token = format!("{}{}", REFERENCES_TOKEN_PREFIX, ctx.request_counter);
     let req_params = ReferenceParams {
         text_document_position: TextDocumentPositionParams {
             text_document: TextDocumentIdentifier {
@@ -150,10 +271,96 @@ pub fn text_document_references(meta: EditorMeta, params: EditorParams, ctx: &mu
         context: ReferenceContext {
             include_declaration: true,
         },
-        partial_result_params: Default::default(),
+        partial_result_params: PartialResultParams {
+            partial_result_token: Some(NumberOrString::String(token.clone())),
+        },
         work_done_progress_params: Default::default(),
     };
+    ctx.partial_references.insert(
+        token.clone(),
+        PartialReferences {
+            meta: meta.clone(),
+            locations: vec![],
+        },
+    );
     ctx.call::<References, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
-        goto(meta, result.map(GotoDefinitionResponse::Array), ctx);
+        // Once partial results have been sent, the response only holds the remaining ones.
+        let mut locations = ctx
+            .partial_references
+            .remove(&token)
+            .map_or_else(Vec::new, |partial| partial.locations);
+        match result {
+            Some(result) => locations.extend(result),
+            None if locations.is_empty() => return,
+            None => (),
+        }
+        goto(meta, Some(GotoDefinitionResponse::Array(locations)), ctx);
     });
 }
+
+/// Show references found so far as a chunk of them arrives via $/progress.
+/// Returns false if the progress notification is not related to references.
+pub fn references_progress(params: Params, ctx: &mut Context) -> bool {
+    let params: ReferencesProgress = match params.parse() {
+        Ok(params) => params,
+        Err(_) => return false,
+    };
+    let token = match &params.token {
+        NumberOrString::String(token) if token.starts_with(REFERENCES_TOKEN_PREFIX) => token,
+        _ => return false,
+    };
+    // Chunks arriving after the response of their request are dropped.
+    let (meta, locations) = match ctx.partial_references.get_mut(token) {
+        Some(partial) => {
+            partial.locations.extend(params.value);
+            (partial.meta.clone(), partial.locations.clone())
+        }
+        None => return true,
+    };
+    goto_locations(meta.clone(), &locations, ctx);
+    let msg = format!("Finding references: {} so far…", locations.len());
+    ctx.exec(meta, format!("echo {}", editor_quote(&msg)));
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::KakounePosition;
+
+    fn link(origin: Range, target: Range, target_selection: Range) -> LocationLink {
+        LocationLink {
+            origin_selection_range: Some(origin),
+            target_uri: Url::parse("file:///src/lib.rs").unwrap(),
+            target_range: target,
+            target_selection_range: target_selection,
+        }
+    }
+
+    #[test]
+    fn link_selects_symbol_name() {
+        let contents = Rope::from_str("/// Doc.\nfn ★foo() {}\n");
+        let link = link(
+            Range::new(Position::new(3, 4), Position::new(3, 7)),
+            Range::new(Position::new(0, 0), Position::new(1, 12)),
+            Range::new(Position::new(1, 4), Position::new(1, 7)),
+        );
+        assert_eq!(
+            link_target_selection(&link, &contents, OffsetEncoding::Utf16),
+            KakouneRange {
+                start: KakounePosition { line: 2, column: 7 },
+                end: KakounePosition { line: 2, column: 9 }
+            }
+        );
+    }
+
+    #[test]
+    fn origin_range_text() {
+        let text = Rope::from_str("let x = ★foo();\n");
+        let range = Range::new(Position::new(0, 8), Position::new(0, 12));
+        assert_eq!(
+            range_text(&range, &text, OffsetEncoding::Utf16),
+            Some("★foo".to_string())
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Logic shared by call and type hierarchies. Both prepare an item at the cursor first, letting the
+//! user pick one if there are several, and then show the locations of items related to it.
+
+use crate::context::Context;
+use crate::language_features::goto;
+use crate::language_features::type_hierarchy::TypeHierarchyItem;
+use crate::types::{EditorMeta, KakounePosition, PreparedHierarchyItem};
+use crate::util::editor_quote;
+use itertools::Itertools;
+use lsp_types::*;
+use serde::Serialize;
+
+pub trait HierarchyItem: Clone + Serialize {
+    fn name(&self) -> &str;
+    fn detail(&self) -> Option<&str>;
+}
+
+impl HierarchyItem for CallHierarchyItem {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+}
+
+impl HierarchyItem for TypeHierarchyItem {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+}
+
+/// Item prepared earlier for the same position, if the buffer hasn't changed since.
+pub fn prepared_item<T: Clone>(
+    prepared: &Option<PreparedHierarchyItem<T>>,
+    meta: &EditorMeta,
+    position: &KakounePosition,
+) -> Option<T> {
+    prepared
+        .as_ref()
+        .filter(|prepared| prepared.is_prepared_for(meta, position))
+        .map(|prepared| prepared.item.clone())
+}
+
+/// The only item prepared by the server. Otherwise the user is told there is none, or gets a menu
+/// to pick one of several, which is sent back with `command` to request `method` for it.
+pub fn single_item<T: HierarchyItem>(
+    meta: &EditorMeta,
+    mut items: Vec<T>,
+    no_item_msg: &str,
+    command: &str,
+    method: &str,
+    ctx: &mut Context,
+) -> Option<T> {
+    match items.len() {
+        0 => {
+            let command = format!("lsp-show-error {}", editor_quote(no_item_msg));
+            ctx.exec(meta.clone(), command);
+            None
+        }
+        1 => items.pop(),
+        _ => {
+            pick_item(meta, &items, command, method, ctx);
+            None
+        }
+    }
+}
+
+/// Let the user choose one of several prepared items with Kakoune menu.
+fn pick_item<T: HierarchyItem>(
+    meta: &EditorMeta,
+    items: &[T],
+    command: &str,
+    method: &str,
+    ctx: &mut Context,
+) {
+    let menu_args = items
+        .iter()
+        .map(|item| {
+            let title = match item.detail() {
+                Some(detail) => format!("{} {}", item.name(), detail),
+                None => item.name().to_string(),
+            };
+            // Double JSON serialization is performed to prevent parsing item as a TOML
+            // structure when it is passed back via the request command.
+            let item = serde_json::to_string(item).unwrap();
+            let item = editor_quote(&serde_json::to_string(&item).unwrap());
+            let select_cmd = editor_quote(&format!("{} {} {}", command, method, item));
+            format!("{} {}", editor_quote(&title), select_cmd)
+        })
+        .join(" ");
+    ctx.exec(meta.clone(), format!("menu {}", menu_args));
+}
+
+/// Show locations of the related items sorted by file and position.
+pub fn show_locations(
+    meta: EditorMeta,
+    mut locations: Vec<Location>,
+    empty_msg: &str,
+    ctx: &mut Context,
+) {
+    if locations.is_empty() {
+        ctx.exec(meta, format!("info {}", editor_quote(empty_msg)));
+        return;
+    }
+    locations.sort_by_key(|Location { uri, range }| {
+        (uri.to_string(), range.start.line, range.start.character)
+    });
+    goto::goto_locations(meta, &locations, ctx);
+}
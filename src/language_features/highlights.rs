@@ -1,32 +1,55 @@
 use crate::context::Context;
 use crate::position::lsp_range_to_kakoune;
-use crate::types::{EditorMeta, EditorParams, PositionParams};
-use crate::util::get_lsp_position;
+use crate::types::{EditorMeta, EditorParams, KakounePosition, KakouneRange, PositionParams};
+use crate::util::{editor_quote, get_lsp_position};
 use itertools::Itertools;
 use lsp_types::{
-    request::DocumentHighlightRequest, DocumentHighlight, DocumentHighlightKind::Write,
+    request::DocumentHighlightRequest, DocumentHighlight, DocumentHighlightKind,
     DocumentHighlightParams, TextDocumentIdentifier, TextDocumentPositionParams,
 };
 use serde::Deserialize;
 use url::Url;
 
+/// Highlighted references of a buffer, as received for its `version`, kept to move between them
+/// without asking the server again.
+pub struct BufferHighlights {
+    version: i32,
+    ranges: Vec<KakouneRange>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FindReferenceParams {
+    pub position: KakounePosition,
+    // Number of references to move by, negative to go backwards.
+    pub offset: i64,
+}
+
 pub fn text_document_highlights(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = PositionParams::deserialize(params).unwrap();
+    request_highlights(meta, &params.position, ctx, |ctx, meta, result| {
+        editor_document_highlights(meta, result, ctx)
+    });
+}
+
+fn request_highlights<F>(
+    meta: EditorMeta,
+    position: &KakounePosition,
+    ctx: &mut Context,
+    callback: F,
+) where
+    F: for<'a> FnOnce(&'a mut Context, EditorMeta, Option<Vec<DocumentHighlight>>) + 'static,
+{
     let req_params = DocumentHighlightParams {
         text_document_position_params: TextDocumentPositionParams {
             text_document: TextDocumentIdentifier {
                 uri: Url::from_file_path(&meta.buffile).unwrap(),
             },
-            position: get_lsp_position(&meta.buffile, &params.position, ctx).unwrap(),
+            position: get_lsp_position(&meta.buffile, position, ctx).unwrap(),
         },
         partial_result_params: Default::default(),
         work_done_progress_params: Default::default(),
     };
-    ctx.call::<DocumentHighlightRequest, _>(
-        meta,
-        req_params,
-        move |ctx: &mut Context, meta, result| editor_document_highlights(meta, result, ctx),
-    );
+    ctx.call::<DocumentHighlightRequest, _>(meta, req_params, callback);
 }
 
 pub fn editor_document_highlights(
@@ -39,25 +62,143 @@ pub fn editor_document_highlights(
         return;
     }
     let document = document.unwrap();
-    if let Some(highlights) = result {
-        let ranges = highlights
-            .into_iter()
-            .map(|highlight| {
-                format!(
-                    "{}|{}",
-                    lsp_range_to_kakoune(&highlight.range, &document.text, ctx.offset_encoding),
-                    if highlight.kind == Some(Write) {
-                        "ReferenceBind"
-                    } else {
-                        "Reference"
-                    }
-                )
-            })
-            .join(" ");
-        let command = format!(
-            "set-option window lsp_references {} {}",
-            meta.version, ranges,
-        );
-        ctx.exec(meta, command);
+    // No highlights clear the previous ones, e.g. once the cursor leaves the symbol.
+    let highlights = result
+        .unwrap_or_default()
+        .into_iter()
+        .map(|highlight| {
+            let range = lsp_range_to_kakoune(&highlight.range, &document.text, ctx.offset_encoding);
+            (range, highlight.kind)
+        })
+        .sorted_by_key(|(range, _)| (range.start.line, range.start.column))
+        .collect::<Vec<_>>();
+    let ranges = highlights
+        .iter()
+        .map(|(range, kind)| {
+            let face = match kind {
+                Some(DocumentHighlightKind::Write) => "ReferenceBind",
+                Some(DocumentHighlightKind::Read) => "ReferenceRead",
+                _ => "Reference",
+            };
+            format!("{}|{}", range, face)
+        })
+        .join(" ");
+    let command = format!(
+        "set-option window lsp_references {} {}",
+        meta.version, ranges,
+    );
+    ctx.document_highlights.insert(
+        meta.buffile.clone(),
+        BufferHighlights {
+            version: meta.version,
+            ranges: highlights.into_iter().map(|(range, _)| range).collect(),
+        },
+    );
+    ctx.exec(meta, command);
+}
+
+/// Select the next or previous reference to the symbol under the cursor. References highlighted
+/// last are reused as long as the buffer is unchanged and the cursor is on one of them.
+pub fn find_reference(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = FindReferenceParams::deserialize(params).unwrap();
+    let is_cached = ctx
+        .document_highlights
+        .get(&meta.buffile)
+        .map_or(false, |highlights| {
+            highlights.version == meta.version
+                && containing_range(&highlights.ranges, &params.position).is_some()
+        });
+    if is_cached {
+        select_reference(meta, params, ctx);
+        return;
+    }
+    let position = params.position.clone();
+    request_highlights(meta, &position, ctx, move |ctx, meta, result| {
+        editor_document_highlights(meta.clone(), result, ctx);
+        select_reference(meta, params, ctx);
+    });
+}
+
+fn select_reference(meta: EditorMeta, params: FindReferenceParams, ctx: &mut Context) {
+    let range = ctx
+        .document_highlights
+        .get(&meta.buffile)
+        .and_then(|highlights| {
+            let index = reference_index(&highlights.ranges, &params.position, params.offset)?;
+            Some(&highlights.ranges[index])
+        });
+    let command = match range {
+        Some(range) => format!("select {}", range),
+        None => format!(
+            "lsp-show-error {}",
+            editor_quote("No references to the symbol under the cursor")
+        ),
+    };
+    ctx.exec(meta, command);
+}
+
+fn containing_range(ranges: &[KakouneRange], position: &KakounePosition) -> Option<usize> {
+    let position = (position.line, position.column);
+    ranges.iter().position(|range| {
+        (range.start.line, range.start.column) <= position
+            && position <= (range.end.line, range.end.column)
+    })
+}
+
+/// Index of the range `offset` references away from the position, wrapping around at the ends.
+/// A position outside of references counts as being just before the next one.
+fn reference_index(
+    ranges: &[KakouneRange],
+    position: &KakounePosition,
+    offset: i64,
+) -> Option<usize> {
+    if ranges.is_empty() {
+        return None;
+    }
+    let count = ranges.len() as i64;
+    let index = match containing_range(ranges, position) {
+        Some(index) => index as i64 + offset,
+        None => {
+            let next = ranges
+                .iter()
+                .position(|range| {
+                    (range.start.line, range.start.column) > (position.line, position.column)
+                })
+                .unwrap_or(ranges.len()) as i64;
+            if offset > 0 {
+                next + offset - 1
+            } else {
+                next + offset
+            }
+        }
     };
+    Some(index.rem_euclid(count) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(line: u32, start: u32, end: u32) -> KakouneRange {
+        KakouneRange {
+            start: KakounePosition {
+                line,
+                column: start,
+            },
+            end: KakounePosition { line, column: end },
+        }
+    }
+
+    #[test]
+    fn reference_navigation_wraps_around() {
+        let ranges = vec![range(1, 5, 7), range(3, 1, 3), range(8, 10, 12)];
+        let on = |line, column| KakounePosition { line, column };
+        assert_eq!(reference_index(&ranges, &on(3, 2), 1), Some(2));
+        assert_eq!(reference_index(&ranges, &on(8, 12), 1), Some(0));
+        assert_eq!(reference_index(&ranges, &on(1, 5), -1), Some(2));
+        // Between references.
+        assert_eq!(reference_index(&ranges, &on(5, 1), 1), Some(2));
+        assert_eq!(reference_index(&ranges, &on(5, 1), -1), Some(1));
+        assert_eq!(reference_index(&[], &on(1, 1), 1), None);
+    }
 }
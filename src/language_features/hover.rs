@@ -1,4 +1,8 @@
 use crate::context::*;
+use crate::diagnostics;
+use crate::fan_out::{self, SharedAnswers};
+use crate::markup;
+use crate::position::lsp_range_to_kakoune;
 use crate::types::*;
 use crate::util::*;
 use itertools::Itertools;
@@ -8,7 +12,39 @@ use serde::Deserialize;
 use std::str;
 use url::Url;
 
+#[derive(Deserialize, Debug)]
+pub struct HoverRequestParams {
+    pub position: KakounePosition,
+    // Whether hover info is requested by auto-hover rather than by the user.
+    #[serde(default)]
+    pub auto: bool,
+}
+
 pub fn text_document_hover(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let HoverRequestParams { position, auto } = HoverRequestParams::deserialize(params).unwrap();
+    let req_params = HoverParams {
+        text_document_position_params: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(&meta.buffile).unwrap(),
+            },
+            position: get_lsp_position(&meta.buffile, &position, ctx).unwrap(),
+        },
+        work_done_progress_params: Default::default(),
+    };
+    let callback = move |ctx: &mut Context, meta, result| {
+        editor_hover(meta, PositionParams { position }, result, ctx)
+    };
+    // Auto-hover must not cancel hover info the user asked for.
+    if auto {
+        ctx.call::<HoverRequest, _>(meta, req_params, callback);
+    } else {
+        ctx.call_pinned::<HoverRequest, _>(meta, req_params, callback);
+    }
+}
+
+/// Show hover info in a scratch buffer, which is easier to read than an info box when it spans
+/// pages like documentation of Rust items does.
+pub fn text_document_hover_buffer(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = PositionParams::deserialize(params).unwrap();
     let req_params = HoverParams {
         text_document_position_params: TextDocumentPositionParams {
@@ -19,45 +55,107 @@ pub fn text_document_hover(meta: EditorMeta, params: EditorParams, ctx: &mut Con
         },
         work_done_progress_params: Default::default(),
     };
-    ctx.call::<HoverRequest, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
-        editor_hover(meta, params, result, ctx)
+    ctx.call_pinned::<HoverRequest, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        editor_hover_buffer(meta, result, ctx)
+    });
+}
+
+fn editor_hover_buffer(meta: EditorMeta, result: Option<Hover>, ctx: &mut Context) {
+    let result = match result {
+        Some(result) => result,
+        None => {
+            let msg = "No hover info at the cursor";
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+            return;
+        }
+    };
+    let (contents, is_markdown) = hover_contents_markdown(result.contents);
+    let filetype = if is_markdown { "markdown" } else { "" };
+    let range = result.range.and_then(|range| {
+        let document = ctx.documents.get(&meta.buffile)?;
+        Some(lsp_range_to_kakoune(
+            &range,
+            &document.text,
+            ctx.offset_encoding,
+        ))
     });
+    let mut command = format!(
+        "lsp-show-hover-buffer {} {}",
+        editor_quote(contents.trim()),
+        editor_quote(filetype)
+    );
+    if let Some(range) = range {
+        command = format!("{} {} {}|HoverRange", command, meta.version, range);
+    }
+    ctx.exec(meta, command);
+}
+
+/// Hover contents as a single document and whether it's markdown. Legacy marked strings are
+/// markdown, the ones with a language being turned into code blocks.
+fn hover_contents_markdown(contents: HoverContents) -> (String, bool) {
+    let marked_string = |s: MarkedString| match s {
+        MarkedString::String(s) => s,
+        MarkedString::LanguageString(s) => format!("```{}\n{}\n```", s.language, s.value),
+    };
+    match contents {
+        HoverContents::Scalar(contents) => (marked_string(contents), true),
+        HoverContents::Array(contents) => (
+            contents
+                .into_iter()
+                .map(marked_string)
+                .filter(|x| !x.trim().is_empty())
+                .join("\n\n"),
+            true,
+        ),
+        HoverContents::Markup(contents) => (contents.value, contents.kind == MarkupKind::Markdown),
+    }
 }
 
+/// Hover info of a language server, shown along with the one of other servers of the buffer.
+pub struct HoverAnswer {
+    position: KakounePosition,
+    contents: String,
+    is_markdown: bool,
+    // Diagnostics of the server at the position, as plain text.
+    diagnostics: Vec<String>,
+}
+
+/// Hover info of the language servers a request was sent to, until all of them have answered.
+pub type SharedHovers = SharedAnswers<HoverAnswer>;
+
 pub fn editor_hover(
     meta: EditorMeta,
     params: PositionParams,
     result: Option<Hover>,
     ctx: &mut Context,
 ) {
-    let diagnostics = ctx.diagnostics.get(&meta.buffile);
+    // Only markdown is rendered as markup, other hover info is shown as plain text.
+    let is_markdown = match &result {
+        Some(Hover {
+            contents: HoverContents::Markup(contents),
+            ..
+        }) => contents.kind == MarkupKind::Markdown,
+        _ => false,
+    };
     let pos = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
-    let diagnostics = diagnostics
-        .and_then(|x| {
-            Some(
-                x.iter()
-                    .filter(|x| {
-                        let start = x.range.start;
-                        let end = x.range.end;
-                        (start.line < pos.line && pos.line < end.line)
-                            || (start.line == pos.line
-                                && pos.line == end.line
-                                && start.character <= pos.character
-                                && pos.character <= end.character)
-                            || (start.line == pos.line
-                                && pos.line <= end.line
-                                && start.character <= pos.character)
-                            || (start.line <= pos.line
-                                && end.line == pos.line
-                                && pos.character <= end.character)
-                    })
-                    .map(|x| str::trim(&x.message))
-                    .filter(|x| !x.is_empty())
-                    .map(|x| format!("• {}", x))
-                    .join("\n"),
-            )
+    let diagnostics = ctx
+        .diagnostics
+        .get(&meta.buffile)
+        .into_iter()
+        .flat_map(|x| diagnostics::diagnostics_at(x, pos))
+        .filter(|x| !x.message.trim().is_empty())
+        .map(|x| {
+            let mut diagnostic = format!("• {}", diagnostics::diagnostic_message(x));
+            for related in x.related_information.iter().flatten() {
+                diagnostic = format!(
+                    "{}\n  ↳ {}",
+                    diagnostic,
+                    diagnostics::related_information_label(related, ctx)
+                );
+            }
+            diagnostic
         })
-        .unwrap_or_else(String::new);
+        .collect();
     let contents = match result {
         None => "".to_string(),
         Some(result) => match result.contents {
@@ -68,20 +166,74 @@ pub fn editor_hover(
                 .filter(|x| !x.is_empty())
                 .map(|x| format!("• {}", x))
                 .join("\n"),
-            HoverContents::Markup(contents) => contents.value,
+            HoverContents::Markup(contents) => match contents.kind {
+                MarkupKind::Markdown => markup::from_markdown(&contents.value),
+                MarkupKind::PlainText => contents.value,
+            },
         },
     };
 
+    let answer = HoverAnswer {
+        position: params.position,
+        contents,
+        is_markdown,
+        diagnostics,
+    };
+    show_hover(meta, Some(answer), ctx);
+}
+
+/// Show the hover info of all language servers the request was sent to once all of them have
+/// answered, None if this one failed to. If any of them is markdown, the others are escaped to be
+/// shown as markup too.
+pub fn show_hover(meta: EditorMeta, answer: Option<HoverAnswer>, ctx: &Context) {
+    let answers = match fan_out::collect(&ctx.shared_hovers, &meta, answer) {
+        Some(answers) => answers,
+        None => return,
+    };
+    let position = match answers.first() {
+        Some(answer) => answer.position.clone(),
+        None => return,
+    };
+    let is_markup = answers
+        .iter()
+        .any(|answer| answer.is_markdown && !answer.contents.is_empty());
+    let escape = |text: &str| {
+        if is_markup {
+            markup::escape(text)
+        } else {
+            text.to_string()
+        }
+    };
+    let contents = answers
+        .iter()
+        .filter(|answer| !answer.contents.is_empty())
+        .map(|answer| {
+            if answer.is_markdown {
+                answer.contents.clone()
+            } else {
+                escape(&answer.contents)
+            }
+        })
+        .join("\n\n");
+    let diagnostics = answers
+        .iter()
+        .flat_map(|answer| &answer.diagnostics)
+        .map(|diagnostic| escape(diagnostic))
+        .join("\n");
+
     if contents.is_empty() && diagnostics.is_empty() {
         return;
     }
 
-    let command = format!(
+    let mut command = format!(
         "lsp-show-hover {} %§{}§ %§{}§",
-        params.position,
+        position,
         contents.replace("§", "\\§"),
         diagnostics.replace("§", "\\§")
     );
+    if is_markup {
+        command.push_str(" markup");
+    }
 
     ctx.exec(meta, command);
 }
@@ -0,0 +1,520 @@
+use crate::context::Context;
+use crate::language_features::goto::{goto_location, goto_location_command};
+use crate::language_features::line_cache::{window_lines, LineCache};
+use crate::markup;
+use crate::position::lsp_position_to_kakoune;
+use crate::types::{EditorMeta, EditorParams, PositionParams};
+use crate::util::{apply_text_edits, editor_quote, get_lsp_position};
+use itertools::Itertools;
+use lsp_types::request::Request;
+use lsp_types::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use url::Url;
+
+// Inlay hints are introduced in LSP 3.17 which is not covered by lsp-types yet.
+
+pub enum InlayHintRequest {}
+
+impl Request for InlayHintRequest {
+    type Params = InlayHintParams;
+    type Result = Option<Vec<InlayHint>>;
+    const METHOD: &'static str = "textDocument/inlayHint";
+}
+
+pub enum InlayHintResolveRequest {}
+
+impl Request for InlayHintResolveRequest {
+    type Params = InlayHint;
+    type Result = InlayHint;
+    const METHOD: &'static str = "inlayHint/resolve";
+}
+
+pub enum InlayHintRefreshRequest {}
+
+impl Request for InlayHintRefreshRequest {
+    type Params = ();
+    type Result = ();
+    const METHOD: &'static str = "workspace/inlayHint/refresh";
+}
+
+/// Client capabilities for inlay hints, to be merged into the ones built with lsp-types.
+pub fn client_capabilities() -> Value {
+    serde_json::json!({
+        "textDocument": {
+            "inlayHint": {
+                "dynamicRegistration": false,
+                "resolveSupport": {"properties": ["tooltip", "textEdits"]},
+            },
+        },
+        "workspace": {
+            "inlayHint": {"refreshSupport": true},
+        },
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InlayHintParams {
+    pub text_document: TextDocumentIdentifier,
+    pub range: Range,
+    #[serde(flatten)]
+    pub work_done_progress_params: WorkDoneProgressParams,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct InlayHint {
+    pub position: Position,
+    pub label: InlayHintLabel,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text_edits: Option<Vec<TextEdit>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tooltip: Option<InlayHintTooltip>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub padding_left: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub padding_right: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+const INLAY_HINT_KIND_TYPE: u8 = 1;
+const INLAY_HINT_KIND_PARAMETER: u8 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum InlayHintLabel {
+    String(String),
+    LabelParts(Vec<InlayHintLabelPart>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct InlayHintLabelPart {
+    pub value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tooltip: Option<InlayHintTooltip>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<Command>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum InlayHintTooltip {
+    String(String),
+    MarkupContent(MarkupContent),
+}
+
+impl InlayHintTooltip {
+    fn text(&self) -> &str {
+        match self {
+            InlayHintTooltip::String(text) => text,
+            InlayHintTooltip::MarkupContent(content) => &content.value,
+        }
+    }
+}
+
+impl InlayHint {
+    fn label(&self) -> String {
+        match &self.label {
+            InlayHintLabel::String(label) => label.clone(),
+            InlayHintLabel::LabelParts(parts) => parts.iter().map(|part| &part.value).join(""),
+        }
+    }
+
+    fn tooltip(&self) -> String {
+        let label_tooltips = match &self.label {
+            InlayHintLabel::String(_) => vec![],
+            InlayHintLabel::LabelParts(parts) => parts
+                .iter()
+                .filter_map(|part| part.tooltip.as_ref())
+                .map(InlayHintTooltip::text)
+                .collect(),
+        };
+        self.tooltip
+            .iter()
+            .map(InlayHintTooltip::text)
+            .chain(label_tooltips)
+            .join("\n\n")
+    }
+
+    /// Label parts with a location along with it.
+    fn part_locations(&self) -> Vec<(String, Location)> {
+        match &self.label {
+            InlayHintLabel::String(_) => vec![],
+            InlayHintLabel::LabelParts(parts) => parts
+                .iter()
+                .filter_map(|part| Some((part.value.clone(), part.location.clone()?)))
+                .collect(),
+        }
+    }
+
+    /// Label with padding as displayed, in the face of the hint's kind.
+    fn markup(&self) -> String {
+        let face = match self.kind {
+            Some(INLAY_HINT_KIND_TYPE) => "LspInlayHintType",
+            Some(INLAY_HINT_KIND_PARAMETER) => "LspInlayHintParameter",
+            _ => "InlayHint",
+        };
+        let pad = |padding| if padding == Some(true) { " " } else { "" };
+        let label = format!(
+            "{}{}{}",
+            pad(self.padding_left),
+            self.label(),
+            pad(self.padding_right),
+        );
+        markup::literal(face, &label)
+    }
+
+    /// Servers may leave out tooltips and edits until the hint is resolved. They need some `data`
+    /// to tell which hint is being resolved, so hints without it are already complete.
+    fn is_resolved(&self) -> bool {
+        self.data.is_none() || (self.tooltip.is_some() && self.text_edits.is_some())
+    }
+}
+
+/// Inlay hints of a buffer grouped by line, as received for its `version`. Hints are requested
+/// for the lines around the window only, and kept so that scrolling back doesn't ask again.
+pub struct BufferInlayHints {
+    version: i32,
+    lines: LineCache<InlayHint>,
+    // Lines of the window hints were last requested for, to request them again on refresh.
+    window: Option<(u32, u32)>,
+}
+
+impl BufferInlayHints {
+    fn new(version: i32) -> Self {
+        BufferInlayHints {
+            version,
+            lines: LineCache::default(),
+            window: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct InlayHintsRequestParams {
+    // Kakoune's window_range: line and column of the top left corner of the window, its height
+    // and width. Empty if there is no window, then hints of the whole buffer are requested.
+    #[serde(default)]
+    pub window_range: String,
+}
+
+pub fn inlay_hints(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = InlayHintsRequestParams::deserialize(params).unwrap_or_default();
+    let window = window_lines(&params.window_range);
+    request_inlay_hints(meta, window, ctx);
+}
+
+fn request_inlay_hints(meta: EditorMeta, window: Option<(u32, u32)>, ctx: &mut Context) {
+    let len_lines = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document.text.len_lines() as u32,
+        None => return,
+    };
+    let hints = ctx
+        .inlay_hints
+        .entry(meta.buffile.clone())
+        .or_insert_with(|| BufferInlayHints::new(meta.version));
+    if hints.version != meta.version {
+        *hints = BufferInlayHints::new(meta.version);
+    }
+    hints.window = window;
+    // A window's height above and below it are requested along, so that scrolling a bit finds
+    // the hints already there.
+    let (start, end) = match window {
+        Some((start, end)) => {
+            let end = end.min(len_lines);
+            let start = start.min(end);
+            if hints.lines.is_covered((start, end)) {
+                show_inlay_hints(meta, ctx);
+                return;
+            }
+            let height = end.saturating_sub(start);
+            (start.saturating_sub(height), (end + height).min(len_lines))
+        }
+        None => (0, len_lines),
+    };
+    let req_params = InlayHintParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        range: Range {
+            start: Position::new(start, 0),
+            end: Position::new(end, 0),
+        },
+        work_done_progress_params: Default::default(),
+    };
+    ctx.call::<InlayHintRequest, _>(meta, req_params, move |ctx, meta, response| {
+        inlay_hints_response(meta, (start, end), response.unwrap_or_default(), ctx)
+    });
+}
+
+fn inlay_hints_response(
+    meta: EditorMeta,
+    lines: (u32, u32),
+    inlay_hints: Vec<InlayHint>,
+    ctx: &mut Context,
+) {
+    let hints = ctx
+        .inlay_hints
+        .entry(meta.buffile.clone())
+        .or_insert_with(|| BufferInlayHints::new(meta.version));
+    // Hints for an older version of the buffer would be misplaced.
+    if hints.version != meta.version {
+        return;
+    }
+    // Hints received for the lines replace any kept from before, the others stay as they are.
+    let mut received: BTreeMap<u32, Vec<InlayHint>> = BTreeMap::new();
+    for hint in inlay_hints {
+        received.entry(hint.position.line).or_default().push(hint);
+    }
+    hints.lines.replace(lines, received);
+    show_inlay_hints(meta, ctx);
+}
+
+/// Show all hints kept for the buffer, so that those received for new lines are merged into the
+/// ones displayed.
+fn show_inlay_hints(meta: EditorMeta, ctx: &Context) {
+    let (document, hints) = match (
+        ctx.documents.get(&meta.buffile),
+        ctx.inlay_hints.get(&meta.buffile),
+    ) {
+        (Some(document), Some(hints)) => (document, hints),
+        _ => return,
+    };
+    let ranges = hints
+        .lines
+        .values()
+        .map(|hint| {
+            let position =
+                lsp_position_to_kakoune(&hint.position, &document.text, ctx.offset_encoding);
+            let markup = hint.markup().replace("|", "\\|");
+            editor_quote(&format!("{}+0|{}", position, markup))
+        })
+        .join(" ");
+    let command = format!("set buffer lsp_inlay_hints {} {}", hints.version, ranges);
+    let command = format!(
+        "eval -buffer {} -verbatim -- {}",
+        editor_quote(&meta.buffile),
+        command
+    );
+    ctx.exec(meta, command)
+}
+
+/// Handle `workspace/inlayHint/refresh` by requesting hints again for all buffers showing them,
+/// for the window they were last requested for.
+pub fn refresh_all_inlay_hints(ctx: &mut Context) {
+    let buffers = ctx
+        .inlay_hints
+        .iter_mut()
+        .map(|(buffile, hints)| {
+            hints.lines.uncover();
+            (buffile.clone(), hints.window)
+        })
+        .collect::<Vec<_>>();
+    for (buffile, window) in buffers {
+        if let Some(meta) = ctx.meta_for_buffer(buffile) {
+            request_inlay_hints(meta, window, ctx);
+        }
+    }
+}
+
+/// Find the hint closest to the position on the same line.
+fn hint_near(meta: &EditorMeta, position: Position, ctx: &Context) -> Option<InlayHint> {
+    let hints = ctx.inlay_hints.get(&meta.buffile)?;
+    if hints.version != meta.version {
+        return None;
+    }
+    hints
+        .lines
+        .line(position.line)?
+        .iter()
+        .min_by_key(|hint| {
+            let character: u64 = hint.position.character.into();
+            let cursor: u64 = position.character.into();
+            character.max(cursor) - character.min(cursor)
+        })
+        .cloned()
+}
+
+/// Pass the hint to the callback once any data deferred by the server has been resolved.
+pub fn resolve_inlay_hint<F>(meta: EditorMeta, hint: InlayHint, ctx: &mut Context, callback: F)
+where
+    F: for<'a> FnOnce(&'a mut Context, EditorMeta, InlayHint) + 'static,
+{
+    if hint.is_resolved() {
+        callback(ctx, meta, hint);
+        return;
+    }
+    let position = hint.position;
+    ctx.call::<InlayHintResolveRequest, _>(meta, hint, move |ctx, meta, hint| {
+        // Remember the resolved hint so we don't have to ask again.
+        if let Some(hints) = ctx.inlay_hints.get_mut(&meta.buffile) {
+            if hints.version == meta.version {
+                if let Some(stored) = hints
+                    .lines
+                    .line_mut(position.line)
+                    .and_then(|line| line.iter_mut().find(|h| h.position == position))
+                {
+                    *stored = hint.clone();
+                }
+            }
+        }
+        callback(ctx, meta, hint)
+    });
+}
+
+pub fn inlay_hints_hover(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let position = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
+    let hint = match hint_near(&meta, position, ctx) {
+        Some(hint) => hint,
+        None => {
+            let msg = "No inlay hint on the cursor line";
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+            return;
+        }
+    };
+    resolve_inlay_hint(meta, hint, ctx, move |ctx, meta, hint| {
+        let tooltip = hint.tooltip();
+        let contents = if tooltip.is_empty() {
+            hint.label()
+        } else {
+            tooltip
+        };
+        let command = format!(
+            "lsp-show-hover {} %§{}§ %§§",
+            params.position,
+            contents.replace("§", "\\§"),
+        );
+        ctx.exec(meta, command);
+    });
+}
+
+/// Apply the text edits of the hint nearest to the cursor, e.g. to spell out an inferred type.
+pub fn inlay_hints_apply(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let position = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
+    let hint = match hint_near(&meta, position, ctx) {
+        Some(hint) => hint,
+        None => {
+            let msg = "No inlay hint on the cursor line";
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+            return;
+        }
+    };
+    resolve_inlay_hint(meta, hint, ctx, move |ctx, meta, hint| {
+        match hint.text_edits {
+            Some(edits) if !edits.is_empty() => {
+                let uri = Url::from_file_path(&meta.buffile).unwrap();
+                apply_text_edits(&meta, &uri, edits, ctx);
+            }
+            _ => {
+                let msg = format!("Inlay hint \"{}\" has nothing to apply", hint.label());
+                ctx.exec(meta, format!("info {}", editor_quote(&msg)));
+            }
+        }
+    });
+}
+
+/// Jump to the location of the hint nearest to the cursor, e.g. to the definition of an inferred
+/// type. Kakoune can't place the cursor within the hint as it's not part of the buffer, so if
+/// several label parts have a location, a menu offers the choice. Without any, the jump is to the
+/// hint's own position.
+pub fn inlay_hints_goto(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let position = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
+    let hint = match hint_near(&meta, position, ctx) {
+        Some(hint) => hint,
+        None => {
+            let msg = "No inlay hint on the cursor line";
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+            return;
+        }
+    };
+    resolve_inlay_hint(meta, hint, ctx, move |ctx, meta, hint| {
+        let locations = hint.part_locations();
+        match locations.as_slice() {
+            [] => {
+                let location = Location {
+                    uri: Url::from_file_path(&meta.buffile).unwrap(),
+                    range: Range {
+                        start: hint.position,
+                        end: hint.position,
+                    },
+                };
+                goto_location(meta, &location, ctx);
+            }
+            [(_, location)] => goto_location(meta, location, ctx),
+            _ => {
+                let menu_args = locations
+                    .iter()
+                    .filter_map(|(value, location)| {
+                        let command = goto_location_command(location, ctx)?;
+                        Some(format!(
+                            "{} {}",
+                            editor_quote(value),
+                            editor_quote(&command)
+                        ))
+                    })
+                    .join(" ");
+                ctx.exec(meta, format!("menu {}", menu_args));
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn string_label_with_padding_and_kind() {
+        let hint: InlayHint = serde_json::from_value(json!({
+            "position": {"line": 0, "character": 5},
+            "label": ": Vec<u8>",
+            "kind": 1,
+            "paddingRight": true,
+        }))
+        .unwrap();
+        assert_eq!(hint.markup(), "{LspInlayHintType}{\\}: Vec<u8> ");
+        assert_eq!(hint.tooltip(), "");
+    }
+
+    #[test]
+    fn label_parts_are_concatenated() {
+        let hint: InlayHint = serde_json::from_value(json!({
+            "position": {"line": 0, "character": 5},
+            "label": [
+                {"value": "count", "tooltip": "Parameter of `repeat`"},
+                {
+                    "value": ":",
+                    "location": {
+                        "uri": "file:///src/lib.rs",
+                        "range": {
+                            "start": {"line": 1, "character": 0},
+                            "end": {"line": 1, "character": 5},
+                        },
+                    },
+                },
+            ],
+            "kind": 2,
+            "paddingLeft": true,
+            "tooltip": {"kind": "markdown", "value": "Number of times"},
+        }))
+        .unwrap();
+        assert_eq!(hint.markup(), "{LspInlayHintParameter}{\\} count:");
+        let locations = hint.part_locations();
+        assert_eq!(locations.len(), 1);
+        assert_eq!(locations[0].0, ":");
+        assert_eq!(locations[0].1.uri.path(), "/src/lib.rs");
+        assert_eq!(hint.tooltip(), "Number of times\n\nParameter of `repeat`");
+    }
+}
@@ -0,0 +1,124 @@
+//! Results of features requested for the lines around the window rather than the whole buffer,
+//! e.g. inlay hints and semantic tokens. They are kept by line, so that results received for some
+//! lines replace the ones kept for those lines only.
+
+use std::collections::BTreeMap;
+
+/// Lines of the window, end exclusive, from Kakoune's window_range: line and column of the top
+/// left corner of the window, zero-based, its height and width.
+pub fn window_lines(window_range: &str) -> Option<(u32, u32)> {
+    let mut values = window_range
+        .split_whitespace()
+        .map(|value| value.parse::<u32>().ok());
+    let line = values.next()??;
+    let _column = values.next()??;
+    let height = values.next()??;
+    Some((line, line + height))
+}
+
+/// Values kept by line along with the ranges of lines they have been received for.
+pub struct LineCache<T> {
+    lines: BTreeMap<u32, Vec<T>>,
+    // Ranges of lines values have been received for, end exclusive. They never overlap or adjoin.
+    covered: Vec<(u32, u32)>,
+}
+
+impl<T> Default for LineCache<T> {
+    fn default() -> Self {
+        LineCache {
+            lines: BTreeMap::new(),
+            covered: vec![],
+        }
+    }
+}
+
+impl<T> LineCache<T> {
+    /// Replace all values kept with the ones received for the whole buffer.
+    pub fn replace_all(&mut self, lines: BTreeMap<u32, Vec<T>>) {
+        self.lines = lines;
+        self.covered = vec![(0, u32::MAX)];
+    }
+
+    /// Replace the values kept for the lines, end exclusive, with the ones received for them.
+    /// Values kept for other lines stay as they are.
+    pub fn replace(&mut self, (start, end): (u32, u32), lines: BTreeMap<u32, Vec<T>>) {
+        let mut replaced = self.lines.split_off(&start);
+        self.lines.append(&mut replaced.split_off(&end));
+        self.lines.extend(lines);
+        self.cover((start, end));
+    }
+
+    /// Whether values have been received for all of the lines.
+    pub fn is_covered(&self, (start, end): (u32, u32)) -> bool {
+        self.covered
+            .iter()
+            .any(|(covered_start, covered_end)| *covered_start <= start && end <= *covered_end)
+    }
+
+    /// Forget which lines values have been received for, so that they are requested again.
+    pub fn uncover(&mut self) {
+        self.covered.clear();
+    }
+
+    pub fn line_mut(&mut self, line: u32) -> Option<&mut Vec<T>> {
+        self.lines.get_mut(&line)
+    }
+
+    pub fn line(&self, line: u32) -> Option<&Vec<T>> {
+        self.lines.get(&line)
+    }
+
+    /// All values kept, in line order.
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.lines.values().flatten()
+    }
+
+    /// Add the lines to the covered ranges, merging ranges which overlap or adjoin.
+    fn cover(&mut self, (mut start, mut end): (u32, u32)) {
+        self.covered.retain(|(covered_start, covered_end)| {
+            if *covered_end < start || end < *covered_start {
+                return true;
+            }
+            start = start.min(*covered_start);
+            end = end.max(*covered_end);
+            false
+        });
+        self.covered.push((start, end));
+        self.covered.sort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_lines_from_window_range() {
+        assert_eq!(window_lines("120 0 40 100"), Some((120, 160)));
+        assert_eq!(window_lines(""), None);
+    }
+
+    #[test]
+    fn covered_ranges_are_merged() {
+        let mut cache = LineCache::<()>::default();
+        cache.replace((100, 200), BTreeMap::new());
+        cache.replace((0, 50), BTreeMap::new());
+        assert!(cache.is_covered((120, 160)));
+        assert!(!cache.is_covered((40, 60)));
+        cache.replace((50, 100), BTreeMap::new());
+        assert_eq!(cache.covered, vec![(0, 200)]);
+        assert!(cache.is_covered((40, 60)));
+    }
+
+    #[test]
+    fn values_for_lines_replace_the_kept_ones() {
+        let mut cache = LineCache::default();
+        cache.replace_all(
+            vec![(1, vec!["a"]), (5, vec!["b"]), (9, vec!["c"])]
+                .into_iter()
+                .collect(),
+        );
+        cache.replace((4, 8), vec![(6, vec!["d"])].into_iter().collect());
+        assert_eq!(cache.values().collect::<Vec<_>>(), vec![&"a", &"d", &"c"]);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::context::Context;
+use crate::position::lsp_range_to_kakoune;
+use crate::types::{EditorMeta, EditorParams, PositionParams};
+use crate::util::get_lsp_position;
+use itertools::Itertools;
+use lsp_types::{
+    request::LinkedEditingRange, LinkedEditingRangeParams, LinkedEditingRanges,
+    TextDocumentIdentifier, TextDocumentPositionParams,
+};
+use serde::Deserialize;
+use url::Url;
+
+pub fn text_document_linked_editing_range(
+    meta: EditorMeta,
+    params: EditorParams,
+    ctx: &mut Context,
+) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let req_params = LinkedEditingRangeParams {
+        text_document_position_params: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(&meta.buffile).unwrap(),
+            },
+            position: get_lsp_position(&meta.buffile, &params.position, ctx).unwrap(),
+        },
+        work_done_progress_params: Default::default(),
+    };
+    ctx.call::<LinkedEditingRange, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        editor_linked_editing_range(meta, result, ctx)
+    });
+}
+
+/// Select all linked ranges so they can be edited simultaneously with Kakoune's multiple
+/// selections. `wordPattern` is ignored as Kakoune is in charge of the actual editing.
+pub fn editor_linked_editing_range(
+    meta: EditorMeta,
+    result: Option<LinkedEditingRanges>,
+    ctx: &mut Context,
+) {
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    let ranges = match result {
+        Some(result) if !result.ranges.is_empty() => result.ranges,
+        _ => return,
+    };
+    let command = format!(
+        "select {}",
+        ranges
+            .iter()
+            .map(|range| lsp_range_to_kakoune(range, &document.text, ctx.offset_encoding))
+            .join(" ")
+    );
+    ctx.exec(meta, command);
+}
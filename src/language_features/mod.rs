@@ -1,17 +1,30 @@
+pub mod call_hierarchy;
 pub mod ccls;
 pub mod clangd;
+pub mod code_lens;
 pub mod codeaction;
 pub mod completion;
 pub mod cquery;
+pub mod document_color;
+pub mod document_link;
 pub mod document_symbol;
 pub mod eclipse_jdt_ls;
+pub mod folding;
 pub mod formatting;
 pub mod goto;
+pub mod hierarchy;
 pub mod highlights;
 pub mod hover;
+pub mod inlay_hints;
+pub mod line_cache;
+pub mod linked_editing;
+pub mod moniker;
+pub mod on_type_formatting;
 pub mod range_formatting;
 pub mod rename;
 pub mod rust_analyzer;
+pub mod selection_range;
 pub mod semantic_highlighting;
 pub mod semantic_tokens;
 pub mod signature_help;
+pub mod type_hierarchy;
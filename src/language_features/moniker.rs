@@ -0,0 +1,61 @@
+use crate::context::Context;
+use crate::types::{EditorMeta, EditorParams, PositionParams};
+use crate::util::{editor_quote, get_lsp_position};
+use itertools::Itertools;
+use lsp_types::request::MonikerRequest;
+use lsp_types::*;
+use serde::Deserialize;
+use url::Url;
+
+pub fn text_document_moniker(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let req_params = MonikerParams {
+        text_document_position_params: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(&meta.buffile).unwrap(),
+            },
+            position: get_lsp_position(&meta.buffile, &params.position, ctx).unwrap(),
+        },
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    ctx.call::<MonikerRequest, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        editor_moniker(meta, result.unwrap_or_default(), ctx)
+    });
+}
+
+fn editor_moniker(meta: EditorMeta, monikers: Vec<Moniker>, ctx: &mut Context) {
+    if monikers.is_empty() {
+        let msg = "No moniker for the symbol under the cursor";
+        ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+        return;
+    }
+    // One moniker per line, e.g. `scip-rust:cargo foo 1.0 bar/baz() unique=global kind=export`.
+    let content = monikers
+        .iter()
+        .map(|moniker| {
+            let mut line = format!(
+                "{}:{} unique={}",
+                moniker.scheme,
+                moniker.identifier,
+                enum_name(&moniker.unique)
+            );
+            if let Some(kind) = &moniker.kind {
+                line.push_str(&format!(" kind={}", enum_name(kind)));
+            }
+            line
+        })
+        .join("\n");
+    ctx.exec(
+        meta,
+        format!("lsp-show-monikers {}", editor_quote(&content)),
+    );
+}
+
+/// Name of the enum variant as serialized in LSP.
+fn enum_name<T: serde::Serialize>(value: &T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
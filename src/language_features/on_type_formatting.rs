@@ -0,0 +1,145 @@
+use crate::context::Context;
+use crate::position::char_to_code_units;
+use crate::types::{EditorMeta, EditorParams};
+use crate::util::apply_text_edits;
+use lsp_types::request::OnTypeFormatting;
+use lsp_types::*;
+use ropey::Rope;
+use serde::Deserialize;
+use url::Url;
+
+/// Insertions longer than that are considered to be pasted rather than typed.
+const MAX_TYPED_CHARS: usize = 8;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OnTypeFormattingEditorParams {
+    pub tab_size: u32,
+    pub insert_spaces: bool,
+}
+
+/// Text inserted into the buffer by the last change.
+pub struct TypedText {
+    pub version: i32,
+    pub text: String,
+    // Position right after the inserted text.
+    pub end: Position,
+}
+
+/// Remember text inserted by the change between `old` and `new` buffer contents, so that
+/// on type formatting, signature help and completion could check if a trigger character was typed.
+pub fn record_typed_text(buffile: &str, old: &Rope, new: &Rope, version: i32, ctx: &mut Context) {
+    ctx.typed_text.remove(buffile);
+    let capabilities = ctx.capabilities.as_ref().unwrap();
+    if capabilities.document_on_type_formatting_provider.is_none()
+        && capabilities.signature_help_provider.is_none()
+        && capabilities.completion_provider.is_none()
+    {
+        return;
+    }
+    let old_len = old.len_chars();
+    let new_len = new.len_chars();
+    if new_len <= old_len || new_len - old_len > MAX_TYPED_CHARS {
+        return;
+    }
+    let prefix = old
+        .chars()
+        .zip(new.chars())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut old_chars = old.chars_at(old_len);
+    let mut new_chars = new.chars_at(new_len);
+    let mut suffix = 0;
+    while suffix < old_len - prefix {
+        match (old_chars.prev(), new_chars.prev()) {
+            (Some(a), Some(b)) if a == b => suffix += 1,
+            _ => break,
+        }
+    }
+    // Only pure insertions count as typing.
+    if prefix + suffix != old_len {
+        return;
+    }
+    let end = new_len - suffix;
+    let line = new.char_to_line(end);
+    let line_start = new.line_to_char(line);
+    let character = char_to_code_units(new.line(line), end - line_start, ctx.offset_encoding);
+    ctx.typed_text.insert(
+        buffile.to_string(),
+        TypedText {
+            version,
+            text: new.slice(prefix..end).to_string(),
+            end: Position::new(line as u32, character as u32),
+        },
+    );
+}
+
+pub fn text_document_on_type_formatting(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = OnTypeFormattingEditorParams::deserialize(params)
+        .expect("Params should follow OnTypeFormattingEditorParams structure");
+    let provider = match &ctx
+        .capabilities
+        .as_ref()
+        .unwrap()
+        .document_on_type_formatting_provider
+    {
+        Some(provider) => provider,
+        None => return,
+    };
+    let typed = match ctx.typed_text.get(&meta.buffile) {
+        Some(typed) if typed.version == meta.version => typed,
+        _ => return,
+    };
+    // Newline is usually followed by indentation inserted by the editor.
+    let text = if typed
+        .text
+        .trim_end_matches(&[' ', '\t'][..])
+        .ends_with('\n')
+    {
+        typed.text.trim_end_matches(&[' ', '\t'][..])
+    } else {
+        &typed.text
+    };
+    let ch = match text.chars().last() {
+        Some(ch) => ch.to_string(),
+        None => return,
+    };
+    let is_trigger = provider.first_trigger_character == ch
+        || provider
+            .more_trigger_character
+            .as_ref()
+            .map_or(false, |chars| chars.contains(&ch));
+    if !is_trigger {
+        return;
+    }
+    let mut position = typed.end;
+    if text.len() < typed.text.len() {
+        // Point right after the newline, before the indentation.
+        position.character = 0;
+    }
+    let uri = Url::from_file_path(&meta.buffile).unwrap();
+    let req_params = DocumentOnTypeFormattingParams {
+        text_document_position: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier { uri: uri.clone() },
+            position,
+        },
+        ch,
+        options: FormattingOptions {
+            tab_size: params.tab_size,
+            insert_spaces: params.insert_spaces,
+            ..FormattingOptions::default()
+        },
+    };
+    ctx.call::<OnTypeFormatting, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
+        // Don't touch the buffer if user has already moved on.
+        let version = ctx.documents.get(&meta.buffile).map(|doc| doc.version);
+        if version != Some(meta.version) {
+            return;
+        }
+        if let Some(edits) = result {
+            if !edits.is_empty() {
+                apply_text_edits(&meta, &uri, edits, ctx);
+            }
+        }
+    });
+}
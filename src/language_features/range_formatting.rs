@@ -1,4 +1,5 @@
 use crate::context::*;
+use crate::position::*;
 use crate::text_edit::apply_text_edits_to_buffer;
 use crate::types::*;
 use lsp_types::request::*;
@@ -6,22 +7,37 @@ use lsp_types::*;
 use serde::Deserialize;
 use url::Url;
 
-pub fn text_document_range_formatting(
-    meta: EditorMeta,
-    params: EditorParams,
-    ranges: Vec<Range>,
-    ctx: &mut Context,
-) {
-    let params = FormattingOptions::deserialize(params)
-        .expect("Params should follow FormattingOptions structure");
-    let req_params = ranges
-        .into_iter()
-        .map(|range| DocumentRangeFormattingParams {
+pub fn text_document_range_formatting(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = RangeFormattingParams::deserialize(params)
+        .expect("Params should follow RangeFormattingParams structure");
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => {
+            // Nothing to do, but sending command back to the editor is required to handle case when
+            // editor is blocked waiting for response via fifo.
+            ctx.exec(meta, "nop".to_string());
+            return;
+        }
+    };
+    let options = FormattingOptions {
+        tab_size: params.tab_size,
+        insert_spaces: params.insert_spaces,
+        ..FormattingOptions::default()
+    };
+    let req_params = params
+        .selections_desc
+        .split_whitespace()
+        .filter_map(parse_selection_desc)
+        .map(|(anchor, cursor)| DocumentRangeFormattingParams {
             text_document: TextDocumentIdentifier {
                 uri: Url::from_file_path(&meta.buffile).unwrap(),
             },
-            range: range,
-            options: params.clone(),
+            range: kakoune_range_to_lsp(
+                &ordered_range(anchor, cursor),
+                &document.text,
+                ctx.offset_encoding,
+            ),
+            options: options.clone(),
             work_done_progress_params: Default::default(),
         })
         .collect();
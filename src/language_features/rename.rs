@@ -1,4 +1,5 @@
 use crate::context::*;
+use crate::position::*;
 use crate::types::*;
 use crate::util::*;
 use lsp_types::request::*;
@@ -25,11 +26,75 @@ pub fn text_document_rename(meta: EditorMeta, params: EditorParams, ctx: &mut Co
     });
 }
 
+pub fn text_document_prepare_rename(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let prepare_provider = match ctx.capabilities.as_ref().unwrap().rename_provider {
+        Some(OneOf::Right(RenameOptions {
+            prepare_provider: Some(prepare_provider),
+            ..
+        })) => prepare_provider,
+        _ => false,
+    };
+    if !prepare_provider {
+        // Fall back to selecting the word under the cursor.
+        ctx.exec(meta, "lsp-rename-prompt-word".to_string());
+        return;
+    }
+    let params = PositionParams::deserialize(params).unwrap();
+    let req_params = TextDocumentPositionParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        position: get_lsp_position(&meta.buffile, &params.position, ctx).unwrap(),
+    };
+    ctx.call::<PrepareRenameRequest, _>(
+        meta,
+        req_params,
+        move |ctx: &mut Context, meta, result| editor_prepare_rename(meta, result, ctx),
+    );
+}
+
+pub fn editor_prepare_rename(
+    meta: EditorMeta,
+    result: Option<PrepareRenameResponse>,
+    ctx: &mut Context,
+) {
+    let (range, placeholder) = match result {
+        None => {
+            let msg = "Nothing renameable at the cursor position";
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+            return;
+        }
+        Some(PrepareRenameResponse::DefaultBehavior { .. }) => {
+            ctx.exec(meta, "lsp-rename-prompt-word".to_string());
+            return;
+        }
+        Some(PrepareRenameResponse::Range(range)) => (range, None),
+        Some(PrepareRenameResponse::RangeWithPlaceholder { range, placeholder }) => {
+            (range, Some(placeholder))
+        }
+    };
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => {
+            ctx.exec(meta, "lsp-rename-prompt-word".to_string());
+            return;
+        }
+    };
+    let range = lsp_range_to_kakoune(&range, &document.text, ctx.offset_encoding);
+    // Without a placeholder, the renamed identifier is the text of the range itself.
+    let init = match placeholder {
+        Some(placeholder) => editor_quote(&placeholder),
+        None => "%val{selection}".to_string(),
+    };
+    let command = format!("select {}; lsp-rename-prompt-show {}", range, init);
+    ctx.exec(meta, command);
+}
+
 // TODO handle version, so change is not applied if buffer is modified (and need to show a warning)
 pub fn editor_rename(meta: EditorMeta, result: Option<WorkspaceEdit>, ctx: &mut Context) {
     if result.is_none() {
         return;
     }
     let result = result.unwrap();
-    workspace::apply_edit(meta, result, ctx);
+    workspace::apply_or_preview_edit(meta, result, None, ctx);
 }
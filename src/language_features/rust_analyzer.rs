@@ -1,7 +1,7 @@
 use crate::context::Context;
 use crate::position::{lsp_position_to_kakoune, lsp_range_to_kakoune};
-use crate::types::{EditorMeta, EditorParams, KakounePosition};
-use crate::util::{apply_text_edits, editor_quote};
+use crate::types::{EditorMeta, EditorParams, KakounePosition, PositionParams};
+use crate::util::{apply_text_edits, editor_quote, get_lsp_position};
 use crate::workspace;
 use lsp_types::request::Request;
 use lsp_types::ExecuteCommandParams;
@@ -41,6 +41,45 @@ pub struct InlayHint {
     pub label: String,
 }
 
+pub enum ExpandMacroRequest {}
+
+impl Request for ExpandMacroRequest {
+    type Params = TextDocumentPositionParams;
+    type Result = Option<ExpandedMacro>;
+    const METHOD: &'static str = "rust-analyzer/expandMacro";
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExpandedMacro {
+    pub name: String,
+    pub expansion: String,
+}
+
+/// Show recursive expansion of the macro call under the cursor.
+pub fn expand_macro(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let req_params = TextDocumentPositionParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        position: get_lsp_position(&meta.buffile, &params.position, ctx).unwrap(),
+    };
+    ctx.call::<ExpandMacroRequest, _>(meta, req_params, move |ctx, meta, response| {
+        let command = match response {
+            Some(ExpandedMacro { name, expansion }) => format!(
+                "rust-analyzer-show-expanded-macro {} {}",
+                editor_quote(&name),
+                editor_quote(&expansion)
+            ),
+            None => format!(
+                "lsp-show-error {}",
+                editor_quote("No macro call under the cursor")
+            ),
+        };
+        ctx.exec(meta, command);
+    });
+}
+
 pub fn inlay_hints(meta: EditorMeta, _params: EditorParams, ctx: &mut Context) {
     let req_params = InlayHintsParams {
         text_document: TextDocumentIdentifier {
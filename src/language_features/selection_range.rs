@@ -0,0 +1,194 @@
+use crate::context::Context;
+use crate::position::{lsp_range_to_kakoune, ordered_range, parse_selection_desc};
+use crate::types::{EditorMeta, EditorParams, KakounePosition, KakouneRange};
+use crate::util::get_lsp_position;
+use itertools::Itertools;
+use lsp_types::request::SelectionRangeRequest;
+use lsp_types::*;
+use serde::Deserialize;
+use url::Url;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectionRangeEditorParams {
+    // Either "expand" or "shrink".
+    pub direction: String,
+    // Current selections as reported by Kakoune's %val{selections_desc}.
+    pub selections_desc: String,
+}
+
+/// Selection ranges obtained for the client's selections, one chain per selection ordered from
+/// the innermost range to the outermost one, and current position in each chain.
+pub struct SelectionRangeState {
+    buffile: String,
+    version: i32,
+    chains: Vec<(Vec<KakouneRange>, usize)>,
+}
+
+impl SelectionRangeState {
+    fn selections(&self) -> Vec<&KakouneRange> {
+        self.chains
+            .iter()
+            .map(|(chain, index)| &chain[*index])
+            .collect()
+    }
+}
+
+pub fn text_document_selection_range(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = SelectionRangeEditorParams::deserialize(params).unwrap();
+    let expand = params.direction == "expand";
+    let selections = params
+        .selections_desc
+        .split_whitespace()
+        .filter_map(parse_selection_desc)
+        .map(|(anchor, cursor)| (cursor.clone(), ordered_range(anchor, cursor)))
+        .collect::<Vec<_>>();
+    let client = meta.client.clone().unwrap_or_default();
+
+    // Walk the stored chains if selections are still the ones we applied last time,
+    // otherwise the user has moved on and we need to start over.
+    if let Some(state) = ctx.selection_ranges.get_mut(&client) {
+        let is_current = state.buffile == meta.buffile
+            && state.version == meta.version
+            && state.selections() == selections.iter().map(|(_, range)| range).collect_vec();
+        if is_current {
+            for (chain, index) in state.chains.iter_mut() {
+                if expand {
+                    *index = (*index + 1).min(chain.len() - 1);
+                } else {
+                    *index = index.saturating_sub(1);
+                }
+            }
+            let command = select_command(state);
+            ctx.exec(meta, command);
+            return;
+        }
+    }
+    ctx.selection_ranges.remove(&client);
+    if !expand || selections.is_empty() {
+        return;
+    }
+
+    let positions = selections
+        .iter()
+        .map(|(cursor, _)| get_lsp_position(&meta.buffile, cursor, ctx).unwrap())
+        .collect();
+    let req_params = SelectionRangeParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        positions,
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    ctx.call::<SelectionRangeRequest, _>(
+        meta,
+        req_params,
+        move |ctx: &mut Context, meta, result| {
+            let selections = selections.into_iter().map(|(_, range)| range).collect();
+            editor_selection_range(meta, selections, result, ctx)
+        },
+    );
+}
+
+fn editor_selection_range(
+    meta: EditorMeta,
+    selections: Vec<KakouneRange>,
+    result: Option<Vec<SelectionRange>>,
+    ctx: &mut Context,
+) {
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    let result = match result {
+        Some(result) if result.len() == selections.len() => result,
+        _ => return,
+    };
+    // Each selection gets its own chain of parents.
+    let chains = result
+        .into_iter()
+        .zip(selections)
+        .map(|(selection_range, selection)| {
+            let mut chain = vec![];
+            let mut next = Some(Box::new(selection_range));
+            while let Some(selection_range) = next {
+                let range = lsp_range_to_kakoune(
+                    &selection_range.range,
+                    &document.text,
+                    ctx.offset_encoding,
+                );
+                if chain.last() != Some(&range) {
+                    chain.push(range);
+                }
+                next = selection_range.parent;
+            }
+            let index = first_expansion(&chain, &selection);
+            (chain, index)
+        })
+        .filter(|(chain, _)| !chain.is_empty())
+        .collect::<Vec<_>>();
+    if chains.is_empty() {
+        return;
+    }
+    let state = SelectionRangeState {
+        buffile: meta.buffile.clone(),
+        version: meta.version,
+        chains,
+    };
+    let command = select_command(&state);
+    ctx.selection_ranges
+        .insert(meta.client.clone().unwrap_or_default(), state);
+    ctx.exec(meta, command);
+}
+
+/// Index of the innermost range of the chain strictly containing the selection, the outermost
+/// one if none does. Servers may start chains with a range smaller than the selection, e.g. the
+/// word at the cursor, which would shrink it rather than expand it.
+fn first_expansion(chain: &[KakouneRange], selection: &KakouneRange) -> usize {
+    let position = |pos: &KakounePosition| (pos.line, pos.column);
+    chain
+        .iter()
+        .position(|range| {
+            range != selection
+                && position(&range.start) <= position(&selection.start)
+                && position(&range.end) >= position(&selection.end)
+        })
+        .unwrap_or(chain.len().saturating_sub(1))
+}
+
+fn select_command(state: &SelectionRangeState) -> String {
+    format!("select {}", state.selections().iter().join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: (u32, u32), end: (u32, u32)) -> KakouneRange {
+        KakouneRange {
+            start: KakounePosition {
+                line: start.0,
+                column: start.1,
+            },
+            end: KakounePosition {
+                line: end.0,
+                column: end.1,
+            },
+        }
+    }
+
+    #[test]
+    fn expansion_starts_with_range_containing_selection() {
+        let chain = [
+            range((2, 5), (2, 7)),
+            range((2, 1), (2, 20)),
+            range((1, 1), (3, 1)),
+        ];
+        assert_eq!(first_expansion(&chain, &range((2, 5), (2, 5))), 0);
+        assert_eq!(first_expansion(&chain, &range((2, 5), (2, 7))), 1);
+        // The word at the cursor is smaller than the selection.
+        assert_eq!(first_expansion(&chain, &range((2, 3), (2, 10))), 1);
+        assert_eq!(first_expansion(&chain, &range((1, 1), (4, 1))), 2);
+    }
+}
@@ -1,81 +1,259 @@
 use crate::context::Context;
+use crate::language_features::line_cache::{window_lines, LineCache};
 use crate::position::lsp_range_to_kakoune;
 use crate::types::{EditorMeta, EditorParams};
 use crate::util::editor_quote;
-use lsp_types::request::SemanticTokensFullRequest;
+use lsp_types::request::{
+    SemanticTokensFullDeltaRequest, SemanticTokensFullRequest, SemanticTokensRangeRequest,
+};
 use lsp_types::{
-    Position, Range, SemanticToken, SemanticTokensOptions, SemanticTokensParams,
-    SemanticTokensRegistrationOptions, SemanticTokensResult, SemanticTokensServerCapabilities::*,
-    TextDocumentIdentifier,
+    Position, Range, SemanticToken, SemanticTokensDeltaParams, SemanticTokensEdit,
+    SemanticTokensFullDeltaResult, SemanticTokensFullOptions, SemanticTokensLegend,
+    SemanticTokensOptions, SemanticTokensParams, SemanticTokensRangeParams,
+    SemanticTokensRangeResult, SemanticTokensRegistrationOptions, SemanticTokensResult,
+    SemanticTokensServerCapabilities::*, TextDocumentIdentifier,
 };
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use url::Url;
 
-pub fn tokens_request(meta: EditorMeta, _params: EditorParams, ctx: &mut Context) {
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticTokensEditorParams {
+    // Kakoune's %val{window_range}: "<line> <column> <height> <width>", zero-based.
+    #[serde(default)]
+    pub window_range: Option<String>,
+}
+
+/// Rendered semantic tokens of a buffer, grouped by line so that results for a range can
+/// replace the lines they cover.
+#[derive(Default)]
+pub struct SemanticTokensState {
+    version: i32,
+    lines: LineCache<String>,
+    // Last full token array and its id, kept when the server can send deltas against it.
+    result_id: Option<String>,
+    data: Vec<SemanticToken>,
+}
+
+fn server_options(ctx: &Context) -> Option<&SemanticTokensOptions> {
+    match ctx.capabilities.as_ref()?.semantic_tokens_provider {
+        Some(SemanticTokensOptions(ref options))
+        | Some(SemanticTokensRegistrationOptions(SemanticTokensRegistrationOptions {
+            semantic_tokens_options: ref options,
+            ..
+        })) => Some(options),
+        None => None,
+    }
+}
+
+pub fn tokens_request(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = SemanticTokensEditorParams::deserialize(params).unwrap();
+    let (supports_range, supports_delta) = match server_options(ctx) {
+        Some(options) => (
+            options.range.unwrap_or(false),
+            matches!(
+                options.full,
+                Some(SemanticTokensFullOptions::Delta { delta: Some(true) })
+            ),
+        ),
+        None => return,
+    };
+    let text_document = TextDocumentIdentifier {
+        uri: Url::from_file_path(&meta.buffile).unwrap(),
+    };
+    // Deltas are cheaper than ranged requests, but they need full results to apply to.
+    if supports_delta {
+        let previous_result_id = ctx
+            .semantic_tokens
+            .get(&meta.buffile)
+            .and_then(|state| state.result_id.clone());
+        match previous_result_id {
+            Some(previous_result_id) => {
+                let req_params = SemanticTokensDeltaParams {
+                    partial_result_params: Default::default(),
+                    text_document,
+                    work_done_progress_params: Default::default(),
+                    previous_result_id,
+                };
+                ctx.call::<SemanticTokensFullDeltaRequest, _>(
+                    meta,
+                    req_params,
+                    move |ctx, meta, response| match response {
+                        Some(response) => tokens_delta_response(meta, response, ctx),
+                        None => tokens_delta_failed(meta, ctx),
+                    },
+                );
+            }
+            None => full_request(meta, text_document, ctx),
+        }
+        return;
+    }
+    let lines = params
+        .window_range
+        .as_deref()
+        .filter(|_| supports_range)
+        .and_then(window_lines);
+    match lines {
+        Some((start, end)) => {
+            let req_params = SemanticTokensRangeParams {
+                partial_result_params: Default::default(),
+                text_document,
+                work_done_progress_params: Default::default(),
+                range: Range {
+                    start: Position::new(start, 0),
+                    end: Position::new(end, 0),
+                },
+            };
+            ctx.call::<SemanticTokensRangeRequest, _>(
+                meta,
+                req_params,
+                move |ctx, meta, response| {
+                    if let Some(response) = response {
+                        let tokens = match response {
+                            SemanticTokensRangeResult::Tokens(tokens) => tokens.data,
+                            SemanticTokensRangeResult::Partial(partial) => partial.data,
+                        };
+                        tokens_response(meta, tokens, lines, ctx);
+                    }
+                },
+            );
+        }
+        None => full_request(meta, text_document, ctx),
+    }
+}
+
+/// Handle `workspace/semanticTokens/refresh` by requesting tokens again for all buffers
+/// showing them. Full results are requested as the server might have dropped its previous ones.
+pub fn refresh_all_tokens(ctx: &mut Context) {
+    let buffiles = ctx.semantic_tokens.keys().cloned().collect::<Vec<_>>();
+    for buffile in buffiles {
+        if let Some(meta) = ctx.meta_for_buffer(buffile) {
+            let text_document = TextDocumentIdentifier {
+                uri: Url::from_file_path(&meta.buffile).unwrap(),
+            };
+            full_request(meta, text_document, ctx);
+        }
+    }
+}
+
+fn full_request(meta: EditorMeta, text_document: TextDocumentIdentifier, ctx: &mut Context) {
     let req_params = SemanticTokensParams {
         partial_result_params: Default::default(),
-        text_document: TextDocumentIdentifier {
-            uri: Url::from_file_path(&meta.buffile).unwrap(),
-        },
+        text_document,
         work_done_progress_params: Default::default(),
     };
     ctx.call::<SemanticTokensFullRequest, _>(meta, req_params, move |ctx, meta, response| {
         if let Some(response) = response {
-            tokens_response(meta, response, ctx);
+            match response {
+                SemanticTokensResult::Tokens(tokens) => {
+                    cache_tokens(&meta, tokens.result_id, &tokens.data, ctx);
+                    tokens_response(meta, tokens.data, None, ctx);
+                }
+                SemanticTokensResult::Partial(partial) => {
+                    tokens_response(meta, partial.data, None, ctx)
+                }
+            }
         }
     });
 }
 
-pub fn tokens_response(meta: EditorMeta, tokens: SemanticTokensResult, ctx: &mut Context) {
-    let legend = match ctx.capabilities.as_ref().unwrap().semantic_tokens_provider {
-        Some(SemanticTokensOptions(SemanticTokensOptions { ref legend, .. }))
-        | Some(SemanticTokensRegistrationOptions(SemanticTokensRegistrationOptions {
-            semantic_tokens_options: SemanticTokensOptions { ref legend, .. },
-            ..
-        })) => legend,
-        None => return,
+fn tokens_delta_response(
+    meta: EditorMeta,
+    response: SemanticTokensFullDeltaResult,
+    ctx: &mut Context,
+) {
+    let (result_id, edits) = match response {
+        // Servers are free to answer with a full result instead.
+        SemanticTokensFullDeltaResult::Tokens(tokens) => {
+            cache_tokens(&meta, tokens.result_id, &tokens.data, ctx);
+            tokens_response(meta, tokens.data, None, ctx);
+            return;
+        }
+        SemanticTokensFullDeltaResult::TokensDelta(delta) => (delta.result_id, delta.edits),
+        SemanticTokensFullDeltaResult::PartialTokensDelta { edits } => (None, edits),
+    };
+    let state = ctx.semantic_tokens.entry(meta.buffile.clone()).or_default();
+    state.data = apply_delta(&state.data, edits);
+    state.result_id = result_id;
+    let tokens = state.data.clone();
+    tokens_response(meta, tokens, None, ctx);
+}
+
+/// Forget the tokens a delta couldn't be computed against, e.g. because the server dropped them,
+/// and request full tokens instead.
+pub fn tokens_delta_failed(meta: EditorMeta, ctx: &mut Context) {
+    if let Some(state) = ctx.semantic_tokens.get_mut(&meta.buffile) {
+        state.result_id = None;
+        state.data.clear();
+    }
+    let text_document = TextDocumentIdentifier {
+        uri: Url::from_file_path(&meta.buffile).unwrap(),
+    };
+    full_request(meta, text_document, ctx);
+}
+
+fn cache_tokens(
+    meta: &EditorMeta,
+    result_id: Option<String>,
+    tokens: &[SemanticToken],
+    ctx: &mut Context,
+) {
+    let state = ctx.semantic_tokens.entry(meta.buffile.clone()).or_default();
+    state.data = match result_id {
+        Some(_) => tokens.to_vec(),
+        None => Vec::new(),
     };
-    let document = match ctx.documents.get(&meta.buffile) {
-        Some(document) => document,
+    state.result_id = result_id;
+}
+
+/// Apply delta edits to a token array. Edit offsets count integers of the encoded array, five per
+/// token, and all refer to the array before any of them is applied.
+fn apply_delta(tokens: &[SemanticToken], mut edits: Vec<SemanticTokensEdit>) -> Vec<SemanticToken> {
+    edits.sort_by_key(|edit| edit.start);
+    let mut result = Vec::with_capacity(tokens.len());
+    let mut index = 0;
+    for edit in edits {
+        let start = (edit.start / 5) as usize;
+        let end = ((edit.start + edit.delete_count) / 5) as usize;
+        result.extend_from_slice(&tokens[index.min(tokens.len())..start.min(tokens.len())]);
+        result.extend(edit.data.unwrap_or_default());
+        index = end;
+    }
+    result.extend_from_slice(&tokens[index.min(tokens.len())..]);
+    result
+}
+
+/// Render tokens and merge them into the buffer's highlighting. Tokens for `lines`, end exclusive,
+/// replace whatever was previously shown on them, otherwise they replace everything.
+pub fn tokens_response(
+    meta: EditorMeta,
+    tokens: Vec<SemanticToken>,
+    lines: Option<(u32, u32)>,
+    ctx: &mut Context,
+) {
+    let legend = match server_options(ctx) {
+        Some(SemanticTokensOptions { legend, .. }) => legend,
         None => return,
     };
-    let tokens = match tokens {
-        SemanticTokensResult::Tokens(tokens) => tokens.data,
-        SemanticTokensResult::Partial(partial) => partial.data,
+    let rendered = match render_tokens(&meta, tokens, legend, ctx) {
+        Some(rendered) => rendered,
+        None => return,
     };
-    let mut line = 0;
-    let mut start = 0;
-    let ranges = tokens
-        .into_iter()
-        .filter_map(
-            |SemanticToken {
-                 delta_line,
-                 delta_start,
-                 length,
-                 token_type,
-                 token_modifiers_bitset,
-             }| {
-                if delta_line != 0 {
-                    line += delta_line;
-                    start = delta_start;
-                } else {
-                    start += delta_start;
-                }
-                let range = Range {
-                    start: Position::new(line.into(), start.into()),
-                    end: Position::new(line.into(), (start + length).into()),
-                };
-                let range = lsp_range_to_kakoune(&range, &document.text, ctx.offset_encoding);
-                let token = &legend.token_types[token_type as usize];
-                (0..32)
-                    .filter(|bit| ((token_modifiers_bitset >> bit) & 1u32) == 1u32)
-                    .map(|bit| &legend.token_modifiers[bit as usize])
-                    .filter_map(|token| ctx.config.semantic_token_modifiers.get(token.as_str()))
-                    .chain(ctx.config.semantic_tokens.get(token.as_str()))
-                    .next()
-                    .map(|face| format!("{}|{}", range, face))
-            },
-        )
-        .collect::<Vec<String>>()
+    let state = ctx.semantic_tokens.entry(meta.buffile.clone()).or_default();
+    match lines {
+        // Tokens of an older version are misplaced, so only keep them if we can replace them all.
+        Some(lines) if state.version == meta.version => state.lines.replace(lines, rendered),
+        _ => {
+            state.version = meta.version;
+            state.lines.replace_all(rendered);
+        }
+    }
+    let ranges = state
+        .lines
+        .values()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
         .join(" ");
     let command = format!(
         "set buffer lsp_semantic_tokens {} {}",
@@ -88,3 +266,49 @@ pub fn tokens_response(meta: EditorMeta, tokens: SemanticTokensResult, ctx: &mut
     );
     ctx.exec(meta, command)
 }
+
+fn render_tokens(
+    meta: &EditorMeta,
+    tokens: Vec<SemanticToken>,
+    legend: &SemanticTokensLegend,
+    ctx: &Context,
+) -> Option<BTreeMap<u32, Vec<String>>> {
+    let document = ctx.documents.get(&meta.buffile)?;
+    let mut lines: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    let mut line = 0;
+    let mut start = 0;
+    for SemanticToken {
+        delta_line,
+        delta_start,
+        length,
+        token_type,
+        token_modifiers_bitset,
+    } in tokens
+    {
+        if delta_line != 0 {
+            line += delta_line;
+            start = delta_start;
+        } else {
+            start += delta_start;
+        }
+        let range = Range {
+            start: Position::new(line, start),
+            end: Position::new(line, start + length),
+        };
+        let range = lsp_range_to_kakoune(&range, &document.text, ctx.offset_encoding);
+        let token = &legend.token_types[token_type as usize];
+        let face = (0..32)
+            .filter(|bit| ((token_modifiers_bitset >> bit) & 1u32) == 1u32)
+            .map(|bit| &legend.token_modifiers[bit as usize])
+            .filter_map(|token| ctx.config.semantic_token_modifiers.get(token.as_str()))
+            .chain(ctx.config.semantic_tokens.get(token.as_str()))
+            .next();
+        if let Some(face) = face {
+            lines
+                .entry(line)
+                .or_default()
+                .push(format!("{}|{}", range, face));
+        }
+    }
+    Some(lines)
+}
@@ -1,4 +1,5 @@
 use crate::context::*;
+use crate::markup;
 use crate::types::*;
 use crate::util::*;
 use lsp_types::request::*;
@@ -6,10 +7,72 @@ use lsp_types::*;
 use serde::Deserialize;
 use url::Url;
 
+/// Last signature help shown to the user, kept to cycle through overloads without asking
+/// the server again.
+pub struct SignatureHelpState {
+    buffile: String,
+    position: KakounePosition,
+    help: SignatureHelp,
+    active_signature: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SignatureHelpCycleParams {
+    // Number of signatures to move by, negative to go backwards.
+    pub offset: i64,
+}
+
 pub fn text_document_signature_help(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = PositionParams::deserialize(params).unwrap();
+    request_signature_help(meta, params, None, ctx);
+}
+
+/// Show signature help again if the character just typed is a trigger one, e.g. `(` or `,`.
+pub fn signature_help_retrigger(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PositionParams::deserialize(params).unwrap();
+    let options = match &ctx.capabilities.as_ref().unwrap().signature_help_provider {
+        Some(options) => options,
+        None => return,
+    };
+    let typed = match ctx.typed_text.get(&meta.buffile) {
+        Some(typed) if typed.version == meta.version => typed,
+        _ => return,
+    };
+    let ch = match typed.text.chars().last() {
+        Some(ch) => ch.to_string(),
+        None => return,
+    };
+    let is_one_of = |chars: &Option<Vec<String>>| chars.as_ref().map_or(false, |c| c.contains(&ch));
+    // Signature help is considered to be still displayed if the cursor hasn't left the line.
+    let active_signature_help = ctx.signature_help.as_ref().and_then(|state| {
+        if state.buffile == meta.buffile && state.position.line == params.position.line {
+            Some(state.help.clone())
+        } else {
+            None
+        }
+    });
+    let is_trigger = is_one_of(&options.trigger_characters);
+    let is_retrigger = active_signature_help.is_some() && is_one_of(&options.retrigger_characters);
+    if !is_trigger && !is_retrigger {
+        return;
+    }
+    let context = SignatureHelpContext {
+        trigger_kind: SignatureHelpTriggerKind::TriggerCharacter,
+        trigger_character: Some(ch),
+        is_retrigger: active_signature_help.is_some(),
+        active_signature_help,
+    };
+    request_signature_help(meta, params, Some(context), ctx);
+}
+
+fn request_signature_help(
+    meta: EditorMeta,
+    params: PositionParams,
+    context: Option<SignatureHelpContext>,
+    ctx: &mut Context,
+) {
     let req_params = SignatureHelpParams {
-        context: None,
+        context,
         text_document_position_params: TextDocumentPositionParams {
             text_document: TextDocumentIdentifier {
                 uri: Url::from_file_path(&meta.buffile).unwrap(),
@@ -32,17 +95,134 @@ pub fn editor_signature_help(
     ctx: &mut Context,
 ) {
     if let Some(result) = result {
-        let active_signature = result.active_signature.unwrap_or(0);
-        if let Some(active_signature) = result.signatures.get(active_signature as usize) {
-            // TODO decide how to use it
-            // let active_parameter = result.active_parameter.unwrap_or(0);
-            let contents = &active_signature.label;
-            let command = format!(
-                "lsp-show-signature-help {} {}",
-                params.position,
-                editor_quote(&contents)
-            );
-            ctx.exec(meta, command);
+        if result.signatures.is_empty() {
+            ctx.signature_help = None;
+            return;
+        }
+        let active_signature = result.active_signature.map_or(0, |index| index as usize);
+        let active_signature = active_signature.min(result.signatures.len() - 1);
+        let state = SignatureHelpState {
+            buffile: meta.buffile.clone(),
+            position: params.position,
+            help: result,
+            active_signature,
+        };
+        show_signature_help(meta, &state, ctx);
+        ctx.signature_help = Some(state);
+    }
+}
+
+/// Show the previous or next signature of the last signature help.
+pub fn signature_help_cycle(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = SignatureHelpCycleParams::deserialize(params).unwrap();
+    let mut state = match ctx.signature_help.take() {
+        Some(state) => state,
+        None => return,
+    };
+    let count = state.help.signatures.len() as i64;
+    state.active_signature =
+        (state.active_signature as i64 + params.offset).rem_euclid(count) as usize;
+    show_signature_help(meta, &state, ctx);
+    ctx.signature_help = Some(state);
+}
+
+fn show_signature_help(meta: EditorMeta, state: &SignatureHelpState, ctx: &Context) {
+    let help = &state.help;
+    let signature = &help.signatures[state.active_signature];
+    // Signature's own active parameter takes precedence, the global one only applies to
+    // the active signature.
+    let server_active_signature = help.active_signature.map_or(0, |index| index as usize);
+    let active_parameter = signature.active_parameter.or_else(|| {
+        if state.active_signature == server_active_signature {
+            help.active_parameter
+        } else {
+            None
+        }
+    });
+    let parameter_range = active_parameter
+        .and_then(|index| signature.parameters.as_ref()?.get(index as usize))
+        .and_then(|parameter| parameter_byte_range(&signature.label, &parameter.label, ctx));
+    let mut contents = match parameter_range {
+        Some((start, end)) => format!(
+            "{}{{SignatureHelpActiveParameter}}{}{{Default}}{}",
+            markup::escape(&signature.label[..start]),
+            markup::escape(&signature.label[start..end]),
+            markup::escape(&signature.label[end..])
+        ),
+        None => markup::escape(&signature.label),
+    };
+    if help.signatures.len() > 1 {
+        contents = format!(
+            "{} ({}/{})",
+            contents,
+            state.active_signature + 1,
+            help.signatures.len()
+        );
+    }
+    let command = format!(
+        "lsp-show-signature-help {} {}",
+        state.position,
+        editor_quote(&contents)
+    );
+    ctx.exec(meta, command);
+}
+
+/// Byte range of the parameter within the signature label.
+fn parameter_byte_range(
+    signature: &str,
+    parameter: &ParameterLabel,
+    ctx: &Context,
+) -> Option<(usize, usize)> {
+    match parameter {
+        ParameterLabel::Simple(label) if !label.is_empty() => {
+            // Skip the function name which might contain parameter label as a substring.
+            let params_start = signature.find('(').unwrap_or(0);
+            let start = params_start + signature[params_start..].find(label.as_str())?;
+            Some((start, start + label.len()))
+        }
+        ParameterLabel::Simple(_) => None,
+        ParameterLabel::LabelOffsets([start, end]) => {
+            let start: u64 = (*start).into();
+            let end: u64 = (*end).into();
+            let start = offset_to_byte(signature, start as usize, ctx.offset_encoding)?;
+            let end = offset_to_byte(signature, end as usize, ctx.offset_encoding)?;
+            if start <= end {
+                Some((start, end))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Convert an offset in the label counted according to the offset encoding into byte offset.
+fn offset_to_byte(text: &str, offset: usize, offset_encoding: OffsetEncoding) -> Option<usize> {
+    match offset_encoding {
+        OffsetEncoding::Utf8 => {
+            if text.is_char_boundary(offset) {
+                Some(offset)
+            } else {
+                None
+            }
+        }
+        OffsetEncoding::Utf16 => {
+            let mut units = 0;
+            for (byte, c) in text.char_indices() {
+                if units == offset {
+                    return Some(byte);
+                }
+                units += c.len_utf16();
+            }
+            if units == offset {
+                Some(text.len())
+            } else {
+                None
+            }
         }
+        OffsetEncoding::Utf32 => text
+            .char_indices()
+            .map(|(byte, _)| byte)
+            .chain(std::iter::once(text.len()))
+            .nth(offset),
     }
 }
@@ -0,0 +1,197 @@
+use crate::context::Context;
+use crate::language_features::hierarchy;
+use crate::types::{EditorMeta, EditorParams, KakounePosition, PreparedHierarchyItem};
+use crate::util::get_lsp_position;
+use lsp_types::request::Request;
+use lsp_types::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
+
+// Type hierarchy is introduced in LSP 3.17 which is not covered by lsp-types yet.
+
+pub enum TypeHierarchyPrepare {}
+
+impl Request for TypeHierarchyPrepare {
+    type Params = TypeHierarchyPrepareParams;
+    type Result = Option<Vec<TypeHierarchyItem>>;
+    const METHOD: &'static str = "textDocument/prepareTypeHierarchy";
+}
+
+pub enum TypeHierarchySupertypes {}
+
+impl Request for TypeHierarchySupertypes {
+    type Params = TypeHierarchySupertypesParams;
+    type Result = Option<Vec<TypeHierarchyItem>>;
+    const METHOD: &'static str = "typeHierarchy/supertypes";
+}
+
+pub enum TypeHierarchySubtypes {}
+
+impl Request for TypeHierarchySubtypes {
+    type Params = TypeHierarchySubtypesParams;
+    type Result = Option<Vec<TypeHierarchyItem>>;
+    const METHOD: &'static str = "typeHierarchy/subtypes";
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TypeHierarchyPrepareParams {
+    #[serde(flatten)]
+    pub text_document_position_params: TextDocumentPositionParams,
+    #[serde(flatten)]
+    pub work_done_progress_params: WorkDoneProgressParams,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TypeHierarchySupertypesParams {
+    pub item: TypeHierarchyItem,
+    #[serde(flatten)]
+    pub work_done_progress_params: WorkDoneProgressParams,
+    #[serde(flatten)]
+    pub partial_result_params: PartialResultParams,
+}
+
+pub type TypeHierarchySubtypesParams = TypeHierarchySupertypesParams;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TypeHierarchyItem {
+    pub name: String,
+    pub kind: SymbolKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<SymbolTag>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub uri: Url,
+    pub range: Range,
+    pub selection_range: Range,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+/// Client capabilities for type hierarchy, to be merged into the ones built with lsp-types.
+pub fn client_capabilities() -> Value {
+    serde_json::json!({
+        "textDocument": {
+            "typeHierarchy": {"dynamicRegistration": false},
+        },
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TypeHierarchyParams {
+    pub position: KakounePosition,
+    // JSON encoded TypeHierarchyItem picked by the user from a menu, empty otherwise.
+    pub item: String,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TypeDirection {
+    Supertypes,
+    Subtypes,
+}
+
+impl TypeDirection {
+    fn method(self) -> &'static str {
+        match self {
+            TypeDirection::Supertypes => TypeHierarchySupertypes::METHOD,
+            TypeDirection::Subtypes => TypeHierarchySubtypes::METHOD,
+        }
+    }
+}
+
+pub fn type_hierarchy(
+    meta: EditorMeta,
+    params: EditorParams,
+    direction: TypeDirection,
+    ctx: &mut Context,
+) {
+    let params = TypeHierarchyParams::deserialize(params).unwrap();
+    if !params.item.is_empty() {
+        let item: TypeHierarchyItem = serde_json::from_str(&params.item).unwrap();
+        ctx.type_hierarchy_item = Some(PreparedHierarchyItem::new(
+            &meta,
+            params.position,
+            item.clone(),
+        ));
+        types(meta, item, direction, ctx);
+        return;
+    }
+    if let Some(item) = hierarchy::prepared_item(&ctx.type_hierarchy_item, &meta, &params.position)
+    {
+        types(meta, item, direction, ctx);
+        return;
+    }
+    let position = params.position;
+    let req_params = TypeHierarchyPrepareParams {
+        text_document_position_params: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(&meta.buffile).unwrap(),
+            },
+            position: get_lsp_position(&meta.buffile, &position, ctx).unwrap(),
+        },
+        work_done_progress_params: Default::default(),
+    };
+    ctx.call::<TypeHierarchyPrepare, _>(
+        meta,
+        req_params,
+        move |ctx: &mut Context, meta, result| {
+            let item = hierarchy::single_item(
+                &meta,
+                result.unwrap_or_default(),
+                "No type hierarchy item at the cursor position",
+                "lsp-type-hierarchy-request",
+                direction.method(),
+                ctx,
+            );
+            if let Some(item) = item {
+                ctx.type_hierarchy_item =
+                    Some(PreparedHierarchyItem::new(&meta, position, item.clone()));
+                types(meta, item, direction, ctx);
+            }
+        },
+    );
+}
+
+fn types(meta: EditorMeta, item: TypeHierarchyItem, direction: TypeDirection, ctx: &mut Context) {
+    let req_params = TypeHierarchySupertypesParams {
+        item,
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    match direction {
+        TypeDirection::Supertypes => ctx.call::<TypeHierarchySupertypes, _>(
+            meta,
+            req_params,
+            move |ctx: &mut Context, meta, result| {
+                show_types(meta, result, "No supertypes found", ctx)
+            },
+        ),
+        TypeDirection::Subtypes => ctx.call::<TypeHierarchySubtypes, _>(
+            meta,
+            req_params,
+            move |ctx: &mut Context, meta, result| {
+                show_types(meta, result, "No subtypes found", ctx)
+            },
+        ),
+    }
+}
+
+fn show_types(
+    meta: EditorMeta,
+    result: Option<Vec<TypeHierarchyItem>>,
+    empty_msg: &str,
+    ctx: &mut Context,
+) {
+    let locations = result
+        .unwrap_or_default()
+        .into_iter()
+        .map(|item| Location {
+            uri: item.uri,
+            range: item.selection_range,
+        })
+        .collect();
+    hierarchy::show_locations(meta, locations, empty_msg, ctx);
+}
@@ -4,8 +4,13 @@ use crossbeam_channel::{Receiver, Sender, TryRecvError};
 use jsonrpc_core::{self, Call, Output};
 use serde_json;
 use std::collections::HashMap;
+use std::env;
 use std::io::{self, BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::net::{self, TcpStream};
 use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 pub struct LanguageServerTransport {
     // The field order is important as it defines the order of drop.
@@ -17,10 +22,146 @@ pub struct LanguageServerTransport {
     pub errors: Worker<Void, Void>,
 }
 
-pub fn start(cmd: &str, args: &[String]) -> Result<LanguageServerTransport, String> {
+/// Start the language server for the project, or connect to it if it's already running. Once told
+/// to exit, a server started by us is killed if it doesn't do so within the shutdown timeout.
+pub fn start(
+    lang: &LanguageConfig,
+    root_path: &str,
+    shutdown_timeout: Duration,
+) -> Result<LanguageServerTransport, String> {
+    match lang.transport {
+        Transport::Stdio => {
+            let cmd = expand_placeholders(&lang.command, root_path);
+            let args = lang
+                .args
+                .iter()
+                .map(|arg| expand_placeholders(arg, root_path))
+                .collect::<Vec<_>>();
+            let env = lang
+                .env
+                .iter()
+                .map(|(name, value)| {
+                    let value = expand_placeholders(&expand_env_vars(value), root_path);
+                    (name.clone(), value)
+                })
+                .collect::<HashMap<_, _>>();
+            spawn(&cmd, &args, &env, shutdown_timeout)
+        }
+        Transport::Tcp => {
+            let host = lang.host.as_deref().unwrap_or("127.0.0.1");
+            match lang.port {
+                Some(port) => connect(host, port),
+                None => Err("port of the language server is required for TCP transport".into()),
+            }
+        }
+    }
+}
+
+/// Substitute placeholders in the server command or argument:
+/// * `{project_root}` and `{workspace_folder}` with the project root;
+/// * `{env:NAME}` with the value of the environment variable, empty if it's not set.
+///
+/// Doubled braces around a placeholder, e.g. `{{project_root}}`, stand for it literally. Unknown
+/// placeholders and any other braces, e.g. of JSON, are left intact.
+fn expand_placeholders(template: &str, root_path: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(i) = rest.find('{') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(name) = rest
+            .strip_prefix("{{")
+            .and_then(|rest| placeholder_name(rest, "}}"))
+        {
+            expanded.push_str(&format!("{{{}}}", name));
+            rest = &rest[name.len() + 4..];
+            continue;
+        }
+        let name = match placeholder_name(&rest[1..], "}") {
+            Some(name) => name,
+            None => {
+                expanded.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        match name {
+            "project_root" | "workspace_folder" => expanded.push_str(root_path),
+            _ if name.starts_with("env:") => {
+                expanded.push_str(&env::var(&name[4..]).unwrap_or_default());
+            }
+            _ => {
+                warn!("Unknown placeholder {{{}}} in `{}`", name, template);
+                expanded.push_str(&format!("{{{}}}", name));
+            }
+        }
+        rest = &rest[name.len() + 2..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Name of the placeholder the text starts with, up to the closing delimiter.
+fn placeholder_name<'a>(text: &'a str, close: &str) -> Option<&'a str> {
+    let end = text.find(close)?;
+    let name = &text[..end];
+    let is_placeholder = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+    if is_placeholder {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// Substitute `$NAME` and `${NAME}` with values of environment variables, empty if not set.
+fn expand_env_vars(value: &str) -> String {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let (name, len) = if rest.starts_with('{') {
+            match rest.find('}') {
+                Some(end) => (&rest[1..end], end + 1),
+                None => ("", 0),
+            }
+        } else {
+            let end = rest
+                .find(|c| !is_name_char(c))
+                .unwrap_or_else(|| rest.len());
+            (&rest[..end], end)
+        };
+        if name.is_empty() {
+            expanded.push('$');
+            continue;
+        }
+        expanded.push_str(&env::var(name).unwrap_or_default());
+        rest = &rest[len..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Command to start the server with, its environment inherited from kak-lsp with variables of
+/// `env` added or replaced.
+fn command(cmd: &str, args: &[String], env: &HashMap<String, String>) -> Command {
+    let mut command = Command::new(cmd);
+    command.args(args).envs(env);
+    command
+}
+
+fn spawn(
+    cmd: &str,
+    args: &[String],
+    env: &HashMap<String, String>,
+    shutdown_timeout: Duration,
+) -> Result<LanguageServerTransport, String> {
     info!("Starting Language server `{} {}`", cmd, args.join(" "));
-    let mut child = match Command::new(cmd)
-        .args(args)
+    let mut child = match command(cmd, args, env)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -68,15 +209,7 @@ pub fn start(cmd: &str, args: &[String]) -> Result<LanguageServerTransport, Stri
     );
     // XXX
 
-    let from_lang_server = Worker::spawn(
-        "Messages from language server",
-        channel_capacity,
-        move |receiver, sender| {
-            if let Err(msg) = reader_loop(reader, receiver, &sender) {
-                error!("{}", msg);
-            }
-        },
-    );
+    let from_lang_server = reader_worker(reader, channel_capacity);
 
     let to_lang_server = Worker::spawn(
         "Messages to language server",
@@ -90,22 +223,27 @@ pub fn start(cmd: &str, args: &[String]) -> Result<LanguageServerTransport, Stri
             drop(child.stdin.take());
             drop(child.stdout.take());
             drop(child.stderr.take());
-            std::thread::sleep(std::time::Duration::from_secs(1));
-            match child.try_wait() {
-                Ok(None) => {
-                    std::thread::sleep(std::time::Duration::from_secs(1));
-                    match child.try_wait() {
-                        Ok(None) => {
-                            // Okay, we asked politely enough and waited long enough.
-                            child.kill().unwrap();
+            let deadline = Instant::now() + shutdown_timeout;
+            loop {
+                match child.try_wait() {
+                    Ok(Some(_)) => break,
+                    Ok(None) if Instant::now() < deadline => {
+                        std::thread::sleep(EXIT_POLL_INTERVAL);
+                    }
+                    Ok(None) => {
+                        // Okay, we asked politely enough and waited long enough.
+                        warn!("Language server didn't exit in time, killing it");
+                        if let Err(e) = child.kill() {
+                            error!("Failed to kill language server: {}", e);
                         }
-                        _ => {}
+                        let _ = child.wait();
+                        break;
+                    }
+                    Err(_) => {
+                        error!("Language server wasn't running was it?!");
+                        break;
                     }
                 }
-                Err(_) => {
-                    error!("Language server wasn't running was it?!");
-                }
-                _ => {}
             }
         },
     );
@@ -117,6 +255,57 @@ pub fn start(cmd: &str, args: &[String]) -> Result<LanguageServerTransport, Stri
     })
 }
 
+fn connect(host: &str, port: u16) -> Result<LanguageServerTransport, String> {
+    info!("Connecting to language server at {}:{}", host, port);
+    let failed = |err: io::Error| format!("cannot connect to {}:{}: {}", host, port, err);
+    let stream = TcpStream::connect((host, port)).map_err(failed)?;
+    let reader = BufReader::new(stream.try_clone().map_err(failed)?);
+    let writer = BufWriter::new(stream.try_clone().map_err(failed)?);
+
+    // NOTE 1024 is arbitrary
+    let channel_capacity = 1024;
+
+    // Only a process we started has stderr to trace.
+    let errors = Worker::spawn("Language server errors", channel_capacity, |_, _| {});
+
+    let from_lang_server = reader_worker(reader, channel_capacity);
+
+    let to_lang_server = Worker::spawn(
+        "Messages to language server",
+        channel_capacity,
+        move |receiver, _| {
+            if writer_loop(writer, &receiver).is_err() {
+                error!("Failed to write message to language server");
+            }
+            // The server keeps running, just stop reading from it.
+            if let Err(e) = stream.shutdown(net::Shutdown::Both) {
+                error!("Failed to close connection to language server: {}", e);
+            }
+        },
+    );
+
+    Ok(LanguageServerTransport {
+        from_lang_server,
+        to_lang_server,
+        errors,
+    })
+}
+
+fn reader_worker(
+    reader: impl BufRead + Send + 'static,
+    channel_capacity: usize,
+) -> Worker<Void, ServerMessage> {
+    Worker::spawn(
+        "Messages from language server",
+        channel_capacity,
+        move |receiver, sender| {
+            if let Err(msg) = reader_loop(reader, receiver, &sender) {
+                error!("{}", msg);
+            }
+        },
+    )
+}
+
 fn reader_loop(
     mut reader: impl BufRead,
     receiver: Receiver<Void>,
@@ -194,3 +383,64 @@ fn writer_loop(mut writer: impl Write, receiver: &Receiver<ServerMessage>) -> io
     debug!("Received signal to stop language server, closing pipe");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_root_placeholder() {
+        assert_eq!(
+            expand_placeholders("--root={project_root}", "/home/user/project"),
+            "--root=/home/user/project"
+        );
+        assert_eq!(
+            expand_placeholders("{workspace_folder}/.cache", "/project"),
+            "/project/.cache"
+        );
+    }
+
+    #[test]
+    fn env_is_added_to_inherited_one() {
+        let mut env = HashMap::new();
+        env.insert(
+            "KAK_LSP_TEST_VAR".to_string(),
+            expand_env_vars("${PATH}:$HOME/bin"),
+        );
+        let args = vec![
+            "-c".to_string(),
+            "printf '%s|%s' \"$KAK_LSP_TEST_VAR\" \"$PATH\"".to_string(),
+        ];
+        let output = command("sh", &args, &env).output().unwrap();
+        let path = env::var("PATH").unwrap_or_default();
+        let home = env::var("HOME").unwrap_or_default();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!("{}:{}/bin|{}", path, home, path)
+        );
+    }
+
+    #[test]
+    fn unknown_placeholders_and_escapes_are_kept() {
+        assert_eq!(
+            expand_placeholders("{unknown} {{project_root}}", "/project"),
+            "{unknown} {project_root}"
+        );
+        assert_eq!(
+            expand_placeholders(r#"--init={"a": 1}"#, "/project"),
+            r#"--init={"a": 1}"#
+        );
+        assert_eq!(
+            expand_placeholders(r#"--init={"a":{"b":1}}"#, "/project"),
+            r#"--init={"a":{"b":1}}"#
+        );
+        assert_eq!(
+            expand_placeholders("{{project_root}} }}", "/project"),
+            "{project_root} }}"
+        );
+        assert_eq!(
+            expand_placeholders("{env:KAK_LSP_TEST_UNSET_VARIABLE}x", "/project"),
+            "x"
+        );
+    }
+}
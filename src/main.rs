@@ -10,12 +10,18 @@ mod context;
 mod controller;
 mod diagnostics;
 mod editor_transport;
+mod fan_out;
+mod file_watcher;
 mod general;
 mod language_features;
 mod language_server_transport;
+mod markup;
 mod position;
+mod progress;
 mod project_root;
 mod session;
+mod settings;
+mod snippet;
 mod text_edit;
 mod text_sync;
 mod thread_worker;
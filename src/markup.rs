@@ -0,0 +1,324 @@
+//! Helpers to build Kakoune markup strings, i.e. text annotated with `{face}` specs as understood
+//! by `info -markup`, `echo -markup` and `replace-ranges` highlighters.
+
+use unicode_width::UnicodeWidthStr;
+
+/// Markup displaying the whole text literally with the given face.
+/// `{\}` disables markup parsing for the rest of the string so text doesn't need escaping.
+pub fn literal(face: &str, text: &str) -> String {
+    format!("{{{}}}{{\\}}{}", face, text)
+}
+
+/// Escape text so that it's displayed as is when embedded in markup.
+pub fn escape(text: &str) -> String {
+    text.replace("\\", "\\\\").replace("{", "\\{")
+}
+
+/// Face spec for a color given as red, green and blue components in range [0, 1].
+pub fn rgb_face(red: f32, green: f32, blue: f32) -> String {
+    let component = |x: f32| (x.max(0.0).min(1.0) * 255.0).round() as u8;
+    format!(
+        "rgb:{:02x}{:02x}{:02x}",
+        component(red),
+        component(green),
+        component(blue)
+    )
+}
+
+/// Convert markdown, as used in hover info, into markup for an info box.
+/// * Code blocks are shown without fences using the `LspCodeBlock` face, keeping indentation.
+/// * Tables are aligned into columns.
+/// * List items are indented according to their nesting, bullets using the `bullet` face.
+/// Everything else is kept as is.
+pub fn from_markdown(markdown: &str) -> String {
+    let mut lines = vec![];
+    let mut table: Vec<&str> = vec![];
+    let mut list_indents: Vec<usize> = vec![];
+    let mut code_fence: Option<CodeFence> = None;
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        if let Some(fence) = &code_fence {
+            if fence.is_closed_by(line) {
+                code_fence = None;
+            } else {
+                lines.push(format!(
+                    "{{LspCodeBlock}}{}{{Information}}",
+                    escape(fence.content(line))
+                ));
+            }
+            continue;
+        }
+        if let Some(fence) = CodeFence::open(line) {
+            code_fence = Some(fence);
+            continue;
+        }
+        if trimmed.starts_with('|') {
+            table.push(trimmed);
+            continue;
+        }
+        if !table.is_empty() {
+            lines.extend(format_table(&table));
+            table.clear();
+        }
+        match list_item(line) {
+            Some((indent, bullet, text)) => {
+                // Items indented deeper than the previous one are nested into it.
+                while list_indents.last().map_or(false, |last| *last > indent) {
+                    list_indents.pop();
+                }
+                if list_indents.last() != Some(&indent) {
+                    list_indents.push(indent);
+                }
+                let bullet = if bullet.ends_with('.') || bullet.ends_with(')') {
+                    bullet.to_string()
+                } else if list_indents.len() % 2 == 1 {
+                    "•".to_string()
+                } else {
+                    "◦".to_string()
+                };
+                lines.push(format!(
+                    "{}{{bullet}}{}{{Information}} {}",
+                    "  ".repeat(list_indents.len() - 1),
+                    bullet,
+                    escape(text)
+                ));
+            }
+            None => {
+                // Lines indented under a list item continue it, anything else ends the list.
+                if !line.starts_with(' ') || trimmed.is_empty() {
+                    list_indents.clear();
+                }
+                lines.push(escape(line));
+            }
+        }
+    }
+    if !table.is_empty() {
+        lines.extend(format_table(&table));
+    }
+    lines.join("\n")
+}
+
+/// Opening fence of a code block, either backticks or tildes.
+struct CodeFence {
+    marker: char,
+    length: usize,
+    indent: usize,
+}
+
+impl CodeFence {
+    /// Parse the opening fence, the language info string following it is of no use for now.
+    fn open(line: &str) -> Option<Self> {
+        let trimmed = line.trim_start();
+        let marker = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+        let length = trimmed.chars().take_while(|c| *c == marker).count();
+        if length < 3 || (marker == '`' && trimmed[length..].contains('`')) {
+            return None;
+        }
+        Some(CodeFence {
+            marker,
+            length,
+            indent: line.len() - trimmed.len(),
+        })
+    }
+
+    /// Closing fence is made of the same marker at least as long as the opening one.
+    fn is_closed_by(&self, line: &str) -> bool {
+        let trimmed = line.trim();
+        trimmed.len() >= self.length && trimmed.chars().all(|c| c == self.marker)
+    }
+
+    /// Code line without the indentation of the fence itself.
+    fn content<'a>(&self, line: &'a str) -> &'a str {
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        &line[indent.min(self.indent)..]
+    }
+}
+
+/// Split a list item line into its indentation, bullet and text.
+fn list_item(line: &str) -> Option<(usize, &str, &str)> {
+    let trimmed = line.trim_start();
+    let indent = line.len() - trimmed.len();
+    let bullet_end = trimmed.find(' ')?;
+    let bullet = &trimmed[..bullet_end];
+    let is_bullet = match bullet {
+        "-" | "*" | "+" => true,
+        _ => {
+            let number = bullet.trim_end_matches(|c| c == '.' || c == ')');
+            bullet.len() == number.len() + 1
+                && !number.is_empty()
+                && number.chars().all(|c| c.is_ascii_digit())
+        }
+    };
+    if is_bullet {
+        Some((indent, bullet, trimmed[bullet_end..].trim_start()))
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Alignment {
+    Left,
+    Center,
+    Right,
+}
+
+/// Align cells of a table into columns, replacing the delimiter row with a rule.
+fn format_table(rows: &[&str]) -> Vec<String> {
+    let rows = rows.iter().map(|row| table_cells(row)).collect::<Vec<_>>();
+    let is_delimiter = |cells: &Vec<String>| {
+        cells.iter().all(|cell| {
+            let dashes = cell.trim_start_matches(':').trim_end_matches(':');
+            !dashes.is_empty() && dashes.chars().all(|c| c == '-')
+        })
+    };
+    let delimiter = rows.iter().position(is_delimiter);
+    let alignments = delimiter.map_or_else(Vec::new, |i| {
+        rows[i]
+            .iter()
+            .map(|cell| match (cell.starts_with(':'), cell.ends_with(':')) {
+                (true, true) => Alignment::Center,
+                (false, true) => Alignment::Right,
+                _ => Alignment::Left,
+            })
+            .collect()
+    });
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .enumerate()
+                .filter(|(i, _)| Some(*i) != delimiter)
+                .filter_map(|(_, cells)| cells.get(column))
+                .map(|cell| cell.width())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+    rows.iter()
+        .enumerate()
+        .map(|(i, cells)| {
+            if Some(i) == delimiter {
+                return widths
+                    .iter()
+                    .map(|width| "─".repeat(*width))
+                    .collect::<Vec<_>>()
+                    .join("─┼─");
+            }
+            (0..columns)
+                .map(|column| {
+                    let cell = cells.get(column).map_or("", String::as_str);
+                    let padding = widths[column] - cell.width();
+                    let alignment = alignments.get(column).copied().unwrap_or(Alignment::Left);
+                    let left = match alignment {
+                        Alignment::Left => 0,
+                        Alignment::Center => padding / 2,
+                        Alignment::Right => padding,
+                    };
+                    format!(
+                        "{}{}{}",
+                        " ".repeat(left),
+                        escape(cell),
+                        " ".repeat(padding - left)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" │ ")
+                .trim_end()
+                .to_string()
+        })
+        .collect()
+}
+
+/// Cells of a table row, pipes escaped with a backslash being part of the cell text.
+fn table_cells(row: &str) -> Vec<String> {
+    let row = row.trim();
+    let row = row.strip_prefix('|').unwrap_or(row);
+    let row = if row.ends_with('|') && !row.ends_with("\\|") {
+        &row[..row.len() - 1]
+    } else {
+        row
+    };
+    let mut cells = vec![];
+    let mut cell = String::new();
+    let mut chars = row.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('|') => cell.push('|'),
+                Some(c) => {
+                    cell.push('\\');
+                    cell.push(c);
+                }
+                None => cell.push('\\'),
+            },
+            '|' => cells.push(std::mem::take(&mut cell).trim().to_string()),
+            c => cell.push(c),
+        }
+    }
+    cells.push(cell.trim().to_string());
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn align_table_columns() {
+        let markdown = "Sizes:\n\
+                        | Type | Size |\n\
+                        |:-----|-----:|\n\
+                        | `u8` | 1 |\n\
+                        | `漢字` | 16 |";
+        assert_eq!(
+            from_markdown(markdown),
+            "Sizes:\n\
+             Type   │ Size\n\
+             ───────┼─────\n\
+             `u8`   │    1\n\
+             `漢字` │   16"
+        );
+    }
+
+    #[test]
+    fn indent_nested_lists() {
+        let markdown = "Options:\n\
+                        - first\n    \
+                            * nested\n    \
+                            * another\n\
+                        - second\n\
+                        1. numbered";
+        assert_eq!(
+            from_markdown(markdown),
+            "Options:\n\
+             {bullet}•{Information} first\n  \
+               {bullet}◦{Information} nested\n  \
+               {bullet}◦{Information} another\n\
+             {bullet}•{Information} second\n\
+             {bullet}1.{Information} numbered"
+        );
+    }
+
+    #[test]
+    fn code_blocks_use_code_face() {
+        assert_eq!(
+            from_markdown("```rust\nfn f() {\n    g()\n}\n```\ntext"),
+            "{LspCodeBlock}fn f() \\{{Information}\n\
+             {LspCodeBlock}    g(){Information}\n\
+             {LspCodeBlock}}{Information}\n\
+             text"
+        );
+    }
+
+    #[test]
+    fn code_fences_without_language() {
+        assert_eq!(
+            from_markdown("  ~~~\n  a\n  ```\n    b\n  ~~~~\n````\nc\n````"),
+            "{LspCodeBlock}a{Information}\n\
+             {LspCodeBlock}```{Information}\n\
+             {LspCodeBlock}  b{Information}\n\
+             {LspCodeBlock}c{Information}"
+        );
+    }
+}
@@ -39,8 +39,9 @@ pub fn lsp_range_to_kakoune(
 ) -> KakouneRange {
     match offset_encoding {
         OffsetEncoding::Utf8 => lsp_range_to_kakoune_utf_8_code_units(range),
-        // Not a proper UTF-16 code units handling, but works within BMP
-        OffsetEncoding::Utf16 => lsp_range_to_kakoune_utf_8_code_points(range, text),
+        OffsetEncoding::Utf16 | OffsetEncoding::Utf32 => {
+            lsp_range_to_kakoune_via_chars(range, text, offset_encoding)
+        }
     }
 }
 
@@ -51,8 +52,9 @@ pub fn lsp_position_to_kakoune(
 ) -> KakounePosition {
     match offset_encoding {
         OffsetEncoding::Utf8 => lsp_position_to_kakoune_utf_8_code_units(position),
-        // Not a proper UTF-16 code units handling, but works within BMP
-        OffsetEncoding::Utf16 => lsp_position_to_kakoune_utf_8_code_points(position, text),
+        OffsetEncoding::Utf16 | OffsetEncoding::Utf32 => {
+            lsp_position_to_kakoune_via_chars(position, text, offset_encoding)
+        }
     }
 }
 
@@ -63,8 +65,99 @@ pub fn kakoune_position_to_lsp(
 ) -> Position {
     match offset_encoding {
         OffsetEncoding::Utf8 => kakoune_position_to_lsp_utf_8_code_units(position),
-        // Not a proper UTF-16 code units handling, but works within BMP
-        OffsetEncoding::Utf16 => kakoune_position_to_lsp_utf_8_code_points(position, text),
+        OffsetEncoding::Utf16 | OffsetEncoding::Utf32 => {
+            kakoune_position_to_lsp_via_chars(position, text, offset_encoding)
+        }
+    }
+}
+
+/// Convert Kakoune's range to LSP Range.
+pub fn kakoune_range_to_lsp(
+    range: &KakouneRange,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Range {
+    Range {
+        start: kakoune_position_to_lsp(&range.start, text, offset_encoding),
+        end: kakoune_range_end_to_lsp(&range.end, text, offset_encoding),
+    }
+}
+
+/// Kakoune's range end points to the last included character, but LSP's one is exclusive and
+/// should point to the next character (or to the start of the next line after the line ending).
+fn kakoune_range_end_to_lsp(
+    position: &KakounePosition,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Position {
+    // -1 because LSP & Rope ranges are 0-based, but Kakoune's are 1-based.
+    let line_idx = position.line - 1;
+    let byte_idx = position.column as usize - 1;
+    if line_idx as usize >= text.len_lines() {
+        return kakoune_position_to_lsp(position, text, offset_encoding);
+    }
+    let line = text.line(line_idx as _);
+    let next_char_idx = if byte_idx < line.len_bytes() {
+        line.byte_to_char(byte_idx) + 1
+    } else {
+        line.len_chars()
+    };
+    // Ending the range within `\r\n` includes the whole line ending.
+    if next_char_idx > line_len_without_ending(line) && line_idx as usize + 1 < text.len_lines() {
+        return Position {
+            line: line_idx + 1,
+            character: 0,
+        };
+    }
+    let character = char_to_code_units(line, next_char_idx, offset_encoding);
+    Position {
+        line: line_idx,
+        character: character as _,
+    }
+}
+
+/// Convert character index in the text to LSP Position.
+pub fn char_to_lsp_position(
+    char_idx: usize,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Position {
+    let line_idx = text.char_to_line(char_idx);
+    let line_char_idx = char_idx - text.line_to_char(line_idx);
+    let character = char_to_code_units(text.line(line_idx), line_char_idx, offset_encoding);
+    Position {
+        line: line_idx as _,
+        character: character as _,
+    }
+}
+
+/// Parse Kakoune's selection description `anchor_line.anchor_column,cursor_line.cursor_column`
+/// as provided by `%val{selections_desc}` into anchor and cursor positions.
+pub fn parse_selection_desc(desc: &str) -> Option<(KakounePosition, KakounePosition)> {
+    let parse_position = |s: &str| {
+        let mut parts = s.splitn(2, '.');
+        let line = parts.next()?.parse().ok()?;
+        let column = parts.next()?.parse().ok()?;
+        Some(KakounePosition { line, column })
+    };
+    let mut parts = desc.splitn(2, ',');
+    let anchor = parse_position(parts.next()?)?;
+    let cursor = parse_position(parts.next()?)?;
+    Some((anchor, cursor))
+}
+
+/// Make a range out of selection anchor and cursor, regardless of selection direction.
+pub fn ordered_range(anchor: KakounePosition, cursor: KakounePosition) -> KakouneRange {
+    if (anchor.line, anchor.column) <= (cursor.line, cursor.column) {
+        KakouneRange {
+            start: anchor,
+            end: cursor,
+        }
+    } else {
+        KakouneRange {
+            start: cursor,
+            end: anchor,
+        }
     }
 }
 
@@ -86,13 +179,107 @@ fn get_byte_index(char_index: usize, text: RopeSlice) -> usize {
     text.char_to_byte(min(char_index, text.len_chars()))
 }
 
-fn lsp_range_to_kakoune_utf_8_code_points(range: &Range, text: &Rope) -> KakouneRange {
+/// Number of characters in the line, not counting its `\n` or `\r\n` line ending.
+pub fn line_len_without_ending(line: RopeSlice) -> usize {
+    let mut len = line.len_chars();
+    if len > 0 && line.char(len - 1) == '\n' {
+        len -= 1;
+        if len > 0 && line.char(len - 1) == '\r' {
+            len -= 1;
+        }
+    }
+    len
+}
+
+/// Clamp the position to the end of its line, as misbehaving servers might send a character past
+/// it.
+pub fn clamp_to_line_end(
+    position: Position,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Position {
+    if position.line as usize >= text.len_lines() {
+        return position;
+    }
+    let line = text.line(position.line as _);
+    let line_end = char_to_code_units(line, line_len_without_ending(line), offset_encoding) as u32;
+    if position.character <= line_end {
+        return position;
+    }
+    warn!(
+        "Position {}:{} is past the end of line, clamping to {}",
+        position.line, position.character, line_end
+    );
+    Position {
+        line: position.line,
+        character: line_end,
+    }
+}
+
+/// Whether the text uses Windows line endings, judging by the first line.
+pub fn uses_crlf(text: &Rope) -> bool {
+    text.len_lines() > 1 && {
+        let line = text.line(0);
+        line_len_without_ending(line) + 2 == line.len_chars()
+    }
+}
+
+/// Index of the character at the offset in code units of the encoding within the line, one past
+/// the last character if the offset is out of bounds. Characters outside the Basic Multilingual
+/// Plane take two UTF-16 code units, an offset between them points to the character after.
+pub fn code_units_to_char(
+    line: RopeSlice,
+    code_units: usize,
+    offset_encoding: OffsetEncoding,
+) -> usize {
+    match offset_encoding {
+        OffsetEncoding::Utf8 => {
+            if code_units < line.len_bytes() {
+                line.byte_to_char(code_units)
+            } else {
+                line.len_chars()
+            }
+        }
+        OffsetEncoding::Utf16 => {
+            let mut offset = 0;
+            for (char_idx, c) in line.chars().enumerate() {
+                if offset >= code_units {
+                    return char_idx;
+                }
+                offset += c.len_utf16();
+            }
+            line.len_chars()
+        }
+        OffsetEncoding::Utf32 => min(code_units, line.len_chars()),
+    }
+}
+
+/// Offset in code units of the encoding of the character at the index within the line.
+pub fn char_to_code_units(
+    line: RopeSlice,
+    char_idx: usize,
+    offset_encoding: OffsetEncoding,
+) -> usize {
+    match offset_encoding {
+        OffsetEncoding::Utf8 => line.char_to_byte(char_idx),
+        OffsetEncoding::Utf16 => line.chars().take(char_idx).map(char::len_utf16).sum(),
+        OffsetEncoding::Utf32 => char_idx,
+    }
+}
+
+fn lsp_range_to_kakoune_via_chars(
+    range: &Range,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> KakouneRange {
     let Range { start, end } = range;
 
     let start_line = get_line(start.line as _, text);
-    let start_byte = get_byte_index(start.character as _, start_line) as u32;
+    let start_char = code_units_to_char(start_line, start.character as _, offset_encoding);
+    let start_byte = get_byte_index(start_char, start_line) as u32;
     let end_line = get_line(end.line as _, text);
-    let end_byte = get_byte_index(end.character as _, end_line) as u32;
+    let end_char = code_units_to_char(end_line, end.character as _, offset_encoding);
+    let end_byte = get_byte_index(end_char, end_line) as u32;
 
     lsp_range_to_kakoune_utf_8_code_units(&Range {
         start: Position {
@@ -175,7 +362,11 @@ fn lsp_range_to_kakoune_utf_8_code_units(range: &Range) -> KakouneRange {
     }
 }
 
-fn kakoune_position_to_lsp_utf_8_code_points(position: &KakounePosition, text: &Rope) -> Position {
+fn kakoune_position_to_lsp_via_chars(
+    position: &KakounePosition,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Position {
     // -1 because LSP & Rope ranges are 0-based, but Kakoune's are 1-based.
     let line_idx = position.line - 1;
     let col_idx = position.column - 1;
@@ -194,7 +385,8 @@ fn kakoune_position_to_lsp_utf_8_code_points(position: &KakounePosition, text: &
         };
     }
 
-    let character = line.byte_to_char(col_idx as _) as _;
+    let char_idx = line.byte_to_char(col_idx as _);
+    let character = char_to_code_units(line, char_idx, offset_encoding) as _;
     Position {
         line: line_idx,
         character,
@@ -209,7 +401,11 @@ fn kakoune_position_to_lsp_utf_8_code_units(position: &KakounePosition) -> Posit
     }
 }
 
-fn lsp_position_to_kakoune_utf_8_code_points(position: &Position, text: &Rope) -> KakounePosition {
+fn lsp_position_to_kakoune_via_chars(
+    position: &Position,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> KakounePosition {
     if position.line as usize >= text.len_lines() {
         return KakounePosition {
             line: position.line + 1,
@@ -218,14 +414,15 @@ fn lsp_position_to_kakoune_utf_8_code_points(position: &Position, text: &Rope) -
     }
 
     let line = text.line(position.line as _);
-    if position.character as usize >= line.len_chars() {
+    let char_idx = code_units_to_char(line, position.character as _, offset_encoding);
+    if char_idx >= line.len_chars() {
         return KakounePosition {
             line: position.line + 1,
             column: 999999999,
         };
     }
 
-    let byte = line.char_to_byte(position.character as _) as u32;
+    let byte = line.char_to_byte(char_idx) as u32;
     // +1 because LSP ranges are 0-based, but Kakoune's are 1-based.
     KakounePosition {
         line: position.line + 1,
@@ -316,4 +513,152 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn kakoune_range_to_lsp_multibyte_end() {
+        let text = Rope::from_str("fn ★() {}\nfoo\n");
+        assert_eq!(
+            kakoune_range_to_lsp(
+                &KakouneRange {
+                    start: KakounePosition { line: 1, column: 4 },
+                    end: KakounePosition { line: 1, column: 6 }
+                },
+                &text,
+                OffsetEncoding::Utf16
+            ),
+            Range {
+                start: Position {
+                    line: 0,
+                    character: 3
+                },
+                end: Position {
+                    line: 0,
+                    character: 4
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn kakoune_range_to_lsp_eol() {
+        let text = Rope::from_str("foo\nbar\n");
+        assert_eq!(
+            kakoune_range_to_lsp(
+                &KakouneRange {
+                    start: KakounePosition { line: 1, column: 1 },
+                    end: KakounePosition { line: 1, column: 4 }
+                },
+                &text,
+                OffsetEncoding::Utf8
+            ),
+            Range {
+                start: Position {
+                    line: 0,
+                    character: 0
+                },
+                end: Position {
+                    line: 1,
+                    character: 0
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn utf_16_code_units_past_astral_character() {
+        // 😀 takes 4 bytes in UTF-8 and 2 code units in UTF-16.
+        let text = Rope::from_str("a😀b = 1;\n");
+        let b = Position {
+            line: 0,
+            character: 3,
+        };
+        let kakoune_b = KakounePosition { line: 1, column: 6 };
+        assert_eq!(
+            lsp_position_to_kakoune(&b, &text, OffsetEncoding::Utf16),
+            kakoune_b.clone()
+        );
+        assert_eq!(
+            kakoune_position_to_lsp(&kakoune_b, &text, OffsetEncoding::Utf16),
+            b
+        );
+        assert_eq!(
+            kakoune_range_to_lsp(
+                &KakouneRange {
+                    start: kakoune_b.clone(),
+                    end: kakoune_b
+                },
+                &text,
+                OffsetEncoding::Utf16
+            ),
+            Range {
+                start: b,
+                end: Position {
+                    line: 0,
+                    character: 4
+                }
+            }
+        );
+        assert_eq!(char_to_lsp_position(2, &text, OffsetEncoding::Utf16), b);
+    }
+
+    #[test]
+    fn crlf_line_ending() {
+        let text = Rope::from_str("foo\r\nbar\r\n");
+        // Selection ending on `\r` takes the whole line ending.
+        assert_eq!(
+            kakoune_range_to_lsp(
+                &KakouneRange {
+                    start: KakounePosition { line: 1, column: 1 },
+                    end: KakounePosition { line: 1, column: 4 }
+                },
+                &text,
+                OffsetEncoding::Utf16
+            ),
+            Range {
+                start: Position {
+                    line: 0,
+                    character: 0
+                },
+                end: Position {
+                    line: 1,
+                    character: 0
+                }
+            }
+        );
+        // Insertion at the end of line goes before `\r`.
+        assert_eq!(
+            lsp_range_to_kakoune(
+                &Range {
+                    start: Position {
+                        line: 1,
+                        character: 3
+                    },
+                    end: Position {
+                        line: 1,
+                        character: 3
+                    }
+                },
+                &text,
+                OffsetEncoding::Utf16
+            ),
+            KakouneRange {
+                start: KakounePosition { line: 2, column: 4 },
+                end: KakounePosition { line: 2, column: 4 }
+            }
+        );
+        assert!(uses_crlf(&text));
+        assert!(!uses_crlf(&Rope::from_str("foo\nbar\r\n")));
+    }
+
+    #[test]
+    fn parse_reversed_selection_desc() {
+        let (anchor, cursor) = parse_selection_desc("3.5,1.2").unwrap();
+        assert_eq!(
+            ordered_range(anchor, cursor),
+            KakouneRange {
+                start: KakounePosition { line: 1, column: 2 },
+                end: KakounePosition { line: 3, column: 5 }
+            }
+        );
+    }
 }
@@ -0,0 +1,212 @@
+//! Track work done progress the language server reports via `$/progress` for tokens it created
+//! with `window/workDoneProgress/create`, e.g. rust-analyzer's "Indexing".
+//!
+//! Every report is passed on to `lsp-handle-progress` and summarized in the `lsp_progress` option
+//! for the modeline. Operations the server marked as cancellable are listed by
+//! `lsp-cancel-progress` which sends `window/workDoneProgress/cancel` for the chosen one.
+
+use crate::context::Context;
+use crate::types::{EditorMeta, EditorParams};
+use crate::util::editor_quote;
+use itertools::Itertools;
+use jsonrpc_core::{Id, Params};
+use lsp_types::notification::WorkDoneProgressCancel;
+use lsp_types::*;
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::Instant;
+
+/// Latest known state of an operation in progress.
+pub struct WorkDoneProgressState {
+    title: String,
+    message: Option<String>,
+    percentage: Option<u32>,
+    cancellable: bool,
+    // When the server last reported on the operation, None until it begins.
+    reported_at: Option<Instant>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CancelProgressParams {
+    // JSON representation of the progress token.
+    pub token: String,
+}
+
+pub fn work_done_progress_create(id: Id, params: Params, ctx: &mut Context) {
+    let params: WorkDoneProgressCreateParams = params
+        .parse()
+        .expect("Failed to parse WorkDoneProgressCreateParams params");
+    // Nothing is known about the operation until it begins.
+    ctx.work_done_progress.insert(
+        params.token,
+        WorkDoneProgressState {
+            title: String::new(),
+            message: None,
+            percentage: None,
+            cancellable: false,
+            reported_at: None,
+        },
+    );
+    ctx.reply(id, Ok(Value::Null));
+}
+
+/// Handle `$/progress` for tokens created by the server. Returns false if the token is not one of
+/// them.
+pub fn work_done_progress(params: Params, ctx: &mut Context) -> bool {
+    let params: ProgressParams = match params.parse() {
+        Ok(params) => params,
+        Err(_) => return false,
+    };
+    let state = match ctx.work_done_progress.get_mut(&params.token) {
+        Some(state) => state,
+        None => return false,
+    };
+    let ProgressParamsValue::WorkDone(progress) = params.value;
+    state.reported_at = Some(Instant::now());
+    let done = match progress {
+        WorkDoneProgress::Begin(begin) => {
+            state.title = begin.title;
+            state.message = begin.message;
+            state.percentage = begin.percentage;
+            state.cancellable = begin.cancellable.unwrap_or(false);
+            false
+        }
+        WorkDoneProgress::Report(report) => {
+            // Omitted message and percentage stay as reported last time.
+            if report.message.is_some() {
+                state.message = report.message;
+            }
+            if report.percentage.is_some() {
+                state.percentage = report.percentage;
+            }
+            if let Some(cancellable) = report.cancellable {
+                state.cancellable = cancellable;
+            }
+            false
+        }
+        WorkDoneProgress::End(end) => {
+            if end.message.is_some() {
+                state.message = end.message;
+            }
+            true
+        }
+    };
+    let command = format!(
+        "lsp-handle-progress {} {} {} {}",
+        editor_quote(&state.title),
+        editor_quote(state.message.as_deref().unwrap_or_default()),
+        editor_quote(&state.percentage.map_or(String::new(), |p| p.to_string())),
+        editor_quote(if done { "done" } else { "" })
+    );
+    if done {
+        ctx.work_done_progress.remove(&params.token);
+    }
+    let command = format!(
+        "{}\nset-option global lsp_progress {}",
+        command,
+        editor_quote(&modeline_progress(ctx.work_done_progress.values()))
+    );
+    ctx.exec(ctx.meta_for_session(), command);
+    true
+}
+
+/// Short summary of operations in progress for the modeline: the one reported last, with the
+/// number of others if any.
+fn modeline_progress<'a>(states: impl Iterator<Item = &'a WorkDoneProgressState>) -> String {
+    let states = states
+        .filter(|state| state.reported_at.is_some())
+        .collect::<Vec<_>>();
+    let latest = match states.iter().max_by_key(|state| state.reported_at) {
+        Some(latest) => latest,
+        None => return String::new(),
+    };
+    let mut progress = format!("⟳ {}", latest.title);
+    if let Some(percentage) = latest.percentage {
+        progress = format!("{} {}%", progress, percentage);
+    }
+    if states.len() > 1 {
+        progress = format!("{} (+{})", progress, states.len() - 1);
+    }
+    progress
+}
+
+/// Show a menu of cancellable operations in progress.
+pub fn cancel_progress_menu(meta: EditorMeta, _params: EditorParams, ctx: &mut Context) {
+    let entries = ctx
+        .work_done_progress
+        .iter()
+        .filter(|(_, state)| state.cancellable)
+        .map(|(token, state)| {
+            let mut title = state.title.clone();
+            if let Some(message) = &state.message {
+                title = format!("{}: {}", title, message);
+            }
+            if let Some(percentage) = state.percentage {
+                title = format!("{} ({}%)", title, percentage);
+            }
+            let token = serde_json::to_string(token).unwrap();
+            let command = format!("lsp-cancel-progress-request {}", editor_quote(&token));
+            (title, command)
+        })
+        .sorted()
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        let msg = "No cancellable operation in progress";
+        ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+        return;
+    }
+    let menu_args = entries
+        .into_iter()
+        .map(|(title, command)| format!("{} {}", editor_quote(&title), editor_quote(&command)))
+        .join(" ");
+    ctx.exec(meta, format!("menu {}", menu_args));
+}
+
+pub fn cancel_progress(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = CancelProgressParams::deserialize(params)
+        .expect("Params should follow CancelProgressParams structure");
+    let token: NumberOrString = match serde_json::from_str(&params.token) {
+        Ok(token) => token,
+        Err(_) => return,
+    };
+    // The operation might have ended while the menu was shown.
+    if !ctx.work_done_progress.contains_key(&token) {
+        let msg = "Operation is not in progress anymore";
+        ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+        return;
+    }
+    ctx.notify::<WorkDoneProgressCancel>(WorkDoneProgressCancelParams { token });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn state(
+        title: &str,
+        percentage: Option<u32>,
+        reported_at: Option<Instant>,
+    ) -> WorkDoneProgressState {
+        WorkDoneProgressState {
+            title: title.to_string(),
+            message: None,
+            percentage,
+            cancellable: false,
+            reported_at,
+        }
+    }
+
+    #[test]
+    fn modeline_shows_latest_operation_and_count_of_others() {
+        let now = Instant::now();
+        let states = vec![
+            state("Indexing", Some(45), Some(now + Duration::from_secs(1))),
+            state("Loading", None, Some(now)),
+            state("", None, None),
+        ];
+        assert_eq!(modeline_progress(states.iter()), "⟳ Indexing 45% (+1)");
+        assert_eq!(modeline_progress(states[1..].iter()), "⟳ Loading");
+        assert_eq!(modeline_progress(states[2..].iter()), "");
+    }
+}
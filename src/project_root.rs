@@ -12,6 +12,8 @@ pub fn find_project_root(language: &str, markers: &[String], path: &str) -> Stri
     }
 }
 
+/// Walk up from the file to the nearest directory containing the first marker, trying the next
+/// marker if none does. Falls back to the directory of the file if no marker is found.
 pub fn roots_by_marker(roots: &[String], path: &str) -> String {
     let mut src = PathBuf::from(path);
     // For scratch buffers we get a bare filename.
@@ -62,3 +64,39 @@ pub fn roots_by_env(roots: &HashSet<PathBuf>, path: &str) -> Option<String> {
         .find(|x| pwd.starts_with(&x))
         .map(|x| x.to_str().unwrap().to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn nested_projects_pick_nearest_marker() {
+        let tmp = env::temp_dir().join(format!("kak-lsp-test-roots-{}", std::process::id()));
+        let workspace = tmp.join("workspace");
+        let member = workspace.join("crates/member");
+        fs::create_dir_all(member.join("src")).unwrap();
+        fs::create_dir_all(workspace.join(".git")).unwrap();
+        fs::write(workspace.join("Cargo.toml"), "").unwrap();
+        fs::write(member.join("Cargo.toml"), "").unwrap();
+        let file = member.join("src/main.rs");
+        let file = file.to_str().unwrap();
+        let markers = |markers: &[&str]| markers.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            roots_by_marker(&markers(&["Cargo.toml", ".git"]), file),
+            member.to_str().unwrap()
+        );
+        // Markers are tried in order.
+        assert_eq!(
+            roots_by_marker(&markers(&[".git", "Cargo.toml"]), file),
+            workspace.to_str().unwrap()
+        );
+        // Without markers the directory of the file is the root.
+        assert_eq!(
+            roots_by_marker(&markers(&["flake.nix"]), file),
+            member.join("src").to_str().unwrap()
+        );
+        fs::remove_dir_all(&tmp).unwrap();
+    }
+}
@@ -1,18 +1,38 @@
-use crate::controller;
+use crate::context::Document;
+use crate::controller::{self, ServerCrash};
 use crate::editor_transport;
 use crate::project_root::find_project_root;
+use crate::text_sync::will_save_capabilities;
 use crate::thread_worker::Worker;
 use crate::types::*;
 use crate::util::*;
-use crossbeam_channel::{after, never, select, Sender};
+use crossbeam_channel::{after, never, select, unbounded, Sender};
 use lsp_types::notification::Notification;
+use lsp_types::request::Request;
 use lsp_types::*;
-use std::collections::HashMap;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 use toml;
 
+/// Delay before restarting a crashed language server, doubled on each further attempt.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+/// Language server which has been running for this long before crashing is considered to have
+/// recovered from previous crashes, restart attempts are counted anew.
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
 struct ControllerHandle {
     worker: Worker<EditorRequest, Void>,
+    started: Instant,
+    // Project roots of the files routed to the controller, more than one for a single instance.
+    roots: HashSet<String>,
+}
+
+/// Restart of a crashed language server waiting for its backoff delay.
+struct Restart {
+    deadline: Instant,
+    // Buffers which were open, to open them again with the new server.
+    documents: HashMap<String, Document>,
 }
 
 type Controllers = HashMap<Route, ControllerHandle>;
@@ -35,9 +55,24 @@ pub fn start(config: &Config, initial_request: Option<String>) -> i32 {
     let editor = editor.unwrap();
 
     let languages = config.language.clone();
+    for (language_id, language) in &languages {
+        for feature in language.unknown_features() {
+            warn!("Unknown feature `{}` of language {}", feature, language_id);
+        }
+    }
     let filetypes = filetype_to_language_id_map(config);
+    let secondary_filetypes = filetype_to_secondary_language_ids_map(config);
 
     let mut controllers: Controllers = HashMap::default();
+    let (crash_sender, crash_receiver) = unbounded::<ServerCrash>();
+    let mut restarts: HashMap<Route, Restart> = HashMap::default();
+    let mut restart_attempts: HashMap<Route, u32> = HashMap::default();
+    // Diagnostics of language servers which crashed, shown as stale by their next controller.
+    let mut stale_diagnostics: HashMap<Route, HashMap<String, Vec<Diagnostic>>> =
+        HashMap::default();
+    let shared = controller::Shared::default();
+    // Requests sent to several language servers so far, to tell their answers apart.
+    let mut fan_outs: u64 = 0;
 
     let timeout = config.server.timeout;
 
@@ -47,6 +82,13 @@ pub fn start(config: &Config, initial_request: Option<String>) -> i32 {
         } else {
             never()
         };
+        let restart_timer = restarts
+            .values()
+            .map(|restart| restart.deadline)
+            .min()
+            .map_or_else(never, |deadline| {
+                after(deadline.saturating_duration_since(Instant::now()))
+            });
 
         select! {
             recv(timeout_channel) -> _ => {
@@ -54,6 +96,100 @@ pub fn start(config: &Config, initial_request: Option<String>) -> i32 {
                 break 'event_loop
             }
 
+            recv(crash_receiver) -> crash => {
+                let crash = match crash {
+                    Ok(crash) => crash,
+                    Err(_) => continue 'event_loop,
+                };
+                let route = crash.route;
+                // A request from the editor might have started a new controller already.
+                let uptime = match controllers.get(&route) {
+                    Some(controller) if controller.started > crash.crashed_at => {
+                        continue 'event_loop;
+                    }
+                    Some(controller) => crash.crashed_at - controller.started,
+                    None => Duration::from_secs(0),
+                };
+                controllers.remove(&route);
+                stale_diagnostics.insert(route.clone(), crash.diagnostics);
+                if uptime >= STABLE_UPTIME {
+                    restart_attempts.remove(&route);
+                }
+                // Without open buffers there is nothing to restart for, the next request from
+                // the editor starts the server again.
+                if crash.documents.is_empty() {
+                    continue 'event_loop;
+                }
+                let attempt = restart_attempts.get(&route).map_or(1, |attempts| attempts + 1);
+                let max_attempts = config.server.restart_attempts;
+                if attempt > max_attempts {
+                    error!(
+                        "Giving up restarting {} in project {}",
+                        route.language, route.root
+                    );
+                    restart_attempts.remove(&route);
+                    let msg = format!(
+                        "{} language server crashed, giving up after {} restart attempts",
+                        route.language, max_attempts
+                    );
+                    let command = format!("lsp-show-error {}", editor_quote(&msg));
+                    show_in_editor(&route, command, editor.to_editor.sender());
+                    continue 'event_loop;
+                }
+                restart_attempts.insert(route.clone(), attempt);
+                let delay = restart_delay(attempt);
+                info!(
+                    "Restarting {} in project {} in {:?}, attempt {}/{}",
+                    route.language, route.root, delay, attempt, max_attempts
+                );
+                if attempt == 1 {
+                    let msg = format!("{} language server crashed, restarting", route.language);
+                    let command = format!("lsp-show-message 2 {}", editor_quote(&msg));
+                    show_in_editor(&route, command, editor.to_editor.sender());
+                }
+                let restart = Restart {
+                    deadline: Instant::now() + delay,
+                    documents: crash.documents,
+                };
+                restarts.insert(route, restart);
+            }
+
+            recv(restart_timer) -> _ => {
+                let now = Instant::now();
+                let due = restarts
+                    .iter()
+                    .filter(|(_, restart)| restart.deadline <= now)
+                    .map(|(route, _)| route.clone())
+                    .collect::<Vec<_>>();
+                for route in due {
+                    let restart = restarts.remove(&route).unwrap();
+                    let mut requests = restart
+                        .documents
+                        .iter()
+                        .map(|(buffile, document)| did_open_request(&route, buffile, document));
+                    let request = match requests.next() {
+                        Some(request) => request,
+                        None => continue,
+                    };
+                    let diagnostics = stale_diagnostics.remove(&route).unwrap_or_default();
+                    let controller = spawn_controller(
+                        config.clone(),
+                        route.clone(),
+                        request,
+                        editor.to_editor.sender().clone(),
+                        crash_sender.clone(),
+                        diagnostics,
+                        shared.clone(),
+                    );
+                    for request in requests {
+                        if controller.worker.sender().send(request).is_err() {
+                            error!("Failed to send message to controller");
+                        }
+                    }
+                    controllers.insert(route, controller);
+                }
+            }
+
             recv(editor.from_editor) -> request  => {
                 // editor.receiver was closed, either because of the unrecoverable error or timeout
                 // nothing we can do except to gracefully exit by stopping session
@@ -74,53 +210,146 @@ pub fn start(config: &Config, initial_request: Option<String>) -> i32 {
                     exit_editor_session(&mut controllers, &request);
                     continue 'event_loop;
                 }
+                // Chunks of an edit are reported from the buffer they edit, which might not be of the
+                // language of the server applying the edit.
+                if request.method == "workspace/applyEdit/chunk" {
+                    report_edit_chunk(&controllers, request);
+                    continue 'event_loop;
+                }
 
-                let language_id = filetypes.get(&request.meta.filetype);
-                if language_id.is_none() {
-                    debug!(
-                        "Language server is not configured for filetype `{}`",
-                        &request.meta.filetype
-                    );
+                let filetype = &request.meta.filetype;
+                let primary_language_id = filetypes.get(filetype);
+                let secondary_language_ids = secondary_filetypes.get(filetype);
+                if primary_language_id.is_none() && secondary_language_ids.is_none() {
+                    debug!("Language server is not configured for filetype `{}`", filetype);
+                    // Don't leave the editor waiting, e.g. to save the buffer.
+                    if let Some(fifo) = &request.meta.fifo {
+                        std::fs::write(fifo, "nop").expect("Failed to write command to fifo");
+                    }
+                    continue 'event_loop;
+                }
+                let advertises = |language_id: &str| {
+                    let meta = &request.meta;
+                    let (route, _) =
+                        editor_route(meta, language_id, &languages, &controllers, &restarts);
+                    let capabilities = shared.capabilities.lock().unwrap();
+                    capabilities.get(&route).map(|c| advertises_request(&request, c))
+                };
+                let secondary_language_ids = secondary_language_ids.map_or(&[][..], Vec::as_slice);
+                let language_ids = match &request.server {
+                    // Requests referring to something a server sent, like a completion item, go
+                    // to that server.
+                    Some(server)
+                        if primary_language_id == Some(server)
+                            || secondary_language_ids.contains(server) =>
+                    {
+                        vec![server.clone()]
+                    }
+                    _ => route_language_ids(
+                        &request.method,
+                        primary_language_id,
+                        secondary_language_ids,
+                        &languages,
+                        advertises,
+                    ),
+                };
+                if language_ids.is_empty() {
+                    let feature = method_feature(&request.method).unwrap_or(&request.method);
+                    debug!("No language server of filetype `{}` provides {}", filetype, feature);
+                    if let Some(fifo) = request.meta.fifo {
+                        let msg = format!("No language server provides {}", feature);
+                        reject_blocking_request(fifo, &msg);
+                    }
                     continue 'event_loop;
                 }
-                let language_id = language_id.unwrap();
 
-                let root_path = find_project_root(&language_id, &languages[language_id].roots, &request.meta.buffile);
-                let route = Route {
-                    session: request.meta.session.clone(),
-                    language: language_id.clone(),
-                    root: root_path.clone(),
+                // Only one server may answer the editor waiting on the fifo, preferably one
+                // which wants to edit the buffer before it's saved.
+                let fifo_language_id = request.meta.fifo.as_ref().and_then(|_| {
+                    let waits_until_saved = |language_id: &&String| {
+                        let meta = &request.meta;
+                        let (route, _) =
+                            editor_route(meta, language_id, &languages, &controllers, &restarts);
+                        let capabilities = shared.capabilities.lock().unwrap();
+                        will_save_capabilities(capabilities.get(&route)).1
+                    };
+                    language_ids.iter().find(waits_until_saved).or_else(|| language_ids.first())
+                });
+                let fans_out = FAN_OUT_METHODS.contains(&request.method.as_str());
+                let fan_out = if fans_out && language_ids.len() > 1 {
+                    fan_outs += 1;
+                    Some(fan_outs)
+                } else {
+                    None
                 };
+                for (index, language_id) in language_ids.iter().enumerate() {
+                    let mut request = request.clone();
+                    if Some(language_id) != fifo_language_id {
+                        request.meta.fifo = None;
+                    }
+                    request.meta.fan_out = fan_out.map(|id| FanOut {
+                        id,
+                        index,
+                        servers: language_ids.len(),
+                    });
+                    let meta = &request.meta;
+                    let (route, root_path) =
+                        editor_route(meta, language_id, &languages, &controllers, &restarts);
 
-                debug!("Routing editor request to {:?}", route);
+                    debug!("Routing editor request to {:?}", route);
 
-                use std::collections::hash_map::Entry;
-                match controllers.entry(route.clone()) {
-                    Entry::Occupied(controller_entry) => {
-                        if controller_entry.get().worker.sender().send(request.clone()).is_err()  {
-                            if let Some(fifo) = request.meta.fifo {
-                                cancel_blocking_request(fifo);
+                    use std::collections::hash_map::Entry;
+                    match controllers.entry(route.clone()) {
+                        Entry::Occupied(mut controller_entry) => {
+                            let controller = controller_entry.get_mut();
+                            if controller.roots.insert(root_path.clone()) {
+                                debug!("Adding workspace folder {} to {:?}", root_path, route);
+                                let request = add_workspace_folder_request(&request.meta, &root_path);
+                                if controller.worker.sender().send(request).is_err() {
+                                    error!("Failed to send message to controller");
+                                }
+                            }
+                            if controller_entry.get().worker.sender().send(request.clone()).is_err()  {
+                                if let Some(fifo) = &request.meta.fifo {
+                                    cancel_blocking_request(fifo.clone());
+                                }
+                                controller_entry.remove();
+                                error!("Failed to send message to controller");
+                                continue;
                             }
-                            controller_entry.remove();
-                            error!("Failed to send message to controller");
-                            continue 'event_loop;
                         }
-                    }
-                    Entry::Vacant(controller_entry) => {
-                        if let Some(fifo) = request.meta.fifo {
-                            cancel_blocking_request(fifo);
-                            // As Kakoune triggers BufClose after KakEnd we don't want to spawn a
-                            // new controller in that case. In normal situation it's unlikely to
-                            // get didClose message without running controller, unless it crashed
-                            // before. In that case didClose can be safely ignored as well.
-                        } else if request.method != notification::DidCloseTextDocument::METHOD {
-                            debug!("Spawning a new controller for {:?}", route);
-                            controller_entry.insert(spawn_controller(
-                                config.clone(),
-                                route,
-                                request,
-                                editor.to_editor.sender().clone(),
-                            ));
+                        Entry::Vacant(controller_entry) => {
+                            if let Some(fifo) = &request.meta.fifo {
+                                cancel_blocking_request(fifo.clone());
+                                // As Kakoune triggers BufClose after KakEnd we don't want to spawn a
+                                // new controller in that case. In normal situation it's unlikely to
+                                // get didClose message without running controller, unless it crashed
+                                // before. In that case didClose can be safely ignored as well.
+                            } else if request.method != notification::DidCloseTextDocument::METHOD {
+                                debug!("Spawning a new controller for {:?}", route);
+                                // Requests from the editor don't wait for a scheduled restart.
+                                let restart = restarts.remove(&route);
+                                let diagnostics = stale_diagnostics.remove(&route).unwrap_or_default();
+                                let controller = spawn_controller(
+                                    config.clone(),
+                                    route.clone(),
+                                    request.clone(),
+                                    editor.to_editor.sender().clone(),
+                                    crash_sender.clone(),
+                                    diagnostics,
+                                    shared.clone(),
+                                );
+                                for (buffile, document) in restart.iter().flat_map(|r| &r.documents) {
+                                    if *buffile == request.meta.buffile {
+                                        continue;
+                                    }
+                                    let request = did_open_request(&route, buffile, document);
+                                    if controller.worker.sender().send(request).is_err() {
+                                        error!("Failed to send message to controller");
+                                    }
+                                }
+                                controller_entry.insert(controller);
+                            }
                         }
                     }
                 }
@@ -137,30 +366,60 @@ pub fn start(config: &Config, initial_request: Option<String>) -> i32 {
 /// than to wait, cancel, and repeat.
 fn cancel_blocking_request(fifo: String) {
     debug!("Blocking request but LSP server is not running");
-    let command = "lsp-show-error 'Language server is not running, cancelling blocking request'";
+    let msg = "Language server is not running, cancelling blocking request";
+    reject_blocking_request(fifo, msg);
+}
+
+fn reject_blocking_request(fifo: String, msg: &str) {
+    let command = format!("lsp-show-error {}", editor_quote(msg));
     std::fs::write(fifo, command).expect("Failed to write command to fifo");
 }
 
 /// Reap controllers associated with editor session.
+/// Pass the report of a chunk of an edit the editor has applied to the controller applying the
+/// edit, that of the server and project root the report names.
+fn report_edit_chunk(controllers: &Controllers, request: EditorRequest) {
+    let root = request.params.get("root").and_then(|root| root.as_str());
+    let controller = controllers.iter().find(|(route, _)| {
+        route.session == request.meta.session
+            && request.server.as_ref() == Some(&route.language)
+            && root == Some(route.root.as_str())
+    });
+    match controller {
+        Some((_, controller)) => {
+            if controller.worker.sender().send(request).is_err() {
+                error!("Failed to send message to controller");
+            }
+        }
+        None => debug!("No controller applies the edit of the chunk"),
+    }
+}
+
 fn exit_editor_session(controllers: &mut Controllers, request: &EditorRequest) {
     info!(
         "Editor session `{}` closed, shutting down associated language servers",
         request.meta.session
     );
-    controllers.retain(|route, controller| {
-        if route.session == request.meta.session {
-            info!("Exit {} in project {}", route.language, route.root);
-            // to notify kak-lsp about editor session end we use the same `exit` notification as
-            // used in LSP spec to notify language server to exit, thus we can just clone request
-            // and pass it along
-            if controller.worker.sender().send(request.clone()).is_err() {
-                error!("Failed to send stop message to language server");
-            }
-            false
-        } else {
-            true
+    let routes = controllers
+        .keys()
+        .filter(|route| route.session == request.meta.session)
+        .cloned()
+        .collect::<Vec<_>>();
+    // Dropping a controller waits for its language server to shut down, so all of them are told
+    // to exit first.
+    let stopping = routes
+        .iter()
+        .filter_map(|route| controllers.remove_entry(route))
+        .collect::<Vec<_>>();
+    for (route, controller) in &stopping {
+        info!("Exit {} in project {}", route.language, route.root);
+        // to notify kak-lsp about editor session end we use the same `exit` notification as
+        // used in LSP spec to notify language server to exit, thus we can just clone request
+        // and pass it along
+        if controller.worker.sender().send(request.clone()).is_err() {
+            error!("Failed to send stop message to language server");
         }
-    });
+    }
 }
 
 /// Shut down all language servers and exit.
@@ -173,13 +432,18 @@ fn stop_session(controllers: &mut Controllers) {
             client: None,
             version: 0,
             fifo: None,
+            fan_out: None,
         },
         method: notification::Exit::METHOD.to_string(),
         params: toml::Value::Table(toml::value::Table::default()),
-        ranges: None,
+        debounce: 0,
+        server: None,
     };
     info!("Shutting down language servers and exiting");
-    for (route, controller) in controllers.drain() {
+    // Dropping a controller waits for its language server to shut down, so all of them are told
+    // to exit first.
+    let stopping = controllers.drain().collect::<Vec<_>>();
+    for (route, controller) in &stopping {
         if controller.worker.sender().send(request.clone()).is_err() {
             error!("Failed to send stop message to language server");
         }
@@ -192,13 +456,454 @@ fn spawn_controller(
     route: Route,
     request: EditorRequest,
     to_editor: Sender<EditorResponse>,
+    crashes: Sender<ServerCrash>,
+    stale_diagnostics: HashMap<String, Vec<Diagnostic>>,
+    shared: controller::Shared,
 ) -> ControllerHandle {
     // NOTE 1024 is arbitrary
     let channel_capacity = 1024;
 
+    let controller_route = route.clone();
     let worker = Worker::spawn("Controller", channel_capacity, move |receiver, _| {
-        controller::start(to_editor, receiver, &route, request, config);
+        controller::start(
+            to_editor,
+            receiver,
+            crashes,
+            &controller_route,
+            request,
+            config,
+            stale_diagnostics,
+            shared,
+        );
     });
 
-    ControllerHandle { worker }
+    let mut roots = HashSet::new();
+    roots.insert(route.root);
+    ControllerHandle {
+        worker,
+        started: Instant::now(),
+        roots,
+    }
+}
+
+/// Route of the editor request to the server of the language, along with the project root of the
+/// buffer.
+fn editor_route(
+    meta: &EditorMeta,
+    language_id: &str,
+    languages: &HashMap<String, LanguageConfig>,
+    controllers: &Controllers,
+    restarts: &HashMap<Route, Restart>,
+) -> (Route, String) {
+    let language = &languages[language_id];
+    let root_path = find_project_root(language_id, &language.roots, &meta.buffile);
+    let route = Route {
+        session: meta.session.clone(),
+        language: language_id.to_string(),
+        root: root_path.clone(),
+    };
+    // Single instance of the server keeps the route it was started for.
+    let route = if language.single_instance {
+        controllers
+            .keys()
+            .chain(restarts.keys())
+            .find(|r| r.session == route.session && r.language == route.language)
+            .cloned()
+            .unwrap_or(route)
+    } else {
+        route
+    };
+    (route, root_path)
+}
+
+/// Editor requests sent to all language servers of the buffer providing the feature, the answers
+/// of which are merged.
+const FAN_OUT_METHODS: &[&str] = &[
+    "textDocument/completion",
+    "textDocument/hover",
+    "textDocument/codeAction",
+    "textDocument/diagnostics/quickfix",
+];
+
+/// Languages whose servers get the editor request. Requests keeping buffers in sync go to all
+/// servers attached to the buffer, and so do requests in `FAN_OUT_METHODS` if the servers provide
+/// the feature. Others go to the first server providing the feature the request belongs to, trying
+/// the primary server first.
+///
+/// Formatting goes to the `formatting_server` of the primary language if set, and otherwise to the
+/// first server which advertises support for the request. Requests sent to several servers skip
+/// the ones which are known not to support them. Commands go to the server which advertises them,
+/// the primary one if none does. `advertises` tells whether the server of a language does, None if
+/// it is not known yet.
+fn route_language_ids<F>(
+    method: &str,
+    primary_language_id: Option<&String>,
+    secondary_language_ids: &[String],
+    languages: &HashMap<String, LanguageConfig>,
+    advertises: F,
+) -> Vec<String>
+where
+    F: Fn(&str) -> Option<bool>,
+{
+    let language_ids = primary_language_id
+        .into_iter()
+        .chain(secondary_language_ids)
+        .cloned();
+    // Any of the servers might hold an edit awaiting confirmation. Each one renders diagnostics
+    // of the buffer, so all of them have to know how to present them.
+    if is_document_sync(method)
+        || method.starts_with("workspace/previewEdits")
+        || method == "textDocument/diagnostics/display"
+        || method == "textDocument/diagnostics/minSeverity"
+    {
+        return language_ids.collect();
+    }
+    let feature = match method_feature(method) {
+        Some(feature) => feature,
+        // Commands of code actions offered by a secondary server are run by that server.
+        None if method == request::ExecuteCommand::METHOD => {
+            let mut language_ids = language_ids;
+            return language_ids
+                .find(|language_id| advertises(language_id) == Some(true))
+                .or_else(|| primary_language_id.cloned())
+                .into_iter()
+                .collect();
+        }
+        // Requests not belonging to a particular feature only go to the primary server.
+        None => return primary_language_id.cloned().into_iter().collect(),
+    };
+    let mut candidates =
+        language_ids.filter(|language_id| languages[language_id].provides(feature));
+    if FAN_OUT_METHODS.contains(&method) {
+        return candidates
+            .filter(|language_id| advertises(language_id) != Some(false))
+            .collect();
+    }
+    if feature != "formatting" {
+        return candidates.next().into_iter().collect();
+    }
+    let candidates = candidates.collect::<Vec<_>>();
+    let preferred = primary_language_id.and_then(|id| languages[id].formatting_server.as_ref());
+    candidates
+        .iter()
+        .filter(|language_id| Some(*language_id) == preferred)
+        .chain(&candidates)
+        .find(|language_id| advertises(language_id) != Some(false))
+        .cloned()
+        .into_iter()
+        .collect()
+}
+
+/// Whether the server capabilities include the formatting request, the request sent to several
+/// servers or the command to execute, true for other requests.
+fn advertises_request(request: &EditorRequest, capabilities: &ServerCapabilities) -> bool {
+    fn enabled<T>(provider: &Option<OneOf<bool, T>>) -> bool {
+        match provider {
+            Some(OneOf::Left(enabled)) => *enabled,
+            Some(OneOf::Right(_)) => true,
+            None => false,
+        }
+    }
+    match request.method.as_str() {
+        "textDocument/formatting" => enabled(&capabilities.document_formatting_provider),
+        "textDocument/rangeFormatting" => enabled(&capabilities.document_range_formatting_provider),
+        "textDocument/onTypeFormatting" => {
+            capabilities.document_on_type_formatting_provider.is_some()
+        }
+        "textDocument/completion" => capabilities.completion_provider.is_some(),
+        "textDocument/hover" => match capabilities.hover_provider {
+            Some(HoverProviderCapability::Simple(enabled)) => enabled,
+            Some(HoverProviderCapability::Options(_)) => true,
+            None => false,
+        },
+        "textDocument/codeAction" | "textDocument/diagnostics/quickfix" => {
+            match capabilities.code_action_provider {
+                Some(CodeActionProviderCapability::Simple(enabled)) => enabled,
+                Some(CodeActionProviderCapability::Options(_)) => true,
+                None => false,
+            }
+        }
+        request::ExecuteCommand::METHOD => {
+            let command = request.params.get("command").and_then(toml::Value::as_str);
+            capabilities
+                .execute_command_provider
+                .as_ref()
+                .map_or(false, |provider| {
+                    provider
+                        .commands
+                        .iter()
+                        .any(|c| Some(c.as_str()) == command)
+                })
+        }
+        _ => true,
+    }
+}
+
+/// Feature out of `FEATURES` the editor request belongs to.
+fn method_feature(method: &str) -> Option<&'static str> {
+    let feature = match method {
+        "textDocument/prepareCallHierarchy"
+        | "callHierarchy/incomingCalls"
+        | "callHierarchy/outgoingCalls" => "call_hierarchy",
+        "textDocument/codeAction" | "textDocument/diagnostics/quickfix" | "codeAction/perform" => {
+            "code_action"
+        }
+        "textDocument/codeLens" | "textDocument/codeLens/menu" => "code_lens",
+        "textDocument/colorPresentation" => "color",
+        "textDocument/completion" | "completionItem/resolve" | "completionItem/accept" => {
+            "completion"
+        }
+        "textDocument/declaration"
+        | "textDocument/definition"
+        | "textDocument/implementation"
+        | "textDocument/typeDefinition" => "definition",
+        "textDocument/diagnostics"
+        | "textDocument/diagnostics/goto"
+        | "textDocument/diagnostics/list"
+        | "textDocument/diagnostics/details"
+        | "textDocument/diagnostics/openDoc"
+        | "workspace/diagnostic" => "diagnostics",
+        "textDocument/documentHighlight" | "textDocument/documentHighlight/find" => {
+            "document_highlight"
+        }
+        "textDocument/documentLink/open" => "document_link",
+        "textDocument/documentSymbol"
+        | "textDocument/documentSymbol/breadcrumb"
+        | "textDocument/documentSymbol/goto" => "document_symbol",
+        "textDocument/foldingRange" => "folding_range",
+        "textDocument/formatting"
+        | "textDocument/rangeFormatting"
+        | "textDocument/onTypeFormatting" => "formatting",
+        "textDocument/hover" | "textDocument/hover/buffer" => "hover",
+        "textDocument/inlayHint"
+        | "textDocument/inlayHint/hover"
+        | "textDocument/inlayHint/apply"
+        | "textDocument/inlayHint/goto" => "inlay_hints",
+        "textDocument/linkedEditingRange" => "linked_editing_range",
+        "textDocument/moniker" => "moniker",
+        "textDocument/references" => "references",
+        "textDocument/rename" | "textDocument/prepareRename" => "rename",
+        "textDocument/selectionRange" => "selection_range",
+        "textDocument/signatureHelp"
+        | "textDocument/signatureHelp/cycle"
+        | "textDocument/signatureHelp/retrigger" => "signature_help",
+        "textDocument/prepareTypeHierarchy"
+        | "typeHierarchy/supertypes"
+        | "typeHierarchy/subtypes" => "type_hierarchy",
+        "workspace/symbol" | "workspaceSymbol/jump" => "workspace_symbol",
+        _ => return None,
+    };
+    Some(feature)
+}
+
+/// Whether the editor request keeps the language server in sync with buffers, which is the only
+/// kind of request sent to secondary language servers unless they provide the feature.
+fn is_document_sync(method: &str) -> bool {
+    method == notification::DidOpenTextDocument::METHOD
+        || method == notification::DidChangeTextDocument::METHOD
+        || method == notification::WillSaveTextDocument::METHOD
+        || method == notification::DidSaveTextDocument::METHOD
+        || method == notification::DidCloseTextDocument::METHOD
+}
+
+/// Delay before the restart attempt, starting from 1.
+fn restart_delay(attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    RESTART_DELAY
+        .checked_mul(factor)
+        .map_or(MAX_RESTART_DELAY, |delay| delay.min(MAX_RESTART_DELAY))
+}
+
+/// Request to add the project root as a workspace folder of a single instance server.
+fn add_workspace_folder_request(meta: &EditorMeta, root_path: &str) -> EditorRequest {
+    let mut params = toml::value::Table::default();
+    params.insert(
+        "added".to_string(),
+        toml::Value::Array(vec![toml::Value::String(root_path.to_string())]),
+    );
+    EditorRequest {
+        meta: EditorMeta {
+            client: None,
+            fifo: None,
+            fan_out: None,
+            ..meta.clone()
+        },
+        method: notification::DidChangeWorkspaceFolders::METHOD.to_string(),
+        params: toml::Value::Table(params),
+        debounce: 0,
+        server: None,
+    }
+}
+
+/// Request to open the buffer as it was known to the crashed language server.
+fn did_open_request(route: &Route, buffile: &str, document: &Document) -> EditorRequest {
+    let mut params = toml::value::Table::default();
+    params.insert(
+        "draft".to_string(),
+        toml::Value::String(document.text.to_string()),
+    );
+    EditorRequest {
+        meta: EditorMeta {
+            session: route.session.clone(),
+            client: None,
+            buffile: buffile.to_string(),
+            filetype: String::new(),
+            version: document.version,
+            fifo: None,
+            fan_out: None,
+        },
+        method: notification::DidOpenTextDocument::METHOD.to_string(),
+        params: toml::Value::Table(params),
+        debounce: 0,
+        server: None,
+    }
+}
+
+fn show_in_editor(route: &Route, command: String, to_editor: &Sender<EditorResponse>) {
+    let meta = EditorMeta {
+        session: route.session.clone(),
+        client: None,
+        buffile: String::new(),
+        filetype: String::new(),
+        version: 0,
+        fifo: None,
+        fan_out: None,
+    };
+    if to_editor.send(EditorResponse { meta, command }).is_err() {
+        error!("Failed to send command to editor");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restart_delay_doubles_up_to_max() {
+        assert_eq!(restart_delay(1), Duration::from_secs(1));
+        assert_eq!(restart_delay(3), Duration::from_secs(4));
+        assert_eq!(restart_delay(6), MAX_RESTART_DELAY);
+        assert_eq!(restart_delay(40), MAX_RESTART_DELAY);
+    }
+
+    #[test]
+    fn requests_go_to_first_server_providing_feature() {
+        let languages: HashMap<String, LanguageConfig> = toml::from_str(
+            r#"
+            [typescript]
+            filetypes = ["typescript"]
+            roots = []
+            disabled_features = ["formatting", "type_hierarchy"]
+
+            [eslint]
+            filetypes = ["typescript"]
+            roots = []
+            secondary = true
+            features = ["code_action", "diagnostics", "formatting", "type_hierarchy"]
+            "#,
+        )
+        .unwrap();
+        let primary = "typescript".to_string();
+        let secondary = ["eslint".to_string()];
+        let route =
+            |method| route_language_ids(method, Some(&primary), &secondary, &languages, |_| None);
+        assert_eq!(
+            route("textDocument/didChange"),
+            vec!["typescript", "eslint"]
+        );
+        assert_eq!(
+            route("textDocument/diagnostics/display"),
+            vec!["typescript", "eslint"]
+        );
+        assert_eq!(route("textDocument/hover"), vec!["typescript"]);
+        assert_eq!(route("textDocument/formatting"), vec!["eslint"]);
+        assert_eq!(
+            route("textDocument/codeAction"),
+            vec!["typescript", "eslint"]
+        );
+        assert_eq!(route("codeAction/perform"), vec!["typescript"]);
+        let without_eslint = |language_id: &str| Some(language_id != "eslint");
+        assert_eq!(
+            route_language_ids(
+                "textDocument/codeAction",
+                Some(&primary),
+                &secondary,
+                &languages,
+                without_eslint
+            ),
+            vec!["typescript"]
+        );
+        assert_eq!(
+            route("textDocument/prepareCallHierarchy"),
+            vec!["typescript"]
+        );
+        assert_eq!(route("textDocument/prepareTypeHierarchy"), vec!["eslint"]);
+        assert_eq!(route("typeHierarchy/subtypes"), vec!["eslint"]);
+        assert!(languages["eslint"].unknown_features().is_empty());
+    }
+
+    #[test]
+    fn commands_go_to_server_advertising_them() {
+        let languages: HashMap<String, LanguageConfig> = toml::from_str(
+            r#"
+            [typescript]
+            filetypes = ["typescript"]
+            roots = []
+
+            [eslint]
+            filetypes = ["typescript"]
+            roots = []
+            secondary = true
+            "#,
+        )
+        .unwrap();
+        let primary = "typescript".to_string();
+        let secondary = ["eslint".to_string()];
+        let route = |advertises: &dyn Fn(&str) -> Option<bool>| {
+            let method = request::ExecuteCommand::METHOD;
+            route_language_ids(method, Some(&primary), &secondary, &languages, advertises)
+        };
+        assert_eq!(
+            route(&|language_id| Some(language_id == "eslint")),
+            vec!["eslint"]
+        );
+        assert_eq!(route(&|_| None), vec!["typescript"]);
+    }
+
+    #[test]
+    fn formatting_falls_back_from_preferred_server_without_support() {
+        let languages: HashMap<String, LanguageConfig> = toml::from_str(
+            r#"
+            [typescript]
+            filetypes = ["typescript"]
+            roots = []
+            formatting_server = "prettier"
+
+            [eslint]
+            filetypes = ["typescript"]
+            roots = []
+            secondary = true
+
+            [prettier]
+            filetypes = ["typescript"]
+            roots = []
+            secondary = true
+            "#,
+        )
+        .unwrap();
+        let primary = "typescript".to_string();
+        let secondary = ["eslint".to_string(), "prettier".to_string()];
+        let route = |method, advertises: &dyn Fn(&str) -> Option<bool>| {
+            route_language_ids(method, Some(&primary), &secondary, &languages, advertises)
+        };
+        let all = |_: &str| None;
+        assert_eq!(route("textDocument/formatting", &all), vec!["prettier"]);
+        let only_eslint = |language_id: &str| Some(language_id == "eslint");
+        assert_eq!(
+            route("textDocument/rangeFormatting", &only_eslint),
+            vec!["eslint"]
+        );
+        let none = |_: &str| Some(false);
+        assert!(route("textDocument/formatting", &none).is_empty());
+    }
 }
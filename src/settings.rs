@@ -0,0 +1,168 @@
+use crate::context::*;
+use crate::types::*;
+use crate::util::*;
+use jsonrpc_core::{Id, Params};
+use lsp_types::notification::*;
+use lsp_types::*;
+use serde::Deserialize;
+use serde_json::{self, Value};
+use std::path::Path;
+
+/// Answer `workspace/configuration` request with settings of each requested section.
+///
+/// Settings are merged from the following sources, later ones taking precedence:
+/// * `settings` of the language in `kak-lsp.toml`;
+/// * `workspace_settings` of the language in `kak-lsp.toml` for the folder closest to `scopeUri`;
+/// * `lsp_server_configuration` option in Kakoune.
+pub fn workspace_configuration(id: Id, params: Params, ctx: &mut Context) {
+    let params: ConfigurationParams = params.parse().expect("Failed to parse params");
+    let response = params
+        .items
+        .iter()
+        .map(|item| {
+            let settings = scope_settings(item.scope_uri.as_ref(), ctx);
+            match &item.section {
+                Some(section) => section_settings(&settings, section),
+                None => settings,
+            }
+        })
+        .collect::<Vec<_>>();
+    ctx.reply(id, Ok(Value::Array(response)));
+}
+
+#[derive(Deserialize, Debug)]
+struct SetConfigParams {
+    section: String,
+    // JSON value of the section.
+    value: String,
+}
+
+/// Merge settings of a section set at runtime into the current ones and let the server know.
+/// They are kept until `lsp_server_configuration` option changes.
+pub fn set_config(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = SetConfigParams::deserialize(params)
+        .expect("Params should follow SetConfigParams structure");
+    let value = match serde_json::from_str(&params.value) {
+        Ok(value) => value,
+        Err(e) => {
+            let msg = format!("Invalid JSON for {}: {}", params.section, e);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+    };
+    merge_section(&mut ctx.settings, &params.section, value);
+    // Servers pulling settings with workspace/configuration ask for them again on this
+    // notification, others take them from it.
+    let settings = scope_settings(None, ctx);
+    ctx.notify::<DidChangeConfiguration>(DidChangeConfigurationParams { settings });
+}
+
+/// Merge value into settings at the dotted section path.
+fn merge_section(settings: &mut serde_json::Map<String, Value>, section: &str, value: Value) {
+    let value = section.rsplit('.').fold(value, |value, key| {
+        let mut object = serde_json::Map::new();
+        object.insert(key.to_string(), value);
+        Value::Object(object)
+    });
+    let mut merged = Value::Object(std::mem::take(settings));
+    merge(&mut merged, value);
+    if let Value::Object(merged) = merged {
+        *settings = merged;
+    }
+}
+
+fn scope_settings(scope_uri: Option<&Url>, ctx: &Context) -> Value {
+    let mut settings = Value::Object(serde_json::Map::new());
+    if let Some(language) = ctx.config.language.get(&ctx.language_id) {
+        if let Some(language_settings) = &language.settings {
+            merge(&mut settings, language_settings.clone());
+        }
+        let scope_path = scope_uri.and_then(|uri| uri.to_file_path().ok());
+        // Folder settings only apply to the folder which contains the scope most closely.
+        let folder_settings = scope_path.and_then(|scope_path| {
+            language
+                .workspace_settings
+                .iter()
+                .filter(|(folder, _)| scope_path.starts_with(Path::new(folder)))
+                .max_by_key(|(folder, _)| Path::new(folder).components().count())
+                .map(|(_, folder_settings)| folder_settings.clone())
+        });
+        if let Some(folder_settings) = folder_settings {
+            merge(&mut settings, folder_settings);
+        }
+    }
+    merge(&mut settings, Value::Object(ctx.settings.clone()));
+    settings
+}
+
+/// Get settings of a dotted section like `rust-analyzer` or `python.analysis`, null if there is no
+/// such section.
+fn section_settings(settings: &Value, section: &str) -> Value {
+    section
+        .split('.')
+        .try_fold(settings, |settings, key| settings.get(key))
+        .cloned()
+        .unwrap_or(Value::Null)
+}
+
+/// Recursively merge objects, values of `overrides` replacing anything else.
+pub fn merge(target: &mut Value, overrides: Value) {
+    match (target, overrides) {
+        (Value::Object(target), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match target.get_mut(&key) {
+                    Some(target_value) => merge(target_value, value),
+                    None => {
+                        target.insert(key, value);
+                    }
+                }
+            }
+        }
+        (target, overrides) => *target = overrides,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merge_nested_objects() {
+        let mut settings = json!({"rust": {"a": 1, "b": {"c": 2}}, "x": true});
+        merge(&mut settings, json!({"rust": {"b": {"d": 3}}, "x": false}));
+        assert_eq!(
+            settings,
+            json!({"rust": {"a": 1, "b": {"c": 2, "d": 3}}, "x": false})
+        );
+    }
+
+    #[test]
+    fn merge_section_keeps_sibling_keys() {
+        let mut settings = serde_json::Map::new();
+        merge_section(
+            &mut settings,
+            "rust-analyzer",
+            json!({"checkOnSave": {"command": "check", "enable": true}}),
+        );
+        merge_section(
+            &mut settings,
+            "rust-analyzer.checkOnSave",
+            json!({"command": "clippy"}),
+        );
+        assert_eq!(
+            Value::Object(settings),
+            json!({"rust-analyzer": {"checkOnSave": {"command": "clippy", "enable": true}}})
+        );
+    }
+
+    #[test]
+    fn dotted_section() {
+        let settings = json!({"python": {"analysis": {"typeCheckingMode": "strict"}}});
+        assert_eq!(
+            section_settings(&settings, "python.analysis"),
+            json!({"typeCheckingMode": "strict"})
+        );
+        assert_eq!(section_settings(&settings, "python.missing"), Value::Null);
+    }
+}
@@ -0,0 +1,413 @@
+//! Parse LSP snippets and render them for Kakoune side `lsp-snippets-insert`.
+//! LSP grammar (https://microsoft.github.io/language-server-protocol/specification#snippet_syntax)
+//! supports nested placeholders, choices and variables, while `lsp-snippets-insert` only knows
+//! flat `$1` and `${1:default}` placeholders with `$0` as the final cursor position.
+//! Renderer flattens the former into the latter:
+//! * Nested placeholders become part of the default text of the enclosing one.
+//! * Choices become placeholders with the first option as default.
+//! * Variables are replaced with their default text, if any.
+
+#[derive(Debug, PartialEq)]
+pub enum SnippetElement {
+    Text(String),
+    /// Either a bare tabstop or a placeholder with some default content.
+    Tabstop {
+        index: u32,
+        placeholder: Vec<SnippetElement>,
+    },
+    Choice {
+        index: u32,
+        options: Vec<String>,
+    },
+    Variable {
+        name: String,
+        default: Vec<SnippetElement>,
+    },
+}
+
+/// Parse snippet into elements. Parsing is lenient: anything which doesn't form a valid construct
+/// is kept as text.
+pub fn parse(snippet: &str) -> Vec<SnippetElement> {
+    let chars = snippet.chars().collect::<Vec<_>>();
+    let mut parser = Parser {
+        chars: &chars,
+        pos: 0,
+    };
+    parser.elements(false)
+}
+
+/// Render elements as a snippet understood by `lsp-snippets-insert`.
+pub fn render(elements: &[SnippetElement]) -> String {
+    let mut result = String::new();
+    for element in elements {
+        match element {
+            SnippetElement::Text(text) => result.push_str(&escape_text(text)),
+            SnippetElement::Tabstop { index, placeholder } if placeholder.is_empty() => {
+                result.push_str(&format!("${{{}}}", index))
+            }
+            SnippetElement::Tabstop { index, placeholder } => result.push_str(&format!(
+                "${{{}:{}}}",
+                index,
+                escape_default(&plain_text(placeholder))
+            )),
+            SnippetElement::Choice { index, options } => result.push_str(&format!(
+                "${{{}:{}}}",
+                index,
+                escape_default(options.first().map(String::as_str).unwrap_or(""))
+            )),
+            SnippetElement::Variable { default, .. } => {
+                result.push_str(&escape_text(&plain_text(default)))
+            }
+        }
+    }
+    result
+}
+
+/// Text the elements expand to when no placeholder is edited.
+fn plain_text(elements: &[SnippetElement]) -> String {
+    elements
+        .iter()
+        .map(|element| match element {
+            SnippetElement::Text(text) => text.clone(),
+            SnippetElement::Tabstop { placeholder, .. } => plain_text(placeholder),
+            SnippetElement::Choice { options, .. } => options.first().cloned().unwrap_or_default(),
+            SnippetElement::Variable { default, .. } => plain_text(default),
+        })
+        .collect()
+}
+
+/// Escape text outside of placeholders, so that a trailing backslash doesn't escape the `$` of
+/// the placeholder following it.
+fn escape_text(text: &str) -> String {
+    text.replace("\\", "\\\\").replace("$", "\\$")
+}
+
+fn escape_default(text: &str) -> String {
+    text.replace("\\", "\\\\")
+        .replace("}", "\\}")
+        .replace("$", "\\$")
+}
+
+struct Parser<'a> {
+    chars: &'a [char],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Parse elements until the end of input or, if `nested`, an unescaped closing brace which is
+    /// left for the caller to consume.
+    fn elements(&mut self, nested: bool) -> Vec<SnippetElement> {
+        let mut elements = vec![];
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            match c {
+                '}' if nested => break,
+                '\\' => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(escaped @ '$') | Some(escaped @ '}') | Some(escaped @ '\\') => {
+                            self.pos += 1;
+                            text.push(escaped);
+                        }
+                        _ => text.push('\\'),
+                    }
+                }
+                '$' => {
+                    let start = self.pos;
+                    match self.dollar() {
+                        Some(element) => {
+                            if !text.is_empty() {
+                                elements.push(SnippetElement::Text(text));
+                                text = String::new();
+                            }
+                            elements.push(element);
+                        }
+                        None => {
+                            self.pos = start + 1;
+                            text.push('$');
+                        }
+                    }
+                }
+                _ => {
+                    self.pos += 1;
+                    text.push(c);
+                }
+            }
+        }
+        if !text.is_empty() {
+            elements.push(SnippetElement::Text(text));
+        }
+        elements
+    }
+
+    fn int(&mut self) -> Option<u32> {
+        let start = self.pos;
+        while self.peek().map_or(false, |c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .ok()
+    }
+
+    fn var(&mut self) -> Option<String> {
+        let start = self.pos;
+        match self.peek() {
+            Some(c) if c == '_' || c.is_ascii_alphabetic() => self.pos += 1,
+            _ => return None,
+        }
+        while self
+            .peek()
+            .map_or(false, |c| c == '_' || c.is_ascii_alphanumeric())
+        {
+            self.pos += 1;
+        }
+        Some(self.chars[start..self.pos].iter().collect())
+    }
+
+    /// Parse a construct starting with `$`, returns None if it's not a valid one.
+    fn dollar(&mut self) -> Option<SnippetElement> {
+        self.eat('$');
+        if !self.eat('{') {
+            if let Some(index) = self.int() {
+                return Some(SnippetElement::Tabstop {
+                    index,
+                    placeholder: vec![],
+                });
+            }
+            let name = self.var()?;
+            return Some(SnippetElement::Variable {
+                name,
+                default: vec![],
+            });
+        }
+        if let Some(index) = self.int() {
+            if self.eat('}') {
+                return Some(SnippetElement::Tabstop {
+                    index,
+                    placeholder: vec![],
+                });
+            }
+            if self.eat(':') {
+                let placeholder = self.elements(true);
+                return if self.eat('}') {
+                    Some(SnippetElement::Tabstop { index, placeholder })
+                } else {
+                    None
+                };
+            }
+            if self.eat('|') {
+                let options = self.choice_options()?;
+                return Some(SnippetElement::Choice { index, options });
+            }
+            return None;
+        }
+        let name = self.var()?;
+        if self.eat('}') {
+            return Some(SnippetElement::Variable {
+                name,
+                default: vec![],
+            });
+        }
+        if self.eat(':') {
+            let default = self.elements(true);
+            return if self.eat('}') {
+                Some(SnippetElement::Variable { name, default })
+            } else {
+                None
+            };
+        }
+        if self.eat('/') {
+            // Transformations can't be applied without knowing variable values, skip them.
+            let mut slashes = 1;
+            while slashes < 3 {
+                match self.peek()? {
+                    '\\' => self.pos += 1,
+                    '/' => slashes += 1,
+                    _ => {}
+                }
+                self.pos += 1;
+            }
+            while !self.eat('}') {
+                self.peek()?;
+                self.pos += 1;
+            }
+            return Some(SnippetElement::Variable {
+                name,
+                default: vec![],
+            });
+        }
+        None
+    }
+
+    /// Parse `a,b,c|}` part of a choice.
+    fn choice_options(&mut self) -> Option<Vec<String>> {
+        let mut options = vec![];
+        let mut option = String::new();
+        loop {
+            match self.peek()? {
+                '\\' => {
+                    self.pos += 1;
+                    match self.peek()? {
+                        c @ '$' | c @ '}' | c @ '\\' | c @ ',' | c @ '|' => {
+                            self.pos += 1;
+                            option.push(c);
+                        }
+                        _ => option.push('\\'),
+                    }
+                }
+                ',' => {
+                    self.pos += 1;
+                    options.push(option);
+                    option = String::new();
+                }
+                '|' => {
+                    self.pos += 1;
+                    if !self.eat('}') {
+                        return None;
+                    }
+                    options.push(option);
+                    return Some(options);
+                }
+                c => {
+                    self.pos += 1;
+                    option.push(c);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SnippetElement::*;
+    use super::*;
+
+    fn text(s: &str) -> SnippetElement {
+        Text(s.to_string())
+    }
+
+    #[test]
+    fn parse_tabstops_and_placeholders() {
+        assert_eq!(
+            parse("foo($1, ${2:bar})$0"),
+            vec![
+                text("foo("),
+                Tabstop {
+                    index: 1,
+                    placeholder: vec![]
+                },
+                text(", "),
+                Tabstop {
+                    index: 2,
+                    placeholder: vec![text("bar")]
+                },
+                text(")"),
+                Tabstop {
+                    index: 0,
+                    placeholder: vec![]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_nested_placeholder() {
+        assert_eq!(
+            parse("${1:a ${2:b}}"),
+            vec![Tabstop {
+                index: 1,
+                placeholder: vec![
+                    text("a "),
+                    Tabstop {
+                        index: 2,
+                        placeholder: vec![text("b")]
+                    }
+                ]
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_choice() {
+        assert_eq!(
+            parse("${1|a,b\\,c,d|}"),
+            vec![Choice {
+                index: 1,
+                options: vec!["a".to_string(), "b,c".to_string(), "d".to_string()]
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_escaped_dollar() {
+        assert_eq!(parse("\\$1 \\${2}"), vec![text("$1 ${2}")]);
+    }
+
+    #[test]
+    fn parse_escaped_brace() {
+        assert_eq!(
+            parse("${1:a\\}b}}"),
+            vec![
+                Tabstop {
+                    index: 1,
+                    placeholder: vec![text("a}b")]
+                },
+                text("}")
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_escaped_backslash() {
+        assert_eq!(
+            parse("a\\\\$1"),
+            vec![
+                text("a\\"),
+                Tabstop {
+                    index: 1,
+                    placeholder: vec![]
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_invalid_construct_as_text() {
+        assert_eq!(parse("$ ${1:a"), vec![text("$ ${1:a")]);
+    }
+
+    #[test]
+    fn render_flattens_nested_placeholders_and_choices() {
+        assert_eq!(
+            render(&parse("${1:a ${2:b}} ${3|x,y|} ${TM_FILENAME:file} $$0")),
+            "${1:a b} ${3:x} file \\$${0}"
+        );
+    }
+
+    #[test]
+    fn render_escapes_backslash_before_placeholder() {
+        assert_eq!(render(&parse("a\\\\${1}")), "a\\\\${1}");
+        assert_eq!(render(&parse("${TM_DIR:a\\\\}$1")), "a\\\\${1}");
+    }
+
+    #[test]
+    fn render_escapes_defaults() {
+        assert_eq!(render(&parse("${1:a\\}\\\\\\$}")), "${1:a\\}\\\\\\$}");
+    }
+}
@@ -7,6 +7,9 @@ use ropey::{Rope, RopeSlice};
 use std::collections::HashSet;
 use std::fs::File;
 use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+const BOM: char = '\u{feff}';
 
 pub fn apply_text_edits_to_file(
     uri: &Url,
@@ -16,7 +19,8 @@ pub fn apply_text_edits_to_file(
     let mut temp_path = temp_dir();
     temp_path.push(format!("{:x}", rand::random::<u64>()));
 
-    let path = uri.to_file_path().unwrap();
+    // Edit the target of a symlink rather than replace the link with a regular file.
+    let path = std::fs::canonicalize(uri.to_file_path().unwrap())?;
     let filename = path.to_str().unwrap();
 
     let path = std::ffi::CString::new(filename).unwrap();
@@ -37,114 +41,333 @@ pub fn apply_text_edits_to_file(
 
     let temp_file = File::create(&temp_path)?;
 
-    fn apply_text_edits_to_file_impl(
-        text: Rope,
-        temp_file: File,
-        text_edits: &[OneOf<TextEdit, AnnotatedTextEdit>],
-        offset_encoding: OffsetEncoding,
-    ) -> Result<(), std::io::Error> {
-        let mut output = BufWriter::new(temp_file);
-
-        let character_to_offset = match offset_encoding {
-            OffsetEncoding::Utf8 => character_to_offset_utf_8_code_units,
-            // Not a proper UTF-16 code units handling, but works within BMP
-            OffsetEncoding::Utf16 => character_to_offset_utf_8_code_points,
-        };
+    write_edited_text(text, temp_file, text_edits, offset_encoding)
+        .and_then(|_| replace_file(&temp_path, filename))
+        .and_then(|_| {
+            Ok(unsafe {
+                libc::chmod(path.as_ptr(), stat.st_mode);
+                // Only permitted to root unless the owner stays the same, which is fine.
+                libc::chown(path.as_ptr(), stat.st_uid, stat.st_gid);
+            })
+        })
+        .or_else(|e| {
+            let _ = std::fs::remove_file(&temp_path);
+            Err(e)
+        })
+}
 
-        let text_len_lines = text.len_lines() as u64;
-        let mut cursor = 0;
+/// Move the file over the target. Temporary files might be on another file system than the
+/// target, then the contents are copied instead.
+fn replace_file(from: &Path, to: &str) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)
+        }
+        result => result,
+    }
+}
 
-        for te in text_edits {
-            let TextEdit {
-                range: Range { start, end },
-                new_text,
-            } = match te {
-                OneOf::Left(edit) => edit,
-                OneOf::Right(annotated_edit) => &annotated_edit.text_edit,
-            };
-            if start.line as u64 >= text_len_lines || end.line as u64 >= text_len_lines {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Text edit range extends past end of file.",
-                ));
-            }
+/// Write the text with the edits applied. Bytes the edits don't touch are written as they are,
+/// including a leading byte order mark which positions of the edits don't count.
+fn write_edited_text<W: Write>(
+    mut text: Rope,
+    output: W,
+    text_edits: &[OneOf<TextEdit, AnnotatedTextEdit>],
+    offset_encoding: OffsetEncoding,
+) -> Result<(), std::io::Error> {
+    let mut output = BufWriter::new(output);
 
-            let start_offset =
-                character_to_offset(text.line(start.line as _), start.character as _);
-            let end_offset = character_to_offset(text.line(end.line as _), end.character as _);
+    if text.len_chars() > 0 && text.char(0) == BOM {
+        text.remove(0..1);
+        write!(output, "{}", BOM)?;
+    }
 
-            if start_offset.is_none() || end_offset.is_none() {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Text edit range points past end of line.",
-                ));
-            }
+    let crlf = uses_crlf(&text);
+    let text_len_lines = text.len_lines() as u64;
+    let mut cursor = 0;
 
-            let start_char = text.line_to_char(start.line as _) + start_offset.unwrap();
-            let end_char = text.line_to_char(end.line as _) + end_offset.unwrap();
+    for te in text_edits {
+        let TextEdit {
+            range: Range { start, end },
+            new_text,
+        } = match te {
+            OneOf::Left(edit) => edit,
+            OneOf::Right(annotated_edit) => &annotated_edit.text_edit,
+        };
+        if start.line as u64 >= text_len_lines || end.line as u64 >= text_len_lines {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Text edit range extends past end of file.",
+            ));
+        }
 
-            for chunk in text.slice(cursor..start_char).chunks() {
-                output.write_all(chunk.as_bytes())?;
-            }
+        let start_offset = character_to_offset(
+            text.line(start.line as _),
+            start.character as _,
+            offset_encoding,
+        );
+        let end_offset = character_to_offset(
+            text.line(end.line as _),
+            end.character as _,
+            offset_encoding,
+        );
 
-            output.write_all(new_text.as_bytes())?;
-            cursor = end_char;
-        }
+        let start_char = text.line_to_char(start.line as _) + start_offset;
+        let end_char = text.line_to_char(end.line as _) + end_offset;
 
-        for chunk in text.slice(cursor..).chunks() {
+        for chunk in text.slice(cursor..start_char).chunks() {
             output.write_all(chunk.as_bytes())?;
         }
 
-        Ok(())
+        output.write_all(with_line_endings(new_text, crlf).as_bytes())?;
+        cursor = end_char;
     }
 
-    apply_text_edits_to_file_impl(text, temp_file, text_edits, offset_encoding)
-        .and_then(|_| std::fs::rename(&temp_path, filename))
-        .and_then(|_| {
-            Ok(unsafe {
-                libc::chmod(path.as_ptr(), stat.st_mode);
-            })
-        })
-        .or_else(|e| {
-            let _ = std::fs::remove_file(&temp_path);
-            Err(e)
+    for chunk in text.slice(cursor..).chunks() {
+        output.write_all(chunk.as_bytes())?;
+    }
+
+    output.flush()
+}
+
+/// Content of the text with the edits applied, e.g. to preview them.
+pub fn edited_text(
+    text: &Rope,
+    text_edits: &[OneOf<TextEdit, AnnotatedTextEdit>],
+    offset_encoding: OffsetEncoding,
+) -> std::io::Result<String> {
+    let mut text_edits = text_edits.to_vec();
+    // Edits are written out in order, which the spec doesn't guarantee.
+    text_edits.sort_by_key(|text_edit| {
+        let range = match text_edit {
+            OneOf::Left(edit) => edit.range,
+            OneOf::Right(annotated_edit) => annotated_edit.text_edit.range,
+        };
+        (range.start.line, range.start.character)
+    });
+    let mut output = vec![];
+    write_edited_text(text.clone(), &mut output, &text_edits, offset_encoding)?;
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LineChange {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Lines of context around changes in the unified diff.
+const DIFF_CONTEXT: usize = 3;
+
+/// Unified diff of the file contents before and after editing, empty if they are the same.
+pub fn unified_diff(path: &str, old: &str, new: &str) -> String {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    let changes = diff_lines(&old, &new);
+
+    // Group changes not further apart than twice the context into hunks.
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for (i, _) in changes
+        .iter()
+        .enumerate()
+        .filter(|(_, change)| **change != LineChange::Equal)
+    {
+        match hunks.last_mut() {
+            Some((_, end)) if i <= *end + 2 * DIFF_CONTEXT => *end = i + 1,
+            _ => hunks.push((i, i + 1)),
+        }
+    }
+    if hunks.is_empty() {
+        return String::new();
+    }
+
+    // Lines of the old and the new content each change starts at.
+    let mut old_line = 0;
+    let mut new_line = 0;
+    let lines = changes
+        .iter()
+        .map(|change| {
+            let lines = (old_line, new_line);
+            match change {
+                LineChange::Equal => {
+                    old_line += 1;
+                    new_line += 1;
+                }
+                LineChange::Delete => old_line += 1,
+                LineChange::Insert => new_line += 1,
+            }
+            lines
         })
+        .chain(std::iter::once((old.len(), new.len())))
+        .collect::<Vec<_>>();
+
+    let mut diff = format!("--- {}\n+++ {}\n", path, path);
+    for (start, end) in hunks {
+        let start = start.saturating_sub(DIFF_CONTEXT);
+        let end = (end + DIFF_CONTEXT).min(changes.len());
+        let (old_start, new_start) = lines[start];
+        let (old_end, new_end) = lines[end];
+        // Empty ranges are denoted by the line before them.
+        let range = |start: usize, end: usize| {
+            if start == end {
+                format!("{},0", start)
+            } else {
+                format!("{},{}", start + 1, end - start)
+            }
+        };
+        diff.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(old_start, old_end),
+            range(new_start, new_end)
+        ));
+        for (change, (old_line, new_line)) in changes[start..end].iter().zip(&lines[start..end]) {
+            let line = match change {
+                LineChange::Equal => format!(" {}", old[*old_line]),
+                LineChange::Delete => format!("-{}", old[*old_line]),
+                LineChange::Insert => format!("+{}", new[*new_line]),
+            };
+            diff.push_str(&line);
+            diff.push('\n');
+        }
+    }
+    diff
 }
 
-fn character_to_offset_utf_8_code_points(line: RopeSlice, character: usize) -> Option<usize> {
-    if character < line.len_chars() {
-        Some(character)
-    } else {
-        None
+/// Shortest sequence of line changes turning old lines into new ones, found with Myers'
+/// algorithm.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<LineChange> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max = (n + m) as usize;
+    // Furthest reaching x on each diagonal k = x - y, indexed by k + offset.
+    let offset = max as isize + 1;
+    let mut v = vec![0isize; 2 * max + 3];
+    // Diagonals -d - 1..=d + 1 of v before each step d, needed to trace the path back.
+    let mut trace = vec![];
+    'search: for d in 0..=max as isize {
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) {
+                v[i + 1]
+            } else {
+                v[i - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut changes = vec![];
+    let mut x = n;
+    let mut y = m;
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            changes.push(LineChange::Equal);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            changes.push(if x == prev_x {
+                LineChange::Insert
+            } else {
+                LineChange::Delete
+            });
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    changes.reverse();
+    changes
+}
+
+/// Index of the character at the offset in code units within the line. Offsets within `\r\n` point
+/// before it, and so do ones past the end of line which misbehaving servers might send.
+fn character_to_offset(
+    line: RopeSlice,
+    character: usize,
+    offset_encoding: OffsetEncoding,
+) -> usize {
+    let char_idx = code_units_to_char(line, character, offset_encoding);
+    let len_without_ending = line_len_without_ending(line);
+    if char_idx > len_without_ending && char_idx >= line.len_chars() {
+        warn!("Offset {} is past the end of line, clamping", character);
     }
+    char_idx.min(len_without_ending)
 }
 
-fn character_to_offset_utf_8_code_units(line: RopeSlice, character: usize) -> Option<usize> {
-    if character < line.len_bytes() {
-        Some(line.byte_to_char(character))
+/// Convert line endings of the new text to the ones of the document, as servers might use
+/// either.
+fn with_line_endings(new_text: &str, crlf: bool) -> String {
+    let new_text = new_text.replace("\r\n", "\n");
+    if crlf {
+        new_text.replace('\n', "\r\n")
     } else {
-        None
+        new_text
     }
 }
 
+/// Editor command applying the edits to the buffer. All of them are made within a single
+/// `evaluate-commands -draft`, so they form one undo step.
 pub fn apply_text_edits_to_buffer(
     uri: Option<&Url>,
     text_edits: &[OneOf<TextEdit, AnnotatedTextEdit>],
     text: &Rope,
     offset_encoding: OffsetEncoding,
 ) -> String {
-    // Empty text edits processed as a special case because Kakoune's `select` command
-    // doesn't support empty arguments list.
-    if text_edits.is_empty() {
-        // Nothing to do, but sending command back to the editor is required to handle case when
-        // editor is blocked waiting for response via fifo.
-        return "nop".to_string();
+    let edits = sorted_kakoune_edits(text_edits, text, offset_encoding);
+    kakoune_edits_command(uri, &edits)
+}
+
+/// Editor commands applying the edits to the buffer in chunks of at most `chunk_size` edits, so
+/// that the editor can handle input in between. Chunks go from the end of the buffer to its
+/// start, hence positions of the edits yet to be applied stay valid.
+pub fn apply_text_edits_to_buffer_in_chunks(
+    uri: Option<&Url>,
+    text_edits: &[OneOf<TextEdit, AnnotatedTextEdit>],
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+    chunk_size: usize,
+) -> Vec<String> {
+    let edits = sorted_kakoune_edits(text_edits, text, offset_encoding);
+    if edits.is_empty() {
+        return vec![kakoune_edits_command(uri, &edits)];
     }
+    edits
+        .chunks(chunk_size.max(1))
+        .rev()
+        .map(|chunk| kakoune_edits_command(uri, chunk))
+        .collect()
+}
+
+fn sorted_kakoune_edits(
+    text_edits: &[OneOf<TextEdit, AnnotatedTextEdit>],
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Vec<KakouneTextEdit> {
     let mut edits = text_edits
         .iter()
-        .map(|text_edit| lsp_text_edit_to_kakoune(text_edit, text, offset_encoding))
+        .filter_map(|text_edit| lsp_text_edit_to_kakoune(text_edit, text, offset_encoding))
         .collect::<Vec<_>>();
-
     // Adjoin selections detection and Kakoune side editing relies on edits being ordered left to
     // right. Language servers usually send them such, but spec doesn't say anything about the order
     // hence we ensure it by sorting. It's improtant to use stable sort to handle properly cases
@@ -157,6 +380,17 @@ pub fn apply_text_edits_to_buffer(
             x.range.end.column,
         )
     });
+    edits
+}
+
+fn kakoune_edits_command(uri: Option<&Url>, edits: &[KakouneTextEdit]) -> String {
+    // Empty text edits processed as a special case because Kakoune's `select` command
+    // doesn't support empty arguments list.
+    if edits.is_empty() {
+        // Nothing to do, but sending command back to the editor is required to handle case when
+        // editor is blocked waiting for response via fifo.
+        return "nop".to_string();
+    }
 
     let select_edits = edits
         .iter()
@@ -251,19 +485,27 @@ struct KakouneTextEdit {
     command: KakouneTextEditCommand,
 }
 
+/// Convert the edit unless it leaves the text as it is. Formatters often replace whole lines with
+/// the same content, and Kakoune merges such selections with adjoining ones of real edits, which
+/// might get lost then.
 fn lsp_text_edit_to_kakoune(
     text_edit: &OneOf<TextEdit, AnnotatedTextEdit>,
     text: &Rope,
     offset_encoding: OffsetEncoding,
-) -> KakouneTextEdit {
+) -> Option<KakouneTextEdit> {
     let TextEdit { range, new_text } = match text_edit {
         OneOf::Left(edit) => edit,
         OneOf::Right(annotated_edit) => &annotated_edit.text_edit,
     };
-    let Range { start, end } = range;
+    let start = clamp_to_line_end(range.start, text, offset_encoding);
+    let end = clamp_to_line_end(range.end, text, offset_encoding);
     let insert = start.line == end.line && start.character == end.character;
+    let new_text = with_line_endings(new_text, uses_crlf(text));
+    if is_redundant(start, end, &new_text, text, offset_encoding) {
+        return None;
+    }
 
-    let range = lsp_range_to_kakoune(&range, text, offset_encoding);
+    let range = lsp_range_to_kakoune(&Range { start, end }, text, offset_encoding);
 
     let command = if insert {
         KakouneTextEditCommand::InsertBefore
@@ -271,9 +513,248 @@ fn lsp_text_edit_to_kakoune(
         KakouneTextEditCommand::Replace
     };
 
-    KakouneTextEdit {
+    Some(KakouneTextEdit {
         range,
-        new_text: new_text.to_owned(),
+        new_text,
         command,
+    })
+}
+
+/// Whether replacing the range with the new text, on one line or spanning several, changes
+/// nothing.
+fn is_redundant(
+    start: Position,
+    end: Position,
+    new_text: &str,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> bool {
+    let to_char = |position: Position| {
+        let line_idx = position.line as usize;
+        if line_idx >= text.len_lines() {
+            return None;
+        }
+        let line = text.line(line_idx);
+        let character = position.character as usize;
+        Some(text.line_to_char(line_idx) + code_units_to_char(line, character, offset_encoding))
+    };
+    match (to_char(start), to_char(end)) {
+        (Some(start), Some(end)) if start <= end => text.slice(start..end) == new_text,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf_16_offset_after_emoji() {
+        let text = Rope::from_str("let s = \"😀\"; x\n");
+        let line = text.line(0);
+        let offset = |character| character_to_offset(line, character, OffsetEncoding::Utf16);
+        // The closing quote follows the two code units of the emoji.
+        assert_eq!(offset(11), 10);
+        assert_eq!(offset(14), 13);
+        // Past the end of line.
+        assert_eq!(offset(16), 14);
+    }
+
+    #[test]
+    fn crlf_end_of_line_edit_lands_before_cr() {
+        let text = Rope::from_str("foo\r\nbar\r\n");
+        let line = text.line(0);
+        assert_eq!(character_to_offset(line, 3, OffsetEncoding::Utf8), 3);
+        // Offset between `\r` and `\n` doesn't split the line ending.
+        assert_eq!(character_to_offset(line, 4, OffsetEncoding::Utf8), 3);
+        assert_eq!(with_line_endings("baz\nqux", true), "baz\r\nqux");
+        assert_eq!(with_line_endings("baz\r\nqux", false), "baz\nqux");
+    }
+
+    fn edited(text: &str, edits: &[((u32, u32), (u32, u32), &str)]) -> String {
+        let edits = edits
+            .iter()
+            .map(|&((start_line, start), (end_line, end), new_text)| {
+                OneOf::Left(TextEdit {
+                    range: Range {
+                        start: Position::new(start_line, start),
+                        end: Position::new(end_line, end),
+                    },
+                    new_text: new_text.to_string(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut output = vec![];
+        write_edited_text(
+            Rope::from_str(text),
+            &mut output,
+            &edits,
+            OffsetEncoding::Utf8,
+        )
+        .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn file_edits_keep_bom_and_final_newline_state() {
+        // Positions don't count the byte order mark.
+        assert_eq!(
+            edited("\u{feff}fn a() {}\n", &[((0, 3), (0, 4), "b")]),
+            "\u{feff}fn b() {}\n"
+        );
+        // Replacing up to the end of the last line doesn't add a final newline.
+        assert_eq!(
+            edited("x = 1\ny = 2", &[((1, 0), (1, 5), "y = 3")]),
+            "x = 1\ny = 3"
+        );
+        assert_eq!(
+            edited("x = 1\ny = 2\n", &[((1, 4), (1, 5), "3")]),
+            "x = 1\ny = 3\n"
+        );
+    }
+
+    #[test]
+    fn file_edit_through_symlink_keeps_link() {
+        let tmp = std::env::temp_dir().join(format!("kak-lsp-test-edit-{}", std::process::id()));
+        std::fs::create_dir_all(&tmp).unwrap();
+        let target = tmp.join("target.toml");
+        let link = tmp.join("link.toml");
+        std::fs::write(&target, "a = 1\n").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let edit = OneOf::Left(TextEdit {
+            range: Range {
+                start: Position::new(0, 4),
+                end: Position::new(0, 5),
+            },
+            new_text: "2".to_string(),
+        });
+        let uri = Url::from_file_path(&link).unwrap();
+        apply_text_edits_to_file(&uri, &[edit], OffsetEncoding::Utf8).unwrap();
+
+        assert!(std::fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "a = 2\n");
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn buffer_edit_past_end_of_line_is_clamped() {
+        let text = Rope::from_str("foo\nbar\n");
+        let edit = OneOf::Left(TextEdit {
+            range: Range {
+                start: Position::new(0, 10),
+                end: Position::new(0, 10),
+            },
+            new_text: "baz".to_string(),
+        });
+        let command = apply_text_edits_to_buffer(None, &[edit], &text, OffsetEncoding::Utf16);
+        assert!(command.contains("select 1.4,1.4"), "{}", command);
+    }
+
+    #[test]
+    fn buffer_edits_are_one_undo_step() {
+        let text = Rope::from_str("foo\nbar\nbaz\n");
+        let edit = |line, new_text: &str| {
+            OneOf::Left(TextEdit {
+                range: Range {
+                    start: Position::new(line, 0),
+                    end: Position::new(line, 1),
+                },
+                new_text: new_text.to_string(),
+            })
+        };
+        let edits = [edit(2, "B"), edit(0, "F"), edit(1, "B")];
+        let uri = Url::from_file_path("/tmp/foo.txt").unwrap();
+        let command = apply_text_edits_to_buffer(Some(&uri), &edits, &text, OffsetEncoding::Utf16);
+        assert!(
+            command.starts_with("evaluate-commands -buffer "),
+            "{}",
+            command
+        );
+        assert_eq!(command.matches("eval -draft").count(), 1, "{}", command);
+        assert_eq!(command.matches("select ").count(), 1, "{}", command);
+        assert_eq!(command.matches("lsp-replace-selection").count(), 3);
+    }
+
+    #[test]
+    fn chunks_go_from_end_of_buffer() {
+        let text = Rope::from_str("foo\nbar\nbaz\n");
+        let edit = |line, new_text: &str| {
+            OneOf::Left(TextEdit {
+                range: Range {
+                    start: Position::new(line, 0),
+                    end: Position::new(line, 1),
+                },
+                new_text: new_text.to_string(),
+            })
+        };
+        let edits = [edit(0, "F"), edit(2, "B"), edit(1, "B")];
+        let commands =
+            apply_text_edits_to_buffer_in_chunks(None, &edits, &text, OffsetEncoding::Utf16, 2);
+        assert_eq!(commands.len(), 2);
+        assert!(commands[0].contains("select 3.1,3.1\n"), "{}", commands[0]);
+        assert!(
+            commands[1].contains("select 1.1,1.1 2.1,2.1\n"),
+            "{}",
+            commands[1]
+        );
+    }
+
+    #[test]
+    fn unified_diff_of_edited_text() {
+        let text = Rope::from_str("a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\n");
+        let edit = |line, new_text: &str| {
+            OneOf::Left(TextEdit {
+                range: Range {
+                    start: Position::new(line, 0),
+                    end: Position::new(line + 1, 0),
+                },
+                new_text: new_text.to_string(),
+            })
+        };
+        let edits = [edit(10, ""), edit(1, "B\nB2\n")];
+        let new_text = edited_text(&text, &edits, OffsetEncoding::Utf16).unwrap();
+        assert_eq!(new_text, "a\nB\nB2\nc\nd\ne\nf\ng\nh\ni\nj\n");
+        assert_eq!(
+            unified_diff("/f", &text.to_string(), &new_text),
+            "--- /f\n+++ /f\n\
+             @@ -1,5 +1,6 @@\n a\n-b\n+B\n+B2\n c\n d\n e\n\
+             @@ -8,4 +9,3 @@\n h\n i\n j\n-k\n"
+        );
+        assert_eq!(unified_diff("/f", "a\n", "a\n"), "");
+        assert_eq!(
+            unified_diff("/f", "", "a\n"),
+            "--- /f\n+++ /f\n@@ -0,0 +1,1 @@\n+a\n"
+        );
+    }
+
+    #[test]
+    fn redundant_multi_line_edit_is_dropped() {
+        let text = Rope::from_str("a\nb\nc\nd\n");
+        let edit = |start, end, new_text: &str| {
+            OneOf::Left(TextEdit {
+                range: Range { start, end },
+                new_text: new_text.to_string(),
+            })
+        };
+        let edits = [
+            edit(Position::new(0, 0), Position::new(2, 0), "a\nb\n"),
+            edit(Position::new(2, 0), Position::new(2, 1), "C"),
+        ];
+        let command = apply_text_edits_to_buffer(None, &edits, &text, OffsetEncoding::Utf16);
+        assert!(command.contains("select 3.1,3.1\n"), "{}", command);
+        assert!(!command.contains("1.1"), "{}", command);
+    }
+
+    #[test]
+    fn offsets_after_emoji_in_each_encoding() {
+        let text = Rope::from_str("a😀b\n");
+        let line = text.line(0);
+        assert_eq!(character_to_offset(line, 5, OffsetEncoding::Utf8), 2);
+        assert_eq!(character_to_offset(line, 3, OffsetEncoding::Utf16), 2);
+        assert_eq!(character_to_offset(line, 2, OffsetEncoding::Utf32), 2);
+        assert_eq!(character_to_offset(line, 4, OffsetEncoding::Utf32), 3);
     }
 }
@@ -1,11 +1,29 @@
 use crate::context::*;
+use crate::diagnostics;
+use crate::language_features::formatting::editor_formatting;
+use crate::language_features::{code_lens, document_color, document_link, on_type_formatting};
+use crate::position::char_to_lsp_position;
 use crate::types::*;
+use crate::util::editor_quote;
 use lsp_types::notification::*;
 use lsp_types::*;
 use ropey::Rope;
 use serde::Deserialize;
+use std::time::Duration;
 use url::Url;
 
+/// How long a buffer change is held back. Newer content of the buffer arriving meanwhile, e.g. on
+/// fast typing or big multi-cursor edits, replaces it, so the server is notified less often.
+/// Anything else sent to the server goes after pending changes.
+const DID_CHANGE_WINDOW: Duration = Duration::from_millis(50);
+
+/// Buffer change waiting to be sent to the server.
+pub struct PendingDidChange {
+    // Buffer content the server knows about.
+    base: Rope,
+    pub params: DidChangeTextDocumentParams,
+}
+
 pub fn text_document_did_open(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = TextDocumentDidOpenParams::deserialize(params)
         .expect("Params should follow TextDocumentDidOpenParams structure");
@@ -24,11 +42,26 @@ pub fn text_document_did_open(meta: EditorMeta, params: EditorParams, ctx: &mut
     };
     ctx.documents.insert(meta.buffile.clone(), document);
     ctx.notify::<DidOpenTextDocument>(params);
+    // Saving the buffer only waits for the server if it might want to edit it first.
+    let (_, will_save_wait_until) = will_save_capabilities(ctx.capabilities.as_ref());
+    if will_save_wait_until {
+        let command = format!(
+            "eval -buffer {} -verbatim -- set-option buffer lsp_will_save_wait_until true",
+            editor_quote(&meta.buffile)
+        );
+        ctx.exec(meta.clone(), command);
+    }
+    diagnostics::show_stale_diagnostics(&meta.buffile, ctx);
+    document_color::refresh_document_colors(meta.clone(), ctx);
+    document_link::refresh_document_links(meta, ctx);
 }
 
 pub fn text_document_did_change(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = TextDocumentDidChangeParams::deserialize(params)
         .expect("Params should follow TextDocumentDidChangeParams structure");
+    if let Some(debounce) = params.diagnostics_debounce {
+        ctx.diagnostics_debounce = ctx.debounce_delay(&meta, debounce);
+    }
     let uri = Url::from_file_path(&meta.buffile).unwrap();
     let version = meta.version;
     let old_version = ctx
@@ -43,24 +76,158 @@ pub fn text_document_did_change(meta: EditorMeta, params: EditorParams, ctx: &mu
         version,
         text: Rope::from_str(&params.draft),
     };
-    ctx.documents.insert(meta.buffile.clone(), document);
-    ctx.diagnostics.insert(meta.buffile.clone(), Vec::new());
+    let buffile = meta.buffile.clone();
+    let old_document = ctx.documents.remove(&buffile);
+    if let Some(old_document) = &old_document {
+        on_type_formatting::record_typed_text(
+            &buffile,
+            &old_document.text,
+            &document.text,
+            version,
+            ctx,
+        );
+    }
+    // Changes not sent yet are coalesced with this one, so the change is computed against the
+    // content the server knows about.
+    let pending = ctx.pending_did_change.remove(&buffile);
+    let scheduled = pending.is_some();
+    let base = pending
+        .map(|pending| pending.base)
+        .or_else(|| old_document.map(|document| document.text));
+    let content_change = match (sync_kind(ctx), &base) {
+        (TextDocumentSyncKind::Incremental, Some(base)) => {
+            incremental_change(base, &document.text, ctx.offset_encoding)
+        }
+        _ => TextDocumentContentChangeEvent {
+            range: None,
+            range_length: None,
+            text: params.draft,
+        },
+    };
+    ctx.documents.insert(buffile.clone(), document);
+    ctx.diagnostics.insert(buffile.clone(), Vec::new());
     let params = DidChangeTextDocumentParams {
         text_document: VersionedTextDocumentIdentifier {
             uri,
             version: meta.version,
         },
-        content_changes: vec![TextDocumentContentChangeEvent {
-            range: None,
-            range_length: None,
-            text: params.draft,
-        }],
+        content_changes: vec![content_change],
+    };
+    ctx.pending_did_change.insert(
+        buffile.clone(),
+        PendingDidChange {
+            base: base.unwrap_or_default(),
+            params,
+        },
+    );
+    if !scheduled {
+        // Editor requests are debounced under their method and buffer, this must not replace it.
+        ctx.debounce(format!("flush {}", buffile), DID_CHANGE_WINDOW, |ctx| {
+            ctx.flush_did_change()
+        });
+    }
+    // Refreshing is postponed as well, otherwise its requests would flush the change at once.
+    ctx.debounce(
+        format!("refresh {}", buffile),
+        DID_CHANGE_WINDOW,
+        move |ctx| {
+            document_color::refresh_document_colors(meta.clone(), ctx);
+            document_link::refresh_document_links(meta, ctx);
+        },
+    );
+}
+
+/// Whether the server wants willSave notifications and willSaveWaitUntil requests.
+pub fn will_save_capabilities(capabilities: Option<&ServerCapabilities>) -> (bool, bool) {
+    match capabilities.and_then(|capabilities| capabilities.text_document_sync.as_ref()) {
+        Some(TextDocumentSyncCapability::Options(options)) => (
+            options.will_save == Some(true),
+            options.will_save_wait_until == Some(true),
+        ),
+        _ => (false, false),
+    }
+}
+
+/// Let the server know the buffer is about to be saved, passing its content along as it might have
+/// changed since the last sync. If the request came through a fifo, Kakoune waits for the edits
+/// the server wants to be made before saving, if any. Otherwise it's already saving.
+pub fn text_document_will_save(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    text_document_did_change(meta.clone(), params, ctx);
+    let (will_save, will_save_wait_until) = will_save_capabilities(ctx.capabilities.as_ref());
+    let params = WillSaveTextDocumentParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        reason: TextDocumentSaveReason::Manual,
     };
-    ctx.notify::<DidChangeTextDocument>(params);
+    if will_save {
+        ctx.notify::<WillSaveTextDocument>(params.clone());
+    }
+    if meta.fifo.is_none() {
+        return;
+    }
+    if !will_save_wait_until {
+        // Nothing to do, but sending command back to the editor is required to handle case when
+        // editor is blocked waiting for response via fifo.
+        ctx.exec(meta, "nop".to_string());
+        return;
+    }
+    ctx.call::<request::WillSaveWaitUntil, _>(meta, params, move |ctx, meta, result| {
+        editor_formatting(meta, result, ctx)
+    });
+}
+
+/// How the server wants buffer changes to be sent, full content by default.
+fn sync_kind(ctx: &Context) -> TextDocumentSyncKind {
+    match ctx
+        .capabilities
+        .as_ref()
+        .and_then(|capabilities| capabilities.text_document_sync.as_ref())
+    {
+        Some(TextDocumentSyncCapability::Kind(kind)) => *kind,
+        Some(TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
+            change: Some(kind),
+            ..
+        })) => *kind,
+        _ => TextDocumentSyncKind::Full,
+    }
+}
+
+/// Single change turning old content into new one, spanning from the first to the last character
+/// that differ.
+fn incremental_change(
+    old: &Rope,
+    new: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> TextDocumentContentChangeEvent {
+    let prefix = old
+        .chars()
+        .zip(new.chars())
+        .take_while(|(old, new)| old == new)
+        .count();
+    let max_suffix = old.len_chars().min(new.len_chars()) - prefix;
+    let suffix = (1..=max_suffix)
+        .take_while(|i| old.char(old.len_chars() - i) == new.char(new.len_chars() - i))
+        .count();
+    let old_end = old.len_chars() - suffix;
+    let new_end = new.len_chars() - suffix;
+    TextDocumentContentChangeEvent {
+        range: Some(Range {
+            start: char_to_lsp_position(prefix, old, offset_encoding),
+            end: char_to_lsp_position(old_end, old, offset_encoding),
+        }),
+        range_length: None,
+        text: new.slice(prefix..new_end).to_string(),
+    }
 }
 
 pub fn text_document_did_close(meta: EditorMeta, ctx: &mut Context) {
     ctx.documents.remove(&meta.buffile);
+    ctx.document_colors.remove(&meta.buffile);
+    ctx.document_highlights.remove(&meta.buffile);
+    ctx.document_links.remove(&meta.buffile);
+    ctx.code_lenses.remove(&meta.buffile);
+    ctx.typed_text.remove(&meta.buffile);
     let uri = Url::from_file_path(&meta.buffile).unwrap();
     let params = DidCloseTextDocumentParams {
         text_document: TextDocumentIdentifier { uri },
@@ -70,9 +237,198 @@ pub fn text_document_did_close(meta: EditorMeta, ctx: &mut Context) {
 
 pub fn text_document_did_save(meta: EditorMeta, ctx: &mut Context) {
     let uri = Url::from_file_path(&meta.buffile).unwrap();
-    let params = DidSaveTextDocumentParams {
-        text_document: TextDocumentIdentifier { uri },
-        text: None,
-    };
+    let sync = ctx
+        .capabilities
+        .as_ref()
+        .and_then(|capabilities| capabilities.text_document_sync.as_ref());
+    let text = ctx
+        .documents
+        .get(&meta.buffile)
+        .map(|document| &document.text);
+    let params = did_save_params(uri, text, sync);
     ctx.notify::<DidSaveTextDocument>(params);
+    code_lens::refresh_code_lenses(meta, ctx);
+}
+
+/// Some servers, e.g. linters only running on save, want the saved content along.
+fn did_save_params(
+    uri: Url,
+    text: Option<&Rope>,
+    sync: Option<&TextDocumentSyncCapability>,
+) -> DidSaveTextDocumentParams {
+    let include_text = match sync {
+        Some(TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
+            save: Some(TextDocumentSyncSaveOptions::SaveOptions(options)),
+            ..
+        })) => options.include_text == Some(true),
+        _ => false,
+    };
+    DidSaveTextDocumentParams {
+        text_document: TextDocumentIdentifier { uri },
+        text: text.filter(|_| include_text).map(Rope::to_string),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::debounce_editor_request;
+    use crossbeam_channel::{unbounded, Receiver};
+    use jsonrpc_core::Call;
+    use std::thread;
+
+    fn meta(version: i32) -> EditorMeta {
+        EditorMeta {
+            session: "session".to_string(),
+            client: Some("client0".to_string()),
+            buffile: "/src/main.rs".to_string(),
+            filetype: "rust".to_string(),
+            version,
+            fifo: None,
+            fan_out: None,
+        }
+    }
+
+    fn draft_params(draft: &str) -> EditorParams {
+        let mut params = toml::value::Table::new();
+        params.insert("draft".to_string(), toml::Value::String(draft.to_string()));
+        toml::Value::Table(params)
+    }
+
+    fn context() -> (Context, Receiver<ServerMessage>) {
+        let (lang_srv_tx, lang_srv_rx) = unbounded();
+        let (editor_tx, _editor_rx) = unbounded();
+        let initial_request = EditorRequest {
+            meta: meta(1),
+            method: "initialize".to_string(),
+            params: draft_params(""),
+            debounce: 0,
+            server: None,
+        };
+        let config: Config = toml::from_str("[language]").unwrap();
+        let mut ctx = Context::new(
+            "rust",
+            initial_request,
+            lang_srv_tx,
+            editor_tx,
+            config,
+            "/src".to_string(),
+            OffsetEncoding::Utf16,
+        );
+        ctx.capabilities = Some(ServerCapabilities::default());
+        text_document_did_open(meta(1), draft_params(""), &mut ctx);
+        (ctx, lang_srv_rx)
+    }
+
+    fn sent_changes(lang_srv_rx: &Receiver<ServerMessage>) -> Vec<DidChangeTextDocumentParams> {
+        lang_srv_rx
+            .try_iter()
+            .filter_map(|msg| match msg {
+                ServerMessage::Request(Call::Notification(notification))
+                    if notification.method == DidChangeTextDocument::METHOD =>
+                {
+                    notification
+                        .params
+                        .parse::<DidChangeTextDocumentParams>()
+                        .ok()
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rapid_edits_are_coalesced() {
+        let (mut ctx, lang_srv_rx) = context();
+        let edits = 100;
+        let mut draft = String::new();
+        for version in 2..edits + 2 {
+            draft.push('x');
+            text_document_did_change(meta(version), draft_params(&draft), &mut ctx);
+        }
+        thread::sleep(DID_CHANGE_WINDOW);
+        ctx.run_debounced();
+
+        let changes = sent_changes(&lang_srv_rx);
+        assert!(!changes.is_empty() && changes.len() < edits as usize);
+        let last = changes.last().unwrap();
+        assert_eq!(last.text_document.version, edits + 1);
+        assert_eq!(last.content_changes[0].text, draft);
+        assert_eq!(ctx.documents["/src/main.rs"].text.to_string(), draft);
+    }
+
+    #[test]
+    fn last_edit_from_editor_is_flushed() {
+        let (mut ctx, lang_srv_rx) = context();
+        // Requests from the editor are debounced by the controller as well, without delay here.
+        for (version, draft) in [(2, "x"), (3, "xy")].iter() {
+            let request = EditorRequest {
+                meta: meta(*version),
+                method: DidChangeTextDocument::METHOD.to_string(),
+                params: draft_params(draft),
+                debounce: 0,
+                server: None,
+            };
+            debounce_editor_request(request, &mut ctx);
+        }
+        thread::sleep(DID_CHANGE_WINDOW);
+        ctx.run_debounced();
+
+        let changes = sent_changes(&lang_srv_rx);
+        let last = changes.last().expect("Change should have been sent");
+        assert_eq!(last.text_document.version, 3);
+        assert_eq!(last.content_changes[0].text, "xy");
+    }
+
+    #[test]
+    fn single_character_insert_is_minimal_change() {
+        let old = Rope::from_str("fn main() {\n    let é = 1;\n}\n");
+        let new = Rope::from_str("fn main() {\n    let é = 12;\n}\n");
+        let change = incremental_change(&old, &new, OffsetEncoding::Utf16);
+        assert_eq!(
+            change.range,
+            Some(Range::new(Position::new(1, 13), Position::new(1, 13)))
+        );
+        assert_eq!(change.text, "2");
+        // Offsets are in bytes with UTF-8 encoding.
+        let change = incremental_change(&old, &new, OffsetEncoding::Utf8);
+        assert_eq!(
+            change.range,
+            Some(Range::new(Position::new(1, 14), Position::new(1, 14)))
+        );
+    }
+
+    #[test]
+    fn repeated_characters_are_not_counted_twice() {
+        // The common prefix and suffix overlap when deleting one of repeated characters.
+        let old = Rope::from_str("aaa\n");
+        let new = Rope::from_str("aa\n");
+        let change = incremental_change(&old, &new, OffsetEncoding::Utf16);
+        assert_eq!(
+            change.range,
+            Some(Range::new(Position::new(0, 2), Position::new(0, 3)))
+        );
+        assert_eq!(change.text, "");
+    }
+
+    #[test]
+    fn did_save_includes_text_on_request() {
+        let uri = Url::parse("file:///src/main.rs").unwrap();
+        let text = Rope::from_str("fn main() {}\n");
+        let sync = |include_text| {
+            TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
+                save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions {
+                    include_text: Some(include_text),
+                })),
+                ..TextDocumentSyncOptions::default()
+            })
+        };
+        let params = did_save_params(uri.clone(), Some(&text), Some(&sync(true)));
+        assert_eq!(params.text.as_deref(), Some("fn main() {}\n"));
+        let params = did_save_params(uri.clone(), Some(&text), Some(&sync(false)));
+        assert_eq!(params.text, None);
+        let full_sync = TextDocumentSyncCapability::Kind(TextDocumentSyncKind::Full);
+        let params = did_save_params(uri, Some(&text), Some(&full_sync));
+        assert_eq!(params.text, None);
+    }
 }
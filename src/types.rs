@@ -1,5 +1,4 @@
 use jsonrpc_core::{Call, Output, Params};
-use lsp_types::Range;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
@@ -20,6 +19,10 @@ pub struct Config {
     pub verbosity: u8,
     #[serde(default)]
     pub snippet_support: bool,
+    // Apply edits to buffers in chunks of this many edits, letting the editor handle input in
+    // between, once an edit makes more changes than that. 0 disables chunking.
+    #[serde(default)]
+    pub edit_chunk_size: usize,
     #[serde(default)]
     pub semantic_scopes: HashMap<String, String>,
     #[serde(default)]
@@ -34,18 +37,104 @@ pub struct ServerConfig {
     pub session: String,
     #[serde(default)]
     pub timeout: u64,
+    // How many times a crashed language server is restarted in a row before giving up.
+    #[serde(default = "default_restart_attempts")]
+    pub restart_attempts: u32,
+    // Seconds to wait for the language server to answer shutdown request, then to exit.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
 }
 
 #[derive(Clone, Deserialize, Debug)]
 pub struct LanguageConfig {
     pub filetypes: Vec<String>,
+    // Files or globs marking the project root, most significant first.
+    #[serde(alias = "root_markers")]
     pub roots: Vec<String>,
+    // Serve all projects of the language in the editor session with one server, adding their
+    // roots as workspace folders.
+    #[serde(default)]
+    pub single_instance: bool,
+    // Attach to buffers of the filetypes along with the language server configured for them
+    // without this flag, to show diagnostics and provide features the other one doesn't.
+    #[serde(default)]
+    pub secondary: bool,
+    // Not needed to connect to a running server over TCP.
+    #[serde(default)]
     pub command: String,
     #[serde(default)]
     pub args: Vec<String>,
+    // Environment variables set for the server on top of the inherited ones.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub transport: Transport,
+    // Where to connect to the server with TCP transport, host defaults to localhost.
+    pub host: Option<String>,
+    pub port: Option<u16>,
     pub initialization_options: Option<Value>,
-    #[serde(default = "default_offset_encoding")]
-    pub offset_encoding: OffsetEncoding,
+    // Overrides the offset encoding negotiated with the server, for servers which don't follow
+    // the spec.
+    pub offset_encoding: Option<OffsetEncoding>,
+    // Returned for workspace/configuration requests.
+    pub settings: Option<Value>,
+    // Settings overriding the above ones for requests scoped to a particular folder.
+    #[serde(default)]
+    pub workspace_settings: HashMap<String, Value>,
+    // Features the server is used for out of FEATURES, all unless given.
+    pub features: Option<Vec<String>>,
+    #[serde(default)]
+    pub disabled_features: Vec<String>,
+    // Language whose server formats buffers of the filetypes when several servers are attached.
+    pub formatting_server: Option<String>,
+}
+
+/// Names of features which can be enabled or disabled per language server.
+pub const FEATURES: &[&str] = &[
+    "call_hierarchy",
+    "code_action",
+    "code_lens",
+    "color",
+    "completion",
+    "definition",
+    "diagnostics",
+    "document_highlight",
+    "document_link",
+    "document_symbol",
+    "folding_range",
+    "formatting",
+    "hover",
+    "inlay_hints",
+    "linked_editing_range",
+    "moniker",
+    "references",
+    "rename",
+    "selection_range",
+    "signature_help",
+    "type_hierarchy",
+    "workspace_symbol",
+];
+
+impl LanguageConfig {
+    /// Whether the feature is enabled for the language server.
+    pub fn provides(&self, feature: &str) -> bool {
+        let enabled = self
+            .features
+            .as_ref()
+            .map_or(true, |features| features.iter().any(|f| f == feature));
+        enabled && !self.disabled_features.iter().any(|f| f == feature)
+    }
+
+    /// Feature names in the configuration which are not known, most likely misspelled.
+    pub fn unknown_features(&self) -> Vec<&str> {
+        self.features
+            .iter()
+            .flatten()
+            .chain(&self.disabled_features)
+            .map(String::as_str)
+            .filter(|feature| !FEATURES.contains(feature))
+            .collect()
+    }
 }
 
 impl Default for ServerConfig {
@@ -53,12 +142,29 @@ impl Default for ServerConfig {
         ServerConfig {
             session: String::new(),
             timeout: 0,
+            restart_attempts: default_restart_attempts(),
+            shutdown_timeout: default_shutdown_timeout(),
         }
     }
 }
 
-fn default_offset_encoding() -> OffsetEncoding {
-    OffsetEncoding::Utf16
+fn default_restart_attempts() -> u32 {
+    5
+}
+
+fn default_shutdown_timeout() -> u64 {
+    2
+}
+
+/// How to talk to the language server.
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Spawn the server and talk over its stdin and stdout.
+    #[default]
+    Stdio,
+    /// Connect to a server which is already running.
+    Tcp,
 }
 
 // Editor
@@ -71,6 +177,19 @@ pub struct EditorMeta {
     pub filetype: String,
     pub version: i32,
     pub fifo: Option<String>,
+    // Set by the session when the request is sent to several language servers.
+    #[serde(skip)]
+    pub fan_out: Option<FanOut>,
+}
+
+/// Editor request sent to all language servers of the buffer which provide the feature, whose
+/// answers are merged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FanOut {
+    pub id: u64,
+    // Position of the server among the ones the request is sent to, primary server first.
+    pub index: usize,
+    pub servers: usize,
 }
 
 pub type EditorParams = toml::Value;
@@ -81,7 +200,14 @@ pub struct EditorRequest {
     pub meta: EditorMeta,
     pub method: String,
     pub params: EditorParams,
-    pub ranges: Option<Vec<Range>>,
+    // Milliseconds to wait for a newer request of the same method for the buffer, which replaces
+    // this one.
+    #[serde(default)]
+    pub debounce: i64,
+    // Language whose server the request is for, e.g. the one which provided the completion item
+    // the request refers to.
+    #[serde(default)]
+    pub server: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -104,6 +230,12 @@ pub struct Route {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EditorCompletion {
     pub offset: u32,
+    // Only request completions if a trigger character of the server was just typed.
+    #[serde(default)]
+    pub trigger_character_only: bool,
+    // Either `insert` or `replace`, telling which range of insert/replace edits to use.
+    #[serde(default)]
+    pub insert_mode: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -114,6 +246,8 @@ pub struct TextDocumentDidOpenParams {
 #[derive(Deserialize, Debug)]
 pub struct TextDocumentDidChangeParams {
     pub draft: String,
+    // Milliseconds to wait for further diagnostics of a buffer before showing them.
+    pub diagnostics_debounce: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -134,6 +268,14 @@ pub struct TextDocumentRenameParams {
     pub new_name: String,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RangeFormattingParams {
+    pub tab_size: u32,
+    pub insert_spaces: bool,
+    pub selections_desc: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct WindowProgress {
     pub title: String,
@@ -197,8 +339,32 @@ impl Display for KakouneRange {
     }
 }
 
+/// Hierarchy item (i.e. call or type hierarchy) prepared for the given cursor position, kept so
+/// that consecutive requests in different directions don't need to prepare it again.
+pub struct PreparedHierarchyItem<T> {
+    pub buffile: String,
+    pub version: i32,
+    pub position: KakounePosition,
+    pub item: T,
+}
+
+impl<T> PreparedHierarchyItem<T> {
+    pub fn new(meta: &EditorMeta, position: KakounePosition, item: T) -> Self {
+        PreparedHierarchyItem {
+            buffile: meta.buffile.clone(),
+            version: meta.version,
+            position,
+            item,
+        }
+    }
+
+    pub fn is_prepared_for(&self, meta: &EditorMeta, position: &KakounePosition) -> bool {
+        self.buffile == meta.buffile && self.version == meta.version && self.position == *position
+    }
+}
+
 /// Represents how language server interprets LSP's `Position.character`
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum OffsetEncoding {
     /// UTF-8 code units aka bytes
     #[serde(rename = "utf-8")]
@@ -206,4 +372,7 @@ pub enum OffsetEncoding {
     /// UTF-16 code units
     #[serde(rename = "utf-16")]
     Utf16,
+    /// UTF-32 code units aka characters
+    #[serde(rename = "utf-32")]
+    Utf32,
 }
@@ -35,74 +35,73 @@ pub fn temp_dir() -> path::PathBuf {
     path
 }
 
-/// Represent list of symbol information as filetype=grep buffer content.
-/// Paths are converted into relative to project root.
-pub fn format_symbol_information(items: Vec<SymbolInformation>, ctx: &Context) -> String {
-    items
-        .into_iter()
-        .map(|symbol| {
-            let SymbolInformation {
-                location,
-                name,
-                kind,
-                ..
-            } = symbol;
-            let filename = location.uri.to_file_path().unwrap();
-            let filename = filename
-                .strip_prefix(&ctx.root_path)
-                .ok()
-                .and_then(|p| p.to_str())
-                .or_else(|| filename.to_str())
-                .unwrap();
-
-            let position = get_kakoune_position(filename, &location.range.start, ctx)
-                .unwrap_or_else(|| KakounePosition {
-                    line: location.range.start.line + 1,
-                    column: location.range.start.character + 1,
-                });
-            let description = format!("{:?} {}", kind, name);
-            format!(
-                "{}:{}:{}:{}",
-                filename, position.line, position.column, description
-            )
-        })
-        .join("\n")
-}
-
 /// Represent list of document symbol as filetype=grep buffer content.
 /// Paths are converted into relative to project root.
-
+/// Children are listed after their parent, indented according to their depth.
 pub fn format_document_symbol(
     items: Vec<DocumentSymbol>,
     meta: &EditorMeta,
     ctx: &Context,
 ) -> String {
-    items
-        .into_iter()
-        .map(|symbol| {
-            let DocumentSymbol {
-                range, name, kind, ..
-            } = symbol;
-            let filename = path::PathBuf::from(&meta.buffile);
-            let filename = filename
-                .strip_prefix(&ctx.root_path)
-                .ok()
-                .and_then(|p| p.to_str())
-                .unwrap_or(&meta.buffile);
-
-            let position = get_kakoune_position(filename, &range.start, ctx).unwrap_or_else(|| {
-                KakounePosition {
-                    line: range.start.line + 1,
-                    column: range.start.character + 1,
-                }
+    let filename = path::PathBuf::from(&meta.buffile);
+    let filename = filename
+        .strip_prefix(&ctx.root_path)
+        .ok()
+        .and_then(|p| p.to_str())
+        .unwrap_or(&meta.buffile);
+    let mut lines = vec![];
+    format_document_symbol_tree(items, 0, filename, meta, ctx, &mut lines);
+    lines.join("\n")
+}
+
+fn format_document_symbol_tree(
+    items: Vec<DocumentSymbol>,
+    depth: usize,
+    filename: &str,
+    meta: &EditorMeta,
+    ctx: &Context,
+    lines: &mut Vec<String>,
+) {
+    for symbol in items {
+        #[allow(deprecated)] // older servers use the boolean field rather than tags
+        let deprecated = if symbol_is_deprecated(&symbol.tags, symbol.deprecated) {
+            DEPRECATED_SUFFIX
+        } else {
+            ""
+        };
+        let DocumentSymbol {
+            selection_range,
+            name,
+            kind,
+            children,
+            ..
+        } = symbol;
+        let position = get_kakoune_position(&meta.buffile, &selection_range.start, ctx)
+            .unwrap_or_else(|| KakounePosition {
+                line: selection_range.start.line + 1,
+                column: selection_range.start.character + 1,
             });
-            let description = format!("{:?} {}", kind, name);
-            format!(
-                "{}:{}:{}:{}",
-                filename, position.line, position.column, description
-            )
-        })
-        .join("\n")
+        let description = format!("{}{:?} {}{}", "  ".repeat(depth), kind, name, deprecated);
+        lines.push(format!(
+            "{}:{}:{}:{}",
+            filename, position.line, position.column, description
+        ));
+        if let Some(children) = children {
+            format_document_symbol_tree(children, depth + 1, filename, meta, ctx, lines);
+        }
+    }
+}
+
+/// Appended to deprecated symbols listed in grep buffers, which highlight it with
+/// `Deprecated` face.
+pub const DEPRECATED_SUFFIX: &str = " (deprecated)";
+
+/// Whether symbol is deprecated according to either its tags or the older boolean field.
+pub fn symbol_is_deprecated(tags: &Option<Vec<SymbolTag>>, deprecated: Option<bool>) -> bool {
+    deprecated == Some(true)
+        || tags
+            .as_ref()
+            .map_or(false, |tags| tags.contains(&SymbolTag::Deprecated))
 }
 
 /// Escape Kakoune string wrapped into single quote
@@ -139,6 +138,9 @@ pub fn goodbye(session: &str, code: i32) {
 pub fn filetype_to_language_id_map(config: &Config) -> HashMap<String, String> {
     let mut filetypes = HashMap::default();
     for (language_id, language) in &config.language {
+        if language.secondary {
+            continue;
+        }
         for filetype in &language.filetypes {
             filetypes.insert(filetype.clone(), language_id.clone());
         }
@@ -146,6 +148,23 @@ pub fn filetype_to_language_id_map(config: &Config) -> HashMap<String, String> {
     filetypes
 }
 
+/// Secondary languages attached to buffers of each filetype, in order of language id.
+pub fn filetype_to_secondary_language_ids_map(config: &Config) -> HashMap<String, Vec<String>> {
+    let mut filetypes: HashMap<String, Vec<String>> = HashMap::default();
+    for (language_id, language) in config.language.iter().sorted_by_key(|(id, _)| *id) {
+        if !language.secondary {
+            continue;
+        }
+        for filetype in &language.filetypes {
+            filetypes
+                .entry(filetype.clone())
+                .or_default()
+                .push(language_id.clone());
+        }
+    }
+    filetypes
+}
+
 /// Wrapper for kakoune_position_to_lsp which uses context to get buffer content and offset encoding.
 pub fn get_lsp_position(
     filename: &str,
@@ -195,18 +214,33 @@ pub fn apply_annotated_text_edits(
     edits: &[OneOf<TextEdit, AnnotatedTextEdit>],
     ctx: &Context,
 ) {
+    if let Some(command) = annotated_text_edits_command(uri, edits, ctx) {
+        ctx.exec(meta.clone(), command);
+    }
+}
+
+/// Editor command applying text edits to the buffer of the file pointed by uri, or None if the
+/// file is not open in editor and has been edited directly.
+pub fn annotated_text_edits_command(
+    uri: &Url,
+    edits: &[OneOf<TextEdit, AnnotatedTextEdit>],
+    ctx: &Context,
+) -> Option<String> {
     if let Some(document) = ctx
         .documents
         .get(uri.to_file_path().unwrap().to_str().unwrap())
     {
-        ctx.exec(
-            meta.clone(),
-            apply_text_edits_to_buffer(Some(uri), edits, &document.text, ctx.offset_encoding),
-        );
+        Some(apply_text_edits_to_buffer(
+            Some(uri),
+            edits,
+            &document.text,
+            ctx.offset_encoding,
+        ))
     } else {
         if let Err(e) = apply_text_edits_to_file(uri, edits, ctx.offset_encoding) {
             error!("Failed to apply edits to file {} ({})", uri, e);
         };
+        None
     }
 }
 
@@ -1,16 +1,25 @@
 use crate::context::*;
-use crate::language_features::rust_analyzer;
+use crate::language_features::codeaction::execute_command_editor_command;
+use crate::language_features::{goto, rust_analyzer};
+use crate::text_edit::{
+    apply_text_edits_to_buffer_in_chunks, apply_text_edits_to_file, edited_text, unified_diff,
+};
 use crate::types::*;
 use crate::util::*;
+use itertools::Itertools;
 use jsonrpc_core::{Id, Params};
 use lsp_types::notification::*;
 use lsp_types::request::*;
 use lsp_types::*;
-use serde::Deserialize;
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
 use serde_json::{self, Value};
+use std::collections::{HashMap, VecDeque};
 use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
 use toml;
+use url::Url;
 
 fn insert_value<'a, 'b, P>(
     target: &'b mut serde_json::map::Map<String, Value>,
@@ -83,38 +92,189 @@ pub fn did_change_configuration(params: EditorParams, ctx: &mut Context) {
         }
     }
 
+    ctx.settings = settings.clone();
     let params = DidChangeConfigurationParams {
         settings: Value::Object(settings),
     };
     ctx.notify::<DidChangeConfiguration>(params);
 }
 
+// Workspace symbols as of LSP 3.17, whose location might lack a range until resolved, are not
+// covered by lsp-types yet.
+
+pub enum WorkspaceSymbolRequest {}
+
+impl Request for WorkspaceSymbolRequest {
+    type Params = WorkspaceSymbolParams;
+    type Result = Option<Vec<WorkspaceSymbolItem>>;
+    const METHOD: &'static str = "workspace/symbol";
+}
+
+pub enum WorkspaceSymbolResolveRequest {}
+
+impl Request for WorkspaceSymbolResolveRequest {
+    type Params = WorkspaceSymbolItem;
+    type Result = WorkspaceSymbolItem;
+    const METHOD: &'static str = "workspaceSymbol/resolve";
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceSymbolItem {
+    pub name: String,
+    pub kind: SymbolKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<SymbolTag>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deprecated: Option<bool>,
+    pub location: OneOf<Location, WorkspaceLocation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+/// Location of a symbol whose range is to be resolved.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkspaceLocation {
+    pub uri: Url,
+}
+
+impl WorkspaceSymbolItem {
+    fn uri(&self) -> &Url {
+        match &self.location {
+            OneOf::Left(location) => &location.uri,
+            OneOf::Right(location) => &location.uri,
+        }
+    }
+
+    fn is_deprecated(&self) -> bool {
+        symbol_is_deprecated(&self.tags, self.deprecated)
+    }
+}
+
+/// Client capabilities for workspace symbols, to be merged into the ones built with lsp-types.
+pub fn client_capabilities() -> Value {
+    serde_json::json!({
+        "workspace": {
+            "symbol": {
+                "resolveSupport": {"properties": ["location.range"]},
+            },
+        },
+    })
+}
+
 pub fn workspace_symbol(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = WorkspaceSymbolParams::deserialize(params)
         .expect("Params should follow WorkspaceSymbolParams structure");
-    ctx.call::<WorkspaceSymbol, _>(meta, params, move |ctx: &mut Context, meta, result| {
+    ctx.call::<WorkspaceSymbolRequest, _>(meta, params, move |ctx: &mut Context, meta, result| {
         editor_workspace_symbol(meta, result, ctx)
     });
 }
 
 pub fn editor_workspace_symbol(
     meta: EditorMeta,
-    result: Option<Vec<SymbolInformation>>,
+    result: Option<Vec<WorkspaceSymbolItem>>,
     ctx: &mut Context,
 ) {
     if result.is_none() {
         return;
     }
-    let result = result.unwrap();
-    let content = format_symbol_information(result, ctx);
+    let symbols = result
+        .unwrap()
+        .into_iter()
+        .map(|symbol| (format_workspace_symbol(&symbol, ctx), symbol))
+        .collect::<Vec<_>>();
+    let content = symbols.iter().map(|(line, _)| line).join("\n");
+    // Kept to jump to symbols, resolving their range first if needed.
+    ctx.workspace_symbols = symbols;
     let command = format!(
-        "lsp-show-workspace-symbol {} {}",
+        "lsp-show-workspace-symbol {} {} {} {} {}",
         editor_quote(&ctx.root_path),
         editor_quote(&content),
+        editor_quote(&meta.buffile),
+        editor_quote(&meta.filetype),
+        meta.version,
     );
     ctx.exec(meta, command);
 }
 
+/// Represent a workspace symbol as a line of filetype=grep buffer content.
+/// Symbols without a range yet are listed at the start of their file.
+fn format_workspace_symbol(symbol: &WorkspaceSymbolItem, ctx: &Context) -> String {
+    let path = symbol.uri().to_file_path().unwrap();
+    let filename = path
+        .strip_prefix(&ctx.root_path)
+        .ok()
+        .and_then(|p| p.to_str())
+        .or_else(|| path.to_str())
+        .unwrap();
+    let position = match &symbol.location {
+        OneOf::Left(location) => {
+            get_kakoune_position(path.to_str().unwrap(), &location.range.start, ctx).unwrap_or_else(
+                || KakounePosition {
+                    line: location.range.start.line + 1,
+                    column: location.range.start.character + 1,
+                },
+            )
+        }
+        OneOf::Right(_) => KakounePosition { line: 1, column: 1 },
+    };
+    let deprecated = if symbol.is_deprecated() {
+        DEPRECATED_SUFFIX
+    } else {
+        ""
+    };
+    format!(
+        "{}:{}:{}:{:?} {}{}",
+        filename,
+        position.line,
+        position.column,
+        symbol.kind,
+        symbol.name.replace('\n', " "),
+        deprecated
+    )
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WorkspaceSymbolJumpParams {
+    // Line of the symbol in the symbols buffer.
+    pub line: String,
+}
+
+/// Jump to a listed workspace symbol. Servers may leave out symbol ranges, which are only
+/// resolved once the symbol is chosen.
+pub fn workspace_symbol_jump(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = WorkspaceSymbolJumpParams::deserialize(params)
+        .expect("Params should follow WorkspaceSymbolJumpParams structure");
+    let symbol = match ctx
+        .workspace_symbols
+        .iter()
+        .find(|(line, _)| *line == params.line.trim_end_matches('\n'))
+    {
+        Some((_, symbol)) => symbol.clone(),
+        None => return,
+    };
+    let goto_symbol = |ctx: &mut Context, meta, symbol: WorkspaceSymbolItem| {
+        let location = match symbol.location {
+            OneOf::Left(location) => location,
+            OneOf::Right(WorkspaceLocation { uri }) => Location {
+                uri,
+                range: Range::default(),
+            },
+        };
+        goto::goto_location(meta, &location, ctx);
+    };
+    match symbol.location {
+        OneOf::Left(_) => goto_symbol(ctx, meta, symbol),
+        OneOf::Right(_) => {
+            ctx.call::<WorkspaceSymbolResolveRequest, _>(meta, symbol, move |ctx, meta, symbol| {
+                goto_symbol(ctx, meta, symbol)
+            })
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct EditorExecuteCommand {
     command: String,
@@ -127,7 +287,9 @@ pub fn execute_command(meta: EditorMeta, params: EditorParams, ctx: &mut Context
     let req_params = ExecuteCommandParams {
         command: params.command,
         // arguments is quoted to avoid parsing issues
-        arguments: serde_json::from_str(&params.arguments).unwrap(),
+        arguments: serde_json::from_str::<Option<Vec<Value>>>(&params.arguments)
+            .expect("Arguments should be a JSON array")
+            .unwrap_or_default(),
         work_done_progress_params: Default::default(),
     };
     match &*req_params.command {
@@ -140,33 +302,104 @@ pub fn execute_command(meta: EditorMeta, params: EditorParams, ctx: &mut Context
     }
 }
 
+#[derive(Deserialize, Debug)]
+struct EditorWorkspaceFolders {
+    #[serde(default)]
+    added: Vec<String>,
+    #[serde(default)]
+    removed: Vec<String>,
+}
+
+/// Add or remove workspace folders in multi-root setups.
+pub fn did_change_workspace_folders(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = EditorWorkspaceFolders::deserialize(params)
+        .expect("Params should follow EditorWorkspaceFolders structure");
+    let to_folder = |path: &String| WorkspaceFolder {
+        uri: Url::from_file_path(path).unwrap(),
+        name: path.clone(),
+    };
+    let added = params
+        .added
+        .iter()
+        .map(to_folder)
+        .filter(|folder| !ctx.workspace_folders.iter().any(|f| f.uri == folder.uri))
+        .collect::<Vec<_>>();
+    let removed = params
+        .removed
+        .iter()
+        .map(to_folder)
+        .filter_map(|folder| {
+            ctx.workspace_folders
+                .iter()
+                .find(|f| f.uri == folder.uri)
+                .cloned()
+        })
+        .collect::<Vec<_>>();
+    if added.is_empty() && removed.is_empty() {
+        return;
+    }
+
+    let change_notifications = ctx
+        .capabilities
+        .as_ref()
+        .and_then(|caps| caps.workspace.as_ref())
+        .and_then(|workspace| workspace.workspace_folders.as_ref())
+        .and_then(|folders| folders.change_notifications.as_ref());
+    match change_notifications {
+        Some(OneOf::Left(true)) | Some(OneOf::Right(_)) => {
+            // The folders are only changed once the server is told so, as it would otherwise be
+            // handed a list different from its own via `workspace/workspaceFolders`.
+            ctx.workspace_folders
+                .retain(|folder| !removed.iter().any(|f| f.uri == folder.uri));
+            ctx.workspace_folders.extend(added.iter().cloned());
+            ctx.notify::<DidChangeWorkspaceFolders>(DidChangeWorkspaceFoldersParams {
+                event: WorkspaceFoldersChangeEvent { added, removed },
+            });
+        }
+        _ => {
+            let msg = "Language server does not support changing workspace folders";
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(msg)));
+        }
+    }
+}
+
 pub fn apply_document_resource_op(
-    _meta: &EditorMeta,
+    meta: &EditorMeta,
     op: ResourceOp,
-    _ctx: &mut Context,
+    ctx: &mut Context,
 ) -> io::Result<()> {
     match op {
         ResourceOp::Create(op) => {
             let path = op.uri.to_file_path().unwrap();
-            let ignore_if_exists = if let Some(options) = op.options {
-                !options.overwrite.unwrap_or(false) && options.ignore_if_exists.unwrap_or(false)
-            } else {
-                false
+            let (overwrite, ignore_if_exists) = match op.options {
+                Some(options) => (
+                    options.overwrite.unwrap_or(false),
+                    options.ignore_if_exists.unwrap_or(false),
+                ),
+                None => (false, false),
             };
-            if ignore_if_exists && path.exists() {
-                Ok(())
-            } else {
-                fs::write(&path, [])
+            if path.exists() && !overwrite {
+                return if ignore_if_exists {
+                    Ok(())
+                } else {
+                    Err(already_exists(&path))
+                };
             }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, [])
         }
         ResourceOp::Delete(op) => {
             let path = op.uri.to_file_path().unwrap();
+            let (recursive, ignore_if_not_exists) = match op.options {
+                Some(options) => (
+                    options.recursive.unwrap_or(false),
+                    options.ignore_if_not_exists.unwrap_or(false),
+                ),
+                None => (false, false),
+            };
             if path.is_dir() {
-                let recursive = if let Some(options) = op.options {
-                    options.recursive.unwrap_or(false)
-                } else {
-                    false
-                };
                 if recursive {
                     fs::remove_dir_all(&path)
                 } else {
@@ -174,59 +407,398 @@ pub fn apply_document_resource_op(
                 }
             } else if path.is_file() {
                 fs::remove_file(&path)
-            } else {
+            } else if ignore_if_not_exists {
                 Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} does not exist", path.display()),
+                ))
             }
         }
         ResourceOp::Rename(op) => {
             let from = op.old_uri.to_file_path().unwrap();
             let to = op.new_uri.to_file_path().unwrap();
-            let ignore_if_exists = if let Some(options) = op.options {
-                !options.overwrite.unwrap_or(false) && options.ignore_if_exists.unwrap_or(false)
-            } else {
-                false
+            let (overwrite, ignore_if_exists) = match op.options {
+                Some(options) => (
+                    options.overwrite.unwrap_or(false),
+                    options.ignore_if_exists.unwrap_or(false),
+                ),
+                None => (false, false),
             };
-            if ignore_if_exists && to.exists() {
-                Ok(())
-            } else {
-                fs::rename(&from, &to)
+            if to.exists() && !overwrite {
+                return if ignore_if_exists {
+                    Ok(())
+                } else {
+                    Err(already_exists(&to))
+                };
+            }
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
             }
+            fs::rename(&from, &to)?;
+            rename_open_documents(meta, &from, &to, ctx);
+            Ok(())
         }
     }
 }
 
-// TODO handle version, so change is not applied if buffer is modified (and need to show a warning)
-pub fn apply_edit(
-    meta: EditorMeta,
-    edit: WorkspaceEdit,
-    ctx: &mut Context,
-) -> ApplyWorkspaceEditResponse {
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{} already exists", path.display()),
+    )
+}
+
+/// Point buffers of the renamed file, or of files within the renamed directory, to the new path
+/// and reopen them under the new URI on the server side.
+fn rename_open_documents(meta: &EditorMeta, from: &Path, to: &Path, ctx: &mut Context) {
+    for (old_buffile, new_buffile) in reopen_renamed_documents(from, to, ctx) {
+        ctx.exec(
+            meta.clone(),
+            format!(
+                "eval -buffer {} -verbatim -- rename-buffer -file {}",
+                editor_quote(&old_buffile),
+                editor_quote(&new_buffile)
+            ),
+        );
+    }
+}
+
+/// Path of the file after renaming `from` to `to`, if it's the renamed file or within
+/// the renamed directory.
+fn renamed_path(path: &Path, from: &Path, to: &Path) -> Option<PathBuf> {
+    let suffix = path.strip_prefix(from).ok()?;
+    if suffix.as_os_str().is_empty() {
+        Some(to.to_path_buf())
+    } else {
+        Some(to.join(suffix))
+    }
+}
+
+/// Move documents affected by the rename to their new paths, reopening them on the server side.
+/// Returns old and new paths of the moved documents.
+fn reopen_renamed_documents(from: &Path, to: &Path, ctx: &mut Context) -> Vec<(String, String)> {
+    let renamed = ctx
+        .documents
+        .keys()
+        .filter_map(|buffile| {
+            let new_buffile = renamed_path(Path::new(buffile), from, to)?;
+            Some((buffile.clone(), new_buffile.to_str()?.to_string()))
+        })
+        .collect::<Vec<_>>();
+    for (old_buffile, new_buffile) in &renamed {
+        let document = ctx.documents.remove(old_buffile).unwrap();
+        ctx.notify::<DidCloseTextDocument>(DidCloseTextDocumentParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(old_buffile).unwrap(),
+            },
+        });
+        ctx.notify::<DidOpenTextDocument>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: Url::from_file_path(new_buffile).unwrap(),
+                language_id: ctx.language_id.clone(),
+                version: document.version,
+                text: document.text.to_string(),
+            },
+        });
+        ctx.documents.insert(new_buffile.clone(), document);
+    }
+    renamed
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EditorRenameFile {
+    old_path: String,
+    new_path: String,
+}
+
+/// Let the server react to a file or directory renamed in the editor, e.g. by fixing imports.
+/// The rename has already happened, so edits the server computes for old paths are redirected
+/// to the new ones.
+pub fn did_rename_file(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = EditorRenameFile::deserialize(params)
+        .expect("Params should follow EditorRenameFile structure");
+    let from = PathBuf::from(&params.old_path);
+    let to = PathBuf::from(&params.new_path);
+    reopen_renamed_documents(&from, &to, ctx);
+
+    let file_operations = server_file_operations(ctx);
+    let is_dir = Some(to.is_dir());
+    let interested = |options: &Option<FileOperationRegistrationOptions>| {
+        file_operation_matches(options, &from, is_dir)
+    };
+    let rename_params = RenameFilesParams {
+        files: vec![FileRename {
+            old_uri: Url::from_file_path(&from).unwrap().to_string(),
+            new_uri: Url::from_file_path(&to).unwrap().to_string(),
+        }],
+    };
+    let notify_did_rename = interested(&file_operations.did_rename);
+    if !interested(&file_operations.will_rename) {
+        if notify_did_rename {
+            ctx.notify::<DidRenameFiles>(rename_params);
+        }
+        return;
+    }
+    ctx.call::<WillRenameFiles, _>(
+        meta,
+        rename_params.clone(),
+        move |ctx: &mut Context, meta, result| {
+            if let Some(edit) = result {
+                let edit = renamed_workspace_edit(edit, &from, &to);
+                apply_edit(meta, edit, AfterEdit::default(), ctx);
+            }
+            if notify_did_rename {
+                ctx.notify::<DidRenameFiles>(rename_params);
+            }
+        },
+    );
+}
+
+#[derive(Deserialize, Debug)]
+struct EditorFiles {
+    paths: Vec<String>,
+}
+
+/// Let the server know about files created in the editor, e.g. to index a new module.
+pub fn did_create_files(params: EditorParams, ctx: &mut Context) {
+    let params =
+        EditorFiles::deserialize(params).expect("Params should follow EditorFiles structure");
+    let registration = server_file_operations(ctx).did_create;
+    let files = params
+        .paths
+        .iter()
+        .map(Path::new)
+        .filter(|path| file_operation_matches(&registration, path, Some(path.is_dir())))
+        .map(|path| FileCreate {
+            uri: Url::from_file_path(path).unwrap().to_string(),
+        })
+        .collect::<Vec<_>>();
+    if !files.is_empty() {
+        ctx.notify::<DidCreateFiles>(CreateFilesParams { files });
+    }
+}
+
+/// Let the server know about files deleted in the editor.
+pub fn did_delete_files(params: EditorParams, ctx: &mut Context) {
+    let params =
+        EditorFiles::deserialize(params).expect("Params should follow EditorFiles structure");
+    let registration = server_file_operations(ctx).did_delete;
+    let files = params
+        .paths
+        .iter()
+        .map(Path::new)
+        .filter(|path| file_operation_matches(&registration, path, None))
+        .map(|path| FileDelete {
+            uri: Url::from_file_path(path).unwrap().to_string(),
+        })
+        .collect::<Vec<_>>();
+    if !files.is_empty() {
+        ctx.notify::<DidDeleteFiles>(DeleteFilesParams { files });
+    }
+}
+
+fn server_file_operations(ctx: &Context) -> WorkspaceFileOperationsServerCapabilities {
+    ctx.capabilities
+        .as_ref()
+        .and_then(|caps| caps.workspace.as_ref())
+        .and_then(|workspace| workspace.file_operations.clone())
+        .unwrap_or_default()
+}
+
+/// Check if the server registered interest in the file operation on this path. Whether the path
+/// is a directory might be unknown, e.g. once it's deleted, then filters match either.
+fn file_operation_matches(
+    options: &Option<FileOperationRegistrationOptions>,
+    path: &Path,
+    is_dir: Option<bool>,
+) -> bool {
+    options.as_ref().map_or(false, |options| {
+        options
+            .filters
+            .iter()
+            .any(|filter| file_operation_filter_matches(filter, path, is_dir))
+    })
+}
+
+fn file_operation_filter_matches(
+    filter: &FileOperationFilter,
+    path: &Path,
+    is_dir: Option<bool>,
+) -> bool {
+    if filter
+        .scheme
+        .as_ref()
+        .map_or(false, |scheme| scheme != "file")
+    {
+        return false;
+    }
+    let pattern = &filter.pattern;
+    match (&pattern.matches, is_dir) {
+        (Some(FileOperationPatternKind::File), Some(true)) => return false,
+        (Some(FileOperationPatternKind::Folder), Some(false)) => return false,
+        _ => (),
+    }
+    let options = glob::MatchOptions {
+        case_sensitive: !pattern
+            .options
+            .as_ref()
+            .and_then(|options| options.ignore_case)
+            .unwrap_or(false),
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+    expand_glob_braces(&pattern.glob).iter().any(|glob| {
+        glob::Pattern::new(glob).map_or(false, |pattern| pattern.matches_path_with(path, options))
+    })
+}
+
+/// Expand `{a,b}` groups of LSP glob patterns, which the glob crate doesn't support.
+pub fn expand_glob_braces(glob: &str) -> Vec<String> {
+    let open = match glob.find('{') {
+        Some(open) => open,
+        None => return vec![glob.to_string()],
+    };
+    let close = match glob[open..].find('}') {
+        Some(close) => open + close,
+        None => return vec![glob.to_string()],
+    };
+    glob[open + 1..close]
+        .split(',')
+        .flat_map(|alternative| {
+            expand_glob_braces(&format!(
+                "{}{}{}",
+                &glob[..open],
+                alternative,
+                &glob[close + 1..]
+            ))
+        })
+        .collect()
+}
+
+/// Redirect edits of files affected by the rename to their new paths.
+fn renamed_workspace_edit(mut edit: WorkspaceEdit, from: &Path, to: &Path) -> WorkspaceEdit {
+    let rename_uri = |uri: &mut Url| {
+        if let Some(path) = uri
+            .to_file_path()
+            .ok()
+            .and_then(|path| renamed_path(&path, from, to))
+        {
+            *uri = Url::from_file_path(path).unwrap();
+        }
+    };
+    if let Some(changes) = edit.changes.take() {
+        edit.changes = Some(
+            changes
+                .into_iter()
+                .map(|(mut uri, edits)| {
+                    rename_uri(&mut uri);
+                    (uri, edits)
+                })
+                .collect(),
+        );
+    }
+    match &mut edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => {
+            for edit in edits {
+                rename_uri(&mut edit.text_document.uri);
+            }
+        }
+        Some(DocumentChanges::Operations(ops)) => {
+            for op in ops {
+                if let DocumentChangeOperation::Edit(edit) = op {
+                    rename_uri(&mut edit.text_document.uri);
+                }
+            }
+        }
+        None => (),
+    }
+    edit
+}
+
+/// Find an edit targeting a document version other than the one we have, as it would garble
+/// the buffer. Documents not open in the editor have no version to compare to.
+fn stale_document_edit(edit: &WorkspaceEdit, ctx: &Context) -> Option<String> {
+    let edits: Vec<&TextDocumentEdit> = match &edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => edits.iter().collect(),
+        Some(DocumentChanges::Operations(ops)) => ops
+            .iter()
+            .filter_map(|op| match op {
+                DocumentChangeOperation::Edit(edit) => Some(edit),
+                DocumentChangeOperation::Op(_) => None,
+            })
+            .collect(),
+        None => vec![],
+    };
+    edits.into_iter().find_map(|edit| {
+        let version = edit.text_document.version?;
+        let path = edit.text_document.uri.to_file_path().ok()?;
+        let document = ctx.documents.get(path.to_str()?)?;
+        if document.version == version {
+            None
+        } else {
+            Some(format!(
+                "Edit is for version {} of {} but the buffer is at version {}",
+                version,
+                path.display(),
+                document.version
+            ))
+        }
+    })
+}
+
+/// What follows an edit once it's applied to all buffers.
+#[derive(Default)]
+pub struct AfterEdit {
+    // Editor command to run, e.g. the command of a code action.
+    command: Option<String>,
+    // Request of the language server which asked for the edit, to answer.
+    request: Option<Id>,
+}
+
+pub fn apply_edit(meta: EditorMeta, edit: WorkspaceEdit, after: AfterEdit, ctx: &mut Context) {
+    // Nothing is applied if any document is stale, in line with advertised abort failure handling.
+    if let Some(reason) = stale_document_edit(&edit, ctx) {
+        return finish_edit(meta, after, Some(reason), ctx);
+    }
+    if is_chunked_edit(&meta, &edit, ctx) {
+        return apply_edit_in_chunks(meta, edit, after, ctx);
+    }
+    // Buffer edits are sent to the editor in one go, each buffer being edited in a single undo
+    // step.
+    let mut commands = vec![];
     if let Some(document_changes) = edit.document_changes {
         match document_changes {
             DocumentChanges::Edits(edits) => {
                 for edit in edits {
-                    apply_annotated_text_edits(&meta, &edit.text_document.uri, &edit.edits, ctx);
+                    commands.extend(annotated_text_edits_command(
+                        &edit.text_document.uri,
+                        &edit.edits,
+                        ctx,
+                    ));
                 }
             }
             DocumentChanges::Operations(ops) => {
                 for op in ops {
                     match op {
                         DocumentChangeOperation::Edit(edit) => {
-                            apply_annotated_text_edits(
-                                &meta,
+                            commands.extend(annotated_text_edits_command(
                                 &edit.text_document.uri,
                                 &edit.edits,
                                 ctx,
-                            );
+                            ));
                         }
                         DocumentChangeOperation::Op(op) => {
+                            // Buffers are renamed by the operation, so edits made so far must
+                            // reach them first.
+                            exec_edit_commands(&meta, &mut commands, ctx);
                             if let Err(e) = apply_document_resource_op(&meta, op, ctx) {
                                 error!("failed to apply document change operation: {}", e);
-                                return ApplyWorkspaceEditResponse {
-                                    applied: false,
-                                    failure_reason: None,
-                                    failed_change: None,
-                                };
+                                let reason =
+                                    format!("Failed to apply document change operation: {}", e);
+                                return finish_edit(meta, after, Some(reason), ctx);
                             }
                         }
                     }
@@ -235,14 +807,226 @@ pub fn apply_edit(
         }
     } else if let Some(changes) = edit.changes {
         for (uri, change) in changes {
-            apply_text_edits(&meta, &uri, change, ctx);
+            let change = change.into_iter().map(OneOf::Left).collect::<Vec<_>>();
+            commands.extend(annotated_text_edits_command(&uri, &change, ctx));
         }
     }
-    ApplyWorkspaceEditResponse {
-        applied: true,
-        failure_reason: None,
-        failed_change: None,
+    exec_edit_commands(&meta, &mut commands, ctx);
+    finish_edit(meta, after, None, ctx);
+}
+
+/// Send pending buffer edit commands to the editor as a single command.
+fn exec_edit_commands(meta: &EditorMeta, commands: &mut Vec<String>, ctx: &Context) {
+    if !commands.is_empty() {
+        ctx.exec(meta.clone(), std::mem::take(commands).join("\n"));
+    }
+}
+
+/// Run the command following the edit and answer the server which asked for it, failure being
+/// why the edit was not applied, or not completely.
+fn finish_edit(meta: EditorMeta, after: AfterEdit, failure: Option<String>, ctx: &mut Context) {
+    match &failure {
+        Some(reason) => ctx.exec(meta, format!("lsp-show-error {}", editor_quote(reason))),
+        None => {
+            if let Some(command) = after.command {
+                ctx.exec(meta, command);
+            }
+        }
+    }
+    if let Some(id) = after.request {
+        let response = ApplyWorkspaceEditResponse {
+            applied: failure.is_none(),
+            failure_reason: failure,
+            failed_change: None,
+        };
+        ctx.reply(id, Ok(serde_json::to_value(response).unwrap()));
+    }
+}
+
+/// Whether the edit is applied in chunks, see `edit_chunk_size`. It must make more changes than
+/// that, and neither have the editor wait for it on the fifo, e.g. to save the buffer, nor create,
+/// rename or delete files, as buffers would have to be edited in between.
+fn is_chunked_edit(meta: &EditorMeta, edit: &WorkspaceEdit, ctx: &Context) -> bool {
+    let chunk_size = ctx.config.edit_chunk_size;
+    if chunk_size == 0 || meta.fifo.is_some() {
+        return false;
+    }
+    let edits = match &edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => edits.iter().map(|edit| edit.edits.len()).sum(),
+        Some(DocumentChanges::Operations(ops)) => {
+            let mut edits = 0;
+            for op in ops {
+                match op {
+                    DocumentChangeOperation::Edit(edit) => edits += edit.edits.len(),
+                    DocumentChangeOperation::Op(_) => return false,
+                }
+            }
+            edits
+        }
+        None => edit
+            .changes
+            .iter()
+            .flat_map(|changes| changes.values())
+            .map(Vec::len)
+            .sum(),
+    };
+    edits > chunk_size
+}
+
+/// Edit applied to buffers chunk by chunk. The editor reports each chunk it has applied, and only
+/// then the next one is sent, so that the editor handles input in between.
+pub struct ChunkedEdit {
+    id: u64,
+    meta: EditorMeta,
+    // Chunks not applied yet, the first one being applied by the editor.
+    chunks: VecDeque<EditChunk>,
+    after: AfterEdit,
+}
+
+struct EditChunk {
+    buffile: String,
+    // Editor command applying the chunk to the buffer.
+    command: String,
+    // Version of the buffer the edit is for, on the first chunk of the buffer. Later chunks are for
+    // the version the editor reports after applying the previous one.
+    version: Option<i32>,
+}
+
+/// Apply the edit in chunks of at most `edit_chunk_size` changes, each buffer being edited from its
+/// end, so that positions of the chunks yet to apply stay valid. Files not open in the editor are
+/// edited right away.
+fn apply_edit_in_chunks(
+    meta: EditorMeta,
+    edit: WorkspaceEdit,
+    after: AfterEdit,
+    ctx: &mut Context,
+) {
+    let edits: Vec<(Url, Vec<OneOf<TextEdit, AnnotatedTextEdit>>)> = match edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => edits
+            .into_iter()
+            .map(|edit| (edit.text_document.uri, edit.edits))
+            .collect(),
+        Some(DocumentChanges::Operations(ops)) => ops
+            .into_iter()
+            .filter_map(|op| match op {
+                DocumentChangeOperation::Edit(edit) => Some((edit.text_document.uri, edit.edits)),
+                DocumentChangeOperation::Op(_) => None,
+            })
+            .collect(),
+        None => edit
+            .changes
+            .into_iter()
+            .flatten()
+            .map(|(uri, edits)| (uri, edits.into_iter().map(OneOf::Left).collect()))
+            .collect(),
+    };
+    let mut chunks = VecDeque::new();
+    for (uri, edits) in edits {
+        let buffile = uri.to_file_path().unwrap().to_str().unwrap().to_string();
+        let document = match ctx.documents.get(&buffile) {
+            Some(document) => document,
+            None => {
+                if let Err(e) = apply_text_edits_to_file(&uri, &edits, ctx.offset_encoding) {
+                    error!("Failed to apply edits to file {} ({})", uri, e);
+                }
+                continue;
+            }
+        };
+        let commands = apply_text_edits_to_buffer_in_chunks(
+            None,
+            &edits,
+            &document.text,
+            ctx.offset_encoding,
+            ctx.config.edit_chunk_size,
+        );
+        let version = document.version;
+        chunks.extend(
+            commands
+                .into_iter()
+                .enumerate()
+                .map(|(i, command)| EditChunk {
+                    buffile: buffile.clone(),
+                    command,
+                    version: if i == 0 { Some(version) } else { None },
+                }),
+        );
+    }
+    if let Some(superseded) = ctx.chunked_edit.take() {
+        let reason = format!(
+            "Another edit came in, {} chunks of the previous one are not applied",
+            superseded.chunks.len()
+        );
+        finish_edit(superseded.meta, superseded.after, Some(reason), ctx);
     }
+    if chunks.is_empty() {
+        return finish_edit(meta, after, None, ctx);
+    }
+    ctx.chunked_edits += 1;
+    let chunked = ChunkedEdit {
+        id: ctx.chunked_edits,
+        meta,
+        chunks,
+        after,
+    };
+    send_edit_chunk(&chunked, None, ctx);
+    ctx.chunked_edit = Some(chunked);
+}
+
+/// Have the editor apply the first pending chunk, unless its buffer has changed since the chunk
+/// was made for it. `reported` is the version the editor reported after the previous chunk.
+fn send_edit_chunk(chunked: &ChunkedEdit, reported: Option<i32>, ctx: &Context) {
+    let chunk = &chunked.chunks[0];
+    let command = format!(
+        "lsp-apply-edit-chunk {} {} {} {} {}",
+        editor_quote(&ctx.language_id),
+        editor_quote(&ctx.root_path),
+        chunked.id,
+        chunk.version.or(reported).unwrap_or_default(),
+        editor_quote(&chunk.command)
+    );
+    let command = format!(
+        "evaluate-commands -buffer {} {}",
+        editor_quote(&chunk.buffile),
+        editor_quote(&command)
+    );
+    ctx.exec(chunked.meta.clone(), command);
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EditChunkParams {
+    pub id: u64,
+    // Whether the chunk was applied, rather than skipped as its buffer had changed.
+    pub applied: bool,
+}
+
+/// Send the next chunk of the edit once the editor reports it has applied the previous one, meta
+/// being the one of the buffer edited. The edit stops at the first chunk whose buffer has changed,
+/// as its positions might be off.
+pub fn apply_next_edit_chunk(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = EditChunkParams::deserialize(params)
+        .expect("Params should follow EditChunkParams structure");
+    let mut chunked = match ctx.chunked_edit.take() {
+        Some(chunked) if chunked.id == params.id => chunked,
+        // Reported by the editor after the edit was superseded.
+        chunked => {
+            ctx.chunked_edit = chunked;
+            return;
+        }
+    };
+    if !params.applied {
+        let reason = format!(
+            "{} has changed, {} chunks of the edit are not applied",
+            meta.buffile,
+            chunked.chunks.len()
+        );
+        return finish_edit(chunked.meta, chunked.after, Some(reason), ctx);
+    }
+    chunked.chunks.pop_front();
+    if chunked.chunks.is_empty() {
+        return finish_edit(chunked.meta, chunked.after, None, ctx);
+    }
+    send_edit_chunk(&chunked, Some(meta.version), ctx);
+    ctx.chunked_edit = Some(chunked);
 }
 
 #[derive(Deserialize)]
@@ -255,12 +1039,318 @@ pub fn apply_edit_from_editor(meta: EditorMeta, params: EditorParams, ctx: &mut
     let edit = WorkspaceEdit::deserialize(serde_json::from_str::<Value>(&params.edit).unwrap())
         .expect("Failed to parse edit");
 
-    apply_edit(meta, edit, ctx);
+    apply_edit(meta, edit, AfterEdit::default(), ctx);
 }
 
 pub fn apply_edit_from_server(id: Id, params: Params, ctx: &mut Context) {
     let params: ApplyWorkspaceEditParams = params.parse().expect("Failed to parse params");
     let meta = ctx.meta_for_session();
-    let response = apply_edit(meta, params.edit, ctx);
-    ctx.reply(id, Ok(serde_json::to_value(response).unwrap()));
+    let after = AfterEdit {
+        command: None,
+        request: Some(id),
+    };
+    apply_edit(meta, params.edit, after, ctx);
+}
+
+/// Workspace edit of a rename or code action awaiting confirmation after its preview.
+pub struct PendingEdit {
+    edit: WorkspaceEdit,
+    // Command of the code action to execute once the edit is applied.
+    command: Option<Command>,
+    // Versions of the open documents the edit touches, as previewed.
+    versions: HashMap<String, i32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PreviewEditsModeParams {
+    pub enabled: bool,
+}
+
+/// Switch whether edits of renames and code actions are previewed before applying them.
+pub fn preview_edits_mode(_meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = PreviewEditsModeParams::deserialize(params)
+        .expect("Params should follow PreviewEditsModeParams structure");
+    ctx.preview_edits = params.enabled;
+}
+
+/// Apply the edit, or keep it pending and show its diff if edits are previewed. The command is
+/// executed after the edit is applied.
+pub fn apply_or_preview_edit(
+    meta: EditorMeta,
+    edit: WorkspaceEdit,
+    command: Option<Command>,
+    ctx: &mut Context,
+) {
+    if !ctx.preview_edits {
+        apply_edit_and_command(meta, edit, command, ctx);
+        return;
+    }
+    let (preview, versions) = workspace_edit_preview(&edit, ctx);
+    ctx.pending_edit = Some(PendingEdit {
+        edit,
+        command,
+        versions,
+    });
+    show_edit_preview(meta, preview, ctx);
+}
+
+fn show_edit_preview(meta: EditorMeta, preview: String, ctx: &Context) {
+    if preview.is_empty() {
+        let msg = "Edit changes nothing, apply or cancel it anyway";
+        ctx.exec(
+            meta,
+            format!("lsp-show-edits-preview {}", editor_quote(msg)),
+        );
+        return;
+    }
+    ctx.exec(
+        meta,
+        format!("lsp-show-edits-preview {}", editor_quote(&preview)),
+    );
+}
+
+fn apply_edit_and_command(
+    meta: EditorMeta,
+    edit: WorkspaceEdit,
+    command: Option<Command>,
+    ctx: &mut Context,
+) {
+    let after = AfterEdit {
+        // Goes through the editor to let it send the edited buffers to the server first.
+        command: command.map(|command| execute_command_editor_command(&command)),
+        request: None,
+    };
+    apply_edit(meta, edit, after, ctx);
+}
+
+/// Show the diff of the pending edit again.
+pub fn preview_pending_edit(meta: EditorMeta, _params: EditorParams, ctx: &mut Context) {
+    let preview = match &ctx.pending_edit {
+        Some(pending) => workspace_edit_preview(&pending.edit, ctx).0,
+        None => return,
+    };
+    show_edit_preview(meta, preview, ctx);
+}
+
+/// Apply the pending edit unless buffers it touches have changed since its preview.
+pub fn confirm_pending_edit(meta: EditorMeta, _params: EditorParams, ctx: &mut Context) {
+    let pending = match ctx.pending_edit.take() {
+        Some(pending) => pending,
+        None => return,
+    };
+    let changed = pending.versions.iter().find(|(buffile, version)| {
+        ctx.documents
+            .get(*buffile)
+            .map_or(false, |document| document.version != **version)
+    });
+    if let Some((buffile, _)) = changed {
+        let msg = format!(
+            "{} has changed since the preview, request the edit again",
+            buffile
+        );
+        ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+        return;
+    }
+    apply_edit_and_command(meta, pending.edit, pending.command, ctx);
+}
+
+pub fn cancel_pending_edit(_meta: EditorMeta, _params: EditorParams, ctx: &mut Context) {
+    ctx.pending_edit = None;
+}
+
+/// Unified diff of the changes the edit would make, file operations being listed among them, and
+/// versions of the open documents it touches.
+fn workspace_edit_preview(edit: &WorkspaceEdit, ctx: &Context) -> (String, HashMap<String, i32>) {
+    let mut operations: Vec<DocumentChangeOperation> = match &edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => edits
+            .iter()
+            .cloned()
+            .map(DocumentChangeOperation::Edit)
+            .collect(),
+        Some(DocumentChanges::Operations(ops)) => ops.clone(),
+        None => vec![],
+    };
+    if let Some(changes) = &edit.changes {
+        operations.extend(changes.iter().sorted_by_key(|(uri, _)| uri.as_str()).map(
+            |(uri, edits)| {
+                DocumentChangeOperation::Edit(TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier {
+                        uri: uri.clone(),
+                        version: None,
+                    },
+                    edits: edits.iter().cloned().map(OneOf::Left).collect(),
+                })
+            },
+        ));
+    }
+
+    let mut preview = String::new();
+    let mut versions = HashMap::new();
+    // Contents of files as edited so far, so that later operations build on earlier ones.
+    let mut contents: HashMap<PathBuf, Option<Rope>> = HashMap::new();
+    for op in operations {
+        match op {
+            DocumentChangeOperation::Edit(edit) => {
+                let path = match edit.text_document.uri.to_file_path() {
+                    Ok(path) => path,
+                    Err(_) => continue,
+                };
+                let buffile = path.to_str().unwrap();
+                if let Some(document) = ctx.documents.get(buffile) {
+                    versions.insert(buffile.to_string(), document.version);
+                }
+                let text = contents
+                    .entry(path.clone())
+                    .or_insert_with(|| get_file_contents(buffile, ctx))
+                    .clone()
+                    .unwrap_or_default();
+                let new_text = match edited_text(&text, &edit.edits, ctx.offset_encoding) {
+                    Ok(new_text) => new_text,
+                    Err(e) => {
+                        preview.push_str(&format!("# Failed to edit {}: {}\n", buffile, e));
+                        continue;
+                    }
+                };
+                preview.push_str(&unified_diff(buffile, &text.to_string(), &new_text));
+                contents.insert(path, Some(Rope::from_str(&new_text)));
+            }
+            DocumentChangeOperation::Op(ResourceOp::Create(op)) => {
+                if let Ok(path) = op.uri.to_file_path() {
+                    preview.push_str(&format!("# Create {}\n", path.display()));
+                    contents.insert(path, Some(Rope::new()));
+                }
+            }
+            DocumentChangeOperation::Op(ResourceOp::Rename(op)) => {
+                if let (Ok(from), Ok(to)) = (op.old_uri.to_file_path(), op.new_uri.to_file_path()) {
+                    preview.push_str(&format!(
+                        "# Rename {} to {}\n",
+                        from.display(),
+                        to.display()
+                    ));
+                    let text = contents
+                        .remove(&from)
+                        .unwrap_or_else(|| get_file_contents(from.to_str().unwrap(), ctx));
+                    contents.insert(from, None);
+                    contents.insert(to, text);
+                }
+            }
+            DocumentChangeOperation::Op(ResourceOp::Delete(op)) => {
+                if let Ok(path) = op.uri.to_file_path() {
+                    preview.push_str(&format!("# Delete {}\n", path.display()));
+                    contents.insert(path, None);
+                }
+            }
+        }
+    }
+    (preview, versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_sync::text_document_did_open;
+    use crossbeam_channel::{unbounded, Receiver};
+
+    fn meta(version: i32) -> EditorMeta {
+        EditorMeta {
+            session: "session".to_string(),
+            client: Some("client0".to_string()),
+            buffile: "/src/main.rs".to_string(),
+            filetype: "rust".to_string(),
+            version,
+            fifo: None,
+            fan_out: None,
+        }
+    }
+
+    fn params(value: &str) -> EditorParams {
+        toml::from_str(value).unwrap()
+    }
+
+    fn context(edit_chunk_size: usize) -> (Context, Receiver<EditorResponse>) {
+        let (lang_srv_tx, _lang_srv_rx) = unbounded();
+        let (editor_tx, editor_rx) = unbounded();
+        let initial_request = EditorRequest {
+            meta: meta(1),
+            method: "initialize".to_string(),
+            params: params(""),
+            debounce: 0,
+            server: None,
+        };
+        let mut config: Config = toml::from_str("[language]").unwrap();
+        config.edit_chunk_size = edit_chunk_size;
+        let mut ctx = Context::new(
+            "rust",
+            initial_request,
+            lang_srv_tx,
+            editor_tx,
+            config,
+            "/src".to_string(),
+            OffsetEncoding::Utf16,
+        );
+        ctx.capabilities = Some(ServerCapabilities::default());
+        text_document_did_open(meta(1), params(r#"draft = "foo\nbar\nbaz\n""#), &mut ctx);
+        (ctx, editor_rx)
+    }
+
+    /// Edit capitalizing each line of the buffer.
+    fn capitalizing_edit() -> WorkspaceEdit {
+        let edit = |line, new_text: &str| TextEdit {
+            range: Range::new(Position::new(line, 0), Position::new(line, 1)),
+            new_text: new_text.to_string(),
+        };
+        let uri = Url::from_file_path("/src/main.rs").unwrap();
+        let mut changes = HashMap::new();
+        changes.insert(uri, vec![edit(0, "F"), edit(1, "B"), edit(2, "B")]);
+        WorkspaceEdit::new(changes)
+    }
+
+    #[test]
+    fn chunks_are_sent_once_the_previous_one_is_applied() {
+        let (mut ctx, editor_rx) = context(2);
+        apply_edit(meta(1), capitalizing_edit(), AfterEdit::default(), &mut ctx);
+        let commands = editor_rx.try_iter().map(|r| r.command).collect::<Vec<_>>();
+        assert_eq!(commands.len(), 1);
+        // Chunks are nested in the command editing the buffer, hence quotes are doubled.
+        assert!(commands[0].starts_with("evaluate-commands -buffer '/src/main.rs' "));
+        assert!(commands[0].contains("lsp-apply-edit-chunk ''rust'' ''/src'' 1 1 "));
+        assert!(commands[0].contains("select 3.1,3.1\n"), "{}", commands[0]);
+
+        // The buffer is at the version the editor reports after the previous chunk.
+        apply_next_edit_chunk(meta(5), params("id = 1\napplied = true"), &mut ctx);
+        let commands = editor_rx.try_iter().map(|r| r.command).collect::<Vec<_>>();
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].contains("lsp-apply-edit-chunk ''rust'' ''/src'' 1 5 "));
+        assert!(
+            commands[0].contains("select 1.1,1.1 2.1,2.1\n"),
+            "{}",
+            commands[0]
+        );
+
+        apply_next_edit_chunk(meta(6), params("id = 1\napplied = true"), &mut ctx);
+        assert_eq!(editor_rx.try_iter().count(), 0);
+        assert!(ctx.chunked_edit.is_none());
+    }
+
+    #[test]
+    fn edit_stops_at_chunk_of_changed_buffer() {
+        let (mut ctx, editor_rx) = context(2);
+        apply_edit(meta(1), capitalizing_edit(), AfterEdit::default(), &mut ctx);
+        editor_rx.try_iter().count();
+        apply_next_edit_chunk(meta(2), params("id = 1\napplied = false"), &mut ctx);
+        let commands = editor_rx.try_iter().map(|r| r.command).collect::<Vec<_>>();
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].starts_with("lsp-show-error"), "{}", commands[0]);
+        assert!(ctx.chunked_edit.is_none());
+    }
+
+    #[test]
+    fn small_edits_are_not_chunked() {
+        let (mut ctx, editor_rx) = context(3);
+        apply_edit(meta(1), capitalizing_edit(), AfterEdit::default(), &mut ctx);
+        let commands = editor_rx.try_iter().map(|r| r.command).collect::<Vec<_>>();
+        assert_eq!(commands.len(), 1);
+        assert!(!commands[0].contains("lsp-apply-edit-chunk"));
+        assert!(ctx.chunked_edit.is_none());
+    }
 }